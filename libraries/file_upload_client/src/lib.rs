@@ -10,6 +10,7 @@ use reqwest::{Body, StatusCode};
 pub use sequeda_file_upload_common::{
     DownloadFileRequestUriParams, FileUpload, UploadFileRequestUriParams,
 };
+use sequeda_service_common::trace_context::propagate;
 use tokio_util::codec::{BytesCodec, FramedRead};
 const X_USER_INFO_HEADER: &str = "X-USER-INFO";
 pub const FILE_UPLOAD_ENDPOINT: &str = "FILE_UPLOAD_ENDPOINT";
@@ -85,9 +86,7 @@ impl FileUploadClient {
                     .unwrap_or_else(|| "false".into()),
             )
             .part("file", part);
-        let resp = self
-            .client
-            .post(format!("{}/upload", self.url))
+        let resp = propagate(self.client.post(format!("{}/upload", self.url)))
             .multipart(form)
             .header(X_USER_INFO_HEADER, x_user_info_header)
             .send()
@@ -111,12 +110,13 @@ impl FileUploadClient {
         x_user_info_header: &str,
         param: DownloadFileRequestUriParams,
     ) -> Result<FileUpload, Box<dyn Error>> {
-        let resp = self
-            .client
-            .get(format!("{}/metadata?id={}", self.url, param.id))
-            .header(X_USER_INFO_HEADER, x_user_info_header)
-            .send()
-            .await?;
+        let resp = propagate(
+            self.client
+                .get(format!("{}/metadata?id={}", self.url, param.id)),
+        )
+        .header(X_USER_INFO_HEADER, x_user_info_header)
+        .send()
+        .await?;
         if resp.status() == StatusCode::OK {
             let resp = resp.json().await?;
             Ok(resp)
@@ -136,12 +136,13 @@ impl FileUploadClient {
         x_user_info_header: &str,
         param: DownloadFileRequestUriParams,
     ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let resp = self
-            .client
-            .get(format!("{}/download?id={}", self.url, param.id))
-            .header(X_USER_INFO_HEADER, x_user_info_header)
-            .send()
-            .await?;
+        let resp = propagate(
+            self.client
+                .get(format!("{}/download?id={}", self.url, param.id)),
+        )
+        .header(X_USER_INFO_HEADER, x_user_info_header)
+        .send()
+        .await?;
         if resp.status() == StatusCode::OK {
             let resp = resp.bytes().await?;
 
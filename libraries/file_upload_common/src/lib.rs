@@ -1,7 +1,7 @@
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Eq, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FileUpload {
     #[serde(rename = "_id")]
@@ -16,6 +16,37 @@ pub struct FileUpload {
     pub size: u64,
     pub public_resource: bool,
     pub correlation_id: Option<String>,
+    /// starts at 1, incremented each time a file is uploaded with the same id
+    #[serde(default = "first_version")]
+    pub version: u32,
+    /// previous versions, the oldest first
+    #[serde(default)]
+    pub versions: Vec<FileVersion>,
+    /// id of the file this one was converted from
+    #[serde(default)]
+    pub converted_from: Option<String>,
+    /// tenant that uploaded a public file, the only one that can change it
+    #[serde(default)]
+    pub owner_tenant: Option<String>,
+}
+
+/// A previous content of a `FileUpload`
+#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileVersion {
+    pub version: u32,
+    /// when this version was uploaded
+    pub creation_date: NaiveDateTime,
+    pub content_type: Option<String>,
+    pub thumbnail_id: Option<String>,
+    pub original_filename: String,
+    pub internal_name: String,
+    pub extension: Option<String>,
+    pub size: u64,
+}
+
+fn first_version() -> u32 {
+    1
 }
 
 impl FileUpload {
@@ -25,6 +56,44 @@ impl FileUpload {
             .filter(|ct| ct.starts_with("image"))
             .is_some()
     }
+
+    pub fn is_pdf(&self) -> bool {
+        self.content_type.as_deref() == Some("application/pdf")
+    }
+
+    /// the current content, as a version
+    pub fn current_version(&self) -> FileVersion {
+        FileVersion {
+            version: self.version,
+            creation_date: self.updated_date.unwrap_or(self.creation_date),
+            content_type: self.content_type.clone(),
+            thumbnail_id: self.thumbnail_id.clone(),
+            original_filename: self.original_filename.clone(),
+            internal_name: self.internal_name.clone(),
+            extension: self.extension.clone(),
+            size: self.size,
+        }
+    }
+
+    /// the file as it was at `version`
+    pub fn at_version(&self, version: u32) -> Option<FileUpload> {
+        if version == self.version {
+            return Some(self.clone());
+        }
+        let v = self.versions.iter().find(|v| v.version == version)?;
+        Some(FileUpload {
+            updated_date: Some(v.creation_date),
+            content_type: v.content_type.clone(),
+            thumbnail_id: v.thumbnail_id.clone(),
+            original_filename: v.original_filename.clone(),
+            internal_name: v.internal_name.clone(),
+            extension: v.extension.clone(),
+            size: v.size,
+            version: v.version,
+            versions: vec![],
+            ..self.clone()
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,7 +103,150 @@ pub struct UploadFileRequestUriParams {
     pub is_public: Option<bool>,
 }
 
+/// Starts a resumable upload, the content is then sent in chunks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateUploadSessionRequest {
+    pub filename: String,
+    /// total size in bytes
+    pub size: u64,
+    pub content_type: Option<String>,
+    /// hex encoded sha256 of the whole file, checked when the upload is finalized
+    pub checksum: Option<String>,
+    /// id of an existing file to add a version to
+    pub id: Option<String>,
+    pub is_public: Option<bool>,
+    pub correlation_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadSessionStatus {
+    pub upload_id: String,
+    /// number of bytes received, where the next chunk starts
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ListFilesRequestUriParams {
+    /// start at 0
+    pub page: Option<i64>,
+    pub limit: Option<i64>,
+    /// exact, or a prefix followed by a wildcard, e.g. `image/*`
+    pub content_type: Option<String>,
+    pub correlation_id: Option<String>,
+    pub created_after: Option<NaiveDateTime>,
+    pub created_before: Option<NaiveDateTime>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+/// either `id`, or `correlation_id` to delete every file attached to it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteFileRequestUriParams {
+    pub id: Option<String>,
+    pub correlation_id: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DownloadFileRequestUriParams {
     pub id: String,
+    /// default to the current version
+    pub version: Option<u32>,
+    /// default to inline for the images, attachment otherwise
+    pub disposition: Option<Disposition>,
+    /// token of a share link, replaces the user session
+    pub token: Option<String>,
+}
+
+/// A signed download url of a private file, for someone without an account
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateShareLinkRequest {
+    /// id of the file
+    pub id: String,
+    /// default to the current version at the time of the download
+    pub version: Option<u32>,
+    /// default to 24
+    pub expires_in_hours: Option<i64>,
+    /// unlimited if not set
+    pub max_downloads: Option<u32>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLinkInfo {
+    pub link_id: String,
+    pub file_id: String,
+    pub version: Option<u32>,
+    /// relative to the upload service, e.g. `/download?id=..&token=..`
+    pub url: String,
+    pub token: String,
+    pub creation_date: NaiveDateTime,
+    pub expiration_date: NaiveDateTime,
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    pub password_protected: bool,
+}
+
+/// either a preset, or the dimensions of the image to generate
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImageRequestUriParams {
+    pub preset: Option<String>,
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fit: Option<ImageFit>,
+    pub format: Option<ImageOutputFormat>,
+    /// page of a pdf, start at 1
+    pub page: Option<u32>,
+    pub version: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFit {
+    /// within the dimensions, keeping the ratio
+    #[default]
+    Contain,
+    /// fills the dimensions, keeping the ratio and cropping the overflow
+    Cover,
+    /// fills the dimensions, without keeping the ratio
+    Fill,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageOutputFormat {
+    #[default]
+    Webp,
+    Avif,
+    Png,
+    Jpeg,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConvertFileRequestUriParams {
+    pub id: String,
+    pub to: ConvertFormat,
+    /// default to the current version
+    pub version: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConvertFormat {
+    Pdf,
+    Png,
+    Docx,
+    Odt,
+    Html,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposition {
+    Inline,
+    Attachment,
 }
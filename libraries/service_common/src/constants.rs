@@ -8,3 +8,13 @@ pub const SERVICE_APPLICATION_NAME: &str = "SERVICE_APPLICATION_NAME";
 pub const SERVICE_COLLECTION_NAME: &str = "SERVICE_COLLECTION_NAME";
 pub const X_USER_INFO_HEADER: &str = "X-USER-INFO";
 pub const PUBLIC_TENANT: &str = "public";
+pub const USER_INFO_SIGNING_KEY: &str = "USER_INFO_SIGNING_KEY";
+pub const USER_INFO_SIGNING_KEY_ID: &str = "USER_INFO_SIGNING_KEY_ID";
+pub const USER_INFO_TOKEN_TTL: &str = "USER_INFO_TOKEN_TTL";
+pub const USER_INFO_PUBLIC_KEYS: &str = "USER_INFO_PUBLIC_KEYS";
+pub const USER_INFO_JWKS_URL: &str = "USER_INFO_JWKS_URL";
+pub const USER_INFO_AUDIENCE: &str = "USER_INFO_AUDIENCE";
+pub const USER_INFO_ALLOW_UNSIGNED: &str = "USER_INFO_ALLOW_UNSIGNED";
+pub const X_REQUEST_ID_HEADER: &str = "X-Request-Id";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+pub const OTEL_EXPORTER_OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
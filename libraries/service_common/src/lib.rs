@@ -1,10 +1,16 @@
 pub mod common_domain_types;
 mod constants;
+#[cfg(feature = "otel")]
+mod otel;
+pub mod trace_context;
 pub mod user_header;
+pub mod user_token;
 pub use constants::{
-    BODY_SIZE_LIMIT, CORS_ALLOW_ORIGIN, PUBLIC_TENANT, SERVICE_APPLICATION_NAME,
-    SERVICE_COLLECTION_NAME, SERVICE_CONFIG_VOLUME, SERVICE_DATA_VOLUME, SERVICE_HOST,
-    SERVICE_PORT, X_USER_INFO_HEADER,
+    BODY_SIZE_LIMIT, CORS_ALLOW_ORIGIN, OTEL_EXPORTER_OTLP_ENDPOINT, PUBLIC_TENANT,
+    SERVICE_APPLICATION_NAME, SERVICE_COLLECTION_NAME, SERVICE_CONFIG_VOLUME, SERVICE_DATA_VOLUME,
+    SERVICE_HOST, SERVICE_PORT, TRACEPARENT_HEADER, USER_INFO_ALLOW_UNSIGNED, USER_INFO_AUDIENCE,
+    USER_INFO_JWKS_URL, USER_INFO_PUBLIC_KEYS, USER_INFO_SIGNING_KEY, USER_INFO_SIGNING_KEY_ID,
+    USER_INFO_TOKEN_TTL, X_REQUEST_ID_HEADER, X_USER_INFO_HEADER,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
@@ -12,6 +18,16 @@ use tracing::Level;
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 pub fn setup_tracing() {
+    #[cfg(feature = "otel")]
+    if let Some(otel) = otel::layer() {
+        use tracing_subscriber::prelude::*;
+        tracing_subscriber::registry()
+            .with(EnvFilter::from_default_env())
+            .with(tracing_subscriber::fmt::layer())
+            .with(otel)
+            .init();
+        return;
+    }
     let subscriber = FmtSubscriber::builder()
         .with_max_level(Level::TRACE)
         .with_env_filter(EnvFilter::from_default_env())
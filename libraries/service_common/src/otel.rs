@@ -0,0 +1,62 @@
+use opentelemetry::{
+    trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider,
+    },
+    Context,
+};
+use opentelemetry_sdk::{runtime, trace::Tracer};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::{constants::OTEL_EXPORTER_OTLP_ENDPOINT, trace_context::TraceContext};
+
+/// exports the spans to the OTLP/HTTP collector at `OTEL_EXPORTER_OTLP_ENDPOINT`.
+/// The service is named with `OTEL_SERVICE_NAME`
+pub fn layer<S>() -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    std::env::var(OTEL_EXPORTER_OTLP_ENDPOINT).ok()?;
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("could not create the OpenTelemetry exporter: {e}");
+            return None;
+        }
+    };
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .build();
+    let tracer = provider.tracer("sequeda");
+    opentelemetry::global::set_tracer_provider(provider);
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// continues the trace of the caller, then adopts the ids of the exported span
+/// so that the next hops become its children
+pub(crate) fn link(span: &tracing::Span, trace_context: &mut TraceContext) {
+    if let Some(parent_id) = &trace_context.parent_id {
+        if let (Ok(trace_id), Ok(parent_id)) = (
+            TraceId::from_hex(&trace_context.trace_id),
+            SpanId::from_hex(parent_id),
+        ) {
+            let flags = if trace_context.sampled {
+                TraceFlags::SAMPLED
+            } else {
+                TraceFlags::default()
+            };
+            let parent = SpanContext::new(trace_id, parent_id, flags, true, TraceState::default());
+            span.set_parent(Context::new().with_remote_span_context(parent));
+        }
+    }
+    let context = span.context();
+    let span_context = context.span().span_context().clone();
+    if span_context.is_valid() {
+        trace_context.trace_id = span_context.trace_id().to_string();
+        trace_context.span_id = span_context.span_id().to_string();
+    }
+}
@@ -0,0 +1,229 @@
+use std::future::Future;
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Instrument;
+
+use crate::constants::{TRACEPARENT_HEADER, X_REQUEST_ID_HEADER};
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// Request id and W3C trace context (`traceparent`) of a request, propagated to the services it calls,
+/// so that a request can be followed from the gateway through invoice, template and file_upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub request_id: String,
+    /// 32 hex digits
+    pub trace_id: String,
+    /// span of the caller, none when the trace starts here
+    pub parent_id: Option<String>,
+    /// 16 hex digits, the parent of the calls made while handling the request
+    pub span_id: String,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// continues the trace of the incoming request, or starts a new one
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let request_id = headers
+            .get(X_REQUEST_ID_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.trim())
+            .filter(|h| valid_request_id(h))
+            .map(|h| h.to_string());
+        let traceparent = headers
+            .get(TRACEPARENT_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(parse_traceparent);
+        let (trace_id, parent_id, sampled) = match traceparent {
+            Some((trace_id, parent_id, sampled)) => (trace_id, Some(parent_id), sampled),
+            None => (random_hex(32), None, true),
+        };
+        TraceContext {
+            request_id: request_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            trace_id,
+            parent_id,
+            span_id: random_hex(16),
+            sampled,
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        let flags = if self.sampled { "01" } else { "00" };
+        format!("00-{}-{}-{flags}", self.trace_id, self.span_id)
+    }
+
+    /// sets `X-Request-Id` and `traceparent` for the next hop
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        if let Ok(request_id) = HeaderValue::from_str(&self.request_id) {
+            headers.insert(X_REQUEST_ID_HEADER, request_id);
+        }
+        if let Ok(traceparent) = HeaderValue::from_str(&self.traceparent()) {
+            headers.insert(TRACEPARENT_HEADER, traceparent);
+        }
+    }
+
+    /// span of the request. When exported to OpenTelemetry, the span continues the incoming trace,
+    /// and the calls made while handling the request become its children
+    pub fn span(&mut self) -> tracing::Span {
+        let span = tracing::info_span!(
+            "request",
+            request_id = %self.request_id,
+            trace_id = tracing::field::Empty
+        );
+        #[cfg(feature = "otel")]
+        crate::otel::link(&span, self);
+        span.record("trace_id", self.trace_id.as_str());
+        span
+    }
+
+    /// the trace context of the request being handled by the current task
+    pub fn current() -> Option<TraceContext> {
+        CURRENT.try_with(|trace_context| trace_context.clone()).ok()
+    }
+
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+}
+
+/// adds the trace headers of the current request to a call to another service
+pub fn propagate(builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match TraceContext::current() {
+        Some(trace_context) => builder
+            .header(X_REQUEST_ID_HEADER, trace_context.request_id.as_str())
+            .header(TRACEPARENT_HEADER, trace_context.traceparent()),
+        None => builder,
+    }
+}
+
+/// middleware of the services: reads the trace context, logs within the span of the request,
+/// and answers with the request id
+pub async fn trace_context(req: Request, next: Next) -> Response {
+    let mut trace_context = TraceContext::from_headers(req.headers());
+    let span = trace_context.span();
+    let request_id = trace_context.request_id.clone();
+    let mut response = trace_context.scope(next.run(req).instrument(span)).await;
+    if let Ok(request_id) = HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(X_REQUEST_ID_HEADER, request_id);
+    }
+    response
+}
+
+/// an id chosen by the client is kept only when it is safe to log and forward
+fn valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LEN
+        && request_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// `00-<trace id>-<parent id>-<flags>`, see https://www.w3.org/TR/trace-context/#traceparent-header
+fn parse_traceparent(traceparent: &str) -> Option<(String, String, bool)> {
+    let mut parts = traceparent.trim().split('-');
+    let (version, trace_id, parent_id, flags) =
+        (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let is_hex = |s: &str, len: usize| {
+        s.len() == len && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+    };
+    let all_zeros = |s: &str| s.chars().all(|c| c == '0');
+    if !is_hex(version, 2)
+        || version == "ff"
+        // a future version may add fields, version 00 has none
+        || (version == "00" && parts.next().is_some())
+        || !is_hex(trace_id, 32)
+        || all_zeros(trace_id)
+        || !is_hex(parent_id, 16)
+        || all_zeros(parent_id)
+        || !is_hex(flags, 2)
+    {
+        return None;
+    }
+    let sampled = u8::from_str_radix(flags, 16).ok()? & 1 == 1;
+    Some((trace_id.to_string(), parent_id.to_string(), sampled))
+}
+
+fn random_hex(len: usize) -> String {
+    let mut hex = String::with_capacity(len);
+    while hex.len() < len {
+        hex.push_str(&uuid::Uuid::new_v4().simple().to_string());
+    }
+    hex.truncate(len);
+    hex
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderMap;
+
+    use super::{parse_traceparent, TraceContext};
+
+    #[tokio::test]
+    async fn test_trace_context() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Request-Id", "req-42".parse().unwrap());
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        let trace_context = TraceContext::from_headers(&headers);
+        assert_eq!("req-42", trace_context.request_id);
+        assert_eq!("4bf92f3577b34da6a3ce929d0e0e4736", trace_context.trace_id);
+        assert_eq!(Some("00f067aa0ba902b7"), trace_context.parent_id.as_deref());
+        assert_ne!("00f067aa0ba902b7", trace_context.span_id);
+        assert!(trace_context.sampled);
+
+        let mut next_hop = HeaderMap::new();
+        trace_context.insert_headers(&mut next_hop);
+        let next = TraceContext::from_headers(&next_hop);
+        assert_eq!(trace_context.request_id, next.request_id);
+        assert_eq!(trace_context.trace_id, next.trace_id);
+        assert_eq!(Some(trace_context.span_id.clone()), next.parent_id);
+
+        // invalid headers start a new trace
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Request-Id", "<script>".parse().unwrap());
+        headers.insert("traceparent", "00-xyz-00f067aa0ba902b7-01".parse().unwrap());
+        let new = TraceContext::from_headers(&headers);
+        assert_ne!("<script>", new.request_id);
+        assert_eq!(None, new.parent_id);
+        assert_eq!(32, new.trace_id.len());
+        assert_eq!(16, new.span_id.len());
+
+        assert!(
+            parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none()
+        );
+        assert!(
+            parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x")
+                .is_none()
+        );
+        assert_eq!(
+            Some(false),
+            parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-x")
+                .map(|(_, _, sampled)| sampled)
+        );
+
+        assert_eq!(None, TraceContext::current());
+        let current = trace_context
+            .clone()
+            .scope(async { TraceContext::current() })
+            .await;
+        assert_eq!(Some(trace_context), current);
+    }
+}
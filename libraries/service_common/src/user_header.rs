@@ -7,7 +7,7 @@ use base64::Engine;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::constants::X_USER_INFO_HEADER;
+use crate::{constants::X_USER_INFO_HEADER, user_token::UserTokenVerifier};
 
 pub struct ExtractUserInfo {
     pub user_info: UserInfo,
@@ -36,33 +36,47 @@ where
     type Rejection = (StatusCode, axum::Json<serde_json::Value>);
 
     async fn from_request_parts(req: &mut Parts, _state: &B) -> Result<Self, Self::Rejection> {
-        if let Some(user_info) = req.headers.get(X_USER_INFO_HEADER) {
-            match user_info
-                .to_str()
+        let Some(header) = req
+            .headers
+            .get(X_USER_INFO_HEADER)
+            .and_then(|u| u.to_str().ok())
+            .filter(|u| !u.trim().is_empty())
+        else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                Json(json!({"error":"X-USER-INFO is missing"})),
+            ));
+        };
+        let verifier = UserTokenVerifier::global();
+        // a signed token always has 3 parts, the legacy base64 json never contains a dot
+        if !header.contains('.') && verifier.allow_unsigned() {
+            return base64::engine::general_purpose::STANDARD
+                .decode(header)
                 .ok()
-                .filter(|u| !u.trim().is_empty())
-                .and_then(|u| {
-                    base64::engine::general_purpose::STANDARD
-                        .decode(u)
-                        .map(|b| (u.to_string(), b))
-                        .ok()
+                .and_then(|d| serde_json::from_slice::<UserInfo>(&d).ok())
+                .map(|user_info| ExtractUserInfo {
+                    user_info,
+                    header: header.to_string(),
                 })
-                .and_then(|(e, d)| {
-                    serde_json::from_slice::<UserInfo>(&d)
-                        .map(|des| (e, des))
-                        .ok()
-                }) {
-                Some((header, user_info)) => Ok(ExtractUserInfo { user_info, header }),
-                _ => Err((
-                    StatusCode::BAD_REQUEST,
+                .ok_or_else(|| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({"error":"X-USER-INFO is invalid"})),
+                    )
+                });
+        }
+        match verifier.verify::<UserInfo>(header).await {
+            Ok(user_info) => Ok(ExtractUserInfo {
+                user_info,
+                header: header.to_string(),
+            }),
+            Err(e) => {
+                tracing::warn!("invalid X-USER-INFO: {e}");
+                Err((
+                    StatusCode::UNAUTHORIZED,
                     Json(json!({"error":"X-USER-INFO is invalid"})),
-                )),
+                ))
             }
-        } else {
-            Err((
-                StatusCode::FORBIDDEN,
-                Json(json!({"error":"X-USER-INFO is missing"})),
-            ))
         }
     }
 }
@@ -126,7 +126,10 @@ pub trait Repository<T: Serialize + DeserializeOwned + Unpin + Send + Sync> {
         } else {
             doc! {}
         };
-        let count = self.count().await? as i64;
+        let count = collection
+            .count_documents(query.clone())
+            .await
+            .map_err(|e| StoreError { msg: e.to_string() })? as i64;
         let skip = pageable.limit * pageable.page; // start at page 0
         if count <= skip {
             return Ok(Some(Page {
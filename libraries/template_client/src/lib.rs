@@ -1,6 +1,7 @@
 use std::{env, error::Error, sync::Arc};
 
 use reqwest::StatusCode;
+use sequeda_service_common::trace_context::propagate;
 use sequeda_template_common::Template;
 pub use sequeda_template_common::{Context, RenderRequest};
 const X_USER_INFO_HEADER: &str = "X-USER-INFO";
@@ -28,9 +29,7 @@ impl TemplateClient {
         x_user_info_header: &str,
         render_request: &RenderRequest,
     ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let resp = self
-            .client
-            .get(format!("{}/render", self.url))
+        let resp = propagate(self.client.get(format!("{}/render", self.url)))
             .header(X_USER_INFO_HEADER, x_user_info_header)
             .json(render_request)
             .send()
@@ -54,9 +53,7 @@ impl TemplateClient {
         x_user_info_header: &str,
         id: &str,
     ) -> Result<Template, Box<dyn Error>> {
-        let resp = self
-            .client
-            .get(format!("{}/find-one/{}", self.url, id))
+        let resp = propagate(self.client.get(format!("{}/find-one/{}", self.url, id)))
             .header(X_USER_INFO_HEADER, x_user_info_header)
             .send()
             .await?;
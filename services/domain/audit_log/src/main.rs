@@ -10,14 +10,15 @@ use std::{
 };
 
 use axum::{
-    extract::Query, http::StatusCode, response::IntoResponse, routing::get, Extension, Json, Router,
+    extract::Query, http::StatusCode, middleware, response::IntoResponse, routing::get, Extension,
+    Json, Router,
 };
 use entity::{AuditLog, AuditLogConfig};
 use sequeda_message_client::{Exchange, MessageClient};
 use sequeda_service_common::{
-    setup_tracing, user_header::ExtractUserInfo, StoreCollection, PUBLIC_TENANT,
-    SERVICE_APPLICATION_NAME, SERVICE_COLLECTION_NAME, SERVICE_CONFIG_VOLUME, SERVICE_HOST,
-    SERVICE_PORT,
+    setup_tracing, trace_context::trace_context, user_header::ExtractUserInfo, StoreCollection,
+    PUBLIC_TENANT, SERVICE_APPLICATION_NAME, SERVICE_COLLECTION_NAME, SERVICE_CONFIG_VOLUME,
+    SERVICE_HOST, SERVICE_PORT,
 };
 use sequeda_store::{Pageable, Repository, StoreClient, StoreRepository};
 use serde_json::json;
@@ -73,7 +74,8 @@ async fn main() {
         let router = Router::new()
             .route("/find-all", get(find_all))
             .layer(Extension(StoreCollection(collection_name)))
-            .layer(Extension(db_client));
+            .layer(Extension(db_client))
+            .layer(middleware::from_fn(trace_context));
         let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
 
         axum::serve(listener, router.into_make_service())
@@ -0,0 +1,295 @@
+use axum::http::{header, HeaderMap, HeaderName};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use sequeda_file_upload_common::{Disposition, FileUpload};
+use sha2::{Digest, Sha256};
+
+/// the content of a version never changes, a new upload creates a new version
+pub fn etag(file: &FileUpload) -> String {
+    let hash = Sha256::digest(format!("{}:{}:{}", file.id, file.internal_name, file.size));
+    let hash: String = hash[..12].iter().map(|b| format!("{b:02x}")).collect();
+    format!("\"{hash}\"")
+}
+
+pub fn last_modified(file: &FileUpload) -> Option<DateTime<Utc>> {
+    let date = file.updated_date.unwrap_or(file.creation_date);
+    Local
+        .from_local_datetime(&date)
+        .earliest()
+        .map(|d| d.with_timezone(&Utc))
+}
+
+pub fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// `If-None-Match` wins over `If-Modified-Since`
+pub fn not_modified(
+    headers: &HeaderMap,
+    etag: &str,
+    last_modified: Option<&DateTime<Utc>>,
+) -> bool {
+    if let Some(if_none_match) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+    {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    let if_modified_since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| DateTime::parse_from_rfc2822(h).ok());
+    match (if_modified_since, last_modified) {
+        (Some(since), Some(last_modified)) => last_modified.timestamp() <= since.timestamp(),
+        _ => false,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    Full,
+    Partial { start: u64, len: u64 },
+    Unsatisfiable,
+}
+
+/// a single range is supported, the whole file is sent otherwise.
+/// with `If-Range`, the range is only sent if the file did not change
+pub fn byte_range(headers: &HeaderMap, size: u64, etag: &str) -> ByteRange {
+    let Some(range) = headers.get(header::RANGE).and_then(|h| h.to_str().ok()) else {
+        return ByteRange::Full;
+    };
+    if let Some(if_range) = headers.get(header::IF_RANGE).and_then(|h| h.to_str().ok()) {
+        if if_range != etag {
+            return ByteRange::Full;
+        }
+    }
+    let Some(range) = range.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if range.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = range.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    let (start, end) = if start.is_empty() {
+        // the last `end` bytes
+        match end.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (size.saturating_sub(suffix), size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return ByteRange::Full;
+        };
+        let end = match end {
+            "" => size.saturating_sub(1),
+            end => match end.parse::<u64>() {
+                Ok(end) if end >= start => end.min(size.saturating_sub(1)),
+                _ => return ByteRange::Full,
+            },
+        };
+        (start, end)
+    };
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial {
+        start,
+        len: end - start + 1,
+    }
+}
+
+/// the types a browser displays without running a script of the file
+const INLINE_CONTENT_TYPES: [&str; 8] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "image/bmp",
+    "image/tiff",
+    "application/pdf",
+];
+
+/// inline only for the raster images, and the pdf when asked. Anything else,
+/// e.g. html or svg, is an attachment so that it does not run in the origin of the app
+pub fn disposition(requested: Option<Disposition>, content_type: Option<&str>) -> Disposition {
+    let content_type = essence(content_type);
+    let inline = content_type
+        .as_deref()
+        .is_some_and(|ct| INLINE_CONTENT_TYPES.contains(&ct));
+    match requested {
+        Some(Disposition::Inline) if inline => Disposition::Inline,
+        None if inline && content_type.is_some_and(|ct| ct.starts_with("image/")) => {
+            Disposition::Inline
+        }
+        _ => Disposition::Attachment,
+    }
+}
+
+/// the uploaded content must not be run as a page of the app. The browsers do not render
+/// a pdf in a sandboxed document, an inline pdf only gets its plugin
+pub fn security_headers(
+    disposition: Disposition,
+    content_type: Option<&str>,
+) -> [(HeaderName, String); 2] {
+    let csp = match (disposition, essence(content_type).as_deref()) {
+        (Disposition::Inline, Some("application/pdf")) => "default-src 'none'; object-src 'self'",
+        _ => "sandbox",
+    };
+    [
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        (header::CONTENT_SECURITY_POLICY, csp.to_string()),
+    ]
+}
+
+/// the lowercase type, without the parameters
+fn essence(content_type: Option<&str>) -> Option<String> {
+    content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|ct| ct.trim().to_ascii_lowercase())
+}
+
+/// with an ascii `filename` for the old clients, and the RFC 5987 `filename*`
+pub fn content_disposition(disposition: Disposition, filename: &str) -> String {
+    let disposition = match disposition {
+        Disposition::Inline => "inline",
+        Disposition::Attachment => "attachment",
+    };
+    let ascii: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect();
+    format!("{disposition}; filename=\"{ascii}\"; filename*=UTF-8''{encoded}")
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::{header, HeaderMap, HeaderValue};
+    use sequeda_file_upload_common::Disposition;
+
+    use super::{
+        byte_range, content_disposition, disposition, not_modified, security_headers, ByteRange,
+    };
+
+    #[test]
+    fn test_download_headers() {
+        let headers = |name, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+        let range = |value| byte_range(&headers(header::RANGE, value), 1000, "\"e\"");
+        assert_eq!(
+            ByteRange::Partial { start: 0, len: 100 },
+            range("bytes=0-99")
+        );
+        assert_eq!(
+            ByteRange::Partial {
+                start: 900,
+                len: 100
+            },
+            range("bytes=900-")
+        );
+        assert_eq!(
+            ByteRange::Partial {
+                start: 990,
+                len: 10
+            },
+            range("bytes=-10")
+        );
+        assert_eq!(
+            ByteRange::Partial {
+                start: 500,
+                len: 500
+            },
+            range("bytes=500-5000")
+        );
+        assert_eq!(ByteRange::Unsatisfiable, range("bytes=1000-"));
+        assert_eq!(ByteRange::Full, range("bytes=0-1,5-6"));
+        assert_eq!(ByteRange::Full, range("lines=1-2"));
+        let mut if_range = headers(header::RANGE, "bytes=0-99");
+        if_range.insert(header::IF_RANGE, HeaderValue::from_static("\"old\""));
+        assert_eq!(ByteRange::Full, byte_range(&if_range, 1000, "\"e\""));
+
+        assert!(not_modified(
+            &headers(header::IF_NONE_MATCH, "\"a\", W/\"e\""),
+            "\"e\"",
+            None
+        ));
+        assert!(!not_modified(
+            &headers(header::IF_NONE_MATCH, "\"a\""),
+            "\"e\"",
+            None
+        ));
+
+        assert_eq!(
+            "attachment; filename=\"facture-_t_.pdf\"; filename*=UTF-8''facture-%C3%A9t%C3%A9.pdf",
+            content_disposition(Disposition::Attachment, "facture-été.pdf")
+        );
+
+        assert_eq!(Disposition::Inline, disposition(None, Some("image/PNG")));
+        assert_eq!(
+            Disposition::Attachment,
+            disposition(None, Some("application/pdf"))
+        );
+        assert_eq!(
+            Disposition::Inline,
+            disposition(Some(Disposition::Inline), Some("application/pdf; q=1"))
+        );
+        assert_eq!(
+            Disposition::Attachment,
+            disposition(Some(Disposition::Inline), Some("image/svg+xml"))
+        );
+        assert_eq!(
+            Disposition::Attachment,
+            disposition(Some(Disposition::Inline), Some("text/html"))
+        );
+        assert_eq!(
+            Disposition::Attachment,
+            disposition(Some(Disposition::Inline), None)
+        );
+
+        let csp = |disposition, content_type| {
+            security_headers(disposition, Some(content_type))
+                .into_iter()
+                .find(|(name, _)| name == header::CONTENT_SECURITY_POLICY)
+                .map(|(_, value)| value)
+                .unwrap()
+        };
+        assert_eq!(
+            [
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+                (
+                    header::CONTENT_SECURITY_POLICY,
+                    "default-src 'none'; object-src 'self'".to_string()
+                ),
+            ],
+            security_headers(Disposition::Inline, Some("application/pdf"))
+        );
+        assert_eq!("sandbox", csp(Disposition::Attachment, "application/pdf"));
+        assert_eq!("sandbox", csp(Disposition::Inline, "image/png"));
+        assert_eq!("sandbox", csp(Disposition::Attachment, "text/html"));
+    }
+}
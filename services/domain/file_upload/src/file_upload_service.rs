@@ -1,42 +1,122 @@
 use std::{
+    env::var,
     io::{Cursor, Read, Seek, SeekFrom},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use chrono::Local;
+use chrono::{Duration, Local, NaiveDateTime};
 use image::{EncodableLayout, ImageFormat};
 use mime_guess::mime::IMAGE_PNG;
-use sequeda_file_upload_common::FileUpload;
+use sequeda_file_upload_common::{FileUpload, FileVersion, ListFilesRequestUriParams};
 use sequeda_service_common::common_domain_types::ServiceError;
-use sequeda_store::{Repository, StoreRepository};
-use tokio::fs::File;
+use sequeda_store::{doc, Document, Page, Pageable, Repository, StoreRepository};
 
-use crate::{make_default_file_upload, soffice::convert_to};
+use crate::{
+    make_default_file_upload,
+    pdftoppm::render_page,
+    renditions::{Renditions, Transform},
+    soffice::{convert_to, ConvertType},
+    storage::{Storage, StorageReader},
+};
 
 pub const SHARE_DRIVE_PATH: &str = "SHARE_DRIVE_PATH";
+/// number of previous versions kept for each file, default to 10
+pub const MAX_FILE_VERSIONS: &str = "MAX_FILE_VERSIONS";
+/// previous versions replaced for more than this number of days are removed
+pub const FILE_VERSION_MAX_AGE_DAYS: &str = "FILE_VERSION_MAX_AGE_DAYS";
 
 const THUMB_HEIGHT: u32 = 300;
 const THUMB_WIDTH: u32 = 300;
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug, Clone)]
+pub struct VersionRetention {
+    pub max_versions: usize,
+    pub max_age: Option<Duration>,
+}
+
+impl VersionRetention {
+    pub fn from_env() -> Self {
+        VersionRetention {
+            max_versions: var(MAX_FILE_VERSIONS)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+            max_age: var(FILE_VERSION_MAX_AGE_DAYS)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::days),
+        }
+    }
+
+    /// removes from `versions` (the oldest first) the ones to delete.
+    /// the age of a version is counted from the moment it was replaced
+    fn prune(
+        &self,
+        versions: &mut Vec<FileVersion>,
+        current_date: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Vec<FileVersion> {
+        let mut pruned = vec![];
+        if let Some(max_age) = self.max_age {
+            let replaced_dates: Vec<NaiveDateTime> = versions
+                .iter()
+                .skip(1)
+                .map(|v| v.creation_date)
+                .chain([current_date])
+                .collect();
+            let mut replaced_dates = replaced_dates.into_iter();
+            let (expired, kept): (Vec<_>, Vec<_>) = versions.drain(..).partition(|_| {
+                replaced_dates
+                    .next()
+                    .is_some_and(|replaced| now - replaced > max_age)
+            });
+            pruned.extend(expired);
+            *versions = kept;
+        }
+        if versions.len() > self.max_versions {
+            let excess = versions.len() - self.max_versions;
+            pruned.extend(versions.drain(..excess));
+        }
+        pruned
+    }
+}
 
 pub struct FileService<'a> {
-    pub share_drive_path: &'a str,
+    pub storage: &'a dyn Storage,
     pub store: &'a StoreRepository<FileUpload>,
+    pub retention: &'a VersionRetention,
 }
 
 impl FileService<'_> {
-    fn get_physical_path(&self, internal_name: &str) -> PathBuf {
-        PathBuf::from(self.share_drive_path).join(internal_name)
-    }
-
     async fn make_thumbnail(
         &self,
         upl: &FileUpload,
         internal_name: &str,
         temp_file_path: &PathBuf,
     ) -> Result<Option<String>, ServiceError> {
-        let (extension, thumb) = {
-            let (ct, image) = if !upl.is_image() {
-                match convert_to(temp_file_path, crate::soffice::ConvertType::Png).await {
+        let (content_type, extension, thumb) = {
+            let (ct, image) = if upl.is_pdf() {
+                let rendered =
+                    match render_page(temp_file_path, 1, THUMB_WIDTH.max(THUMB_HEIGHT)).await {
+                        Ok(bytes) => Ok(bytes),
+                        Err(e) => {
+                            tracing::warn!("pdftoppm failed, falling back to soffice: {e}");
+                            convert_to(temp_file_path, Some("pdf"), ConvertType::Png).await
+                        }
+                    };
+                match rendered {
+                    Ok(bytes) => image::load_from_memory(&bytes)
+                        .map_err(|e| ServiceError::from(&e))
+                        .map(|im| (Some(IMAGE_PNG.to_string()), im)),
+                    Err(e) => {
+                        tracing::error!("error converting file {}: {} ", upl.original_filename, e);
+                        return Ok(None);
+                    }
+                }
+            } else if !upl.is_image() {
+                match convert_to(temp_file_path, upl.extension.as_deref(), ConvertType::Png).await {
                     Ok(bytes) => image::load_from_memory(&bytes)
                         .map_err(|e| ServiceError::from(&e))
                         .map(|im| (Some(IMAGE_PNG.to_string()), im)),
@@ -60,7 +140,7 @@ impl FileService<'_> {
                 return Err(ServiceError("No Content type! Should not happen".into()));
             };
 
-            let Some(image_format) = ImageFormat::from_mime_type(ct) else {
+            let Some(image_format) = ImageFormat::from_mime_type(&ct) else {
                 return Err(ServiceError(
                     "Format cannot be transformed to thumbnail".into(),
                 ));
@@ -82,10 +162,12 @@ impl FileService<'_> {
             cursor
                 .read_to_end(&mut thumb)
                 .map_err(|e| ServiceError(format!("{e}")))?;
-            (upl.extension.clone(), thumb)
+            // the thumbnail of a document is a png
+            let extension = image_format.extensions_str().first().map(|e| e.to_string());
+            (ct, extension, thumb)
         };
         let thumbnail = FileUpload {
-            content_type: upl.content_type.clone(),
+            content_type: Some(content_type),
             thumbnail_id: None,
             original_filename: format!("thumb-{internal_name}"),
             internal_name: format!("thumb-{internal_name}"),
@@ -96,10 +178,10 @@ impl FileService<'_> {
             ..make_default_file_upload()
         };
 
-        let path_buf = PathBuf::from(&self.share_drive_path).join(&thumbnail.internal_name);
-        tracing::debug!("save thumbnail... {path_buf:?}");
+        tracing::debug!("save thumbnail... {}", thumbnail.internal_name);
 
-        tokio::fs::write(path_buf, thumb.as_bytes())
+        self.storage
+            .put_bytes(&thumbnail.internal_name, thumb.as_bytes().to_vec())
             .await
             .map_err(|e| ServiceError::from(&e))?;
 
@@ -111,69 +193,54 @@ impl FileService<'_> {
     }
 }
 impl FileService<'_> {
+    /// uploading a file with the id of an existing one adds a version
     pub async fn upload(
         &self,
         mut upl: FileUpload,
         temp_file_path: Option<&PathBuf>,
     ) -> Result<FileUpload, ServiceError> {
         if let Some(temp_file_path) = temp_file_path {
-            let upload = self
+            let previous = self
                 .store
                 .find_by_id(&upl.id)
                 .await
                 .map_err(|e| ServiceError::from(&e))?;
-            let (old_internal_name, old_thumbnail_id) = if let Some(upload) = upload {
-                (Some(upload.internal_name), upload.thumbnail_id)
+
+            let mut pruned = vec![];
+            if let Some(previous) = previous {
+                let now = Local::now().naive_local();
+                upl.creation_date = previous.creation_date;
+                upl.updated_date = Some(now);
+                upl.correlation_id = upl.correlation_id.or(previous.correlation_id.clone());
+                upl.version = previous.version + 1;
+                upl.versions = previous.versions.clone();
+                upl.versions.push(previous.current_version());
+                pruned = self.retention.prune(&mut upl.versions, now, now);
             } else {
-                (None, None)
-            };
-            let extension = &upl.extension;
-            let internal_name = format!(
-                "{}.{}",
-                upl.id,
-                extension.as_ref().cloned().unwrap_or_else(|| "".into())
-            );
-
-            if let Some(old_internal_name) = old_internal_name {
-                upl.updated_date = Some(Local::now().naive_local());
-                // override file
-                tracing::info!("removing old file {}", old_internal_name);
-                if let Err(e) = tokio::fs::remove_file(
-                    PathBuf::from(self.share_drive_path).join(&old_internal_name),
-                )
-                .await
-                {
-                    tracing::error!("could not remove old file: {e}");
-                }
-                if let Some(old_thumbnail_id) = old_thumbnail_id {
-                    self.store
-                        .delete_by_id(&old_thumbnail_id)
-                        .await
-                        .map_err(|e| ServiceError::from(&e))?;
-
-                    tracing::info!("removing old thumbnail {}", old_thumbnail_id);
-                    if let Err(e) = tokio::fs::remove_file(
-                        PathBuf::from(&self.share_drive_path)
-                            .join(format!("thumb-{old_internal_name}")),
-                    )
-                    .await
-                    {
-                        tracing::error!("could not remove old thumbnail: {e}");
-                    }
-                }
+                upl.version = 1;
+                upl.versions = vec![];
             }
 
+            let extension = upl.extension.as_ref().cloned().unwrap_or_default();
+            let internal_name = if upl.version == 1 {
+                format!("{}.{extension}", upl.id)
+            } else {
+                format!("{}-v{}.{extension}", upl.id, upl.version)
+            };
+
             upl.thumbnail_id = self
                 .make_thumbnail(&upl, &internal_name, temp_file_path)
                 .await?;
 
-            tokio::fs::rename(
-                temp_file_path,
-                PathBuf::from(&self.share_drive_path).join(&internal_name),
-            )
-            .await
-            .map_err(|e| ServiceError::from(&e))?;
+            self.storage
+                .put_file(&internal_name, temp_file_path)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
             upl.internal_name = internal_name;
+
+            for version in pruned {
+                self.remove_version(&version).await?;
+            }
         }
 
         self.store
@@ -182,9 +249,405 @@ impl FileService<'_> {
             .map_err(|e| ServiceError::from(&e))?;
         Ok(upl)
     }
-    pub async fn download(&self, upl: &FileUpload) -> Result<File, ServiceError> {
-        tokio::fs::File::open(self.get_physical_path(&upl.internal_name))
+
+    /// uploads the content of a previous version as a new version
+    pub async fn restore(
+        &self,
+        upl: &FileUpload,
+        version: u32,
+        temp_dir: &Path,
+    ) -> Result<FileUpload, ServiceError> {
+        let Some(restored) = upl.at_version(version) else {
+            return Err(ServiceError(format!("{} has no version {version}", upl.id)));
+        };
+        tokio::fs::create_dir_all(temp_dir)
+            .await
+            .map_err(|e| ServiceError::from(&e))?;
+        let temp_file_path = temp_dir.join(uuid::Uuid::new_v4().to_string());
+        let copy = async {
+            let mut reader = self.download(&restored).await?;
+            let mut file = tokio::fs::File::create(&temp_file_path)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
+            tokio::io::copy(&mut reader, &mut file)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
+            self.upload(restored, Some(&temp_file_path)).await
+        };
+        let restored = copy.await;
+        if restored.is_err() {
+            let _ = tokio::fs::remove_file(&temp_file_path).await;
+        }
+        restored
+    }
+
+    async fn remove_version(&self, version: &FileVersion) -> Result<(), ServiceError> {
+        tracing::info!("removing version {}", version.internal_name);
+        if let Err(e) = self.storage.delete(&version.internal_name).await {
+            tracing::error!("could not remove old file: {e}");
+        }
+        self.remove_renditions(&version.internal_name).await;
+        if let Some(thumbnail_id) = &version.thumbnail_id {
+            self.store
+                .delete_by_id(thumbnail_id)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
+            if let Err(e) = self
+                .storage
+                .delete(&format!("thumb-{}", version.internal_name))
+                .await
+            {
+                tracing::error!("could not remove old thumbnail: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// removes the file with all its versions and thumbnails
+    pub async fn delete(&self, upl: &FileUpload) -> Result<(), ServiceError> {
+        for version in upl.versions.iter().chain([&upl.current_version()]) {
+            self.remove_version(version).await?;
+        }
+        self.store
+            .delete_by_id(&upl.id)
+            .await
+            .map_err(|e| ServiceError::from(&e))?;
+        Ok(())
+    }
+
+    /// removes every file attached to `correlation_id`, returns the number of files removed
+    pub async fn delete_by_correlation_id(
+        &self,
+        correlation_id: &str,
+    ) -> Result<usize, ServiceError> {
+        let query = list_query(&ListFilesRequestUriParams {
+            correlation_id: Some(correlation_id.into()),
+            ..Default::default()
+        });
+        let uploads = self
+            .store
+            .find_by_query(query, None)
+            .await
+            .map_err(|e| ServiceError::from(&e))?;
+        for upl in &uploads {
+            self.delete(upl).await?;
+        }
+        Ok(uploads.len())
+    }
+
+    pub async fn list(
+        &self,
+        params: &ListFilesRequestUriParams,
+    ) -> Result<Page<FileUpload>, ServiceError> {
+        let pageable = Pageable {
+            page: params.page.unwrap_or(0).max(0),
+            limit: params
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+            sort: Some(doc! {"creationDate": -1}),
+        };
+        self.store
+            .find_page(Some(list_query(params)), pageable)
+            .await
+            .map_err(|e| ServiceError::from(&e))?
+            .ok_or_else(|| ServiceError("no page".into()))
+    }
+
+    async fn remove_renditions(&self, internal_name: &str) {
+        let keys = match self
+            .storage
+            .list_prefix(&format!("renditions/{internal_name}/"))
+            .await
+        {
+            Ok(keys) => keys,
+            Err(e) => {
+                tracing::error!("could not list the renditions of {internal_name}: {e}");
+                return;
+            }
+        };
+        for key in keys {
+            if let Err(e) = self.storage.delete(&key).await {
+                tracing::error!("could not remove rendition {key}: {e}");
+            }
+        }
+    }
+
+    /// converts the file with LibreOffice into a temp file. The result is a new file
+    /// attached to the same `correlation_id`, to be validated and stored by the caller
+    pub async fn convert(
+        &self,
+        upl: &FileUpload,
+        to: ConvertType,
+        temp_dir: &Path,
+    ) -> Result<(FileUpload, PathBuf), ServiceError> {
+        tokio::fs::create_dir_all(temp_dir)
+            .await
+            .map_err(|e| ServiceError::from(&e))?;
+        let source_path = temp_dir.join(uuid::Uuid::new_v4().to_string());
+        let converted_path = temp_dir.join(uuid::Uuid::new_v4().to_string());
+        let converted = async {
+            let mut reader = self.download(upl).await?;
+            let mut file = tokio::fs::File::create(&source_path)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
+            tokio::io::copy(&mut reader, &mut file)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
+            let bytes = convert_to(&source_path, upl.extension.as_deref(), to)
+                .await
+                .map_err(|e| ServiceError(e.to_string()))?;
+            tokio::fs::write(&converted_path, &bytes)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
+            let stem = Path::new(&upl.original_filename)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_else(|| upl.id.clone());
+            Ok(FileUpload {
+                content_type: Some(to.content_type().to_string()),
+                original_filename: format!("{stem}.{}", to.extension()),
+                extension: Some(to.extension().to_string()),
+                size: bytes.len() as u64,
+                correlation_id: upl.correlation_id.clone(),
+                converted_from: Some(upl.id.clone()),
+                ..make_default_file_upload()
+            })
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&source_path).await;
+        if converted.is_err() {
+            let _ = tokio::fs::remove_file(&converted_path).await;
+        }
+        converted.map(|converted| (converted, converted_path))
+    }
+
+    /// the image generated from an image or a pdf, cached in the storage
+    pub async fn rendition(
+        &self,
+        upl: &FileUpload,
+        transform: &Transform,
+        renditions: &Renditions,
+        temp_dir: &Path,
+    ) -> Result<StorageReader, ServiceError> {
+        let key = transform.cache_key(&upl.internal_name);
+        if self
+            .storage
+            .exists(&key)
+            .await
+            .map_err(|e| ServiceError::from(&e))?
+        {
+            return self
+                .storage
+                .get(&key)
+                .await
+                .map_err(|e| ServiceError::from(&e));
+        }
+        let _permit = renditions
+            .permit()
+            .await
+            .map_err(|e| ServiceError::from(&e))?;
+        let mut reader = self.download(upl).await?;
+        let source = if upl.is_pdf() {
+            tokio::fs::create_dir_all(temp_dir)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
+            let temp_file_path = temp_dir.join(uuid::Uuid::new_v4().to_string());
+            let rendered = async {
+                let mut file = tokio::fs::File::create(&temp_file_path).await?;
+                tokio::io::copy(&mut reader, &mut file).await?;
+                render_page(&temp_file_path, transform.page, transform.page_size()).await
+            }
+            .await;
+            let _ = tokio::fs::remove_file(&temp_file_path).await;
+            rendered.map_err(|e| ServiceError(e.to_string()))?
+        } else {
+            let mut bytes = vec![];
+            tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut bytes)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
+            bytes
+        };
+        let preset = transform.preset;
+        let transform = transform.clone();
+        let bytes = tokio::task::spawn_blocking(move || {
+            let image = image::load_from_memory(&source).map_err(|e| ServiceError::from(&e))?;
+            transform.apply(&image).map_err(|e| ServiceError::from(&e))
+        })
+        .await
+        .map_err(|e| ServiceError::from(&e))??;
+        // past the limit, the images generated on demand are not kept
+        let cached = if preset {
+            0
+        } else {
+            self.storage
+                .list_prefix(&format!("renditions/{}/", upl.internal_name))
+                .await
+                .map(|keys| keys.len())
+                .unwrap_or(usize::MAX)
+        };
+        if cached < renditions.max_per_file() {
+            if let Err(e) = self.storage.put_bytes(&key, bytes.clone()).await {
+                tracing::error!("could not cache rendition {key}: {e}");
+            }
+        }
+        Ok(Box::pin(Cursor::new(bytes)))
+    }
+
+    pub async fn download(&self, upl: &FileUpload) -> Result<StorageReader, ServiceError> {
+        self.storage
+            .get(&upl.internal_name)
+            .await
+            .map_err(|e| ServiceError::from(&e))
+    }
+
+    pub async fn download_range(
+        &self,
+        upl: &FileUpload,
+        start: u64,
+        len: u64,
+    ) -> Result<StorageReader, ServiceError> {
+        self.storage
+            .get_range(&upl.internal_name, start, len)
             .await
             .map_err(|e| ServiceError::from(&e))
     }
 }
+
+/// the thumbnails are stored as uploads too, they are never listed
+fn list_query(params: &ListFilesRequestUriParams) -> Document {
+    let date = |d: &NaiveDateTime| d.format("%Y-%m-%dT%H:%M:%S%.f").to_string();
+    let mut query = doc! {"internalName": {"$not": {"$regex": "^thumb-"}}};
+    match params.content_type.as_deref() {
+        Some(ct) if ct.ends_with('*') => {
+            let escaped: String = ct[..ct.len() - 1]
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '/' {
+                        c.to_string()
+                    } else {
+                        format!("\\{c}")
+                    }
+                })
+                .collect();
+            query.insert("contentType", doc! {"$regex": format!("^{escaped}")});
+        }
+        Some(ct) => {
+            query.insert("contentType", ct);
+        }
+        None => {}
+    }
+    if let Some(correlation_id) = &params.correlation_id {
+        query.insert("correlationId", correlation_id);
+    }
+    let mut creation_date = Document::new();
+    if let Some(after) = &params.created_after {
+        creation_date.insert("$gte", date(after));
+    }
+    if let Some(before) = &params.created_before {
+        creation_date.insert("$lt", date(before));
+    }
+    if !creation_date.is_empty() {
+        query.insert("creationDate", creation_date);
+    }
+    let mut size = Document::new();
+    if let Some(min_size) = params.min_size {
+        size.insert("$gte", min_size as i64);
+    }
+    if let Some(max_size) = params.max_size {
+        size.insert("$lte", max_size as i64);
+    }
+    if !size.is_empty() {
+        query.insert("size", size);
+    }
+    query
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, NaiveDate, NaiveDateTime};
+    use sequeda_file_upload_common::{FileVersion, ListFilesRequestUriParams};
+    use sequeda_store::doc;
+
+    use super::{list_query, VersionRetention};
+
+    fn version(version: u32, creation_date: NaiveDateTime) -> FileVersion {
+        FileVersion {
+            version,
+            creation_date,
+            content_type: None,
+            thumbnail_id: None,
+            original_filename: "contract.pdf".into(),
+            internal_name: format!("42-v{version}.pdf"),
+            extension: Some("pdf".into()),
+            size: 0,
+        }
+    }
+
+    #[test]
+    fn test_prune() {
+        let day = |d| {
+            NaiveDate::from_ymd_opt(2024, 1, d)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        };
+        let mut versions = vec![
+            version(1, day(1)),
+            version(2, day(2)),
+            version(3, day(10)),
+            version(4, day(11)),
+        ];
+        let retention = VersionRetention {
+            max_versions: 2,
+            max_age: Some(Duration::days(5)),
+        };
+        // v1 was replaced on day 2, v2 on day 10
+        let pruned = retention.prune(&mut versions, day(12), day(12));
+        assert_eq!(
+            vec![1, 2],
+            pruned.iter().map(|v| v.version).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![3, 4],
+            versions.iter().map(|v| v.version).collect::<Vec<_>>()
+        );
+
+        let retention = VersionRetention {
+            max_versions: 1,
+            max_age: None,
+        };
+        let pruned = retention.prune(&mut versions, day(12), day(12));
+        assert_eq!(
+            vec![3],
+            pruned.iter().map(|v| v.version).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![4],
+            versions.iter().map(|v| v.version).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_list_query() {
+        let params = ListFilesRequestUriParams {
+            content_type: Some("application/vnd.ms-*".into()),
+            correlation_id: Some("invoice-42".into()),
+            created_after: NaiveDate::from_ymd_opt(2024, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0),
+            max_size: Some(1024),
+            ..Default::default()
+        };
+        assert_eq!(
+            doc! {
+                "internalName": {"$not": {"$regex": "^thumb-"}},
+                "contentType": {"$regex": "^application/vnd\\.ms\\-"},
+                "correlationId": "invoice-42",
+                "creationDate": {"$gte": "2024-01-01T00:00:00"},
+                "size": {"$lte": 1024_i64},
+            },
+            list_query(&params)
+        );
+    }
+}
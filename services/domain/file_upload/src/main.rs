@@ -1,52 +1,120 @@
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::{env::var, net::SocketAddr, str::FromStr};
 
+use axum::body::Bytes;
 use axum::extract::multipart::Field;
-use axum::http::{header, StatusCode};
-use axum::response::{AppendHeaders, IntoResponse};
-use axum::routing::get;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{AppendHeaders, IntoResponse, Response};
+use axum::routing::{delete, get};
 use axum::Json;
-use axum::{routing::post, Extension, Router};
+use axum::{middleware, routing::post, Extension, Router};
 
-use axum::extract::{Multipart, Query};
-use chrono::Local;
+use axum::extract::{Multipart, Path as UriPath, Query};
+use chrono::{Duration, Local};
 use mime_guess::mime::APPLICATION_OCTET_STREAM;
 use sequeda_file_upload_common::{
-    DownloadFileRequestUriParams, FileUpload, UploadFileRequestUriParams,
+    ConvertFileRequestUriParams, CreateShareLinkRequest, CreateUploadSessionRequest,
+    DeleteFileRequestUriParams, DownloadFileRequestUriParams, FileUpload, FileVersion,
+    ImageRequestUriParams, ListFilesRequestUriParams, UploadFileRequestUriParams,
 };
 use sequeda_message_client::{Exchange, MessageClient};
+use sequeda_service_common::common_domain_types::ServiceError;
 use sequeda_service_common::user_header::ExtractUserInfo;
 use sequeda_service_common::{
-    setup_tracing, IdGenerator, StoreCollection, BODY_SIZE_LIMIT, PUBLIC_TENANT,
-    SERVICE_COLLECTION_NAME, SERVICE_HOST, SERVICE_PORT,
+    setup_tracing, trace_context::trace_context, IdGenerator, StoreCollection, BODY_SIZE_LIMIT,
+    PUBLIC_TENANT, SERVICE_COLLECTION_NAME, SERVICE_CONFIG_VOLUME, SERVICE_HOST, SERVICE_PORT,
 };
-use sequeda_store::{Repository, StoreClient, StoreRepository};
+use sequeda_store::{doc, Repository, StoreClient, StoreRepository};
 use serde_json::json;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::broadcast::Sender;
 use tokio_util::io::ReaderStream;
 use tower_http::limit::RequestBodyLimitLayer;
 
-use crate::file_upload_service::{FileService, SHARE_DRIVE_PATH};
+use crate::download::ByteRange;
+use crate::file_upload_service::{FileService, VersionRetention, SHARE_DRIVE_PATH};
+use crate::renditions::Renditions;
+use crate::resumable::{
+    SessionError, SessionErrorKind, UploadSessions, DEFAULT_MAX_PER_USER, DEFAULT_MAX_SIZE,
+    UPLOAD_CHECKSUM, UPLOAD_OFFSET, UPLOAD_SESSION_MAX_PER_USER, UPLOAD_SESSION_MAX_SIZE,
+    UPLOAD_SESSION_TTL_HOURS,
+};
+use crate::share::{
+    check_password_attempt, consume, share_link_collection, ShareLink, ShareLinkError,
+    ShareLinkErrorKind, ShareLinks, SHARE_PASSWORD,
+};
+use crate::storage::{migrate, storage_from_env, SharedStorage, STORAGE_BACKEND};
+use crate::validation::{quarantine_from_env, Quarantine, ValidationErrorKind, ValidationPipeline};
 
+mod download;
 mod file_upload_service;
+mod pdftoppm;
+mod renditions;
+mod resumable;
+mod share;
 mod soffice;
+mod storage;
+mod validation;
 
 #[derive(Clone, Debug)]
 struct ShareDrive(String);
 
 const TOPIC_UPLOAD: &str = "TOPIC_UPLOAD";
+/// 16Mb, larger files are sent in chunks with the resumable uploads
+const DEFAULT_BODY_SIZE_LIMIT: usize = 16 * 1024 * 1024;
 
 #[tokio::main]
 async fn main() {
     setup_tracing();
 
     let share_drive_path: String = std::env::var(SHARE_DRIVE_PATH).unwrap();
+
+    // sequeda_file_upload migrate <from> <to> [--delete]
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(|a| a.as_str()) == Some("migrate") {
+        let (Some(from), Some(to)) = (args.get(1), args.get(2)) else {
+            eprintln!("usage: migrate <local|s3|content_addressed> <local|s3|content_addressed> [--delete]");
+            std::process::exit(1);
+        };
+        if from == to {
+            eprintln!("cannot migrate {from} to itself");
+            std::process::exit(1);
+        }
+        let delete = args.iter().any(|a| a == "--delete");
+        let migrated = async {
+            let from = storage_from_env(from).await?;
+            let to = storage_from_env(to).await?;
+            let temp_dir = PathBuf::from(&share_drive_path).join("tmp");
+            migrate(from.as_ref(), to.as_ref(), &temp_dir, delete).await
+        }
+        .await;
+        match migrated {
+            Ok(copied) => {
+                tracing::info!("migration done, {copied} files copied");
+                return;
+            }
+            Err(e) => {
+                tracing::error!("migration failed: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let storage_backend = var(STORAGE_BACKEND).unwrap_or_else(|_| "local".into());
+    let storage = match storage_from_env(&storage_backend).await {
+        Ok(storage) => storage,
+        Err(e) => {
+            tracing::error!("could not create the {storage_backend} storage: {e}");
+            std::process::exit(1);
+        }
+    };
     let host = var(SERVICE_HOST).unwrap_or_else(|_| String::from("127.0.0.1"));
-    let body_size_limit = (var(BODY_SIZE_LIMIT).unwrap_or_else(|_| "1024".into()))
-        .parse::<usize>()
-        .unwrap_or_else(|_| panic!("could not extract {}", BODY_SIZE_LIMIT));
+    let body_size_limit = (var(BODY_SIZE_LIMIT)
+        .unwrap_or_else(|_| DEFAULT_BODY_SIZE_LIMIT.to_string()))
+    .parse::<usize>()
+    .unwrap_or_else(|_| panic!("could not extract {}", BODY_SIZE_LIMIT));
     let port = var(SERVICE_PORT).unwrap_or_else(|_| String::from("0"));
     let app_name =
         var(SERVICE_COLLECTION_NAME).unwrap_or_else(|_| String::from("sequeda-upload-service"));
@@ -60,17 +128,85 @@ async fn main() {
     let client = StoreClient::new(app_name).await.unwrap();
     let collection_name: String =
         var(SERVICE_COLLECTION_NAME).unwrap_or_else(|_| String::from("upload"));
+    let retention = VersionRetention::from_env();
+    let renditions = match Renditions::from_env() {
+        Ok(renditions) => Arc::new(renditions),
+        Err(e) => {
+            tracing::error!("could not read the renditions: {e}");
+            std::process::exit(1);
+        }
+    };
+    let config_volume = var(SERVICE_CONFIG_VOLUME).unwrap_or_else(|_| String::from("/tmp"));
+    let validation = match ValidationPipeline::from_env(Path::new(&config_volume)) {
+        Ok(validation) => Arc::new(validation),
+        Err(e) => {
+            tracing::error!("could not create the validation pipeline: {e}");
+            std::process::exit(1);
+        }
+    };
+    let quarantine = quarantine_from_env(&share_drive_path);
+    let share_links = Arc::new(ShareLinks::from_env());
+    let session_ttl_hours = var(UPLOAD_SESSION_TTL_HOURS)
+        .ok()
+        .and_then(|h| h.parse().ok())
+        .unwrap_or(24);
+    let upload_sessions = Arc::new(
+        UploadSessions::new(
+            PathBuf::from(&share_drive_path)
+                .join("tmp")
+                .join("sessions"),
+            Duration::hours(session_ttl_hours),
+        )
+        .with_limits(
+            var(UPLOAD_SESSION_MAX_SIZE)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_MAX_SIZE),
+            var(UPLOAD_SESSION_MAX_PER_USER)
+                .ok()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(DEFAULT_MAX_PER_USER),
+        ),
+    );
+    upload_sessions.spawn_cleanup();
 
     let mut server = tokio::spawn(async move {
         let app = Router::new()
             .route("/upload", post(upload))
             .route("/download", get(download))
             .route("/metadata", get(metadata))
+            .route("/versions", get(versions))
+            .route("/restore", post(restore))
+            .route("/list", get(list))
+            .route("/delete", delete(delete_files))
+            .route("/image/:id", get(image))
+            .route("/convert", post(convert))
+            .route("/share", post(create_share_link).get(list_share_links))
+            .route("/share/:link_id", delete(revoke_share_link))
+            .route("/uploads", post(create_upload_session))
+            .route(
+                "/uploads/:upload_id",
+                get(upload_session_status)
+                    .patch(append_upload_chunk)
+                    .delete(abort_upload_session),
+            )
+            .route(
+                "/uploads/:upload_id/finalize",
+                post(finalize_upload_session),
+            )
             .layer(RequestBodyLimitLayer::new(body_size_limit))
             .layer(Extension(client))
             .layer(Extension(sender))
             .layer(Extension(ShareDrive(share_drive_path)))
-            .layer(Extension(StoreCollection(collection_name)));
+            .layer(Extension(storage))
+            .layer(Extension(retention))
+            .layer(Extension(upload_sessions))
+            .layer(Extension(renditions))
+            .layer(Extension(validation))
+            .layer(Extension(quarantine))
+            .layer(Extension(share_links))
+            .layer(Extension(StoreCollection(collection_name)))
+            .layer(middleware::from_fn(trace_context));
 
         tracing::info!("listening on {:?}", addr);
         let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
@@ -93,7 +229,7 @@ async fn metadata(
     Extension(client): Extension<StoreClient>,
     Extension(collection): Extension<StoreCollection>,
     x_user_info: Option<ExtractUserInfo>,
-    Query(DownloadFileRequestUriParams { id }): Query<DownloadFileRequestUriParams>,
+    Query(DownloadFileRequestUriParams { id, .. }): Query<DownloadFileRequestUriParams>,
 ) -> impl IntoResponse {
     tracing::debug!("Metadata route entered!");
 
@@ -102,6 +238,206 @@ async fn metadata(
         None => (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response(),
     }
 }
+async fn versions(
+    Extension(client): Extension<StoreClient>,
+    Extension(collection): Extension<StoreCollection>,
+    x_user_info: Option<ExtractUserInfo>,
+    Query(DownloadFileRequestUriParams { id, .. }): Query<DownloadFileRequestUriParams>,
+) -> impl IntoResponse {
+    tracing::debug!("Versions route entered!");
+
+    match get_file_upload(&id, &x_user_info, &client, &collection).await {
+        Some((_, upl)) => {
+            let versions: Vec<FileVersion> = upl
+                .versions
+                .iter()
+                .cloned()
+                .chain([upl.current_version()])
+                .collect();
+            Json(versions).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response(),
+    }
+}
+#[allow(clippy::too_many_arguments)]
+async fn restore(
+    Extension(client): Extension<StoreClient>,
+    Extension(message_sender): Extension<Sender<Exchange>>,
+    Extension(collection): Extension<StoreCollection>,
+    Extension(ShareDrive(share_drive_path)): Extension<ShareDrive>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(retention): Extension<VersionRetention>,
+    x_user_info: ExtractUserInfo,
+    Query(DownloadFileRequestUriParams { id, version, .. }): Query<DownloadFileRequestUriParams>,
+) -> impl IntoResponse {
+    tracing::debug!("Restore route entered!");
+
+    let Some(version) = version else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "version is required"})),
+        )
+            .into_response();
+    };
+    let user_info = x_user_info.user_info;
+    let Some(tenant) = user_info.tenant.as_deref() else {
+        return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"}))).into_response();
+    };
+    let Some((repo, file)) = get_owned_file_upload(&id, tenant, &client, &collection).await else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response();
+    };
+    if file.at_version(version).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "Version not found"})),
+        )
+            .into_response();
+    }
+    let file_service = FileService {
+        storage: storage.as_ref(),
+        store: &repo,
+        retention: &retention,
+    };
+    let temp_dir = PathBuf::from(share_drive_path).join("tmp");
+    match file_service.restore(&file, version, &temp_dir).await {
+        Ok(upl) => {
+            if let Err(e) = message_sender.send(Exchange::new(
+                format!(
+                    "user {} restored version {version} of file '{}' with id {}",
+                    &user_info.username.unwrap_or(user_info.id),
+                    &upl.original_filename,
+                    &upl.id
+                )
+                .as_bytes(),
+                TOPIC_UPLOAD,
+                user_info.tenant,
+                HashMap::new(),
+            )) {
+                tracing::error!("could not send message {e}");
+            }
+            (StatusCode::OK, Json(upl)).into_response()
+        }
+        Err(e) => {
+            tracing::error!("could not restore version {version} of {id}: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "could not restore the version"})),
+            )
+                .into_response()
+        }
+    }
+}
+async fn list(
+    Extension(client): Extension<StoreClient>,
+    Extension(collection): Extension<StoreCollection>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(retention): Extension<VersionRetention>,
+    ExtractUserInfo {
+        user_info: x_user_info,
+        ..
+    }: ExtractUserInfo,
+    Query(params): Query<ListFilesRequestUriParams>,
+) -> impl IntoResponse {
+    tracing::debug!("List route entered!");
+
+    let Some(tenant) = x_user_info.tenant else {
+        return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"}))).into_response();
+    };
+    let repository: StoreRepository<FileUpload> =
+        StoreRepository::get_repository(client, &collection.0, &tenant).await;
+    let file_service = FileService {
+        storage: storage.as_ref(),
+        store: &repository,
+        retention: &retention,
+    };
+    match file_service.list(&params).await {
+        Ok(page) => (StatusCode::OK, Json(page)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+#[allow(clippy::too_many_arguments)]
+async fn delete_files(
+    Extension(client): Extension<StoreClient>,
+    Extension(message_sender): Extension<Sender<Exchange>>,
+    Extension(collection): Extension<StoreCollection>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(retention): Extension<VersionRetention>,
+    x_user_info: ExtractUserInfo,
+    Query(DeleteFileRequestUriParams { id, correlation_id }): Query<DeleteFileRequestUriParams>,
+) -> impl IntoResponse {
+    tracing::debug!("Delete route entered!");
+
+    let user_info = x_user_info.user_info;
+    let deleted = match (id, correlation_id) {
+        (Some(id), None) => {
+            let Some(tenant) = user_info.tenant.as_deref() else {
+                return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"})))
+                    .into_response();
+            };
+            let Some((repo, file)) = get_owned_file_upload(&id, tenant, &client, &collection).await
+            else {
+                return (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"})))
+                    .into_response();
+            };
+            let file_service = FileService {
+                storage: storage.as_ref(),
+                store: &repo,
+                retention: &retention,
+            };
+            file_service.delete(&file).await.map(|_| 1)
+        }
+        (None, Some(correlation_id)) => {
+            let Some(tenant) = user_info.tenant.as_ref() else {
+                return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"})))
+                    .into_response();
+            };
+            let repository: StoreRepository<FileUpload> =
+                StoreRepository::get_repository(client, &collection.0, tenant).await;
+            let file_service = FileService {
+                storage: storage.as_ref(),
+                store: &repository,
+                retention: &retention,
+            };
+            file_service.delete_by_correlation_id(&correlation_id).await
+        }
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "either id or correlation_id is required"})),
+            )
+                .into_response();
+        }
+    };
+    match deleted {
+        Ok(deleted) => {
+            if let Err(e) = message_sender.send(Exchange::new(
+                format!(
+                    "user {} deleted {deleted} file(s)",
+                    &user_info.username.unwrap_or(user_info.id),
+                )
+                .as_bytes(),
+                TOPIC_UPLOAD,
+                user_info.tenant,
+                HashMap::new(),
+            )) {
+                tracing::error!("could not send message {e}");
+            }
+            (StatusCode::OK, Json(json!({"deleted": deleted}))).into_response()
+        }
+        Err(e) => {
+            tracing::error!("could not delete: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "could not delete"})),
+            )
+                .into_response()
+        }
+    }
+}
 // region: helper method
 async fn get_file_upload(
     id: &str,
@@ -109,119 +445,448 @@ async fn get_file_upload(
     client: &StoreClient,
     collection: &StoreCollection,
 ) -> Option<(StoreRepository<FileUpload>, FileUpload)> {
-    async fn get_upload(repository: &StoreRepository<FileUpload>, id: &str) -> Option<FileUpload> {
-        match repository.find_by_id(id).await {
-            Ok(Some(response)) => Some(response),
-            Ok(None) => None,
-            Err(e) => {
-                tracing::error!("db error {e}");
-                None
-            }
+    let tenant = x_user_info
+        .as_ref()
+        .and_then(|u| u.user_info.tenant.as_deref());
+    get_file_upload_in_tenant(id, tenant, client, collection).await
+}
+
+async fn find_file_upload(
+    repository: &StoreRepository<FileUpload>,
+    id: &str,
+) -> Option<FileUpload> {
+    match repository.find_by_id(id).await {
+        Ok(Some(response)) => Some(response),
+        Ok(None) => None,
+        Err(e) => {
+            tracing::error!("db error {e}");
+            None
         }
     }
+}
 
+/// the public files, then the ones of the tenant
+async fn get_file_upload_in_tenant(
+    id: &str,
+    tenant: Option<&str>,
+    client: &StoreClient,
+    collection: &StoreCollection,
+) -> Option<(StoreRepository<FileUpload>, FileUpload)> {
     let public_repository: StoreRepository<FileUpload> =
         StoreRepository::get_repository(client.clone(), &collection.0, PUBLIC_TENANT).await;
 
-    if let Some(fu) = get_upload(&public_repository, id).await {
+    if let Some(fu) = find_file_upload(&public_repository, id).await {
         Some((public_repository, fu))
-    } else if let Some(tenant) = x_user_info
-        .as_ref()
-        .map(|u| &u.user_info)
-        .and_then(|u| u.tenant.clone())
-    {
+    } else if let Some(tenant) = tenant {
         let private_repository: StoreRepository<FileUpload> =
-            StoreRepository::get_repository(client.clone(), &collection.0, &tenant).await;
-        get_upload(&private_repository, id)
+            StoreRepository::get_repository(client.clone(), &collection.0, tenant).await;
+        find_file_upload(&private_repository, id)
             .await
             .map(|fu| (private_repository, fu))
     } else {
         None
     }
 }
+
+/// the files the tenant can change: its own, then the public files it uploaded
+async fn get_owned_file_upload(
+    id: &str,
+    tenant: &str,
+    client: &StoreClient,
+    collection: &StoreCollection,
+) -> Option<(StoreRepository<FileUpload>, FileUpload)> {
+    let repository: StoreRepository<FileUpload> =
+        StoreRepository::get_repository(client.clone(), &collection.0, tenant).await;
+    if let Some(fu) = find_file_upload(&repository, id).await {
+        return Some((repository, fu));
+    }
+    let public_repository: StoreRepository<FileUpload> =
+        StoreRepository::get_repository(client.clone(), &collection.0, PUBLIC_TENANT).await;
+    find_file_upload(&public_repository, id)
+        .await
+        .filter(|fu| fu.owner_tenant.as_deref() == Some(tenant))
+        .map(|fu| (public_repository, fu))
+}
+
+/// a public file with this id was uploaded by another tenant, no version can be added to it
+async fn is_public_file_of_other_tenant(
+    id: &str,
+    tenant: Option<&str>,
+    client: &StoreClient,
+    collection: &StoreCollection,
+) -> bool {
+    let public_repository: StoreRepository<FileUpload> =
+        StoreRepository::get_repository(client.clone(), &collection.0, PUBLIC_TENANT).await;
+    find_file_upload(&public_repository, id)
+        .await
+        .is_some_and(|fu| tenant.is_none() || fu.owner_tenant.as_deref() != tenant)
+}
 // endregion
 
+#[allow(clippy::too_many_arguments)]
 async fn download(
     Extension(client): Extension<StoreClient>,
     Extension(collection): Extension<StoreCollection>,
-    Extension(ShareDrive(share_drive_path)): Extension<ShareDrive>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(retention): Extension<VersionRetention>,
+    Extension(share_links): Extension<Arc<ShareLinks>>,
     x_user_info: Option<ExtractUserInfo>,
-    Query(DownloadFileRequestUriParams { id }): Query<DownloadFileRequestUriParams>,
+    Query(DownloadFileRequestUriParams {
+        id,
+        version,
+        disposition,
+        token,
+    }): Query<DownloadFileRequestUriParams>,
+    request_headers: HeaderMap,
 ) -> impl IntoResponse {
     tracing::debug!("Download route entered!");
 
     tracing::debug!("trying to fetch document with id {id}");
 
-    match get_file_upload(&id, &x_user_info, &client, &collection).await {
-        Some((repo, file)) => {
-            let file_service = FileService {
-                share_drive_path: &share_drive_path,
-                store: &repo,
-            };
-            let file_handle = file_service.download(&file).await.unwrap();
-            let stream = ReaderStream::new(file_handle);
-            let body = axum::body::Body::from_stream(stream);
-
-            let content_header = if file.is_image() {
-                (header::CONTENT_LENGTH, format!("{}", &file.size))
-            } else {
-                (
-                    header::CONTENT_DISPOSITION,
-                    format!(r#"attachment; filename="{}""#, &file.original_filename),
+    // a share link replaces the user session
+    let share_link = match &token {
+        Some(token) => {
+            let password = request_headers
+                .get(SHARE_PASSWORD)
+                .and_then(|p| p.to_str().ok());
+            match get_share_link(&share_links, &client, &collection, token, &id, password).await {
+                Ok(share_link) => Some(share_link),
+                Err(e) => return share_link_error_response(e),
+            }
+        }
+        None => None,
+    };
+    let file = match &share_link {
+        Some((_, link)) => {
+            get_file_upload_in_tenant(&id, Some(&link.tenant), &client, &collection).await
+        }
+        None => get_file_upload(&id, &x_user_info, &client, &collection).await,
+    };
+    let version = share_link
+        .as_ref()
+        .and_then(|(_, link)| link.version)
+        .or(version);
+    let file = file.and_then(|(repo, file)| match version {
+        Some(version) => file.at_version(version).map(|file| (repo, file)),
+        None => Some((repo, file)),
+    });
+    let Some((repo, file)) = file else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response();
+    };
+
+    let etag = download::etag(&file);
+    let last_modified = download::last_modified(&file);
+    let disposition = download::disposition(disposition, file.content_type.as_deref());
+    let mut headers = vec![
+        (header::ETAG, etag.clone()),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+    ];
+    headers.extend(download::security_headers(
+        disposition,
+        file.content_type.as_deref(),
+    ));
+    if let Some(last_modified) = &last_modified {
+        headers.push((header::LAST_MODIFIED, download::http_date(last_modified)));
+    }
+    if download::not_modified(&request_headers, &etag, last_modified.as_ref()) {
+        return (StatusCode::NOT_MODIFIED, AppendHeaders(headers)).into_response();
+    }
+
+    headers.push((
+        header::CONTENT_DISPOSITION,
+        download::content_disposition(disposition, &file.original_filename),
+    ));
+    headers.push((
+        header::CONTENT_TYPE,
+        file.content_type
+            .clone()
+            .unwrap_or_else(|| APPLICATION_OCTET_STREAM.to_string()),
+    ));
+
+    let file_service = FileService {
+        storage: storage.as_ref(),
+        store: &repo,
+        retention: &retention,
+    };
+    let range = download::byte_range(&request_headers, file.size, &etag);
+    // every part of a download is counted, nothing is served from an unsatisfiable range
+    let served = !matches!(range, ByteRange::Unsatisfiable);
+    if let (Some((link_repo, link)), true) = (&share_link, served) {
+        if let Err(e) = consume(link_repo, link).await {
+            return share_link_error_response(e);
+        }
+    }
+    let (status, reader) = match range {
+        ByteRange::Full => {
+            headers.push((header::CONTENT_LENGTH, file.size.to_string()));
+            (StatusCode::OK, file_service.download(&file).await)
+        }
+        ByteRange::Partial { start, len } => {
+            headers.push((header::CONTENT_LENGTH, len.to_string()));
+            headers.push((
+                header::CONTENT_RANGE,
+                format!("bytes {start}-{}/{}", start + len - 1, file.size),
+            ));
+            (
+                StatusCode::PARTIAL_CONTENT,
+                file_service.download_range(&file, start, len).await,
+            )
+        }
+        ByteRange::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", file.size))],
+            )
+                .into_response();
+        }
+    };
+    match reader {
+        Ok(reader) => {
+            let body = axum::body::Body::from_stream(ReaderStream::new(reader));
+            (status, AppendHeaders(headers), body).into_response()
+        }
+        Err(e) => {
+            tracing::error!("could not read {}: {e}", file.internal_name);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "could not read the file"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn convert(
+    Extension(client): Extension<StoreClient>,
+    Extension(message_sender): Extension<Sender<Exchange>>,
+    Extension(collection): Extension<StoreCollection>,
+    Extension(ShareDrive(share_drive_path)): Extension<ShareDrive>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(retention): Extension<VersionRetention>,
+    Extension(validation): Extension<Arc<ValidationPipeline>>,
+    Extension(quarantine): Extension<Quarantine>,
+    x_user_info: ExtractUserInfo,
+    Query(ConvertFileRequestUriParams { id, to, version }): Query<ConvertFileRequestUriParams>,
+) -> impl IntoResponse {
+    tracing::debug!("Convert route entered!");
+
+    let user_info = x_user_info.user_info.clone();
+    // the result belongs to the caller, even when the source is a public file
+    let Some(tenant) = user_info.tenant.clone() else {
+        return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"}))).into_response();
+    };
+    let x_user_info = Some(x_user_info);
+    let file = get_file_upload(&id, &x_user_info, &client, &collection)
+        .await
+        .and_then(|(repo, file)| match version {
+            Some(version) => file.at_version(version).map(|file| (repo, file)),
+            None => Some((repo, file)),
+        });
+    let Some((repo, file)) = file else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response();
+    };
+    let file_service = FileService {
+        storage: storage.as_ref(),
+        store: &repo,
+        retention: &retention,
+    };
+    let temp_dir = PathBuf::from(share_drive_path).join("tmp");
+    let (mut upl, converted_path) = match file_service.convert(&file, to.into(), &temp_dir).await {
+        Ok(converted) => converted,
+        Err(e) => {
+            tracing::error!("could not convert {id} to {to:?}: {e}");
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({"error": "could not convert the file"})),
+            )
+                .into_response();
+        }
+    };
+    upl.owner_tenant = Some(tenant.clone());
+    if let Err((status, error)) =
+        validate_upload(&validation, &quarantine, &mut upl, &converted_path, &tenant).await
+    {
+        let _ = tokio::fs::remove_file(&converted_path).await;
+        return (
+            status,
+            Json(json!({"error": "conversion rejected", "files": [error]})),
+        )
+            .into_response();
+    }
+    let repository: StoreRepository<FileUpload> =
+        StoreRepository::get_repository(client, &collection.0, &tenant).await;
+    let file_service = FileService {
+        storage: storage.as_ref(),
+        store: &repository,
+        retention: &retention,
+    };
+    match file_service.upload(upl, Some(&converted_path)).await {
+        Ok(upl) => {
+            if let Err(e) = message_sender.send(Exchange::new(
+                format!(
+                    "user {} converted file '{}' with id {} to {}",
+                    &user_info.username.unwrap_or(user_info.id),
+                    &file.original_filename,
+                    &file.id,
+                    &upl.id
                 )
-            };
+                .as_bytes(),
+                TOPIC_UPLOAD,
+                Some(tenant),
+                HashMap::new(),
+            )) {
+                tracing::error!("could not send message {e}");
+            }
+            (StatusCode::OK, Json(upl)).into_response()
+        }
+        Err(e) => {
+            tracing::error!("could not store the conversion of {id} to {to:?}: {e}");
+            let _ = tokio::fs::remove_file(&converted_path).await;
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "could not store the file"})),
+            )
+                .into_response()
+        }
+    }
+}
 
-            let ct = file
-                .content_type
-                .unwrap_or_else(|| APPLICATION_OCTET_STREAM.to_string());
+#[allow(clippy::too_many_arguments)]
+async fn image(
+    Extension(client): Extension<StoreClient>,
+    Extension(collection): Extension<StoreCollection>,
+    Extension(ShareDrive(share_drive_path)): Extension<ShareDrive>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(retention): Extension<VersionRetention>,
+    Extension(renditions): Extension<Arc<Renditions>>,
+    x_user_info: Option<ExtractUserInfo>,
+    UriPath(id): UriPath<String>,
+    Query(params): Query<ImageRequestUriParams>,
+    request_headers: HeaderMap,
+) -> impl IntoResponse {
+    tracing::debug!("Image route entered!");
 
-            let content_type = (header::CONTENT_TYPE, ct);
+    let file = get_file_upload(&id, &x_user_info, &client, &collection)
+        .await
+        .and_then(|(repo, file)| match params.version {
+            Some(version) => file.at_version(version).map(|file| (repo, file)),
+            None => Some((repo, file)),
+        });
+    let Some((repo, file)) = file else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response();
+    };
+    if !file.is_image() && !file.is_pdf() {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Json(json!({"error": "only images and pdf can be transformed"})),
+        )
+            .into_response();
+    }
+    let transform = match renditions.resolve(&params) {
+        Ok(transform) => transform,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": e.to_string()})),
+            )
+                .into_response()
+        }
+    };
 
-            let headers = AppendHeaders([content_type, content_header]);
+    // the etag of the generated image
+    let etag = download::etag(&FileUpload {
+        internal_name: transform.cache_key(&file.internal_name),
+        ..file.clone()
+    });
+    let headers = [
+        (header::ETAG, etag.clone()),
+        (
+            header::CONTENT_TYPE,
+            renditions::content_type(transform.format).to_string(),
+        ),
+    ];
+    if download::not_modified(&request_headers, &etag, None) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
 
+    let file_service = FileService {
+        storage: storage.as_ref(),
+        store: &repo,
+        retention: &retention,
+    };
+    let temp_dir = PathBuf::from(share_drive_path).join("tmp");
+    match file_service
+        .rendition(&file, &transform, &renditions, &temp_dir)
+        .await
+    {
+        Ok(reader) => {
+            let body = axum::body::Body::from_stream(ReaderStream::new(reader));
             (headers, body).into_response()
         }
-        None => (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response(),
+        Err(e) => {
+            tracing::error!("could not transform {}: {e}", file.internal_name);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "could not transform the file"})),
+            )
+                .into_response()
+        }
     }
 }
 
+/// the temp file has a unique name, so that concurrent uploads of the same file do not collide
 async fn write_field_to_temp_file<'a>(
     field: &mut Field<'a>,
     volume: impl Into<PathBuf>,
-    file_name: &str,
-) -> (PathBuf, u64) {
+) -> Result<(PathBuf, u64), ServiceError> {
     let volume = volume.into();
     let temp_volume = volume.join("tmp"); // necessary to
                                           // then move the file in the same volume
     tracing::debug!("temp_volume: - {temp_volume:?}");
-    if !temp_volume.exists() {
-        tokio::fs::create_dir(&temp_volume).await.unwrap();
-    }
-    let temp_file_path = temp_volume.join(file_name);
-    if temp_file_path.exists() {
-        tracing::info!(
-            "file {file_name} exists. removing: {:?}",
-            tokio::fs::remove_file(&temp_file_path).await
-        );
-    }
+    tokio::fs::create_dir_all(&temp_volume)
+        .await
+        .map_err(|e| ServiceError::from(&e))?;
+    let temp_file_path = temp_volume.join(uuid::Uuid::new_v4().to_string());
 
-    let mut temp_file = {
-        let mut o = tokio::fs::OpenOptions::new();
-        o.append(true).create(true).open(&temp_file_path).await
-    }
-    .unwrap();
-
-    while let Ok(Some(chunk)) = field.chunk().await {
-        temp_file.write_all(&chunk).await.unwrap();
+    let write = async {
+        let mut temp_file = tokio::fs::File::create(&temp_file_path)
+            .await
+            .map_err(|e| ServiceError::from(&e))?;
+        while let Some(chunk) = field.chunk().await.map_err(|e| ServiceError::from(&e))? {
+            temp_file
+                .write_all(&chunk)
+                .await
+                .map_err(|e| ServiceError::from(&e))?;
+        }
+        temp_file
+            .flush()
+            .await
+            .map_err(|e| ServiceError::from(&e))?;
+        let metadata = temp_file
+            .metadata()
+            .await
+            .map_err(|e| ServiceError::from(&e))?;
+        Ok(metadata.len())
+    };
+    match write.await {
+        Ok(len) => Ok((temp_file_path, len)),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&temp_file_path).await;
+            Err(e)
+        }
     }
-    let metadata = temp_file.metadata().await.unwrap();
-    (temp_file_path, metadata.len())
 }
+#[allow(clippy::too_many_arguments)]
 async fn upload(
     Extension(client): Extension<StoreClient>,
     Extension(message_sender): Extension<Sender<Exchange>>,
     Extension(collection): Extension<StoreCollection>,
     Extension(ShareDrive(share_drive_path)): Extension<ShareDrive>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(retention): Extension<VersionRetention>,
+    Extension(validation): Extension<Arc<ValidationPipeline>>,
+    Extension(quarantine): Extension<Quarantine>,
     ExtractUserInfo {
         user_info: x_user_info,
         ..
@@ -233,8 +898,26 @@ async fn upload(
 
     let mut uploads = HashMap::new();
 
-    while let Some(mut field) = multipart.next_field().await.unwrap() {
-        let file_name = field.file_name().unwrap().to_string();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field.file_name().is_some().then_some(field),
+            Ok(None) => break,
+            Err(e) => {
+                tracing::error!("invalid multipart upload: {e}");
+                None
+            }
+        };
+        let Some(mut field) = field else {
+            for (_, temp_file_path) in uploads.values() {
+                let _ = tokio::fs::remove_file(temp_file_path).await;
+            }
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "invalid multipart upload"})),
+            )
+                .into_response();
+        };
+        let file_name = field.file_name().unwrap_or_default().to_string();
 
         let mut file_upload = FileUpload {
             content_type: field.content_type().map(|ct| ct.into()).or_else(|| {
@@ -250,13 +933,67 @@ async fn upload(
             ..make_default_file_upload()
         };
         let (temp_file_path, len) =
-            write_field_to_temp_file(&mut field, &share_drive_path, &file_name).await;
+            match write_field_to_temp_file(&mut field, &share_drive_path).await {
+                Ok(written) => written,
+                Err(e) => {
+                    tracing::error!("could not receive {file_name}: {e}");
+                    for (_, temp_file_path) in uploads.values() {
+                        let _ = tokio::fs::remove_file(temp_file_path).await;
+                    }
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({"error": "upload interrupted"})),
+                    )
+                        .into_response();
+                }
+            };
 
         file_upload.size = len;
 
         tracing::debug!("Length of `{}` is {} bytes", file_name, len);
 
-        uploads.insert(file_name, (file_upload, temp_file_path));
+        if let Some((_, replaced)) = uploads.insert(file_name, (file_upload, temp_file_path)) {
+            let _ = tokio::fs::remove_file(replaced).await;
+        }
+    }
+
+    // the policy of the tenant of the user applies to the public files too
+    let policy_tenant = x_user_info
+        .tenant
+        .clone()
+        .unwrap_or_else(|| PUBLIC_TENANT.into());
+    let mut rejected = vec![];
+    for (upl, temp_file_path) in uploads.values_mut() {
+        if let Err(e) = validate_upload(
+            &validation,
+            &quarantine,
+            upl,
+            temp_file_path,
+            &policy_tenant,
+        )
+        .await
+        {
+            rejected.push(e);
+        }
+    }
+    if !rejected.is_empty() {
+        for (_, temp_file_path) in uploads.values() {
+            let _ = tokio::fs::remove_file(temp_file_path).await;
+        }
+        let status = if rejected
+            .iter()
+            .any(|(status, _)| *status == StatusCode::SERVICE_UNAVAILABLE)
+        {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::UNPROCESSABLE_ENTITY
+        };
+        let errors: Vec<_> = rejected.into_iter().map(|(_, error)| error).collect();
+        return (
+            status,
+            Json(json!({"error": "upload rejected", "files": errors})),
+        )
+            .into_response();
     }
 
     if uploads.len() == 1 {
@@ -264,28 +1001,56 @@ async fn upload(
             unreachable!("should never happen")
         };
 
+        upl.public_resource = query.is_public.unwrap_or(false);
         if let Some(id) = query.id.take() {
+            if upl.public_resource
+                && is_public_file_of_other_tenant(
+                    &id,
+                    x_user_info.tenant.as_deref(),
+                    &client,
+                    &collection,
+                )
+                .await
+            {
+                let _ = tokio::fs::remove_file(&temp_file_path).await;
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(json!({"error": "the file belongs to another tenant"})),
+                )
+                    .into_response();
+            }
             upl.id = id;
         }
 
-        upl.public_resource = query.is_public.unwrap_or(false);
-
         let tenant = if upl.public_resource {
+            upl.owner_tenant = x_user_info.tenant.clone();
             PUBLIC_TENANT.into()
+        } else if let Some(tenant) = x_user_info.tenant.clone() {
+            tenant
         } else {
-            x_user_info.tenant.unwrap()
+            let _ = tokio::fs::remove_file(&temp_file_path).await;
+            return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"}))).into_response();
         };
 
         let repository: StoreRepository<FileUpload> =
             StoreRepository::get_repository(client, &collection.0, &tenant).await;
         let file_service = FileService {
-            share_drive_path: &share_drive_path,
+            storage: storage.as_ref(),
             store: &repository,
+            retention: &retention,
+        };
+        let upl = match file_service.upload(upl, Some(&temp_file_path)).await {
+            Ok(upl) => upl,
+            Err(e) => {
+                tracing::error!("could not store upload: {e}");
+                let _ = tokio::fs::remove_file(&temp_file_path).await;
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({"error": "could not store the file"})),
+                )
+                    .into_response();
+            }
         };
-        let upl = file_service
-            .upload(upl, Some(&temp_file_path))
-            .await
-            .unwrap();
         if let Err(e) = message_sender.send(Exchange::new(
             format!(
                 "user {} uploaded file '{}' with id {}",
@@ -304,19 +1069,43 @@ async fn upload(
         (StatusCode::OK, Json(upl)).into_response()
     } else {
         let mut uploads_resp = Vec::with_capacity(uploads.len());
+        let Some(tenant) = &x_user_info.tenant else {
+            for (_, temp_file_path) in uploads.values() {
+                let _ = tokio::fs::remove_file(temp_file_path).await;
+            }
+            return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"}))).into_response();
+        };
         let username = &x_user_info.username.unwrap_or(x_user_info.id);
-        let tenant = &x_user_info.tenant.unwrap();
         let repository: StoreRepository<FileUpload> =
             StoreRepository::get_repository(client, &collection.0, tenant).await;
         let file_service = FileService {
-            share_drive_path: &share_drive_path,
+            storage: storage.as_ref(),
             store: &repository,
+            retention: &retention,
         };
-        for (_, (upl, temp_file_path)) in uploads {
-            let upl = file_service
-                .upload(upl, Some(&temp_file_path))
-                .await
-                .unwrap();
+        let mut uploads = uploads.into_values();
+        while let Some((upl, temp_file_path)) = uploads.next() {
+            let filename = upl.original_filename.clone();
+            let upl = match file_service.upload(upl, Some(&temp_file_path)).await {
+                Ok(upl) => upl,
+                Err(e) => {
+                    tracing::error!("could not store upload {filename}: {e}");
+                    let _ = tokio::fs::remove_file(&temp_file_path).await;
+                    for (_, temp_file_path) in uploads {
+                        let _ = tokio::fs::remove_file(temp_file_path).await;
+                    }
+                    // the files stored before the failure are kept
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({
+                            "error": "could not store the file",
+                            "filename": filename,
+                            "stored": uploads_resp
+                        })),
+                    )
+                        .into_response();
+                }
+            };
             if let Err(e) = message_sender.send(Exchange::new(
                 format!(
                     "user {} uploaded file '{}' with id {}",
@@ -334,6 +1123,456 @@ async fn upload(
         (StatusCode::OK, Json(uploads_resp)).into_response()
     }
 }
+
+/// runs the validation pipeline, the rejected files are moved to the quarantine.
+/// the error is the status and the description of the failure
+async fn validate_upload(
+    validation: &ValidationPipeline,
+    quarantine: &Quarantine,
+    upl: &mut FileUpload,
+    temp_file_path: &Path,
+    tenant: &str,
+) -> Result<(), (StatusCode, serde_json::Value)> {
+    let Err(e) = validation.validate(upl, temp_file_path, tenant).await else {
+        return Ok(());
+    };
+    let error = json!({"filename": upl.original_filename, "error": e.to_string()});
+    match e.kind {
+        ValidationErrorKind::Rejected => {
+            if let Err(qe) = quarantine
+                .put(tenant, upl, temp_file_path, &e.to_string())
+                .await
+            {
+                tracing::error!("could not quarantine {}: {qe}", upl.original_filename);
+            }
+            Err((StatusCode::UNPROCESSABLE_ENTITY, error))
+        }
+        ValidationErrorKind::Unavailable => {
+            tracing::error!("could not validate {}: {e}", upl.original_filename);
+            Err((StatusCode::SERVICE_UNAVAILABLE, error))
+        }
+    }
+}
+
+// region: share links
+fn share_link_error_response(e: ShareLinkError) -> Response {
+    let status = match e.kind {
+        ShareLinkErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ShareLinkErrorKind::Expired
+        | ShareLinkErrorKind::Exhausted
+        | ShareLinkErrorKind::Locked => StatusCode::GONE,
+        ShareLinkErrorKind::PasswordRequired => StatusCode::UNAUTHORIZED,
+        ShareLinkErrorKind::Invalid => StatusCode::BAD_REQUEST,
+        ShareLinkErrorKind::Store => {
+            tracing::error!("share link error: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, Json(json!({"error": e.to_string()}))).into_response()
+}
+
+/// checks the signature, the expiration and the password of a link to the file
+async fn get_share_link(
+    share_links: &ShareLinks,
+    client: &StoreClient,
+    collection: &StoreCollection,
+    token: &str,
+    file_id: &str,
+    password: Option<&str>,
+) -> Result<(StoreRepository<ShareLink>, ShareLink), ShareLinkError> {
+    let claims = share_links.verify(token, Local::now().naive_local())?;
+    let repository: StoreRepository<ShareLink> = StoreRepository::get_repository(
+        client.clone(),
+        &share_link_collection(&collection.0),
+        &claims.tenant,
+    )
+    .await;
+    let link = match repository.find_by_id(&claims.link_id).await {
+        Ok(Some(link)) if link.file_id == file_id => link,
+        Ok(_) => {
+            return Err(ShareLinkError::new(
+                ShareLinkErrorKind::NotFound,
+                "invalid share link",
+            ))
+        }
+        Err(e) => {
+            return Err(ShareLinkError::new(
+                ShareLinkErrorKind::Store,
+                e.to_string(),
+            ))
+        }
+    };
+    check_password_attempt(&repository, &link, password).await?;
+    Ok((repository, link))
+}
+
+async fn create_share_link(
+    Extension(client): Extension<StoreClient>,
+    Extension(message_sender): Extension<Sender<Exchange>>,
+    Extension(collection): Extension<StoreCollection>,
+    Extension(share_links): Extension<Arc<ShareLinks>>,
+    x_user_info: ExtractUserInfo,
+    Json(request): Json<CreateShareLinkRequest>,
+) -> impl IntoResponse {
+    tracing::debug!("Create share link route entered!");
+
+    let user_info = x_user_info.user_info.clone();
+    let Some(tenant) = user_info.tenant.clone() else {
+        return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"}))).into_response();
+    };
+    let x_user_info = Some(x_user_info);
+    let Some((_, file)) = get_file_upload(&request.id, &x_user_info, &client, &collection).await
+    else {
+        return (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response();
+    };
+    let link = match share_links.create(
+        request,
+        &file,
+        &tenant,
+        &user_info.id,
+        Local::now().naive_local(),
+    ) {
+        Ok(link) => link,
+        Err(e) => return share_link_error_response(e),
+    };
+    let repository: StoreRepository<ShareLink> =
+        StoreRepository::get_repository(client, &share_link_collection(&collection.0), &tenant)
+            .await;
+    if let Err(e) = repository.insert_one(&link).await {
+        tracing::error!("could not save the share link: {e}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "could not save the share link"})),
+        )
+            .into_response();
+    }
+    if let Err(e) = message_sender.send(Exchange::new(
+        format!(
+            "user {} shared file '{}' with id {} until {}",
+            &user_info.username.unwrap_or(user_info.id),
+            &file.original_filename,
+            &file.id,
+            link.expiration_date
+        )
+        .as_bytes(),
+        TOPIC_UPLOAD,
+        user_info.tenant,
+        HashMap::new(),
+    )) {
+        tracing::error!("could not send message {e}");
+    }
+    (StatusCode::CREATED, Json(share_links.info(&link))).into_response()
+}
+
+async fn list_share_links(
+    Extension(client): Extension<StoreClient>,
+    Extension(collection): Extension<StoreCollection>,
+    Extension(share_links): Extension<Arc<ShareLinks>>,
+    x_user_info: ExtractUserInfo,
+    Query(DownloadFileRequestUriParams { id, .. }): Query<DownloadFileRequestUriParams>,
+) -> impl IntoResponse {
+    tracing::debug!("List share links route entered!");
+
+    let Some(tenant) = x_user_info.user_info.tenant.clone() else {
+        return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"}))).into_response();
+    };
+    let repository: StoreRepository<ShareLink> =
+        StoreRepository::get_repository(client, &share_link_collection(&collection.0), &tenant)
+            .await;
+    match repository.find_by_query(doc! {"fileId": &id}, None).await {
+        Ok(links) => {
+            let links: Vec<_> = links.iter().map(|link| share_links.info(link)).collect();
+            Json(links).into_response()
+        }
+        Err(e) => {
+            tracing::error!("could not list the share links of {id}: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "could not list the share links"})),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn revoke_share_link(
+    Extension(client): Extension<StoreClient>,
+    Extension(message_sender): Extension<Sender<Exchange>>,
+    Extension(collection): Extension<StoreCollection>,
+    x_user_info: ExtractUserInfo,
+    UriPath(link_id): UriPath<String>,
+) -> impl IntoResponse {
+    tracing::debug!("Revoke share link route entered!");
+
+    let user_info = x_user_info.user_info;
+    let Some(tenant) = user_info.tenant.clone() else {
+        return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"}))).into_response();
+    };
+    let repository: StoreRepository<ShareLink> =
+        StoreRepository::get_repository(client, &share_link_collection(&collection.0), &tenant)
+            .await;
+    match repository.delete_by_id(&link_id).await {
+        Ok(Some(link)) => {
+            if let Err(e) = message_sender.send(Exchange::new(
+                format!(
+                    "user {} revoked a share link of file with id {}",
+                    &user_info.username.unwrap_or(user_info.id),
+                    &link.file_id
+                )
+                .as_bytes(),
+                TOPIC_UPLOAD,
+                user_info.tenant,
+                HashMap::new(),
+            )) {
+                tracing::error!("could not send message {e}");
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({"error": "Not found"}))).into_response(),
+        Err(e) => {
+            tracing::error!("could not revoke the share link {link_id}: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "could not revoke the share link"})),
+            )
+                .into_response()
+        }
+    }
+}
+// endregion
+
+// region: resumable uploads
+fn session_error_response(e: SessionError) -> Response {
+    let status = match e.kind {
+        SessionErrorKind::NotFound => StatusCode::NOT_FOUND,
+        SessionErrorKind::OffsetMismatch(offset) => {
+            return (
+                StatusCode::CONFLICT,
+                [(UPLOAD_OFFSET, offset.to_string())],
+                Json(json!({"error": e.to_string(), "offset": offset})),
+            )
+                .into_response();
+        }
+        SessionErrorKind::Invalid => StatusCode::BAD_REQUEST,
+        SessionErrorKind::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        SessionErrorKind::TooMany => StatusCode::TOO_MANY_REQUESTS,
+        SessionErrorKind::Io => {
+            tracing::error!("upload session error: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    (status, Json(json!({"error": e.to_string()}))).into_response()
+}
+
+async fn create_upload_session(
+    Extension(sessions): Extension<Arc<UploadSessions>>,
+    ExtractUserInfo {
+        user_info: x_user_info,
+        ..
+    }: ExtractUserInfo,
+    Json(request): Json<CreateUploadSessionRequest>,
+) -> impl IntoResponse {
+    tracing::debug!("Create upload session route entered!");
+
+    let tenant = if request.is_public.unwrap_or(false) {
+        PUBLIC_TENANT.into()
+    } else if let Some(tenant) = x_user_info.tenant {
+        tenant
+    } else {
+        return (StatusCode::FORBIDDEN, Json(json!({"error": "no tenant"}))).into_response();
+    };
+    match sessions.create(request, &tenant, &x_user_info.id).await {
+        Ok(session) => (StatusCode::CREATED, Json(session.status(0))).into_response(),
+        Err(e) => session_error_response(e),
+    }
+}
+
+async fn upload_session_status(
+    Extension(sessions): Extension<Arc<UploadSessions>>,
+    ExtractUserInfo {
+        user_info: x_user_info,
+        ..
+    }: ExtractUserInfo,
+    UriPath(upload_id): UriPath<String>,
+) -> impl IntoResponse {
+    match sessions.get(&upload_id, &x_user_info.id).await {
+        Ok((session, offset)) => (
+            [(UPLOAD_OFFSET, offset.to_string())],
+            Json(session.status(offset)),
+        )
+            .into_response(),
+        Err(e) => session_error_response(e),
+    }
+}
+
+async fn append_upload_chunk(
+    Extension(sessions): Extension<Arc<UploadSessions>>,
+    ExtractUserInfo {
+        user_info: x_user_info,
+        ..
+    }: ExtractUserInfo,
+    UriPath(upload_id): UriPath<String>,
+    headers: HeaderMap,
+    chunk: Bytes,
+) -> impl IntoResponse {
+    let Some(offset) = headers
+        .get(UPLOAD_OFFSET)
+        .and_then(|o| o.to_str().ok())
+        .and_then(|o| o.parse::<u64>().ok())
+    else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("{UPLOAD_OFFSET} header is required")})),
+        )
+            .into_response();
+    };
+    let checksum = headers.get(UPLOAD_CHECKSUM).and_then(|c| c.to_str().ok());
+    match sessions
+        .append(&upload_id, &x_user_info.id, offset, &chunk, checksum)
+        .await
+    {
+        Ok(offset) => (
+            StatusCode::NO_CONTENT,
+            [(UPLOAD_OFFSET, offset.to_string())],
+        )
+            .into_response(),
+        Err(e) => session_error_response(e),
+    }
+}
+
+async fn abort_upload_session(
+    Extension(sessions): Extension<Arc<UploadSessions>>,
+    ExtractUserInfo {
+        user_info: x_user_info,
+        ..
+    }: ExtractUserInfo,
+    UriPath(upload_id): UriPath<String>,
+) -> impl IntoResponse {
+    match sessions.abort(&upload_id, &x_user_info.id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => session_error_response(e),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn finalize_upload_session(
+    Extension(client): Extension<StoreClient>,
+    Extension(message_sender): Extension<Sender<Exchange>>,
+    Extension(collection): Extension<StoreCollection>,
+    Extension(storage): Extension<SharedStorage>,
+    Extension(retention): Extension<VersionRetention>,
+    Extension(sessions): Extension<Arc<UploadSessions>>,
+    Extension(validation): Extension<Arc<ValidationPipeline>>,
+    Extension(quarantine): Extension<Quarantine>,
+    ExtractUserInfo {
+        user_info: x_user_info,
+        ..
+    }: ExtractUserInfo,
+    UriPath(upload_id): UriPath<String>,
+) -> impl IntoResponse {
+    tracing::debug!("Finalize upload session route entered!");
+
+    let (session, temp_file_path) = match sessions.finalize(&upload_id, &x_user_info.id).await {
+        Ok(finalized) => finalized,
+        Err(e) => return session_error_response(e),
+    };
+    let request = session.request;
+    let default_upload = make_default_file_upload();
+    let mut upl = FileUpload {
+        id: request.id.unwrap_or(default_upload.id.clone()),
+        content_type: request.content_type.or_else(|| {
+            mime_guess::from_path(&request.filename)
+                .first_raw()
+                .map(|ct| ct.into())
+        }),
+        correlation_id: request.correlation_id,
+        extension: Path::new(&request.filename)
+            .extension()
+            .map(|s| s.to_string_lossy().to_string()),
+        original_filename: request.filename,
+        size: request.size,
+        public_resource: request.is_public.unwrap_or(false),
+        owner_tenant: request
+            .is_public
+            .unwrap_or(false)
+            .then(|| x_user_info.tenant.clone())
+            .flatten(),
+        ..default_upload
+    };
+    if upl.public_resource
+        && is_public_file_of_other_tenant(
+            &upl.id,
+            x_user_info.tenant.as_deref(),
+            &client,
+            &collection,
+        )
+        .await
+    {
+        let _ = tokio::fs::remove_file(&temp_file_path).await;
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "the file belongs to another tenant"})),
+        )
+            .into_response();
+    }
+    let policy_tenant = x_user_info
+        .tenant
+        .clone()
+        .unwrap_or_else(|| session.tenant.clone());
+    if let Err((status, error)) = validate_upload(
+        &validation,
+        &quarantine,
+        &mut upl,
+        &temp_file_path,
+        &policy_tenant,
+    )
+    .await
+    {
+        let _ = tokio::fs::remove_file(&temp_file_path).await;
+        return (
+            status,
+            Json(json!({"error": "upload rejected", "files": [error]})),
+        )
+            .into_response();
+    }
+    let repository: StoreRepository<FileUpload> =
+        StoreRepository::get_repository(client, &collection.0, &session.tenant).await;
+    let file_service = FileService {
+        storage: storage.as_ref(),
+        store: &repository,
+        retention: &retention,
+    };
+    match file_service.upload(upl, Some(&temp_file_path)).await {
+        Ok(upl) => {
+            if let Err(e) = message_sender.send(Exchange::new(
+                format!(
+                    "user {} uploaded file '{}' with id {}",
+                    &x_user_info.username.unwrap_or(x_user_info.id),
+                    &upl.original_filename,
+                    &upl.id
+                )
+                .as_bytes(),
+                TOPIC_UPLOAD,
+                Some(session.tenant),
+                HashMap::new(),
+            )) {
+                tracing::error!("could not send message {e}");
+            }
+            (StatusCode::OK, Json(upl)).into_response()
+        }
+        Err(e) => {
+            tracing::error!("could not store upload {upload_id}: {e}");
+            let _ = tokio::fs::remove_file(&temp_file_path).await;
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": "could not store the file"})),
+            )
+                .into_response()
+        }
+    }
+}
+// endregion
+
 fn make_default_file_upload() -> FileUpload {
     FileUpload {
         id: IdGenerator.get(),
@@ -347,5 +1586,9 @@ fn make_default_file_upload() -> FileUpload {
         size: Default::default(),
         public_resource: Default::default(),
         correlation_id: Default::default(),
+        version: 1,
+        versions: Default::default(),
+        converted_from: Default::default(),
+        owner_tenant: Default::default(),
     }
 }
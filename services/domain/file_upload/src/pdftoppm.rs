@@ -0,0 +1,37 @@
+use std::{error::Error, path::Path, process::Stdio};
+
+use tokio::process::Command;
+
+/// renders a page of a pdf as png with poppler, much faster than a LibreOffice run.
+/// `scale_to` is the size of the longest side
+pub async fn render_page(
+    input_path: &Path,
+    page: u32,
+    scale_to: u32,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    tracing::debug!("render page {page} of {input_path:?}");
+    let output_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    tokio::fs::create_dir_all(&output_dir).await?;
+    let output_prefix = output_dir.join("page");
+    let page = page.max(1).to_string();
+    let output = Command::new("pdftoppm")
+        .args(["-png", "-singlefile", "-f", &page, "-l", &page, "-scale-to"])
+        .arg(scale_to.to_string())
+        .arg(input_path)
+        .arg(&output_prefix)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await;
+    let bytes = match output {
+        Ok(output) if output.status.success() => {
+            tokio::fs::read(output_prefix.with_extension("png"))
+                .await
+                .map_err(|e| e.into())
+        }
+        Ok(output) => Err(format!("error {}", String::from_utf8_lossy(&output.stderr)).into()),
+        Err(e) => Err(e.into()),
+    };
+    let _ = tokio::fs::remove_dir_all(&output_dir).await;
+    bytes
+}
@@ -0,0 +1,342 @@
+use std::{collections::HashMap, env::var, error::Error, fmt::Display, io::Cursor, sync::Arc};
+
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use sequeda_file_upload_common::{ImageFit, ImageOutputFormat, ImageRequestUriParams};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// comma separated `name:<width>x<height>:<format>[:<fit>]`,
+/// default to `small:150x150:webp,medium:600x600:webp,large:1600x1600:webp`
+pub const RENDITION_PRESETS: &str = "RENDITION_PRESETS";
+/// largest width or height of the images generated on demand, default to 2048
+pub const IMAGE_MAX_DIMENSION: &str = "IMAGE_MAX_DIMENSION";
+/// images generated at the same time, default to the number of cpus
+pub const RENDITION_CONCURRENCY: &str = "RENDITION_CONCURRENCY";
+/// images generated on demand kept for each version of a file, default to 32. The presets are always kept
+pub const RENDITION_MAX_PER_FILE: &str = "RENDITION_MAX_PER_FILE";
+
+const DEFAULT_PRESETS: &str = "small:150x150:webp,medium:600x600:webp,large:1600x1600:webp";
+/// size of the pdf page rendered when no dimension is given
+const DEFAULT_PAGE_SIZE: u32 = 1024;
+/// the dimensions asked are rounded up to one of these, so that a few images are generated per file
+const DIMENSION_BUCKETS: [u32; 16] = [
+    32, 64, 96, 128, 160, 240, 320, 480, 640, 800, 960, 1280, 1600, 1920, 2560, 3840,
+];
+
+#[derive(Debug)]
+pub struct RenditionError {
+    msg: String,
+}
+
+impl RenditionError {
+    fn new(msg: impl Into<String>) -> Self {
+        RenditionError { msg: msg.into() }
+    }
+}
+
+impl Error for RenditionError {}
+
+impl Display for RenditionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+/// How an image is generated from the uploaded file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: ImageFit,
+    pub format: ImageOutputFormat,
+    /// page of a pdf, start at 1
+    pub page: u32,
+    pub preset: bool,
+}
+
+impl Transform {
+    /// the key of the generated image in the storage. the internal name changes with each
+    /// version, so the images of a previous version are never served
+    pub fn cache_key(&self, internal_name: &str) -> String {
+        let dimension = |d: Option<u32>| d.map(|d| d.to_string()).unwrap_or_else(|| "auto".into());
+        format!(
+            "renditions/{internal_name}/{}x{}-{:?}-p{}.{}",
+            dimension(self.width),
+            dimension(self.height),
+            self.fit,
+            self.page,
+            extension(self.format)
+        )
+        .to_lowercase()
+    }
+
+    /// the size of the pdf page to render before resizing it
+    pub fn page_size(&self) -> u32 {
+        match (self.width, self.height) {
+            (None, None) => DEFAULT_PAGE_SIZE,
+            (w, h) => w.unwrap_or(0).max(h.unwrap_or(0)),
+        }
+    }
+
+    pub fn apply(&self, image: &DynamicImage) -> Result<Vec<u8>, RenditionError> {
+        let (width, height) = match (self.width, self.height) {
+            (None, None) => (image.width(), image.height()),
+            (Some(w), None) => (w, u32::MAX),
+            (None, Some(h)) => (u32::MAX, h),
+            (Some(w), Some(h)) => (w, h),
+        };
+        let resized = match (self.fit, self.width, self.height) {
+            (_, None, None) => image.clone(),
+            (ImageFit::Cover, Some(_), Some(_)) => {
+                image.resize_to_fill(width, height, FilterType::Lanczos3)
+            }
+            (ImageFit::Fill, Some(_), Some(_)) => {
+                image.resize_exact(width, height, FilterType::Lanczos3)
+            }
+            // never upscaled
+            _ if image.width() <= width && image.height() <= height => image.clone(),
+            _ => image.resize(width, height, FilterType::Lanczos3),
+        };
+        // the encoders do not support every color type
+        let resized = match self.format {
+            ImageOutputFormat::Jpeg => DynamicImage::ImageRgb8(resized.to_rgb8()),
+            _ => DynamicImage::ImageRgba8(resized.to_rgba8()),
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        resized
+            .write_to(&mut cursor, image_format(self.format))
+            .map_err(|e| RenditionError::new(e.to_string()))?;
+        Ok(cursor.into_inner())
+    }
+}
+
+pub fn content_type(format: ImageOutputFormat) -> &'static str {
+    match format {
+        ImageOutputFormat::Webp => "image/webp",
+        ImageOutputFormat::Avif => "image/avif",
+        ImageOutputFormat::Png => "image/png",
+        ImageOutputFormat::Jpeg => "image/jpeg",
+    }
+}
+
+fn extension(format: ImageOutputFormat) -> &'static str {
+    match format {
+        ImageOutputFormat::Webp => "webp",
+        ImageOutputFormat::Avif => "avif",
+        ImageOutputFormat::Png => "png",
+        ImageOutputFormat::Jpeg => "jpg",
+    }
+}
+
+fn image_format(format: ImageOutputFormat) -> ImageFormat {
+    match format {
+        ImageOutputFormat::Webp => ImageFormat::WebP,
+        ImageOutputFormat::Avif => ImageFormat::Avif,
+        ImageOutputFormat::Png => ImageFormat::Png,
+        ImageOutputFormat::Jpeg => ImageFormat::Jpeg,
+    }
+}
+
+/// The presets, and the limits of the images generated on demand
+#[derive(Debug, Clone)]
+pub struct Renditions {
+    presets: HashMap<String, Transform>,
+    max_dimension: u32,
+    max_per_file: usize,
+    renders: Arc<Semaphore>,
+}
+
+impl Renditions {
+    pub fn new(presets: HashMap<String, Transform>, max_dimension: u32) -> Self {
+        let concurrency = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Renditions {
+            presets,
+            max_dimension,
+            max_per_file: 32,
+            renders: Arc::new(Semaphore::new(concurrency)),
+        }
+    }
+
+    pub fn from_env() -> Result<Self, RenditionError> {
+        let parse = |name: &str| -> Result<Option<usize>, RenditionError> {
+            var(name)
+                .ok()
+                .map(|value| {
+                    value
+                        .parse()
+                        .map_err(|_| RenditionError::new(format!("invalid {name} {value}")))
+                })
+                .transpose()
+        };
+        let presets = var(RENDITION_PRESETS).unwrap_or_else(|_| DEFAULT_PRESETS.into());
+        let max_dimension = parse(IMAGE_MAX_DIMENSION)?.unwrap_or(2048) as u32;
+        let mut renditions = Renditions::new(parse_presets(&presets)?, max_dimension);
+        if let Some(concurrency) = parse(RENDITION_CONCURRENCY)? {
+            renditions.renders = Arc::new(Semaphore::new(concurrency.max(1)));
+        }
+        if let Some(max_per_file) = parse(RENDITION_MAX_PER_FILE)? {
+            renditions.max_per_file = max_per_file;
+        }
+        Ok(renditions)
+    }
+
+    /// waits until fewer than `RENDITION_CONCURRENCY` images are being generated
+    pub async fn permit(&self) -> Result<SemaphorePermit<'_>, RenditionError> {
+        self.renders
+            .acquire()
+            .await
+            .map_err(|e| RenditionError::new(e.to_string()))
+    }
+
+    pub fn max_per_file(&self) -> usize {
+        self.max_per_file
+    }
+
+    /// the smallest bucket that holds the dimension, or the max dimension
+    fn bucket(&self, dimension: u32) -> u32 {
+        DIMENSION_BUCKETS
+            .into_iter()
+            .find(|bucket| *bucket >= dimension)
+            .unwrap_or(self.max_dimension)
+            .min(self.max_dimension)
+    }
+
+    pub fn resolve(&self, params: &ImageRequestUriParams) -> Result<Transform, RenditionError> {
+        let page = params.page.unwrap_or(1).max(1);
+        if let Some(preset) = &params.preset {
+            let transform = self
+                .presets
+                .get(preset)
+                .ok_or_else(|| RenditionError::new(format!("unknown preset {preset}")))?;
+            return Ok(Transform {
+                page,
+                ..transform.clone()
+            });
+        }
+        for dimension in [params.w, params.h].into_iter().flatten() {
+            if dimension == 0 || dimension > self.max_dimension {
+                return Err(RenditionError::new(format!(
+                    "dimensions must be between 1 and {}",
+                    self.max_dimension
+                )));
+            }
+        }
+        Ok(Transform {
+            width: params.w.map(|w| self.bucket(w)),
+            height: params.h.map(|h| self.bucket(h)),
+            fit: params.fit.unwrap_or_default(),
+            format: params.format.unwrap_or_default(),
+            page,
+            preset: false,
+        })
+    }
+}
+
+fn parse_presets(presets: &str) -> Result<HashMap<String, Transform>, RenditionError> {
+    let invalid = |preset: &str| RenditionError::new(format!("invalid preset {preset}"));
+    let enum_value = |value: &str| serde_json::Value::String(value.trim().to_lowercase());
+    presets
+        .split(',')
+        .filter(|preset| !preset.trim().is_empty())
+        .map(|preset| {
+            let parts: Vec<&str> = preset.trim().split(':').collect();
+            let (name, dimensions, format) = match parts.as_slice() {
+                [name, dimensions, format] | [name, dimensions, format, _] => {
+                    (name, dimensions, format)
+                }
+                _ => return Err(invalid(preset)),
+            };
+            let (width, height) = dimensions.split_once('x').ok_or_else(|| invalid(preset))?;
+            let fit = match parts.get(3) {
+                Some(fit) => {
+                    serde_json::from_value(enum_value(fit)).map_err(|_| invalid(preset))?
+                }
+                None => ImageFit::default(),
+            };
+            let transform = Transform {
+                width: Some(width.trim().parse().map_err(|_| invalid(preset))?),
+                height: Some(height.trim().parse().map_err(|_| invalid(preset))?),
+                fit,
+                format: serde_json::from_value(enum_value(format)).map_err(|_| invalid(preset))?,
+                page: 1,
+                preset: true,
+            };
+            Ok((name.trim().to_string(), transform))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use image::{DynamicImage, GenericImageView};
+    use sequeda_file_upload_common::{ImageFit, ImageOutputFormat, ImageRequestUriParams};
+
+    use super::{parse_presets, Renditions};
+
+    #[test]
+    fn test_renditions() {
+        let renditions = Renditions::new(
+            parse_presets("small:150x100:webp, large:1600x1600:avif:cover").unwrap(),
+            2048,
+        );
+        assert!(parse_presets("small:150:webp").is_err());
+        let large = renditions
+            .resolve(&ImageRequestUriParams {
+                preset: Some("large".into()),
+                page: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(ImageFit::Cover, large.fit);
+        assert_eq!(ImageOutputFormat::Avif, large.format);
+        assert_eq!(
+            "renditions/42.pdf/1600x1600-cover-p2.avif",
+            large.cache_key("42.pdf")
+        );
+        assert!(renditions
+            .resolve(&ImageRequestUriParams {
+                w: Some(4096),
+                ..Default::default()
+            })
+            .is_err());
+
+        // rounded up to a bucket
+        let resolve = |w: u32, h: Option<u32>| {
+            let transform = renditions
+                .resolve(&ImageRequestUriParams {
+                    w: Some(w),
+                    h,
+                    ..Default::default()
+                })
+                .unwrap();
+            (transform.width, transform.height)
+        };
+        assert_eq!((Some(128), None), resolve(100, None));
+        assert_eq!((Some(128), Some(32)), resolve(128, Some(1)));
+        assert_eq!((Some(2048), None), resolve(2000, None));
+
+        let image = DynamicImage::new_rgb8(400, 200);
+        let small = renditions
+            .resolve(&ImageRequestUriParams {
+                preset: Some("small".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        let bytes = small.apply(&image).unwrap();
+        let generated = image::load_from_memory(&bytes).unwrap();
+        assert_eq!((150, 75), generated.dimensions());
+
+        let cover = renditions
+            .resolve(&ImageRequestUriParams {
+                w: Some(90),
+                h: Some(96),
+                fit: Some(ImageFit::Cover),
+                format: Some(ImageOutputFormat::Png),
+                ..Default::default()
+            })
+            .unwrap();
+        let generated = image::load_from_memory(&cover.apply(&image).unwrap()).unwrap();
+        assert_eq!((96, 96), generated.dimensions());
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use chrono::{Duration, Local, NaiveDateTime};
+use sequeda_file_upload_common::{CreateUploadSessionRequest, UploadSessionStatus};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{Mutex, OwnedMutexGuard},
+};
+
+use crate::storage::sha256;
+
+/// hours after which an unfinished upload is removed, default to 24
+pub const UPLOAD_SESSION_TTL_HOURS: &str = "UPLOAD_SESSION_TTL_HOURS";
+/// max size of a file sent in several requests, in bytes, default to 5GB
+pub const UPLOAD_SESSION_MAX_SIZE: &str = "UPLOAD_SESSION_MAX_SIZE";
+/// max unfinished uploads of a user, default to 10
+pub const UPLOAD_SESSION_MAX_PER_USER: &str = "UPLOAD_SESSION_MAX_PER_USER";
+pub const DEFAULT_MAX_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+pub const DEFAULT_MAX_PER_USER: usize = 10;
+/// how often the expired uploads are removed
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+/// where the chunk starts, in the request, and the bytes received, in the responses
+pub const UPLOAD_OFFSET: &str = "Upload-Offset";
+/// hex encoded sha256 of the chunk
+pub const UPLOAD_CHECKSUM: &str = "Upload-Checksum";
+
+#[derive(Debug, PartialEq)]
+pub enum SessionErrorKind {
+    NotFound,
+    /// the chunk does not start where the previous one ended
+    OffsetMismatch(u64),
+    Invalid,
+    TooLarge,
+    /// the user has too many unfinished uploads
+    TooMany,
+    Io,
+}
+
+#[derive(Debug)]
+pub struct SessionError {
+    pub kind: SessionErrorKind,
+    msg: String,
+}
+
+impl SessionError {
+    fn new(kind: SessionErrorKind, msg: impl Into<String>) -> Self {
+        SessionError {
+            kind,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl From<std::io::Error> for SessionError {
+    fn from(e: std::io::Error) -> Self {
+        SessionError::new(SessionErrorKind::Io, e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadSession {
+    pub upload_id: String,
+    pub tenant: String,
+    pub user_id: String,
+    pub creation_date: NaiveDateTime,
+    pub request: CreateUploadSessionRequest,
+}
+
+/// Uploads sent in several requests, kept on disk so they survive a restart:
+/// `<upload id>.json` holds the session, `<upload id>.part` the bytes received so far
+#[derive(Debug)]
+pub struct UploadSessions {
+    root: PathBuf,
+    ttl: Duration,
+    max_size: u64,
+    max_per_user: usize,
+    /// serializes the creations, to count the uploads of the user
+    create_lock: Mutex<()>,
+    /// serializes the writes to each session
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl UploadSessions {
+    pub fn new(root: impl Into<PathBuf>, ttl: Duration) -> Self {
+        UploadSessions {
+            root: root.into(),
+            ttl,
+            max_size: DEFAULT_MAX_SIZE,
+            max_per_user: DEFAULT_MAX_PER_USER,
+            create_lock: Mutex::new(()),
+            locks: Default::default(),
+        }
+    }
+
+    pub fn with_limits(mut self, max_size: u64, max_per_user: usize) -> Self {
+        self.max_size = max_size;
+        self.max_per_user = max_per_user;
+        self
+    }
+
+    /// removes the expired uploads every `CLEANUP_INTERVAL`
+    pub fn spawn_cleanup(self: &Arc<Self>) {
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                sessions.remove_expired().await;
+            }
+        });
+    }
+
+    async fn lock(&self, upload_id: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            // the locks nobody holds or waits for
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(upload_id.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
+    fn session_path(&self, upload_id: &str) -> Result<PathBuf, SessionError> {
+        // the id ends up in a path
+        uuid::Uuid::parse_str(upload_id).map_err(|_| {
+            SessionError::new(SessionErrorKind::NotFound, format!("{upload_id} not found"))
+        })?;
+        Ok(self.root.join(format!("{upload_id}.json")))
+    }
+
+    fn part_path(&self, upload_id: &str) -> Result<PathBuf, SessionError> {
+        Ok(self.session_path(upload_id)?.with_extension("part"))
+    }
+
+    pub async fn create(
+        &self,
+        request: CreateUploadSessionRequest,
+        tenant: &str,
+        user_id: &str,
+    ) -> Result<UploadSession, SessionError> {
+        if let Some(checksum) = &request.checksum {
+            check_checksum(checksum)?;
+        }
+        if request.size > self.max_size {
+            return Err(SessionError::new(
+                SessionErrorKind::TooLarge,
+                format!("the size exceeds {} bytes", self.max_size),
+            ));
+        }
+        tokio::fs::create_dir_all(&self.root).await?;
+        let _lock = self.create_lock.lock().await;
+        if self.count(user_id).await >= self.max_per_user {
+            return Err(SessionError::new(
+                SessionErrorKind::TooMany,
+                format!("at most {} unfinished uploads", self.max_per_user),
+            ));
+        }
+        let session = UploadSession {
+            upload_id: uuid::Uuid::new_v4().to_string(),
+            tenant: tenant.into(),
+            user_id: user_id.into(),
+            creation_date: Local::now().naive_local(),
+            request,
+        };
+        let json = serde_json::to_vec(&session)
+            .map_err(|e| SessionError::new(SessionErrorKind::Io, e.to_string()))?;
+        tokio::fs::File::create(self.part_path(&session.upload_id)?).await?;
+        tokio::fs::write(self.session_path(&session.upload_id)?, json).await?;
+        Ok(session)
+    }
+
+    /// the session and its offset, if it belongs to the user
+    pub async fn get(
+        &self,
+        upload_id: &str,
+        user_id: &str,
+    ) -> Result<(UploadSession, u64), SessionError> {
+        let not_found =
+            || SessionError::new(SessionErrorKind::NotFound, format!("{upload_id} not found"));
+        let json = match tokio::fs::read(self.session_path(upload_id)?).await {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_found()),
+            Err(e) => return Err(e.into()),
+        };
+        let session: UploadSession = serde_json::from_slice(&json)
+            .map_err(|e| SessionError::new(SessionErrorKind::Io, e.to_string()))?;
+        if session.user_id != user_id {
+            return Err(not_found());
+        }
+        let offset = tokio::fs::metadata(self.part_path(upload_id)?).await?.len();
+        Ok((session, offset))
+    }
+
+    /// appends `chunk` if it starts at `offset`, returns the new offset
+    pub async fn append(
+        &self,
+        upload_id: &str,
+        user_id: &str,
+        offset: u64,
+        chunk: &[u8],
+        checksum: Option<&str>,
+    ) -> Result<u64, SessionError> {
+        let _lock = self.lock(upload_id).await;
+        let (session, current_offset) = self.get(upload_id, user_id).await?;
+        if offset != current_offset {
+            return Err(SessionError::new(
+                SessionErrorKind::OffsetMismatch(current_offset),
+                format!("expected offset {current_offset}, got {offset}"),
+            ));
+        }
+        let new_offset = offset + chunk.len() as u64;
+        if new_offset > session.request.size {
+            return Err(SessionError::new(
+                SessionErrorKind::Invalid,
+                format!("chunk exceeds the size {}", session.request.size),
+            ));
+        }
+        if let Some(checksum) = checksum {
+            let actual: String = Sha256::digest(chunk)
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            if !actual.eq_ignore_ascii_case(checksum.trim()) {
+                return Err(SessionError::new(
+                    SessionErrorKind::Invalid,
+                    "chunk checksum mismatch",
+                ));
+            }
+        }
+        let mut part = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(self.part_path(upload_id)?)
+            .await?;
+        part.write_all(chunk).await?;
+        part.flush().await?;
+        Ok(new_offset)
+    }
+
+    /// checks the upload is complete, and returns the file received.
+    /// the session is removed, the caller owns the file
+    pub async fn finalize(
+        &self,
+        upload_id: &str,
+        user_id: &str,
+    ) -> Result<(UploadSession, PathBuf), SessionError> {
+        let _lock = self.lock(upload_id).await;
+        let (session, offset) = self.get(upload_id, user_id).await?;
+        if offset != session.request.size {
+            return Err(SessionError::new(
+                SessionErrorKind::OffsetMismatch(offset),
+                format!("{offset} of {} bytes received", session.request.size),
+            ));
+        }
+        let part_path = self.part_path(upload_id)?;
+        if let Some(checksum) = &session.request.checksum {
+            let actual = sha256(&part_path)
+                .await
+                .map_err(|e| SessionError::new(SessionErrorKind::Io, e.to_string()))?;
+            if !actual.eq_ignore_ascii_case(checksum) {
+                self.remove(upload_id).await?;
+                return Err(SessionError::new(
+                    SessionErrorKind::Invalid,
+                    "checksum mismatch, the upload must be restarted",
+                ));
+            }
+        }
+        tokio::fs::remove_file(self.session_path(upload_id)?).await?;
+        Ok((session, part_path))
+    }
+
+    pub async fn abort(&self, upload_id: &str, user_id: &str) -> Result<(), SessionError> {
+        let _lock = self.lock(upload_id).await;
+        self.get(upload_id, user_id).await?;
+        self.remove(upload_id).await
+    }
+
+    async fn remove(&self, upload_id: &str) -> Result<(), SessionError> {
+        for path in [self.session_path(upload_id)?, self.part_path(upload_id)?] {
+            match tokio::fs::remove_file(path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// the sessions on disk, `None` when one cannot be read
+    async fn sessions(&self) -> Vec<(PathBuf, Option<UploadSession>)> {
+        let mut sessions = vec![];
+        let Ok(mut entries) = tokio::fs::read_dir(&self.root).await else {
+            return sessions;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let session = read_session(&path).await;
+                sessions.push((path, session));
+            }
+        }
+        sessions
+    }
+
+    /// the unfinished uploads of the user
+    async fn count(&self, user_id: &str) -> usize {
+        let now = Local::now().naive_local();
+        self.sessions()
+            .await
+            .iter()
+            .filter_map(|(_, session)| session.as_ref())
+            .filter(|session| session.user_id == user_id && now - session.creation_date <= self.ttl)
+            .count()
+    }
+
+    async fn remove_expired(&self) {
+        let now = Local::now().naive_local();
+        for (path, session) in self.sessions().await {
+            let expired = match &session {
+                Some(session) => now - session.creation_date > self.ttl,
+                None => true,
+            };
+            if expired {
+                let upload_id = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let _lock = self.lock(&upload_id).await;
+                tracing::info!("removing expired upload {path:?}");
+                let _ = tokio::fs::remove_file(path.with_extension("part")).await;
+                let _ = tokio::fs::remove_file(path).await;
+            }
+        }
+    }
+}
+
+impl UploadSession {
+    pub fn status(&self, offset: u64) -> UploadSessionStatus {
+        UploadSessionStatus {
+            upload_id: self.upload_id.clone(),
+            offset,
+            size: self.request.size,
+        }
+    }
+}
+
+async fn read_session(path: &Path) -> Option<UploadSession> {
+    let json = tokio::fs::read(path).await.ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+fn check_checksum(checksum: &str) -> Result<(), SessionError> {
+    if checksum.len() != 64 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(SessionError::new(
+            SessionErrorKind::Invalid,
+            "checksum must be a hex encoded sha256",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use sequeda_file_upload_common::CreateUploadSessionRequest;
+
+    use super::{SessionErrorKind, UploadSessions};
+
+    #[tokio::test]
+    async fn test_upload_sessions() {
+        let root = std::env::temp_dir().join(format!("sessions-{}", uuid::Uuid::new_v4()));
+        let sessions = UploadSessions::new(&root, Duration::hours(1)).with_limits(1024, 2);
+        let request = CreateUploadSessionRequest {
+            filename: "scan.pdf".into(),
+            size: 11,
+            content_type: None,
+            // sha256 of "hello world"
+            checksum: Some(
+                "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9".into(),
+            ),
+            id: None,
+            is_public: None,
+            correlation_id: None,
+        };
+        let session = sessions
+            .create(request.clone(), "tenant", "user")
+            .await
+            .unwrap();
+        let id = &session.upload_id;
+
+        assert_eq!(
+            6,
+            sessions
+                .append(id, "user", 0, b"hello ", None)
+                .await
+                .unwrap()
+        );
+        let err = sessions
+            .append(id, "user", 0, b"hello ", None)
+            .await
+            .unwrap_err();
+        assert_eq!(SessionErrorKind::OffsetMismatch(6), err.kind);
+        let err = sessions
+            .append(id, "user", 6, b"world", Some("00"))
+            .await
+            .unwrap_err();
+        assert_eq!(SessionErrorKind::Invalid, err.kind);
+        assert_eq!(
+            SessionErrorKind::NotFound,
+            sessions.get(id, "other").await.unwrap_err().kind
+        );
+        assert_eq!(
+            SessionErrorKind::OffsetMismatch(6),
+            sessions.finalize(id, "user").await.unwrap_err().kind
+        );
+
+        let checksum = "486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7";
+        assert_eq!(
+            11,
+            sessions
+                .append(id, "user", 6, b"world", Some(checksum))
+                .await
+                .unwrap()
+        );
+        let (_, part) = sessions.finalize(id, "user").await.unwrap();
+        assert_eq!(
+            b"hello world".to_vec(),
+            tokio::fs::read(&part).await.unwrap()
+        );
+        assert_eq!(
+            SessionErrorKind::NotFound,
+            sessions.get(id, "user").await.unwrap_err().kind
+        );
+
+        let too_large = CreateUploadSessionRequest {
+            size: 2048,
+            ..request.clone()
+        };
+        assert_eq!(
+            SessionErrorKind::TooLarge,
+            sessions
+                .create(too_large, "tenant", "user")
+                .await
+                .unwrap_err()
+                .kind
+        );
+        for _ in 0..2 {
+            sessions
+                .create(request.clone(), "tenant", "user")
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            SessionErrorKind::TooMany,
+            sessions
+                .create(request.clone(), "tenant", "user")
+                .await
+                .unwrap_err()
+                .kind
+        );
+        sessions.create(request, "tenant", "other").await.unwrap();
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+}
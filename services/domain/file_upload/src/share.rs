@@ -0,0 +1,478 @@
+use std::{env::var, error::Error, fmt::Display, num::NonZeroU32};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, NaiveDateTime};
+use ring::{
+    hmac, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use sequeda_file_upload_common::{CreateShareLinkRequest, FileUpload, ShareLinkInfo};
+use sequeda_store::{doc, MongoError, Repository, StoreRepository};
+use serde::{Deserialize, Serialize};
+
+/// key of the signature of the links. without it a random key is used,
+/// and the links stop working when the service restarts
+pub const SHARE_LINK_SECRET: &str = "SHARE_LINK_SECRET";
+/// the longest a link can live, default to 720 (30 days)
+pub const SHARE_LINK_MAX_TTL_HOURS: &str = "SHARE_LINK_MAX_TTL_HOURS";
+/// password of a protected link
+pub const SHARE_PASSWORD: &str = "X-Share-Password";
+
+const DEFAULT_TTL_HOURS: i64 = 24;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+/// wrong passwords after which a link is locked
+const MAX_PASSWORD_ATTEMPTS: i64 = 5;
+static PBKDF2_ALGORITHM: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
+
+#[derive(Debug, PartialEq)]
+pub enum ShareLinkErrorKind {
+    /// unknown, revoked or badly signed
+    NotFound,
+    Expired,
+    /// no download left
+    Exhausted,
+    /// missing or wrong password
+    PasswordRequired,
+    /// too many wrong passwords
+    Locked,
+    Invalid,
+    Store,
+}
+
+#[derive(Debug)]
+pub struct ShareLinkError {
+    pub kind: ShareLinkErrorKind,
+    msg: String,
+}
+
+impl ShareLinkError {
+    pub fn new(kind: ShareLinkErrorKind, msg: impl Into<String>) -> Self {
+        ShareLinkError {
+            kind,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl Error for ShareLinkError {}
+
+impl Display for ShareLinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+/// A link stored in the tenant of its creator, the token only carries its id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLink {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub file_id: String,
+    pub version: Option<u32>,
+    pub tenant: String,
+    pub created_by: String,
+    pub creation_date: NaiveDateTime,
+    pub expiration_date: NaiveDateTime,
+    pub max_downloads: Option<u32>,
+    #[serde(default)]
+    pub downloads: u32,
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub failed_attempts: u32,
+}
+
+/// The signed part of a token
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShareLinkClaims {
+    #[serde(rename = "l")]
+    pub link_id: String,
+    #[serde(rename = "t")]
+    pub tenant: String,
+    /// expiration, in seconds
+    #[serde(rename = "e")]
+    pub expires: i64,
+}
+
+/// Signs and verifies the tokens: `<base64 claims>.<base64 hmac-sha256 of the claims>`
+pub struct ShareLinks {
+    key: hmac::Key,
+    max_ttl: Duration,
+}
+
+impl std::fmt::Debug for ShareLinks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShareLinks")
+            .field("max_ttl", &self.max_ttl)
+            .finish()
+    }
+}
+
+impl ShareLinks {
+    pub fn new(secret: &[u8], max_ttl: Duration) -> Self {
+        ShareLinks {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            max_ttl,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let max_ttl = var(SHARE_LINK_MAX_TTL_HOURS)
+            .ok()
+            .and_then(|h| h.parse().ok())
+            .unwrap_or(720);
+        let secret = var(SHARE_LINK_SECRET)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(String::into_bytes)
+            .unwrap_or_else(|| {
+                tracing::warn!(
+                    "{SHARE_LINK_SECRET} not set, the share links will not survive a restart"
+                );
+                let mut secret = vec![0u8; 32];
+                SystemRandom::new()
+                    .fill(&mut secret)
+                    .expect("could not generate the share link secret");
+                secret
+            });
+        ShareLinks::new(&secret, Duration::hours(max_ttl))
+    }
+
+    pub fn create(
+        &self,
+        request: CreateShareLinkRequest,
+        file: &FileUpload,
+        tenant: &str,
+        user_id: &str,
+        now: NaiveDateTime,
+    ) -> Result<ShareLink, ShareLinkError> {
+        let ttl = Duration::hours(request.expires_in_hours.unwrap_or(DEFAULT_TTL_HOURS));
+        if ttl <= Duration::zero() || ttl > self.max_ttl {
+            return Err(ShareLinkError::new(
+                ShareLinkErrorKind::Invalid,
+                format!(
+                    "a link expires within 1 and {} hours",
+                    self.max_ttl.num_hours()
+                ),
+            ));
+        }
+        if request.max_downloads == Some(0) {
+            return Err(ShareLinkError::new(
+                ShareLinkErrorKind::Invalid,
+                "max downloads must be positive",
+            ));
+        }
+        if let Some(version) = request.version {
+            if file.at_version(version).is_none() {
+                return Err(ShareLinkError::new(
+                    ShareLinkErrorKind::Invalid,
+                    format!("version {version} not found"),
+                ));
+            }
+        }
+        Ok(ShareLink {
+            id: uuid::Uuid::new_v4().to_string(),
+            file_id: file.id.clone(),
+            version: request.version,
+            tenant: tenant.into(),
+            created_by: user_id.into(),
+            creation_date: now,
+            expiration_date: now + ttl,
+            max_downloads: request.max_downloads,
+            downloads: 0,
+            failed_attempts: 0,
+            password_hash: request
+                .password
+                .filter(|p| !p.is_empty())
+                .map(|p| hash_password(&p)),
+        })
+    }
+
+    pub fn token(&self, link: &ShareLink) -> String {
+        let claims = ShareLinkClaims {
+            link_id: link.id.clone(),
+            tenant: link.tenant.clone(),
+            expires: link.expiration_date.and_utc().timestamp(),
+        };
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap_or_default());
+        let signature = URL_SAFE_NO_PAD.encode(hmac::sign(&self.key, claims.as_bytes()));
+        format!("{claims}.{signature}")
+    }
+
+    pub fn info(&self, link: &ShareLink) -> ShareLinkInfo {
+        let token = self.token(link);
+        ShareLinkInfo {
+            link_id: link.id.clone(),
+            file_id: link.file_id.clone(),
+            version: link.version,
+            url: format!("/download?id={}&token={token}", link.file_id),
+            token,
+            creation_date: link.creation_date,
+            expiration_date: link.expiration_date,
+            max_downloads: link.max_downloads,
+            downloads: link.downloads,
+            password_protected: link.password_hash.is_some(),
+        }
+    }
+
+    pub fn verify(
+        &self,
+        token: &str,
+        now: NaiveDateTime,
+    ) -> Result<ShareLinkClaims, ShareLinkError> {
+        let invalid = || ShareLinkError::new(ShareLinkErrorKind::NotFound, "invalid share link");
+        let (claims, signature) = token.split_once('.').ok_or_else(invalid)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        hmac::verify(&self.key, claims.as_bytes(), &signature).map_err(|_| invalid())?;
+        let claims: ShareLinkClaims = URL_SAFE_NO_PAD
+            .decode(claims)
+            .ok()
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or_else(invalid)?;
+        if claims.expires <= now.and_utc().timestamp() {
+            return Err(ShareLinkError::new(
+                ShareLinkErrorKind::Expired,
+                "the share link expired",
+            ));
+        }
+        Ok(claims)
+    }
+}
+
+/// `pbkdf2-sha256$<iterations>$<salt>$<hash>`
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .expect("could not generate a salt");
+    let mut hash = [0u8; 32];
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).expect("iterations are not 0");
+    pbkdf2::derive(
+        PBKDF2_ALGORITHM,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "pbkdf2-sha256${PBKDF2_ITERATIONS}${}${}",
+        URL_SAFE_NO_PAD.encode(salt),
+        URL_SAFE_NO_PAD.encode(hash)
+    )
+}
+
+fn verify_password(password_hash: &str, password: &str) -> bool {
+    let mut parts = password_hash.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(hash), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return false;
+    };
+    let (Some(iterations), Ok(salt), Ok(hash)) = (
+        iterations.parse().ok().and_then(NonZeroU32::new),
+        URL_SAFE_NO_PAD.decode(salt),
+        URL_SAFE_NO_PAD.decode(hash),
+    ) else {
+        return false;
+    };
+    pbkdf2::verify(
+        PBKDF2_ALGORITHM,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &hash,
+    )
+    .is_ok()
+}
+
+pub fn check_password(link: &ShareLink, password: Option<&str>) -> Result<(), ShareLinkError> {
+    let Some(password_hash) = &link.password_hash else {
+        return Ok(());
+    };
+    match password {
+        Some(password) if verify_password(password_hash, password) => Ok(()),
+        Some(_) => Err(ShareLinkError::new(
+            ShareLinkErrorKind::PasswordRequired,
+            "wrong password",
+        )),
+        None => Err(ShareLinkError::new(
+            ShareLinkErrorKind::PasswordRequired,
+            "password required",
+        )),
+    }
+}
+
+/// checks the password of a protected link. an attempt is counted before hashing the password
+/// and given back when it is right, so a link is locked after `MAX_PASSWORD_ATTEMPTS` wrong ones
+pub async fn check_password_attempt(
+    repository: &StoreRepository<ShareLink>,
+    link: &ShareLink,
+    password: Option<&str>,
+) -> Result<(), ShareLinkError> {
+    let (Some(_), Some(password)) = (&link.password_hash, password) else {
+        return check_password(link, password);
+    };
+    let store = |e: MongoError| ShareLinkError::new(ShareLinkErrorKind::Store, e.to_string());
+    let attempt = repository
+        .get_collection()
+        .find_one_and_update(
+            doc! {
+                "_id": &link.id,
+                "failedAttempts": {"$not": {"$gte": MAX_PASSWORD_ATTEMPTS}},
+            },
+            doc! {"$inc": {"failedAttempts": 1}},
+        )
+        .await
+        .map_err(store)?;
+    if attempt.is_none() {
+        return Err(ShareLinkError::new(
+            ShareLinkErrorKind::Locked,
+            "too many wrong passwords",
+        ));
+    }
+    let checked = {
+        let (link, password) = (link.clone(), password.to_string());
+        tokio::task::spawn_blocking(move || check_password(&link, Some(&password)))
+            .await
+            .map_err(|e| ShareLinkError::new(ShareLinkErrorKind::Store, e.to_string()))?
+    };
+    if checked.is_ok() {
+        repository
+            .get_collection()
+            .update_one(
+                doc! {"_id": &link.id},
+                doc! {"$inc": {"failedAttempts": -1}},
+            )
+            .await
+            .map_err(store)?;
+    }
+    checked
+}
+
+/// the links of the files of a collection
+pub fn share_link_collection(collection: &str) -> String {
+    format!("{collection}_share_links")
+}
+
+/// counts a download, unless the link has none left
+pub async fn consume(
+    repository: &StoreRepository<ShareLink>,
+    link: &ShareLink,
+) -> Result<(), ShareLinkError> {
+    let updated = repository
+        .get_collection()
+        .find_one_and_update(
+            doc! {
+                "_id": &link.id,
+                "$or": [
+                    {"maxDownloads": null},
+                    {"$expr": {"$lt": ["$downloads", "$maxDownloads"]}},
+                ],
+            },
+            doc! {"$inc": {"downloads": 1}},
+        )
+        .await
+        .map_err(|e| ShareLinkError::new(ShareLinkErrorKind::Store, e.to_string()))?;
+    match updated {
+        Some(_) => Ok(()),
+        None => Err(ShareLinkError::new(
+            ShareLinkErrorKind::Exhausted,
+            "no download left",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Local};
+    use sequeda_file_upload_common::{CreateShareLinkRequest, FileUpload};
+
+    use super::{check_password, ShareLinkErrorKind, ShareLinks};
+
+    #[test]
+    fn test_share_links() {
+        let now = Local::now().naive_local();
+        let file = FileUpload {
+            id: "42".into(),
+            creation_date: now,
+            updated_date: None,
+            content_type: Some("application/pdf".into()),
+            thumbnail_id: None,
+            original_filename: "invoice.pdf".into(),
+            internal_name: "42.pdf".into(),
+            extension: Some("pdf".into()),
+            size: 8,
+            public_resource: false,
+            correlation_id: None,
+            version: 1,
+            versions: vec![],
+            converted_from: None,
+            owner_tenant: None,
+        };
+        let share_links = ShareLinks::new(b"secret", Duration::hours(48));
+        let request = |expires_in_hours, password: Option<&str>| CreateShareLinkRequest {
+            id: "42".into(),
+            version: None,
+            expires_in_hours,
+            max_downloads: Some(3),
+            password: password.map(String::from),
+        };
+        assert_eq!(
+            ShareLinkErrorKind::Invalid,
+            share_links
+                .create(request(Some(49), None), &file, "acme", "user", now)
+                .unwrap_err()
+                .kind
+        );
+
+        let link = share_links
+            .create(request(None, Some("s3cr3t")), &file, "acme", "user", now)
+            .unwrap();
+        let token = share_links.token(&link);
+        let claims = share_links.verify(&token, now).unwrap();
+        assert_eq!(link.id, claims.link_id);
+        assert_eq!("acme", claims.tenant);
+
+        // expired
+        assert_eq!(
+            ShareLinkErrorKind::Expired,
+            share_links
+                .verify(&token, now + Duration::hours(25))
+                .unwrap_err()
+                .kind
+        );
+        // signed with another key
+        let other = ShareLinks::new(b"other", Duration::hours(48));
+        assert_eq!(
+            ShareLinkErrorKind::NotFound,
+            other.verify(&token, now).unwrap_err().kind
+        );
+        // claims changed to another tenant
+        let (_, signature) = token.split_once('.').unwrap();
+        let mut forged = link.clone();
+        forged.tenant = "other".into();
+        let forged = share_links.token(&forged);
+        let (forged_claims, _) = forged.split_once('.').unwrap();
+        assert_eq!(
+            ShareLinkErrorKind::NotFound,
+            share_links
+                .verify(&format!("{forged_claims}.{signature}"), now)
+                .unwrap_err()
+                .kind
+        );
+
+        assert!(check_password(&link, Some("s3cr3t")).is_ok());
+        assert_eq!(
+            ShareLinkErrorKind::PasswordRequired,
+            check_password(&link, Some("secret")).unwrap_err().kind
+        );
+        assert_eq!(
+            ShareLinkErrorKind::PasswordRequired,
+            check_password(&link, None).unwrap_err().kind
+        );
+    }
+}
@@ -1,50 +1,220 @@
-use std::{error::Error, path::PathBuf, process::Stdio};
+use std::{
+    env::var,
+    error::Error,
+    path::{Path, PathBuf},
+    process::{Output, Stdio},
+    sync::OnceLock,
+    time::Duration,
+};
 
-use tokio::process::Command;
+use sequeda_file_upload_common::ConvertFormat;
+use tokio::{process::Command, sync::Semaphore};
 
+/// number of conversions running at the same time, default to 2
+pub const CONVERSION_WORKERS: &str = "CONVERSION_WORKERS";
+/// a conversion running longer is killed, default to 120
+pub const CONVERSION_TIMEOUT_SECS: &str = "CONVERSION_TIMEOUT_SECS";
+
+static WORKERS: OnceLock<Semaphore> = OnceLock::new();
+
+fn workers() -> &'static Semaphore {
+    WORKERS.get_or_init(|| {
+        let workers = var(CONVERSION_WORKERS)
+            .ok()
+            .and_then(|w| w.parse().ok())
+            .unwrap_or(2);
+        Semaphore::new(workers)
+    })
+}
+
+fn conversion_timeout() -> Duration {
+    let secs = var(CONVERSION_TIMEOUT_SECS)
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(120);
+    Duration::from_secs(secs)
+}
+
+/// waits for a free worker, then runs the command. the process is killed after `timeout`
+async fn run_bounded(
+    mut command: Command,
+    timeout: Duration,
+) -> Result<Output, Box<dyn Error + Send + Sync>> {
+    let _worker = workers().acquire().await?;
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    match tokio::time::timeout(timeout, command.output()).await {
+        Ok(output) => Ok(output?),
+        Err(_) => Err(format!("conversion timed out after {timeout:?}").into()),
+    }
+}
+
+/// converts the file with LibreOffice. each conversion has its own output dir and profile,
+/// so that several conversions can run at the same time.
+/// `extension` is the one of the original file, soffice guesses the input type from it
 pub async fn convert_to(
     input_path: impl Into<PathBuf>,
+    extension: Option<&str>,
     to: ConvertType,
-) -> Result<Vec<u8>, Box<dyn Error>> {
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
     let input_path: PathBuf = input_path.into();
     tracing::debug!("convert file {input_path:?}");
-    let input_path_str = &input_path.display().to_string();
-    let temp_dir = &std::env::temp_dir().display().to_string();
-    let output = Command::new("soffice")
-        .args([
-            "--headless",
-            "--convert-to",
-            to.to_str(),
-            "--outdir",
-            temp_dir,
-            input_path_str,
-        ])
-        .stdout(Stdio::piped())
-        .output()
-        .await?;
-    if output.status.success() {
-        let input_path = input_path.with_extension(to.to_str());
-        let input_path = input_path
-            .file_name()
-            .ok_or("no file name")?
-            .to_string_lossy();
-        let bytes = tokio::fs::read(&format!("{temp_dir}/{input_path}")).await?;
-        tokio::fs::remove_file(&format!("{temp_dir}/{input_path}")).await?;
-        Ok(bytes)
+    let work_dir = std::env::temp_dir().join(format!("soffice-{}", uuid::Uuid::new_v4()));
+    let converted = async {
+        let output_dir = work_dir.join("out");
+        tokio::fs::create_dir_all(&output_dir).await?;
+        let source = work_dir.join(match extension {
+            Some(extension) => format!("source.{extension}"),
+            None => "source".into(),
+        });
+        if tokio::fs::hard_link(&input_path, &source).await.is_err() {
+            tokio::fs::copy(&input_path, &source).await?;
+        }
+        let mut command = Command::new("soffice");
+        command
+            .arg(format!(
+                "-env:UserInstallation=file://{}",
+                work_dir.join("profile").display()
+            ))
+            .args(["--headless", "--convert-to", to.filter(), "--outdir"])
+            .arg(&output_dir)
+            .arg(&source);
+        let output = run_bounded(command, conversion_timeout()).await?;
+        if !output.status.success() {
+            return Err(format!(
+                "error {} {}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+        let converted = output_file(&output_dir, &source, to).await?;
+        Ok(tokio::fs::read(converted).await?)
+    }
+    .await;
+    if let Err(e) = tokio::fs::remove_dir_all(&work_dir).await {
+        tracing::warn!("could not remove {work_dir:?}: {e}");
+    }
+    converted
+}
+
+/// `<source stem>.<extension>`: the html export writes the images next to it.
+/// soffice does not fail when it cannot convert the file, it just writes nothing
+async fn output_file(
+    output_dir: &Path,
+    source: &Path,
+    to: ConvertType,
+) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let stem = source.file_stem().ok_or("the source has no name")?;
+    let converted = output_dir.join(stem).with_extension(to.extension());
+    if tokio::fs::try_exists(&converted).await? {
+        Ok(converted)
     } else {
-        Err(format!("error {}", String::from_utf8_lossy(&output.stdout)).into())
+        Err("the file could not be converted".into())
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ConvertType {
     Png,
-    // Pdf,
+    Pdf,
+    Docx,
+    Odt,
+    Html,
 }
+
 impl ConvertType {
-    fn to_str(&self) -> &str {
+    /// the `--convert-to` argument
+    fn filter(&self) -> &str {
         match self {
             ConvertType::Png => "png",
-            //  ConvertType::Pdf => "pdf",
+            ConvertType::Pdf => "pdf",
+            ConvertType::Docx => "docx:MS Word 2007 XML",
+            ConvertType::Odt => "odt",
+            ConvertType::Html => "html",
         }
     }
+
+    pub fn extension(&self) -> &str {
+        match self {
+            ConvertType::Png => "png",
+            ConvertType::Pdf => "pdf",
+            ConvertType::Docx => "docx",
+            ConvertType::Odt => "odt",
+            ConvertType::Html => "html",
+        }
+    }
+
+    pub fn content_type(&self) -> &str {
+        match self {
+            ConvertType::Png => "image/png",
+            ConvertType::Pdf => "application/pdf",
+            ConvertType::Docx => {
+                "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+            }
+            ConvertType::Odt => "application/vnd.oasis.opendocument.text",
+            ConvertType::Html => "text/html",
+        }
+    }
+}
+
+impl From<ConvertFormat> for ConvertType {
+    fn from(format: ConvertFormat) -> Self {
+        match format {
+            ConvertFormat::Pdf => ConvertType::Pdf,
+            ConvertFormat::Png => ConvertType::Png,
+            ConvertFormat::Docx => ConvertType::Docx,
+            ConvertFormat::Odt => ConvertType::Odt,
+            ConvertFormat::Html => ConvertType::Html,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::process::Command;
+
+    use super::{output_file, run_bounded, ConvertType};
+
+    #[tokio::test]
+    async fn test_run_bounded() {
+        let mut sleep = Command::new("sleep");
+        sleep.arg("5");
+        let started = std::time::Instant::now();
+        let err = run_bounded(sleep, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let mut echo = Command::new("echo");
+        echo.arg("converted");
+        let output = run_bounded(echo, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(b"converted\n".to_vec(), output.stdout);
+    }
+
+    #[tokio::test]
+    async fn test_output_file() {
+        let dir = std::env::temp_dir().join(format!("soffice-out-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let source = dir.join("source.docx");
+        assert!(output_file(&dir, &source, ConvertType::Html).await.is_err());
+        tokio::fs::write(dir.join("source_html_1.png"), b"image")
+            .await
+            .unwrap();
+        assert!(output_file(&dir, &source, ConvertType::Html).await.is_err());
+        tokio::fs::write(dir.join("source.html"), b"<html>")
+            .await
+            .unwrap();
+        assert_eq!(
+            dir.join("source.html"),
+            output_file(&dir, &source, ConvertType::Html).await.unwrap()
+        );
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
 }
@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use tokio::{io::AsyncReadExt, sync::Mutex};
+
+use super::{check_key, sha256, LocalStorage, Storage, StorageError, StorageReader};
+
+/// Identical files are stored once, under their sha256:
+/// `names/<key>` holds the hash, `objects/<2 first digits>/<hash>` the content,
+/// and `<hash>.refs` the number of keys pointing to it
+#[derive(Debug)]
+pub struct ContentAddressedStorage {
+    root: PathBuf,
+    names: LocalStorage,
+    /// serializes the updates of the reference counts
+    lock: Mutex<()>,
+}
+
+impl ContentAddressedStorage {
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self, StorageError> {
+        let root = root.into();
+        tokio::fs::create_dir_all(root.join("names")).await?;
+        tokio::fs::create_dir_all(root.join("objects")).await?;
+        tokio::fs::create_dir_all(root.join("tmp")).await?;
+        Ok(ContentAddressedStorage {
+            names: LocalStorage::new(root.join("names")),
+            root,
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.root.join("objects").join(&hash[..2]).join(hash)
+    }
+
+    fn refs_path(&self, hash: &str) -> PathBuf {
+        self.object_path(hash).with_extension("refs")
+    }
+
+    async fn hash_of(&self, key: &str) -> Result<Option<String>, StorageError> {
+        if !self.names.exists(key).await? {
+            return Ok(None);
+        }
+        let mut hash = String::new();
+        self.names.get(key).await?.read_to_string(&mut hash).await?;
+        let hash = hash.trim();
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(StorageError::new(format!("{key}: invalid hash {hash:?}")));
+        }
+        Ok(Some(hash.to_string()))
+    }
+
+    async fn refs(&self, hash: &str) -> Result<u64, StorageError> {
+        match tokio::fs::read_to_string(self.refs_path(hash)).await {
+            Ok(refs) => Ok(refs.trim().parse().unwrap_or_default()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// removes the content once no key points to it
+    async fn release(&self, hash: &str) -> Result<(), StorageError> {
+        let refs = self.refs(hash).await?.saturating_sub(1);
+        if refs == 0 {
+            tracing::debug!("removing object {hash}");
+            for path in [self.object_path(hash), self.refs_path(hash)] {
+                match tokio::fs::remove_file(path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        } else {
+            tokio::fs::write(self.refs_path(hash), refs.to_string()).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for ContentAddressedStorage {
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), StorageError> {
+        check_key(key)?;
+        let hash = sha256(path).await?;
+        let _lock = self.lock.lock().await;
+        let previous = self.hash_of(key).await?;
+        if previous.as_deref() == Some(hash.as_str()) {
+            tokio::fs::remove_file(path).await?;
+            return Ok(());
+        }
+        let object = self.object_path(&hash);
+        if tokio::fs::try_exists(&object).await? {
+            tracing::debug!("{key}: same content as object {hash}");
+            tokio::fs::remove_file(path).await?;
+        } else {
+            LocalStorage::new(object.parent().expect("objects have a parent"))
+                .put_file(&hash, path)
+                .await?;
+        }
+        let refs = self.refs(&hash).await? + 1;
+        tokio::fs::write(self.refs_path(&hash), refs.to_string()).await?;
+        self.names.put_bytes(key, hash.into_bytes()).await?;
+        if let Some(previous) = previous {
+            self.release(&previous).await?;
+        }
+        Ok(())
+    }
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError> {
+        let temp_file = self.root.join("tmp").join(uuid::Uuid::new_v4().to_string());
+        tokio::fs::write(&temp_file, bytes).await?;
+        let put = self.put_file(key, &temp_file).await;
+        if put.is_err() {
+            let _ = tokio::fs::remove_file(&temp_file).await;
+        }
+        put
+    }
+
+    async fn get(&self, key: &str) -> Result<StorageReader, StorageError> {
+        let hash = self
+            .hash_of(key)
+            .await?
+            .ok_or_else(|| StorageError::new(format!("{key} not found")))?;
+        let file = tokio::fs::File::open(self.object_path(&hash)).await?;
+        Ok(Box::pin(file))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let _lock = self.lock.lock().await;
+        if let Some(hash) = self.hash_of(key).await? {
+            self.names.delete(key).await?;
+            self.release(&hash).await?;
+        }
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        self.names.exists(key).await
+    }
+
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        self.names.list().await
+    }
+
+    async fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        self.names.list_prefix(prefix).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ContentAddressedStorage;
+    use crate::storage::Storage;
+
+    #[tokio::test]
+    async fn test_content_addressed_storage() {
+        let root = std::env::temp_dir().join(format!("cas-{}", uuid::Uuid::new_v4()));
+        let storage = ContentAddressedStorage::new(&root).await.unwrap();
+        storage.put_bytes("a.pdf", b"same".to_vec()).await.unwrap();
+        storage.put_bytes("b.pdf", b"same".to_vec()).await.unwrap();
+        storage.put_bytes("c.pdf", b"other".to_vec()).await.unwrap();
+
+        let hash = storage.hash_of("a.pdf").await.unwrap().unwrap();
+        assert_eq!(Some(hash.clone()), storage.hash_of("b.pdf").await.unwrap());
+        assert_eq!(2, storage.refs(&hash).await.unwrap());
+        assert_eq!(
+            vec!["a.pdf", "b.pdf", "c.pdf"],
+            storage.list().await.unwrap()
+        );
+
+        storage.delete("a.pdf").await.unwrap();
+        assert!(storage.object_path(&hash).exists());
+        // replacing the content of a key releases the previous one
+        storage.put_bytes("b.pdf", b"other".to_vec()).await.unwrap();
+        assert!(!storage.object_path(&hash).exists());
+        assert!(!storage.exists("a.pdf").await.unwrap());
+
+        storage.delete("b.pdf").await.unwrap();
+        storage.delete("c.pdf").await.unwrap();
+        let objects = std::fs::read_dir(root.join("objects"))
+            .unwrap()
+            .flat_map(|dir| std::fs::read_dir(dir.unwrap().path()).unwrap())
+            .count();
+        assert_eq!(0, objects);
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::{check_key, Storage, StorageError, StorageReader};
+
+/// Files under `SHARE_DRIVE_PATH`, named by their key
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+
+    async fn create_parent(path: &Path) -> Result<(), StorageError> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), StorageError> {
+        let dest = self.path(key)?;
+        Self::create_parent(&dest).await?;
+        // the temp file may be on another volume
+        if tokio::fs::rename(path, &dest).await.is_err() {
+            tokio::fs::copy(path, &dest).await?;
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError> {
+        let dest = self.path(key)?;
+        Self::create_parent(&dest).await?;
+        tokio::fs::write(dest, bytes).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<StorageReader, StorageError> {
+        let file = tokio::fs::File::open(self.path(key)?).await?;
+        Ok(Box::pin(file))
+    }
+
+    async fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        len: u64,
+    ) -> Result<StorageReader, StorageError> {
+        let mut file = tokio::fs::File::open(self.path(key)?).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Box::pin(file.take(len)))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        Ok(tokio::fs::try_exists(self.path(key)?).await?)
+    }
+
+    /// the temp files (`tmp`) and hidden directories (`.cas`) are not listed
+    async fn list(&self) -> Result<Vec<String>, StorageError> {
+        self.list_dir(self.root.clone(), String::new()).await
+    }
+
+    /// only walks the directory of the prefix
+    async fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let dir = match prefix.rfind('/') {
+            Some(end) => &prefix[..=end],
+            None => "",
+        };
+        let path = if dir.is_empty() {
+            self.root.clone()
+        } else {
+            self.path(dir.trim_end_matches('/'))?
+        };
+        if !tokio::fs::try_exists(&path).await? {
+            return Ok(vec![]);
+        }
+        let mut keys = self.list_dir(path, dir.to_string()).await?;
+        keys.retain(|key| key.starts_with(prefix));
+        Ok(keys)
+    }
+}
+
+impl LocalStorage {
+    async fn list_dir(&self, dir: PathBuf, prefix: String) -> Result<Vec<String>, StorageError> {
+        let mut keys = vec![];
+        let mut dirs = vec![(dir, prefix)];
+        while let Some((dir, prefix)) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.starts_with('.') || (prefix.is_empty() && name == "tmp") {
+                    continue;
+                }
+                let key = format!("{prefix}{name}");
+                if entry.file_type().await?.is_dir() {
+                    dirs.push((entry.path(), format!("{key}/")));
+                } else {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+
+    use super::LocalStorage;
+    use crate::storage::Storage;
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let root = std::env::temp_dir().join(format!("local-storage-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(root.join("tmp")).await.unwrap();
+        let storage = LocalStorage::new(&root);
+
+        let temp_file = root.join("tmp").join("upload");
+        tokio::fs::write(&temp_file, b"invoice").await.unwrap();
+        storage.put_file("42.pdf", &temp_file).await.unwrap();
+        assert!(!temp_file.exists());
+        storage
+            .put_bytes("renditions/42-small.png", b"png".to_vec())
+            .await
+            .unwrap();
+
+        let mut content = String::new();
+        storage
+            .get("42.pdf")
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .await
+            .unwrap();
+        assert_eq!("invoice", content);
+        assert_eq!(
+            vec!["42.pdf".to_string(), "renditions/42-small.png".to_string()],
+            storage.list().await.unwrap()
+        );
+        assert_eq!(
+            vec!["renditions/42-small.png".to_string()],
+            storage.list_prefix("renditions/42").await.unwrap()
+        );
+        assert!(storage.list_prefix("other/").await.unwrap().is_empty());
+        assert!(storage.get("../42.pdf").await.is_err());
+
+        storage.delete("42.pdf").await.unwrap();
+        storage.delete("42.pdf").await.unwrap();
+        assert!(!storage.exists("42.pdf").await.unwrap());
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+use std::path::Path;
+
+use super::{Storage, StorageError};
+
+/// Copies the files missing in `to`. With `delete`, the files found in `to` are removed from `from`.
+/// Returns the number of files copied
+pub async fn migrate(
+    from: &dyn Storage,
+    to: &dyn Storage,
+    temp_dir: &Path,
+    delete: bool,
+) -> Result<usize, StorageError> {
+    tokio::fs::create_dir_all(temp_dir).await?;
+    let keys = from.list().await?;
+    tracing::info!("migrating {} files from {from:?} to {to:?}", keys.len());
+    let mut copied = 0;
+    for key in keys {
+        if to.exists(&key).await? {
+            tracing::debug!("{key} already migrated");
+        } else {
+            let temp_file = temp_dir.join(uuid::Uuid::new_v4().to_string());
+            let mut reader = from.get(&key).await?;
+            let mut file = tokio::fs::File::create(&temp_file).await?;
+            tokio::io::copy(&mut reader, &mut file).await?;
+            if let Err(e) = to.put_file(&key, &temp_file).await {
+                let _ = tokio::fs::remove_file(&temp_file).await;
+                return Err(e);
+            }
+            copied += 1;
+            tracing::info!("{key} migrated");
+        }
+        if delete {
+            if !to.exists(&key).await? {
+                return Err(StorageError::new(format!(
+                    "{key} not found in {to:?} after the copy, not deleted"
+                )));
+            }
+            from.delete(&key).await?;
+        }
+    }
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod test {
+    use super::migrate;
+    use crate::storage::{ContentAddressedStorage, LocalStorage, Storage};
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let root = std::env::temp_dir().join(format!("migrate-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&root).await.unwrap();
+        let local = LocalStorage::new(&root);
+        local.put_bytes("1.pdf", b"same".to_vec()).await.unwrap();
+        local.put_bytes("2.pdf", b"same".to_vec()).await.unwrap();
+        let cas = ContentAddressedStorage::new(root.join(".cas"))
+            .await
+            .unwrap();
+        cas.put_bytes("2.pdf", b"same".to_vec()).await.unwrap();
+
+        let copied = migrate(&local, &cas, &root.join("tmp"), true)
+            .await
+            .unwrap();
+        assert_eq!(1, copied);
+        assert_eq!(vec!["1.pdf", "2.pdf"], cas.list().await.unwrap());
+        assert!(local.list().await.unwrap().is_empty());
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+use std::{env::var, error::Error, fmt::Display, path::Path, pin::Pin, sync::Arc};
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::file_upload_service::SHARE_DRIVE_PATH;
+
+mod content_addressed;
+mod local;
+mod migrate;
+mod s3;
+
+pub use content_addressed::ContentAddressedStorage;
+pub use local::LocalStorage;
+pub use migrate::migrate;
+pub use s3::{S3Config, S3Storage};
+
+/// `local` (default), `s3` or `content_addressed`
+pub const STORAGE_BACKEND: &str = "STORAGE_BACKEND";
+/// root of the content addressed storage, default to `<SHARE_DRIVE_PATH>/.cas`
+pub const CONTENT_ADDRESSED_PATH: &str = "CONTENT_ADDRESSED_PATH";
+
+pub type StorageReader = Pin<Box<dyn AsyncRead + Send>>;
+
+#[derive(Debug)]
+pub struct StorageError {
+    msg: String,
+}
+
+impl StorageError {
+    pub fn new(msg: impl Into<String>) -> Self {
+        StorageError { msg: msg.into() }
+    }
+}
+
+impl Error for StorageError {}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::new(e.to_string())
+    }
+}
+
+/// Where the files are kept, by key (the `internal_name` of the `FileUpload`)
+#[async_trait]
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    /// moves the local file under the key, replacing the previous one
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), StorageError>;
+    async fn put_bytes(&self, key: &str, bytes: Vec<u8>) -> Result<(), StorageError>;
+    async fn get(&self, key: &str) -> Result<StorageReader, StorageError>;
+    /// `len` bytes from `start`, the backends that can seek should override it
+    async fn get_range(
+        &self,
+        key: &str,
+        start: u64,
+        len: u64,
+    ) -> Result<StorageReader, StorageError> {
+        skip_take(self.get(key).await?, start, len).await
+    }
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+    async fn exists(&self, key: &str) -> Result<bool, StorageError>;
+    /// every key, to migrate to another backend
+    async fn list(&self) -> Result<Vec<String>, StorageError>;
+    /// the keys starting with `prefix`
+    async fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        let mut keys = self.list().await?;
+        keys.retain(|key| key.starts_with(prefix));
+        Ok(keys)
+    }
+}
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// the backend named `kind`, configured from the environment
+pub async fn storage_from_env(kind: &str) -> Result<SharedStorage, StorageError> {
+    let share_drive_path = || {
+        var(SHARE_DRIVE_PATH).map_err(|_| StorageError::new(format!("{SHARE_DRIVE_PATH} not set")))
+    };
+    let storage: SharedStorage = match kind {
+        "local" => Arc::new(LocalStorage::new(share_drive_path()?)),
+        "s3" => Arc::new(S3Storage::new(S3Config::from_env()?)?),
+        "content_addressed" => {
+            let root = match var(CONTENT_ADDRESSED_PATH) {
+                Ok(root) => root.into(),
+                Err(_) => Path::new(&share_drive_path()?).join(".cas"),
+            };
+            Arc::new(ContentAddressedStorage::new(root).await?)
+        }
+        _ => return Err(StorageError::new(format!("unknown storage backend {kind}"))),
+    };
+    Ok(storage)
+}
+
+/// discards the first `start` bytes of the reader, and stops after `len` bytes
+async fn skip_take(
+    mut reader: StorageReader,
+    start: u64,
+    len: u64,
+) -> Result<StorageReader, StorageError> {
+    let skipped = tokio::io::copy(&mut (&mut reader).take(start), &mut tokio::io::sink()).await?;
+    if skipped < start {
+        return Err(StorageError::new("range out of bounds"));
+    }
+    Ok(Box::pin(reader.take(len)))
+}
+
+/// hex encoded sha256 of the content of a local file
+pub async fn sha256(path: &Path) -> Result<String, StorageError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// keys are relative paths, they must not escape the root of the storage
+fn check_key(key: &str) -> Result<(), StorageError> {
+    if key.is_empty()
+        || key.starts_with('/')
+        || key.contains('\\')
+        || key
+            .split('/')
+            .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        return Err(StorageError::new(format!("invalid key {key:?}")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::check_key;
+
+    #[test]
+    fn test_check_key() {
+        assert!(check_key("6512f.pdf").is_ok());
+        assert!(check_key("renditions/6512f-small.webp").is_ok());
+        assert!(check_key("../etc/passwd").is_err());
+        assert!(check_key("/etc/passwd").is_err());
+        assert!(check_key("a//b").is_err());
+        assert!(check_key("").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use std::{path::Path, time::Duration};
+
+use async_trait::async_trait;
+use sequeda_file_upload_common::FileUpload;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{ValidationError, ValidationStage};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Scans the files with a clamd daemon, with the `INSTREAM` command
+#[derive(Debug, Clone)]
+pub struct ClamAv {
+    /// `host:port`
+    pub address: String,
+    pub timeout: Duration,
+}
+
+impl ClamAv {
+    /// the signature found, if any
+    pub async fn scan(&self, path: &Path) -> Result<Option<String>, ValidationError> {
+        let reply = tokio::time::timeout(self.timeout, self.instream(path))
+            .await
+            .map_err(|_| ValidationError::unavailable("clamd timed out"))?
+            .map_err(|e| ValidationError::unavailable(format!("clamd: {e}")))?;
+        // `stream: OK`, `stream: <signature> FOUND` or `<message> ERROR`
+        let reply = reply.trim_end_matches('\0').trim();
+        if reply.ends_with("OK") {
+            Ok(None)
+        } else if let Some(found) = reply.strip_suffix("FOUND") {
+            let signature = found.trim().trim_start_matches("stream:").trim();
+            Ok(Some(signature.to_string()))
+        } else {
+            Err(ValidationError::unavailable(format!("clamd: {reply}")))
+        }
+    }
+
+    async fn instream(&self, path: &Path) -> std::io::Result<String> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut stream = TcpStream::connect(&self.address).await?;
+        stream.write_all(b"zINSTREAM\0").await?;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut chunk).await?;
+            stream.write_all(&(read as u32).to_be_bytes()).await?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&chunk[..read]).await?;
+        }
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        Ok(reply)
+    }
+}
+
+#[async_trait]
+impl ValidationStage for ClamAv {
+    async fn validate(
+        &self,
+        upload: &mut FileUpload,
+        path: &Path,
+        _tenant: &str,
+    ) -> Result<(), ValidationError> {
+        match self.scan(path).await? {
+            Some(signature) => {
+                tracing::warn!("{}: {signature} found", upload.original_filename);
+                Err(ValidationError::rejected(format!("{signature} found")))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::ClamAv;
+    use crate::validation::ValidationErrorKind;
+
+    /// answers like clamd, anything containing `EICAR` is infected
+    async fn clamd_stub() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut command = [0u8; 10];
+                stream.read_exact(&mut command).await.unwrap();
+                assert_eq!(b"zINSTREAM\0", &command);
+                let mut content = vec![];
+                loop {
+                    let len = stream.read_u32().await.unwrap() as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0u8; len];
+                    stream.read_exact(&mut chunk).await.unwrap();
+                    content.extend(chunk);
+                }
+                let reply: &[u8] = if content.windows(5).any(|w| w == b"EICAR") {
+                    b"stream: Eicar-Test-Signature FOUND\0"
+                } else {
+                    b"stream: OK\0"
+                };
+                stream.write_all(reply).await.unwrap();
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_clamav() {
+        let dir = std::env::temp_dir().join(format!("clamav-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let clean = dir.join("clean");
+        let infected = dir.join("infected");
+        tokio::fs::write(&clean, vec![b'a'; 200 * 1024])
+            .await
+            .unwrap();
+        tokio::fs::write(
+            &infected,
+            b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*",
+        )
+        .await
+        .unwrap();
+
+        let clamav = ClamAv {
+            address: clamd_stub().await,
+            timeout: Duration::from_secs(5),
+        };
+        assert_eq!(None, clamav.scan(&clean).await.unwrap());
+        assert_eq!(
+            Some("Eicar-Test-Signature".to_string()),
+            clamav.scan(&infected).await.unwrap()
+        );
+
+        let unreachable = ClamAv {
+            address: "127.0.0.1:1".into(),
+            timeout: Duration::from_secs(5),
+        };
+        assert_eq!(
+            ValidationErrorKind::Unavailable,
+            unreachable.scan(&clean).await.unwrap_err().kind
+        );
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    env::var,
+    error::Error,
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use sequeda_file_upload_common::FileUpload;
+
+mod clamav;
+mod policy;
+mod quarantine;
+mod sniff;
+
+pub use clamav::ClamAv;
+pub use policy::UploadPolicy;
+pub use quarantine::Quarantine;
+pub use sniff::ContentSniffer;
+
+/// file name of the upload policy in `SERVICE_CONFIG_VOLUME`, default to `upload_policy.json`.
+/// without it, everything but the executables is allowed
+pub const UPLOAD_POLICY_FILE_NAME: &str = "UPLOAD_POLICY_FILE_NAME";
+/// `host:port` of clamd, the files are not scanned if not set
+pub const CLAMAV_ADDRESS: &str = "CLAMAV_ADDRESS";
+/// default to 60
+pub const CLAMAV_TIMEOUT_SECS: &str = "CLAMAV_TIMEOUT_SECS";
+/// where the rejected files go, default to `<SHARE_DRIVE_PATH>/.quarantine`
+pub const QUARANTINE_PATH: &str = "QUARANTINE_PATH";
+
+#[derive(Debug, PartialEq)]
+pub enum ValidationErrorKind {
+    /// the file must not be stored
+    Rejected,
+    /// the file could not be checked, e.g. clamd is down
+    Unavailable,
+}
+
+#[derive(Debug)]
+pub struct ValidationError {
+    pub kind: ValidationErrorKind,
+    msg: String,
+}
+
+impl ValidationError {
+    pub fn rejected(msg: impl Into<String>) -> Self {
+        ValidationError {
+            kind: ValidationErrorKind::Rejected,
+            msg: msg.into(),
+        }
+    }
+
+    pub fn unavailable(msg: impl Into<String>) -> Self {
+        ValidationError {
+            kind: ValidationErrorKind::Unavailable,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl From<std::io::Error> for ValidationError {
+    fn from(e: std::io::Error) -> Self {
+        ValidationError::unavailable(e.to_string())
+    }
+}
+
+/// A check of an uploaded file, before it is stored. a stage may fix the upload,
+/// e.g. its content type
+#[async_trait]
+pub trait ValidationStage: std::fmt::Debug + Send + Sync {
+    async fn validate(
+        &self,
+        upload: &mut FileUpload,
+        path: &Path,
+        tenant: &str,
+    ) -> Result<(), ValidationError>;
+}
+
+/// The stages run in order, the first error stops the pipeline
+#[derive(Debug, Default)]
+pub struct ValidationPipeline {
+    stages: Vec<Box<dyn ValidationStage>>,
+}
+
+impl ValidationPipeline {
+    pub fn new(stages: Vec<Box<dyn ValidationStage>>) -> Self {
+        ValidationPipeline { stages }
+    }
+
+    /// content sniffing, the upload policy, then the antivirus if configured
+    pub fn from_env(config_volume: &Path) -> Result<Self, ValidationError> {
+        let policy_file = config_volume
+            .join(var(UPLOAD_POLICY_FILE_NAME).unwrap_or_else(|_| "upload_policy.json".into()));
+        let policy = match std::fs::read(&policy_file) {
+            Ok(policy) => serde_json::from_slice(&policy).map_err(|e| {
+                ValidationError::unavailable(format!("invalid {policy_file:?}: {e}"))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("no upload policy in {policy_file:?}, using the default one");
+                UploadPolicy::default()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut stages: Vec<Box<dyn ValidationStage>> =
+            vec![Box::new(ContentSniffer), Box::new(policy)];
+        if let Ok(address) = var(CLAMAV_ADDRESS) {
+            let timeout = var(CLAMAV_TIMEOUT_SECS)
+                .ok()
+                .and_then(|t| t.parse().ok())
+                .unwrap_or(60);
+            stages.push(Box::new(ClamAv {
+                address,
+                timeout: Duration::from_secs(timeout),
+            }));
+        }
+        Ok(ValidationPipeline::new(stages))
+    }
+
+    pub async fn validate(
+        &self,
+        upload: &mut FileUpload,
+        path: &Path,
+        tenant: &str,
+    ) -> Result<(), ValidationError> {
+        for stage in &self.stages {
+            stage.validate(upload, path, tenant).await?;
+        }
+        Ok(())
+    }
+}
+
+pub fn quarantine_from_env(share_drive_path: &str) -> Quarantine {
+    let root = var(QUARANTINE_PATH)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| Path::new(share_drive_path).join(".quarantine"));
+    Quarantine::new(root)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use chrono::Local;
+    use sequeda_file_upload_common::FileUpload;
+
+    use super::{
+        policy::TypePolicy, ContentSniffer, Quarantine, UploadPolicy, ValidationErrorKind,
+        ValidationPipeline,
+    };
+
+    #[tokio::test]
+    async fn test_pipeline() {
+        let dir = std::env::temp_dir().join(format!("validation-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("upload");
+        tokio::fs::write(&path, b"MZ\x90\0\x03\0\0\0")
+            .await
+            .unwrap();
+        let mut upload = FileUpload {
+            id: "42".into(),
+            creation_date: Local::now().naive_local(),
+            updated_date: None,
+            content_type: Some("application/pdf".into()),
+            thumbnail_id: None,
+            original_filename: "invoice.pdf".into(),
+            internal_name: String::new(),
+            extension: Some("pdf".into()),
+            size: 8,
+            public_resource: false,
+            correlation_id: None,
+            version: 1,
+            versions: vec![],
+            converted_from: None,
+            owner_tenant: None,
+        };
+        let pipeline = ValidationPipeline::new(vec![
+            Box::new(ContentSniffer),
+            Box::new(UploadPolicy {
+                default: TypePolicy {
+                    allow: vec!["application/pdf".into()],
+                    ..Default::default()
+                },
+                tenants: HashMap::new(),
+            }),
+        ]);
+        let err = pipeline
+            .validate(&mut upload, &path, "acme")
+            .await
+            .unwrap_err();
+        assert_eq!(ValidationErrorKind::Rejected, err.kind);
+        assert_eq!(
+            Some("application/x-msdownload"),
+            upload.content_type.as_deref()
+        );
+
+        let quarantine = Quarantine::new(dir.join(".quarantine"));
+        let id = quarantine
+            .put("acme", &upload, &path, &err.to_string())
+            .await
+            .unwrap();
+        assert!(!path.exists());
+        assert!(dir.join(".quarantine").join("acme").join(&id).exists());
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use std::{collections::HashMap, path::Path};
+
+use async_trait::async_trait;
+use sequeda_file_upload_common::FileUpload;
+use serde::Deserialize;
+
+use super::{ValidationError, ValidationStage};
+
+/// The types a tenant may upload. the patterns are a type (`application/pdf`),
+/// a whole family (`image/*`) or anything (`*`)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypePolicy {
+    /// empty to allow everything not denied
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// maximum size in bytes by pattern, the most specific pattern wins
+    #[serde(default)]
+    pub max_size: HashMap<String, u64>,
+}
+
+/// `default` applies to the tenants without their own policy
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadPolicy {
+    #[serde(default = "default_policy")]
+    pub default: TypePolicy,
+    #[serde(default)]
+    pub tenants: HashMap<String, TypePolicy>,
+}
+
+impl Default for UploadPolicy {
+    fn default() -> Self {
+        UploadPolicy {
+            default: default_policy(),
+            tenants: HashMap::new(),
+        }
+    }
+}
+
+fn default_policy() -> TypePolicy {
+    TypePolicy {
+        deny: vec![
+            "application/x-msdownload".into(),
+            "application/x-executable".into(),
+            "application/x-sh".into(),
+        ],
+        ..Default::default()
+    }
+}
+
+/// 0 if the pattern does not match, higher for the more specific ones
+fn specificity(pattern: &str, content_type: &str) -> u8 {
+    if pattern == content_type {
+        3
+    } else if pattern
+        .strip_suffix("/*")
+        .is_some_and(|family| content_type.split('/').next() == Some(family))
+    {
+        2
+    } else if pattern == "*" {
+        1
+    } else {
+        0
+    }
+}
+
+impl TypePolicy {
+    pub fn check(&self, content_type: &str, size: u64) -> Result<(), String> {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| specificity(pattern, content_type) > 0)
+        };
+        if matches(&self.deny) {
+            return Err(format!("{content_type} is not allowed"));
+        }
+        if !self.allow.is_empty() && !matches(&self.allow) {
+            return Err(format!("{content_type} is not allowed"));
+        }
+        let max_size = self
+            .max_size
+            .iter()
+            .map(|(pattern, max)| (specificity(pattern, content_type), max))
+            .filter(|(specificity, _)| *specificity > 0)
+            .max_by_key(|(specificity, _)| *specificity);
+        if let Some((_, max)) = max_size {
+            if size > *max {
+                return Err(format!(
+                    "{content_type} files are limited to {max} bytes, got {size}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ValidationStage for UploadPolicy {
+    async fn validate(
+        &self,
+        upload: &mut FileUpload,
+        _path: &Path,
+        tenant: &str,
+    ) -> Result<(), ValidationError> {
+        let policy = self.tenants.get(tenant).unwrap_or(&self.default);
+        let content_type = upload
+            .content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+        policy
+            .check(content_type, upload.size)
+            .map_err(ValidationError::rejected)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::UploadPolicy;
+
+    #[test]
+    fn test_policy() {
+        let policy: UploadPolicy = serde_json::from_str(
+            r#"{
+                "tenants": {
+                    "acme": {
+                        "allow": ["image/*", "application/pdf"],
+                        "deny": ["image/svg+xml"],
+                        "maxSize": {"*": 1000, "image/*": 100, "image/png": 500}
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let acme = &policy.tenants["acme"];
+        assert!(acme.check("application/pdf", 1000).is_ok());
+        assert!(acme.check("application/pdf", 1001).is_err());
+        assert!(acme.check("image/jpeg", 101).is_err());
+        assert!(acme.check("image/png", 500).is_ok());
+        assert!(acme.check("image/svg+xml", 1).is_err());
+        assert!(acme.check("text/html", 1).is_err());
+
+        assert!(policy.default.check("text/html", 1).is_ok());
+        assert!(policy.default.check("application/x-msdownload", 1).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use sequeda_file_upload_common::FileUpload;
+use serde_json::json;
+
+/// The rejected files, kept for an administrator to review:
+/// `<tenant>/<id>` is the content, `<tenant>/<id>.json` the upload and the reason
+#[derive(Debug, Clone)]
+pub struct Quarantine {
+    root: PathBuf,
+}
+
+impl Quarantine {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Quarantine { root: root.into() }
+    }
+
+    /// moves the file to the quarantine, returns its id there
+    pub async fn put(
+        &self,
+        tenant: &str,
+        upload: &FileUpload,
+        path: &Path,
+        reason: &str,
+    ) -> std::io::Result<String> {
+        let tenant: String = tenant
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let dir = self.root.join(tenant);
+        tokio::fs::create_dir_all(&dir).await?;
+        let id = uuid::Uuid::new_v4().to_string();
+        let dest = dir.join(&id);
+        if tokio::fs::rename(path, &dest).await.is_err() {
+            tokio::fs::copy(path, &dest).await?;
+            tokio::fs::remove_file(path).await?;
+        }
+        let metadata = json!({
+            "upload": upload,
+            "reason": reason,
+            "quarantineDate": Local::now().naive_local(),
+        });
+        tokio::fs::write(dest.with_extension("json"), metadata.to_string()).await?;
+        tracing::warn!("{} quarantined as {id}: {reason}", upload.original_filename);
+        Ok(id)
+    }
+}
@@ -0,0 +1,225 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use mime_guess::mime::APPLICATION_OCTET_STREAM;
+use sequeda_file_upload_common::FileUpload;
+use tokio::io::AsyncReadExt;
+
+use super::{ValidationError, ValidationStage};
+
+/// zip based documents list their entries in the first local headers
+const HEAD_SIZE: usize = 64 * 1024;
+
+/// the ole2 container of the old office documents, the client type is kept if it is one of them
+const OLE_TYPES: &[&str] = &[
+    "application/msword",
+    "application/vnd.ms-excel",
+    "application/vnd.ms-powerpoint",
+    "application/vnd.ms-outlook",
+];
+
+/// the types recognized by their magic bytes. a file claiming one of them without the
+/// matching bytes is spoofed
+const SNIFFED_TYPES: &[&str] = &[
+    "application/pdf",
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/tiff",
+    "image/bmp",
+    "image/avif",
+    "image/heic",
+    "video/mp4",
+    "application/zip",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    "application/vnd.oasis.opendocument.text",
+    "application/vnd.oasis.opendocument.spreadsheet",
+    "application/vnd.oasis.opendocument.presentation",
+    "application/msword",
+    "application/vnd.ms-excel",
+    "application/vnd.ms-powerpoint",
+    "application/rtf",
+    "application/gzip",
+    "application/vnd.rar",
+    "application/x-7z-compressed",
+];
+
+/// Replaces the content type sent by the client by the one found in the content
+#[derive(Debug, Default)]
+pub struct ContentSniffer;
+
+#[async_trait]
+impl ValidationStage for ContentSniffer {
+    async fn validate(
+        &self,
+        upload: &mut FileUpload,
+        path: &Path,
+        _tenant: &str,
+    ) -> Result<(), ValidationError> {
+        let mut head = Vec::with_capacity(HEAD_SIZE);
+        tokio::fs::File::open(path)
+            .await?
+            .take(HEAD_SIZE as u64)
+            .read_to_end(&mut head)
+            .await?;
+        let content_type = content_type(&head, upload.content_type.as_deref());
+        if upload.content_type.as_deref() != Some(content_type.as_str()) {
+            tracing::info!(
+                "{}: content type {:?} replaced by {content_type}",
+                upload.original_filename,
+                upload.content_type
+            );
+            upload.content_type = Some(content_type);
+        }
+        Ok(())
+    }
+}
+
+/// the type found in the content, or the claimed one if it cannot be checked
+pub fn content_type(head: &[u8], claimed: Option<&str>) -> String {
+    if let Some(sniffed) = sniff(head) {
+        if sniffed == "application/x-ole-storage" {
+            if let Some(claimed) = claimed.filter(|c| OLE_TYPES.contains(c)) {
+                return claimed.to_string();
+            }
+        }
+        return sniffed.to_string();
+    }
+    match claimed {
+        Some(claimed) if SNIFFED_TYPES.contains(&claimed) => APPLICATION_OCTET_STREAM.to_string(),
+        // text does not contain nul bytes
+        Some(claimed) if claimed.starts_with("text/") && head.contains(&0) => {
+            APPLICATION_OCTET_STREAM.to_string()
+        }
+        Some(claimed) => claimed.to_string(),
+        None => APPLICATION_OCTET_STREAM.to_string(),
+    }
+}
+
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    let starts = |magic: &[u8]| head.starts_with(magic);
+    let contains = |needle: &[u8]| head.windows(needle.len()).any(|w| w == needle);
+    let sniffed = if starts(b"%PDF-") {
+        "application/pdf"
+    } else if starts(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if starts(b"\xFF\xD8\xFF") {
+        "image/jpeg"
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        "image/gif"
+    } else if starts(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        "image/webp"
+    } else if starts(b"II*\0") || starts(b"MM\0*") {
+        "image/tiff"
+    } else if starts(b"BM") && head.len() > 14 && head[6..10] == [0, 0, 0, 0] {
+        "image/bmp"
+    } else if head.get(4..8) == Some(b"ftyp") {
+        match head.get(8..12)? {
+            b"avif" | b"avis" => "image/avif",
+            b"heic" | b"heix" | b"mif1" | b"msf1" => "image/heic",
+            _ => "video/mp4",
+        }
+    } else if starts(b"PK\x03\x04") {
+        // the open document files start with an uncompressed `mimetype` entry
+        if head.get(30..38) == Some(b"mimetype") {
+            match head.get(38..).map(|rest| rest.split(|b| *b == b'P').next()) {
+                Some(Some(b"application/vnd.oasis.opendocument.text")) => {
+                    "application/vnd.oasis.opendocument.text"
+                }
+                Some(Some(b"application/vnd.oasis.opendocument.spreadsheet")) => {
+                    "application/vnd.oasis.opendocument.spreadsheet"
+                }
+                Some(Some(b"application/vnd.oasis.opendocument.presentation")) => {
+                    "application/vnd.oasis.opendocument.presentation"
+                }
+                _ => "application/zip",
+            }
+        } else if contains(b"word/") {
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        } else if contains(b"xl/") {
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+        } else if contains(b"ppt/") {
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation"
+        } else {
+            "application/zip"
+        }
+    } else if starts(b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1") {
+        "application/x-ole-storage"
+    } else if starts(b"{\\rtf") {
+        "application/rtf"
+    } else if starts(b"\x1f\x8b") {
+        "application/gzip"
+    } else if starts(b"Rar!\x1a\x07") {
+        "application/vnd.rar"
+    } else if starts(b"7z\xBC\xAF\x27\x1C") {
+        "application/x-7z-compressed"
+    } else if starts(b"MZ") {
+        "application/x-msdownload"
+    } else if starts(b"\x7fELF") {
+        "application/x-executable"
+    } else if starts(b"#!") {
+        "application/x-sh"
+    } else {
+        let text = String::from_utf8_lossy(&head[..head.len().min(512)]).to_lowercase();
+        let text = text.trim_start_matches('\u{feff}').trim_start();
+        if text.starts_with("<svg") || (text.starts_with("<?xml") && text.contains("<svg")) {
+            "image/svg+xml"
+        } else if text.starts_with("<!doctype html") || text.starts_with("<html") {
+            "text/html"
+        } else {
+            return None;
+        }
+    };
+    Some(sniffed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::content_type;
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(
+            "application/pdf",
+            content_type(b"%PDF-1.7\n...", Some("image/png"))
+        );
+        assert_eq!(
+            "application/x-msdownload",
+            content_type(b"MZ\x90\0\x03", Some("application/pdf"))
+        );
+        // a pdf without the pdf signature
+        assert_eq!(
+            "application/octet-stream",
+            content_type(b"hello", Some("application/pdf"))
+        );
+        assert_eq!("text/csv", content_type(b"a;b\n1;2", Some("text/csv")));
+        assert_eq!(
+            "application/octet-stream",
+            content_type(b"a\0b", Some("text/plain"))
+        );
+        assert_eq!(
+            "application/msword",
+            content_type(
+                b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1\0\0",
+                Some("application/msword")
+            )
+        );
+        let mut odt = b"PK\x03\x04".to_vec();
+        odt.extend([0u8; 26]);
+        odt.extend(b"mimetypeapplication/vnd.oasis.opendocument.textPK\x03\x04");
+        assert_eq!(
+            "application/vnd.oasis.opendocument.text",
+            content_type(&odt, None)
+        );
+        assert_eq!(
+            "image/svg+xml",
+            content_type(
+                b"<?xml version=\"1.0\"?>\n<svg xmlns=\"\"/>",
+                Some("image/png")
+            )
+        );
+    }
+}
@@ -3,6 +3,7 @@ use std::env::var;
 use axum::{
     extract::Path,
     http::StatusCode,
+    middleware,
     response::IntoResponse,
     routing::{delete, get, post},
     Extension, Json, Router,
@@ -13,7 +14,8 @@ use sequeda_file_upload_client::{
     DownloadFileRequestUriParams, FileUploadClient, UploadFileRequestUriParams,
 };
 use sequeda_service_common::{
-    user_header::ExtractUserInfo, QueryIds, StoreCollection, PUBLIC_TENANT, SERVICE_COLLECTION_NAME,
+    trace_context::trace_context, user_header::ExtractUserInfo, QueryIds, StoreCollection,
+    PUBLIC_TENANT, SERVICE_COLLECTION_NAME,
 };
 use sequeda_store::{
     doc, FindOneAndReplaceOptions, MongoError, Repository, StoreClient, StoreRepository,
@@ -41,6 +43,7 @@ pub fn get_router(
         .layer(Extension(file_client))
         .layer(Extension(template_client))
         .layer(Extension(StoreCollection(collection_name)))
+        .layer(middleware::from_fn(trace_context))
 }
 
 async fn delete_by_id(
@@ -303,6 +306,9 @@ async fn upsert(
                     &x_user_info_header,
                     DownloadFileRequestUriParams {
                         id: logo_id.clone(),
+                        version: None,
+                        disposition: None,
+                        token: None,
                     },
                 )
                 .await
@@ -316,6 +322,9 @@ async fn upsert(
                     &x_user_info_header,
                     DownloadFileRequestUriParams {
                         id: logo_id.clone(),
+                        version: None,
+                        disposition: None,
+                        token: None,
                     },
                 )
                 .await
@@ -3,14 +3,15 @@ use std::env::var;
 use axum::{
     extract::{self, Path, Query},
     http::StatusCode,
+    middleware,
     response::IntoResponse,
     routing::{delete, get, post},
     Extension, Json, Router,
 };
 use chrono::Local;
 use sequeda_service_common::{
-    user_header::ExtractUserInfo, IdGenerator, QueryIds, StoreCollection, PUBLIC_TENANT,
-    SERVICE_COLLECTION_NAME,
+    trace_context::trace_context, user_header::ExtractUserInfo, IdGenerator, QueryIds,
+    StoreCollection, PUBLIC_TENANT, SERVICE_COLLECTION_NAME,
 };
 use sequeda_store::{doc, Regex, Repository, StoreClient, StoreRepository};
 use serde::Deserialize;
@@ -31,6 +32,7 @@ pub fn get_router(client: StoreClient) -> Router {
         .route("/", post(upsert))
         .layer(Extension(client))
         .layer(Extension(StoreCollection(collection_name)))
+        .layer(middleware::from_fn(trace_context))
 }
 
 /// routes
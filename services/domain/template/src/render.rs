@@ -36,6 +36,9 @@ pub async fn render<T: Serialize>(
             x_user_info,
             DownloadFileRequestUriParams {
                 id: templ.file_id.clone(),
+                version: None,
+                disposition: None,
+                token: None,
             },
         )
         .await?;
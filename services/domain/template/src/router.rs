@@ -3,6 +3,7 @@ use std::{env::var, io::Cursor};
 use axum::{
     extract::{Multipart, Path, Query},
     http::{header, StatusCode},
+    middleware,
     response::{AppendHeaders, IntoResponse},
     routing::{delete, get, post},
     Extension, Json, Router,
@@ -12,7 +13,8 @@ use chrono::Local;
 use mime_guess::mime::APPLICATION_PDF;
 use sequeda_file_upload_client::{FileUploadClient, UploadFileRequestUriParams};
 use sequeda_service_common::{
-    user_header::ExtractUserInfo, QueryIds, StoreCollection, PUBLIC_TENANT, SERVICE_COLLECTION_NAME,
+    trace_context::trace_context, user_header::ExtractUserInfo, QueryIds, StoreCollection,
+    PUBLIC_TENANT, SERVICE_COLLECTION_NAME,
 };
 use sequeda_store::{
     doc, FindOneAndReplaceOptions, MongoError, Repository, StoreClient, StoreRepository,
@@ -39,6 +41,7 @@ pub fn get_router(store_client: StoreClient, file_upload_client: FileUploadClien
         .layer(Extension(store_client))
         .layer(Extension(file_upload_client))
         .layer(Extension(StoreCollection(collection_name)))
+        .layer(middleware::from_fn(trace_context))
 }
 
 async fn render(
@@ -0,0 +1,132 @@
+use std::time::Instant;
+
+use axum::{extract::Request, http::HeaderValue, middleware::Next, response::Response};
+use sequeda_service_common::{trace_context::TraceContext, X_REQUEST_ID_HEADER};
+use tracing::Instrument;
+
+/// What the gateway did with a request, attached to its response by the handler
+#[derive(Debug, Clone, Default)]
+pub struct AccessLogEntry {
+    pub route_id: Option<String>,
+    pub upstream: Option<String>,
+    pub user: Option<String>,
+    pub tenant: Option<String>,
+}
+
+/// Route matched by the request handler
+#[derive(Debug, Clone)]
+pub struct MatchedRoute(pub String);
+
+/// Logs one line per request on the `access_log` target, and propagates the request id
+/// and the trace context to the upstreams
+pub async fn access_log(mut req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().clone();
+    // without the query, which may carry secrets
+    let path = req.uri().path().to_string();
+    let mut trace_context = TraceContext::from_headers(req.headers());
+    let span = trace_context.span();
+    // the upstreams see the gateway as their parent span
+    trace_context.insert_headers(req.headers_mut());
+    let request_id = trace_context.request_id.clone();
+
+    let mut response = trace_context
+        .scope(next.run(req).instrument(span.clone()))
+        .await;
+
+    let entry = response
+        .extensions_mut()
+        .remove::<AccessLogEntry>()
+        .unwrap_or_default();
+    span.in_scope(|| {
+        tracing::info!(
+            target: "access_log",
+            method = %method,
+            path,
+            status = response.status().as_u16(),
+            latency_ms = start.elapsed().as_millis() as u64,
+            route_id = entry.route_id.as_deref().unwrap_or("-"),
+            upstream = entry.upstream.as_deref().unwrap_or("-"),
+            user = entry.user.as_deref().unwrap_or("-"),
+            tenant = entry.tenant.as_deref().unwrap_or("-"),
+        );
+    });
+    if let Ok(request_id) = HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(X_REQUEST_ID_HEADER, request_id);
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        body::Body,
+        http::{HeaderMap, Request},
+        middleware,
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+    use sequeda_service_common::{trace_context::TraceContext, TRACEPARENT_HEADER};
+    use tower::ServiceExt;
+
+    use super::{access_log, AccessLogEntry};
+
+    /// what the upstream would receive
+    async fn upstream(headers: HeaderMap) -> impl IntoResponse {
+        let trace_context = TraceContext::from_headers(&headers);
+        let mut response = format!(
+            "{} {}",
+            trace_context.request_id,
+            trace_context.parent_id.unwrap_or_default()
+        )
+        .into_response();
+        response.extensions_mut().insert(AccessLogEntry {
+            route_id: Some("invoice".into()),
+            ..Default::default()
+        });
+        response
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let app = Router::new()
+            .route("/invoice", get(upstream))
+            .layer(middleware::from_fn(access_log));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/invoice")
+                    .header("X-Request-Id", "req-42")
+                    .header(
+                        TRACEPARENT_HEADER,
+                        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!("req-42", response.headers()["X-Request-Id"]);
+        assert!(response.extensions().get::<AccessLogEntry>().is_none());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let (request_id, parent_id) = body.split_once(' ').unwrap();
+        assert_eq!("req-42", request_id);
+        // the gateway is the parent of the upstream span
+        assert_eq!(16, parent_id.len());
+        assert_ne!("00f067aa0ba902b7", parent_id);
+
+        let response = app
+            .oneshot(Request::get("/invoice").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let request_id = response.headers()["X-Request-Id"].to_str().unwrap();
+        assert_eq!(36, request_id.len());
+    }
+}
@@ -0,0 +1,156 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use axum::{
+    body::Body,
+    extract::Query,
+    http::Request,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    openapi,
+    openid::{check_admin, User},
+    request_handler::SharedRequestHandler,
+    Client,
+};
+
+type AdminError = (StatusCode, Json<serde_json::Value>);
+
+#[derive(Deserialize, Debug)]
+struct CacheInvalidation {
+    /// every route when absent
+    route: Option<String>,
+    /// path of the gateway, e.g. `/template/find-all`
+    prefix: Option<String>,
+}
+
+/// A request to route, as the gateway would receive it
+#[derive(Deserialize, Debug)]
+struct DryRunRequest {
+    /// default to GET
+    method: Option<String>,
+    /// path and query, e.g `/invoice/find-all?page=1`
+    uri: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    client_ip: Option<IpAddr>,
+    /// checks the authorizations for this user
+    user: Option<DryRunUser>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DryRunUser {
+    id: Option<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    tenant: Option<String>,
+}
+
+/// Operations on the running gateway, for the admins
+pub fn admin_router() -> Router {
+    Router::new()
+        .route("/admin/cache", delete(invalidate_cache))
+        .route("/admin/routes", get(list_routes))
+        .route("/admin/routes/dry-run", post(dry_run))
+        .route("/admin/openapi", get(aggregate_openapi))
+}
+
+fn bad_request(e: impl std::fmt::Display) -> AdminError {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({"error": e.to_string()})),
+    )
+}
+
+/// the routes currently loaded, in the order they are matched
+async fn list_routes(
+    Extension(request_handler): Extension<SharedRequestHandler>,
+    user: User,
+) -> Result<impl IntoResponse, AdminError> {
+    check_admin(&user)?;
+    Ok(Json(json!(request_handler.load().routes())))
+}
+
+/// which route would handle the request, which predicates matched and which authorizations apply
+async fn dry_run(
+    Extension(request_handler): Extension<SharedRequestHandler>,
+    user: User,
+    Json(dry_run): Json<DryRunRequest>,
+) -> Result<impl IntoResponse, AdminError> {
+    check_admin(&user)?;
+    let DryRunRequest {
+        method,
+        uri,
+        headers,
+        client_ip,
+        user,
+    } = dry_run;
+    let mut req = Request::builder()
+        .method(method.as_deref().unwrap_or("GET"))
+        .uri(&uri);
+    for (name, value) in &headers {
+        req = req.header(name, value);
+    }
+    let req = req.body(Body::empty()).map_err(bad_request)?;
+    let user = user.map(|user| User {
+        id: user.id.unwrap_or_else(|| "dry-run".into()),
+        full_name: None,
+        given_name: None,
+        family_name: None,
+        middle_name: None,
+        username: None,
+        email: None,
+        roles: user.roles,
+        groups: user.groups,
+        tenant: user.tenant,
+    });
+    Ok(Json(request_handler.load().dry_run(
+        &req,
+        client_ip,
+        user.as_ref(),
+    )))
+}
+
+/// the OpenAPI documents of the upstreams, merged in a single spec
+async fn aggregate_openapi(
+    Extension(request_handler): Extension<SharedRequestHandler>,
+    Extension(client): Extension<Client>,
+    user: User,
+) -> Result<impl IntoResponse, AdminError> {
+    check_admin(&user)?;
+    let request_handler = request_handler.load();
+    Ok(Json(
+        openapi::aggregate(&client, request_handler.openapi_sources()).await,
+    ))
+}
+
+/// removes the cached responses of the tenant of the admin
+async fn invalidate_cache(
+    Extension(request_handler): Extension<SharedRequestHandler>,
+    user: User,
+    Query(invalidation): Query<CacheInvalidation>,
+) -> Result<impl IntoResponse, AdminError> {
+    check_admin(&user)?;
+    let CacheInvalidation { route, prefix } = invalidation;
+    match request_handler.load().invalidate_cache(
+        route.as_deref(),
+        user.tenant.as_deref(),
+        prefix.as_deref(),
+    ) {
+        Some(invalidated) => {
+            tracing::info!("{invalidated} cached responses invalidated by {}", user.id);
+            Ok(Json(json!({ "invalidated": invalidated })))
+        }
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("route {} not found", route.unwrap_or_default())})),
+        )),
+    }
+}
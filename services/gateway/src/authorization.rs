@@ -0,0 +1,316 @@
+use regex::{Captures, Regex};
+
+use crate::{
+    config::{Authorization, ConfigError, Rule, RuleExpr},
+    openid::User,
+    predicate::ant_pattern_to_regex,
+};
+
+#[derive(Debug)]
+pub enum CompiledAuthorization {
+    /// kept for the routes configured with `!authorization`
+    Legacy {
+        method: String,
+        has_roles: Option<Vec<String>>,
+        has_groups: Option<Vec<String>>,
+    },
+    Rule {
+        methods: Option<Vec<String>>,
+        path: Option<Regex>,
+        allow: CompiledRuleExpr,
+    },
+}
+
+#[derive(Debug)]
+pub enum CompiledRuleExpr {
+    Any(Vec<CompiledRuleExpr>),
+    All(Vec<CompiledRuleExpr>),
+    Not(Vec<CompiledRuleExpr>),
+    Role(String),
+    Group(String),
+    Tenant(String),
+    Authenticated,
+    PathParam { name: String, regex: Regex },
+    PathParamIsTenant(String),
+    PathParamIsUser(String),
+}
+
+impl CompiledAuthorization {
+    pub fn compile(route_id: &str, authorization: Authorization) -> Result<Self, ConfigError> {
+        let authorization = match authorization {
+            Authorization::Authorization {
+                method,
+                has_roles,
+                has_groups,
+            } => CompiledAuthorization::Legacy {
+                method,
+                has_roles,
+                has_groups,
+            },
+            Authorization::Rule(Rule {
+                methods,
+                path,
+                allow,
+            }) => {
+                let path = path
+                    .map(|path| {
+                        ant_pattern_to_regex(&path).map_err(|e| {
+                            ConfigError::new(format!(
+                                "route {route_id}: invalid rule path `{path}`: {e}"
+                            ))
+                        })
+                    })
+                    .transpose()?;
+                let params: Vec<&str> = path
+                    .as_ref()
+                    .map(|path| path.capture_names().flatten().collect())
+                    .unwrap_or_default();
+                CompiledAuthorization::Rule {
+                    methods,
+                    allow: CompiledRuleExpr::compile(route_id, allow, &params)?,
+                    path,
+                }
+            }
+        };
+        Ok(authorization)
+    }
+
+    /// whether the authorization must be checked for this request
+    pub fn applies(&self, method: &str, path: &str) -> bool {
+        match self {
+            CompiledAuthorization::Legacy { method: m, .. } => m.eq_ignore_ascii_case(method),
+            CompiledAuthorization::Rule {
+                methods,
+                path: path_regex,
+                ..
+            } => {
+                methods
+                    .as_ref()
+                    .map(|methods| methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+                    .unwrap_or(true)
+                    && path_regex
+                        .as_ref()
+                        .map(|re| re.is_match(path))
+                        .unwrap_or(true)
+            }
+        }
+    }
+
+    pub fn check_auth(&self, path: &str, user: &User) -> bool {
+        match self {
+            CompiledAuthorization::Legacy {
+                has_roles,
+                has_groups,
+                ..
+            } => {
+                let has_roles = if let Some(roles) = has_roles {
+                    roles.iter().any(|a| user.roles.contains(a)) // or
+                } else {
+                    true
+                };
+                let has_groups = if let Some(groups) = has_groups {
+                    groups.iter().all(|a| user.groups.contains(a)) // and
+                } else {
+                    true
+                };
+                has_roles && has_groups
+            }
+            CompiledAuthorization::Rule {
+                path: path_regex,
+                allow,
+                ..
+            } => {
+                let params = path_regex.as_ref().and_then(|re| re.captures(path));
+                allow.eval(user, params.as_ref())
+            }
+        }
+    }
+}
+
+impl CompiledRuleExpr {
+    /// `params`: the path parameters of the rule, a rule cannot refer to an unknown one
+    fn compile(route_id: &str, expr: RuleExpr, params: &[&str]) -> Result<Self, ConfigError> {
+        let check_param = |name: &str| {
+            if params.contains(&name) {
+                Ok(())
+            } else {
+                Err(ConfigError::new(format!(
+                    "route {route_id}: unknown path parameter `{name}` in rule"
+                )))
+            }
+        };
+        let compile_all = |exprs: Vec<RuleExpr>| {
+            exprs
+                .into_iter()
+                .map(|expr| Self::compile(route_id, expr, params))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let expr = match expr {
+            RuleExpr::Any(exprs) => CompiledRuleExpr::Any(compile_all(exprs)?),
+            RuleExpr::All(exprs) => CompiledRuleExpr::All(compile_all(exprs)?),
+            RuleExpr::Not(exprs) => CompiledRuleExpr::Not(compile_all(exprs)?),
+            RuleExpr::Role(role) => CompiledRuleExpr::Role(role),
+            RuleExpr::Group(group) => CompiledRuleExpr::Group(group),
+            RuleExpr::Tenant(tenant) => CompiledRuleExpr::Tenant(tenant),
+            RuleExpr::Authenticated => CompiledRuleExpr::Authenticated,
+            RuleExpr::PathParam { name, regex } => {
+                check_param(&name)?;
+                // the whole parameter must match
+                let anchored = format!("^(?:{regex})$");
+                CompiledRuleExpr::PathParam {
+                    regex: Regex::new(&anchored).map_err(|e| {
+                        ConfigError::new(format!(
+                            "route {route_id}: invalid path parameter regex `{regex}`: {e}"
+                        ))
+                    })?,
+                    name,
+                }
+            }
+            RuleExpr::PathParamIsTenant(name) => {
+                check_param(&name)?;
+                CompiledRuleExpr::PathParamIsTenant(name)
+            }
+            RuleExpr::PathParamIsUser(name) => {
+                check_param(&name)?;
+                CompiledRuleExpr::PathParamIsUser(name)
+            }
+        };
+        Ok(expr)
+    }
+
+    fn eval(&self, user: &User, params: Option<&Captures>) -> bool {
+        let param = |name: &str| {
+            params
+                .and_then(|params| params.name(name))
+                .map(|param| param.as_str())
+        };
+        match self {
+            CompiledRuleExpr::Any(exprs) => exprs.iter().any(|expr| expr.eval(user, params)),
+            CompiledRuleExpr::All(exprs) => exprs.iter().all(|expr| expr.eval(user, params)),
+            CompiledRuleExpr::Not(exprs) => !exprs.iter().any(|expr| expr.eval(user, params)),
+            CompiledRuleExpr::Role(role) => user.roles.contains(role),
+            CompiledRuleExpr::Group(group) => user.groups.contains(group),
+            CompiledRuleExpr::Tenant(tenant) => user.tenant.as_ref() == Some(tenant),
+            CompiledRuleExpr::Authenticated => true,
+            CompiledRuleExpr::PathParam { name, regex } => {
+                param(name).is_some_and(|value| regex.is_match(value))
+            }
+            CompiledRuleExpr::PathParamIsTenant(name) => {
+                param(name).is_some_and(|value| user.tenant.as_deref() == Some(value))
+            }
+            CompiledRuleExpr::PathParamIsUser(name) => param(name) == Some(user.id.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::{Authorization, Route},
+        openid::User,
+    };
+
+    use super::CompiledAuthorization;
+
+    fn user(roles: &[&str], groups: &[&str], tenant: Option<&str>) -> User {
+        User {
+            id: "nb".into(),
+            full_name: None,
+            given_name: None,
+            family_name: None,
+            middle_name: None,
+            username: None,
+            email: None,
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            tenant: tenant.map(|t| t.to_string()),
+        }
+    }
+
+    fn compile(yaml: &str) -> Vec<CompiledAuthorization> {
+        let route: Route = serde_yml::from_str(yaml).unwrap();
+        route
+            .authorizations
+            .unwrap()
+            .into_iter()
+            .map(|auth: Authorization| CompiledAuthorization::compile(&route.id, auth).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        let authorizations = compile(
+            r#"
+            id: invoice
+            uri: http://invoice
+            authorizations:
+              - !authorization
+                method: GET
+                has_roles: [creep, demo]
+                has_groups: [/yahoo]
+              - !rule
+                methods: [POST, DELETE]
+                path: /invoice/{tenant}/{id}/**
+                allow: !all
+                  - !any
+                    - !role creep
+                    - !group /manager
+                  - !not
+                    - !role demo
+                  - !path_param_is_tenant tenant
+                  - !path_param
+                    name: id
+                    regex: "[0-9]+"
+            "#,
+        );
+        let [legacy, rule] = authorizations.as_slice() else {
+            panic!("expected 2 authorizations");
+        };
+        assert!(legacy.applies("get", "/anything"));
+        assert!(!legacy.applies("POST", "/anything"));
+        assert!(legacy.check_auth("/", &user(&["demo"], &["/yahoo"], None)));
+        assert!(!legacy.check_auth("/", &user(&["demo"], &[], None)));
+
+        let path = "/invoice/Xre/42/lines";
+        assert!(rule.applies("POST", path));
+        assert!(!rule.applies("GET", path));
+        assert!(!rule.applies("POST", "/person/Xre/42"));
+        assert!(rule.check_auth(path, &user(&["creep"], &[], Some("Xre"))));
+        assert!(rule.check_auth(path, &user(&[], &["/manager"], Some("Xre"))));
+        assert!(!rule.check_auth(path, &user(&["creep"], &[], Some("Other"))));
+        assert!(!rule.check_auth(path, &user(&["creep", "demo"], &[], Some("Xre"))));
+        assert!(!rule.check_auth(
+            "/invoice/Xre/42a/lines",
+            &user(&["creep"], &[], Some("Xre"))
+        ));
+
+        let route: Route = serde_yml::from_str(
+            r#"
+            id: invoice
+            authorizations:
+              - !rule
+                path: /invoice/{id}
+                allow: !path_param_is_user owner
+            "#,
+        )
+        .unwrap();
+        let err =
+            CompiledAuthorization::compile(&route.id, route.authorizations.unwrap().pop().unwrap())
+                .unwrap_err();
+        assert_eq!(
+            "route invoice: unknown path parameter `owner` in rule",
+            err.to_string()
+        );
+
+        let authorizations = compile(
+            r#"
+            id: invoice
+            authorizations:
+              - !rule
+                allow: !authenticated
+            "#,
+        );
+        assert!(authorizations[0].applies("PATCH", "/any"));
+        assert!(authorizations[0].check_auth("/any", &user(&[], &[], None)));
+    }
+}
@@ -0,0 +1,687 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderName, HeaderValue, Request, Response},
+};
+use hyper::{
+    header::{
+        ACCEPT_ENCODING, AGE, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, ETAG,
+        IF_MODIFIED_SINCE, IF_NONE_MATCH, SET_COOKIE, VARY,
+    },
+    Method, StatusCode, Uri,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{config::Cache, openid::User};
+
+const DEFAULT_TTL_SECS: u64 = 60;
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_MAX_ENTRY_SIZE: u64 = 1024 * 1024;
+const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// In-memory LRU cache of the responses of a route, bounded by the size of the cached responses
+#[derive(Debug)]
+pub struct ResponseCache {
+    ttl: Duration,
+    max_size: u64,
+    max_entry_size: u64,
+    key_query: bool,
+    key_tenant: bool,
+    key_user: bool,
+    authenticated: bool,
+    invalidate_on_write: bool,
+    lru: Mutex<Lru>,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<String, CacheEntry>,
+    /// last use => key, the least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    size: u64,
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    etag: Option<HeaderValue>,
+    tenant: Option<String>,
+    path: String,
+    stored_at: Instant,
+    fresh_until: Instant,
+    last_used: u64,
+    size: u64,
+}
+
+/// Where a cacheable request is kept
+#[derive(Debug, Clone)]
+struct CacheKey {
+    key: String,
+    path: String,
+    tenant: Option<String>,
+}
+
+enum Lookup {
+    Fresh(CacheEntry),
+    /// expired, but can be revalidated with its etag
+    Stale(CacheEntry),
+    Miss,
+}
+
+/// What to do with a GET or HEAD request of a cached route
+#[derive(Debug)]
+pub enum CacheLookup {
+    /// a fresh entry, answered without calling the upstream
+    Hit(Response<Body>),
+    /// call the upstream, then store its response
+    Forward(CacheContext),
+    /// the client asked not to use the cache, or the route does not cache the responses to a user
+    Bypass,
+}
+
+impl ResponseCache {
+    pub fn new(cache: Cache) -> Self {
+        ResponseCache {
+            ttl: Duration::from_secs(cache.ttl_secs.unwrap_or(DEFAULT_TTL_SECS)),
+            max_size: cache.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            max_entry_size: cache.max_entry_size.unwrap_or(DEFAULT_MAX_ENTRY_SIZE),
+            key_query: cache.key_query.unwrap_or(true),
+            key_tenant: cache.key_tenant.unwrap_or(true),
+            key_user: cache.key_user.unwrap_or(true),
+            authenticated: cache.authenticated.unwrap_or(false),
+            invalidate_on_write: cache.invalidate_on_write.unwrap_or(true),
+            lru: Default::default(),
+        }
+    }
+
+    pub fn invalidate_on_write(&self) -> bool {
+        self.invalidate_on_write
+    }
+
+    /// the path and query of the original request, the tenant and the user
+    fn key(&self, uri: &Uri, user: Option<&User>) -> CacheKey {
+        let tenant = self
+            .key_tenant
+            .then(|| user.and_then(|u| u.tenant.clone()))
+            .flatten();
+        let user_id = self.key_user.then(|| user.map(|u| u.id.as_str())).flatten();
+        let path = uri.path().to_string();
+        let query = self.key_query.then(|| uri.query()).flatten();
+        CacheKey {
+            key: format!(
+                "{}|{}|{}?{}",
+                tenant.as_deref().unwrap_or("-"),
+                user_id.unwrap_or("-"),
+                path,
+                query.unwrap_or_default()
+            ),
+            path,
+            tenant,
+        }
+    }
+
+    fn lookup(&self, key: &CacheKey) -> Lookup {
+        let mut lru = self.lru.lock().unwrap();
+        let Some(entry) = lru.entries.get(&key.key) else {
+            return Lookup::Miss;
+        };
+        let fresh = entry.fresh_until > Instant::now();
+        if !fresh && entry.etag.is_none() {
+            lru.remove(&key.key);
+            return Lookup::Miss;
+        }
+        let entry = lru.touch(&key.key);
+        if fresh {
+            Lookup::Fresh(entry)
+        } else {
+            Lookup::Stale(entry)
+        }
+    }
+
+    /// keeps the response if it can be cached, and gives it back
+    async fn store(&self, key: &CacheKey, response: Response<Body>) -> Response<Body> {
+        let Some(ttl) = self.cacheable(&response) else {
+            return response;
+        };
+        let (mut parts, body) = response.into_parts();
+        let body = match axum::body::to_bytes(body, self.max_entry_size as usize).await {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("could not read the response to cache: {e}");
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+        };
+        if !parts.headers.contains_key(ETAG) {
+            // a weak etag, the compression filter may encode the body
+            let digest = Sha256::digest(&body);
+            let etag = format!("W/\"{}\"", hex(&digest[..16]));
+            parts
+                .headers
+                .insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+        }
+        let size = key.key.len() as u64
+            + body.len() as u64
+            + parts
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str().len() + value.len()) as u64)
+                .sum::<u64>();
+        if size <= self.max_size {
+            let now = Instant::now();
+            let entry = CacheEntry {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                etag: parts.headers.get(ETAG).cloned(),
+                tenant: key.tenant.clone(),
+                path: key.path.clone(),
+                stored_at: now,
+                fresh_until: now + ttl,
+                last_used: 0,
+                size,
+            };
+            let mut lru = self.lru.lock().unwrap();
+            lru.insert(key.key.clone(), entry);
+            lru.evict(self.max_size);
+        }
+        parts
+            .headers
+            .insert(X_CACHE, HeaderValue::from_static("MISS"));
+        Response::from_parts(parts, Body::from(body))
+    }
+
+    /// the upstream answered `304 Not Modified` to the revalidation of a stale entry.
+    /// The entry is put back if it was evicted meanwhile, unless a newer response replaced it
+    fn refresh(
+        &self,
+        key: &CacheKey,
+        not_modified: &Response<Body>,
+        mut entry: CacheEntry,
+    ) -> CacheEntry {
+        let ttl = max_age(not_modified.headers()).unwrap_or(self.ttl);
+        let now = Instant::now();
+        entry.stored_at = now;
+        entry.fresh_until = now + ttl;
+        let mut lru = self.lru.lock().unwrap();
+        let replaced = lru
+            .entries
+            .get(&key.key)
+            .is_some_and(|current| current.etag != entry.etag);
+        if !replaced {
+            lru.insert(key.key.clone(), entry.clone());
+            lru.evict(self.max_size);
+        }
+        entry
+    }
+
+    /// removes the entries of the tenant whose path starts with `prefix`.
+    /// Without `key_tenant`, the entries are shared by every tenant
+    pub fn invalidate(&self, tenant: Option<&str>, prefix: Option<&str>) -> usize {
+        let tenant = self.key_tenant.then_some(tenant);
+        let mut lru = self.lru.lock().unwrap();
+        let keys: Vec<String> = lru
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                tenant.is_none_or(|tenant| entry.tenant.as_deref() == tenant)
+                    && prefix.is_none_or(|prefix| entry.path.starts_with(prefix))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            lru.remove(key);
+        }
+        keys.len()
+    }
+
+    /// how long the response stays fresh, `None` when it cannot be cached
+    fn cacheable(&self, response: &Response<Body>) -> Option<Duration> {
+        let headers = response.headers();
+        if response.status() != StatusCode::OK
+            || headers.contains_key(SET_COOKIE)
+            || headers.contains_key(CONTENT_ENCODING)
+        {
+            return None;
+        }
+        let varies = headers
+            .get_all(VARY)
+            .iter()
+            .filter_map(|vary| vary.to_str().ok())
+            .flat_map(|vary| vary.split(','))
+            .any(|vary| !vary.trim().eq_ignore_ascii_case("accept-encoding"));
+        let no_store = directives(headers)
+            .any(|(name, _)| matches!(name.as_str(), "no-store" | "no-cache" | "private"));
+        let size_known = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<u64>().ok())
+            .is_some_and(|length| length <= self.max_entry_size);
+        if varies || no_store || !size_known {
+            return None;
+        }
+        let ttl = max_age(headers).unwrap_or(self.ttl);
+        (!ttl.is_zero()).then_some(ttl)
+    }
+}
+
+impl CacheEntry {
+    /// `304 Not Modified` when the client already has this version
+    fn to_response(
+        &self,
+        head: bool,
+        if_none_match: Option<&HeaderValue>,
+        status: &'static str,
+    ) -> Response<Body> {
+        let not_modified = match (if_none_match, &self.etag) {
+            (Some(if_none_match), Some(etag)) => etag_matches(if_none_match, etag),
+            _ => false,
+        };
+        let mut response = if not_modified || head {
+            let mut response = Response::new(Body::empty());
+            if not_modified {
+                *response.status_mut() = StatusCode::NOT_MODIFIED;
+            }
+            response
+        } else {
+            Response::new(Body::from(self.body.clone()))
+        };
+        if !not_modified {
+            *response.status_mut() = self.status;
+        }
+        *response.headers_mut() = self.headers.clone();
+        if not_modified {
+            response.headers_mut().remove(CONTENT_LENGTH);
+        }
+        response
+            .headers_mut()
+            .insert(AGE, HeaderValue::from(self.stored_at.elapsed().as_secs()));
+        response
+            .headers_mut()
+            .insert(X_CACHE, HeaderValue::from_static(status));
+        response
+    }
+}
+
+impl Lru {
+    fn insert(&mut self, key: String, mut entry: CacheEntry) {
+        self.remove(&key);
+        self.tick += 1;
+        entry.last_used = self.tick;
+        self.size += entry.size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, entry);
+    }
+
+    fn touch(&mut self, key: &str) -> CacheEntry {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key).expect("touched a missing entry");
+        self.order.remove(&entry.last_used);
+        entry.last_used = tick;
+        self.order.insert(tick, key.to_string());
+        entry.clone()
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+
+    fn evict(&mut self, max_size: u64) {
+        while self.size > max_size {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.size;
+            }
+        }
+    }
+}
+
+/// Cache of a proxied request: the response of the upstream is stored,
+/// or refreshes the stale entry being revalidated
+#[derive(Debug)]
+pub struct CacheContext {
+    cache: Arc<ResponseCache>,
+    key: CacheKey,
+    head: bool,
+    if_none_match: Option<HeaderValue>,
+    /// the entry whose etag was sent to the upstream
+    revalidated: Option<Box<CacheEntry>>,
+}
+
+impl CacheContext {
+    /// The conditional headers of the client are answered by the gateway, the upstream only sees the
+    /// ones of the revalidation, and its response is not encoded: the compression filter encodes it per client
+    pub fn lookup(
+        cache: &Arc<ResponseCache>,
+        req: &mut Request<Body>,
+        uri: &Uri,
+        user: Option<&User>,
+    ) -> CacheLookup {
+        let head = req.method() == Method::HEAD;
+        let client_directives: Vec<String> =
+            directives(req.headers()).map(|(name, _)| name).collect();
+        if client_directives.iter().any(|d| d == "no-store")
+            || (user.is_some() && !cache.authenticated)
+        {
+            return CacheLookup::Bypass;
+        }
+        let key = cache.key(uri, user);
+        let if_none_match = req.headers_mut().remove(IF_NONE_MATCH);
+        req.headers_mut().remove(IF_MODIFIED_SINCE);
+        req.headers_mut().remove(ACCEPT_ENCODING);
+        let revalidate = client_directives.iter().any(|d| d == "no-cache");
+        let revalidated = match cache.lookup(&key) {
+            Lookup::Fresh(entry) if !revalidate => {
+                return CacheLookup::Hit(entry.to_response(head, if_none_match.as_ref(), "HIT"));
+            }
+            Lookup::Fresh(entry) | Lookup::Stale(entry) => match entry.etag.clone() {
+                Some(etag) => {
+                    req.headers_mut().insert(IF_NONE_MATCH, etag);
+                    Some(Box::new(entry))
+                }
+                None => None,
+            },
+            Lookup::Miss => None,
+        };
+        CacheLookup::Forward(CacheContext {
+            cache: cache.clone(),
+            key,
+            head,
+            if_none_match,
+            revalidated,
+        })
+    }
+
+    pub async fn store(&self, response: Response<Body>) -> Response<Body> {
+        if let (StatusCode::NOT_MODIFIED, Some(revalidated)) =
+            (response.status(), &self.revalidated)
+        {
+            return self
+                .cache
+                .refresh(&self.key, &response, (**revalidated).clone())
+                .to_response(self.head, self.if_none_match.as_ref(), "REVALIDATED");
+        }
+        if self.head {
+            return response;
+        }
+        let response = self.cache.store(&self.key, response).await;
+        match (&self.if_none_match, response.headers().get(ETAG)) {
+            (Some(if_none_match), Some(etag))
+                if response.status() == StatusCode::OK && etag_matches(if_none_match, etag) =>
+            {
+                let (mut parts, _) = response.into_parts();
+                parts.status = StatusCode::NOT_MODIFIED;
+                parts.headers.remove(CONTENT_LENGTH);
+                Response::from_parts(parts, Body::empty())
+            }
+            _ => response,
+        }
+    }
+}
+
+/// `max-age` and `s-maxage`, the latter wins as the gateway is a shared cache
+fn max_age(headers: &HeaderMap) -> Option<Duration> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for (name, value) in directives(headers) {
+        let value = value.and_then(|v| v.parse::<u64>().ok());
+        match name.as_str() {
+            "max-age" => max_age = value.or(max_age),
+            "s-maxage" => s_maxage = value.or(s_maxage),
+            _ => {}
+        }
+    }
+    s_maxage.or(max_age).map(Duration::from_secs)
+}
+
+/// `Cache-Control` directives, lower cased, with their value
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter(|directive| !directive.trim().is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_lowercase(), None),
+        })
+}
+
+/// weak comparison, as for `If-None-Match`
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let Ok(etag) = etag.to_str() else {
+        return false;
+    };
+    if_none_match
+        .to_str()
+        .map(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag))
+        })
+        .unwrap_or(false)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{Request, Response},
+    };
+    use hyper::{
+        header::{CACHE_CONTROL, CONTENT_LENGTH, ETAG, IF_NONE_MATCH},
+        StatusCode, Uri,
+    };
+
+    use super::{CacheContext, CacheLookup, ResponseCache};
+    use crate::{config::Cache, openid::User};
+
+    fn upstream(body: &str, cache_control: &str) -> Response<Body> {
+        Response::builder()
+            .header(CONTENT_LENGTH, body.len())
+            .header(CACHE_CONTROL, cache_control)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn user(id: &str, tenant: &str) -> User {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "roles": [],
+            "groups": [],
+            "tenant": tenant
+        }))
+        .unwrap()
+    }
+
+    fn lookup(cache: &Arc<ResponseCache>, req: &mut Request<Body>, tenant: &str) -> CacheLookup {
+        let uri: Uri = req.uri().clone();
+        CacheContext::lookup(cache, req, &uri, Some(&user("nb", tenant)))
+    }
+
+    async fn body(response: Response<Body>) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let cache = Arc::new(ResponseCache::new(Cache {
+            max_size: Some(1024),
+            authenticated: Some(true),
+            ..Default::default()
+        }));
+        let get = || {
+            Request::get("/template/find-all?page=1")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let CacheLookup::Forward(ctx) = lookup(&cache, &mut get(), "acme") else {
+            panic!("expected a miss");
+        };
+        let response = ctx.store(upstream("[1]", "max-age=30")).await;
+        assert_eq!("MISS", response.headers()["x-cache"]);
+        let etag = response.headers()[ETAG].clone();
+        assert!(etag.to_str().unwrap().starts_with("W/\""));
+        assert_eq!("[1]", body(response).await);
+
+        let CacheLookup::Hit(response) = lookup(&cache, &mut get(), "acme") else {
+            panic!("expected a hit");
+        };
+        assert_eq!("HIT", response.headers()["x-cache"]);
+        assert_eq!("[1]", body(response).await);
+
+        // the client already has it
+        let mut req = get();
+        req.headers_mut().insert(IF_NONE_MATCH, etag.clone());
+        let CacheLookup::Hit(response) = lookup(&cache, &mut req, "acme") else {
+            panic!("expected a hit");
+        };
+        assert_eq!(StatusCode::NOT_MODIFIED, response.status());
+
+        // each tenant and each user has its own entries
+        assert!(matches!(
+            lookup(&cache, &mut get(), "other"),
+            CacheLookup::Forward(_)
+        ));
+        let uri: Uri = get().uri().clone();
+        assert!(matches!(
+            CacheContext::lookup(&cache, &mut get(), &uri, Some(&user("other", "acme"))),
+            CacheLookup::Forward(_)
+        ));
+
+        // revalidated with the etag of the entry when the client asks for it
+        let mut req = get();
+        req.headers_mut()
+            .insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+        let CacheLookup::Forward(ctx) = lookup(&cache, &mut req, "acme") else {
+            panic!("expected a revalidation");
+        };
+        assert_eq!(etag, req.headers()[IF_NONE_MATCH]);
+        let not_modified = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+        let response = ctx.store(not_modified).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("REVALIDATED", response.headers()["x-cache"]);
+
+        // evicted during the revalidation, the client still gets the body
+        let mut req = get();
+        req.headers_mut()
+            .insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+        let CacheLookup::Forward(ctx) = lookup(&cache, &mut req, "acme") else {
+            panic!("expected a revalidation");
+        };
+        cache.invalidate(None, None);
+        let not_modified = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+        let response = ctx.store(not_modified).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("[1]", body(response).await);
+        assert!(matches!(
+            lookup(&cache, &mut get(), "acme"),
+            CacheLookup::Hit(_)
+        ));
+
+        // not cacheable
+        let uncached = || {
+            Request::get("/template/private")
+                .body(Body::empty())
+                .unwrap()
+        };
+        for cache_control in ["private", "no-store", "max-age=0"] {
+            let CacheLookup::Forward(ctx) = lookup(&cache, &mut uncached(), "acme") else {
+                panic!("expected a miss");
+            };
+            ctx.store(upstream("secret", cache_control)).await;
+            assert!(matches!(
+                lookup(&cache, &mut uncached(), "acme"),
+                CacheLookup::Forward(_)
+            ));
+        }
+        assert!(matches!(
+            lookup(
+                &cache,
+                &mut Request::get("/template/find-all?page=1")
+                    .header(CACHE_CONTROL, "no-store")
+                    .body(Body::empty())
+                    .unwrap(),
+                "acme"
+            ),
+            CacheLookup::Bypass
+        ));
+
+        assert_eq!(0, cache.invalidate(Some("acme"), Some("/invoice")));
+        assert_eq!(1, cache.invalidate(Some("acme"), Some("/template")));
+        assert!(matches!(
+            lookup(&cache, &mut get(), "acme"),
+            CacheLookup::Forward(_)
+        ));
+
+        // the least recently used entries are evicted past max_size
+        for page in 0..10 {
+            let mut req = Request::get(format!("/template/find-all?page={page}"))
+                .body(Body::empty())
+                .unwrap();
+            let CacheLookup::Forward(ctx) = lookup(&cache, &mut req, "acme") else {
+                panic!("expected a miss");
+            };
+            ctx.store(upstream(&"x".repeat(200), "max-age=30")).await;
+        }
+        assert!(cache.lru.lock().unwrap().size <= 1024);
+        assert!(matches!(
+            lookup(&cache, &mut get(), "acme"),
+            CacheLookup::Forward(_)
+        ));
+        let mut last = Request::get("/template/find-all?page=9")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matches!(
+            lookup(&cache, &mut last, "acme"),
+            CacheLookup::Hit(_)
+        ));
+
+        // the responses to a user are only cached when the route opts in
+        let cache = Arc::new(ResponseCache::new(Cache::default()));
+        assert!(matches!(
+            lookup(&cache, &mut get(), "acme"),
+            CacheLookup::Bypass
+        ));
+        assert!(matches!(
+            CacheContext::lookup(&cache, &mut get(), &uri, None),
+            CacheLookup::Forward(_)
+        ));
+    }
+}
@@ -1,4 +1,11 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{
+    error::Error,
+    fmt::Display,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -6,7 +13,9 @@ use serde::{Deserialize, Serialize};
 #[serde(rename_all = "snake_case")]
 pub struct Route {
     pub id: String,
-    pub uri: String,
+    pub uri: Option<String>,
+    pub upstreams: Option<Vec<Upstream>>,
+    pub load_balancer: Option<LoadBalancer>,
     pub predicates: Option<Vec<Predicate>>,
     pub filters: Option<Vec<Filter>>,
     pub authorizations: Option<Vec<Authorization>>,
@@ -14,10 +23,90 @@ pub struct Route {
 
 #[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
-pub struct Authorization {
-    pub method: String,
-    pub has_roles: Option<Vec<String>>,
-    pub has_groups: Option<Vec<String>>,
+pub struct Upstream {
+    pub uri: String,
+    pub weight: Option<usize>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct LoadBalancer {
+    pub strategy: Option<Strategy>,
+    pub passive_health: Option<PassiveHealth>,
+    pub health_check: Option<HealthCheck>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct PassiveHealth {
+    pub max_failures: usize,
+    pub eject_secs: u64,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval_secs: u64,
+    pub timeout_secs: Option<u64>,
+    pub expected_status: Option<Vec<u16>>,
+    pub healthy_threshold: Option<usize>,
+    pub unhealthy_threshold: Option<usize>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Authorization {
+    /// any of the roles, and all of the groups
+    Authorization {
+        method: String,
+        has_roles: Option<Vec<String>>,
+        has_groups: Option<Vec<String>>,
+    },
+    Rule(Rule),
+}
+
+/// Applies to the requests matching the methods and the path, and requires `allow` to be true
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct Rule {
+    /// default: every method
+    pub methods: Option<Vec<String>>,
+    /// ant pattern matched against the path before rewrite, its `{variables}` are the path parameters.
+    /// default: every path of the route
+    pub path: Option<String>,
+    pub allow: RuleExpr,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleExpr {
+    Any(Vec<RuleExpr>),
+    All(Vec<RuleExpr>),
+    /// none of the expressions, a list as yaml tags cannot be nested directly
+    Not(Vec<RuleExpr>),
+    Role(String),
+    Group(String),
+    /// the tenant of the user
+    Tenant(String),
+    /// any logged in user
+    Authenticated,
+    PathParam {
+        name: String,
+        regex: String,
+    },
+    /// the path parameter is the tenant of the user
+    PathParamIsTenant(String),
+    /// the path parameter is the id of the user
+    PathParamIsUser(String),
 }
 
 #[derive(Deserialize, PartialEq, Eq, Serialize, Debug)]
@@ -25,21 +114,205 @@ pub struct Authorization {
 pub struct Config {
     pub order: usize,
     pub routes: Vec<Route>,
+    /// OpenAPI documents of the upstreams, merged by the admin api
+    pub openapi: Option<Vec<OpenApiSource>>,
+}
+
+/// OpenAPI document of an upstream, whose paths are exposed by the gateway under `prefix`
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct OpenApiSource {
+    pub id: String,
+    /// e.g `http://invoice/openapi.json`
+    pub uri: String,
+    /// e.g `/invoice`, default to none
+    pub prefix: Option<String>,
 }
 
 #[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum Predicate {
     Host(String),
+    /// ant style pattern, e.g `/api/**`
     Path(String),
+    PathRegex(String),
     Method(String),
+    Header {
+        name: String,
+        regex: Option<String>,
+    },
+    Query {
+        name: String,
+        regex: Option<String>,
+    },
+    Cookie {
+        name: String,
+        regex: Option<String>,
+    },
+    /// CIDR ranges or ip addresses
+    RemoteAddr(Vec<String>),
+    /// routes of the same group are picked randomly, proportionally to their weight
+    Weight {
+        group: String,
+        weight: u32,
+    },
 }
 #[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum Filter {
-    RewritePath { source: String, dest: String },
-    AddRequestHeader { key: String, value: String },
+    RewritePath {
+        source: String,
+        dest: String,
+    },
+    AddRequestHeader {
+        key: String,
+        value: String,
+    },
     RemoveRequestHeader(String),
+    RateLimit(RateLimit),
+    AddResponseHeader {
+        key: String,
+        value: String,
+    },
+    /// replace the header if the upstream already set it
+    SetResponseHeader {
+        key: String,
+        value: String,
+    },
+    RemoveResponseHeader(String),
+    SecureHeaders(SecureHeaders),
+    Cors(Cors),
+    Compression(Compression),
+    /// keep the responses of the GET requests in memory
+    Cache(Cache),
+    /// override the status of the upstream response
+    SetStatus(u16),
+    /// answer with a redirect, without calling the upstream
+    RedirectTo {
+        status: u16,
+        url: String,
+    },
+}
+
+/// Security headers added to the response, unless the upstream already set them.
+/// A header left empty uses the default, an empty string disables it.
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case", default)]
+pub struct SecureHeaders {
+    pub strict_transport_security: Option<String>,
+    pub content_security_policy: Option<String>,
+    pub frame_options: Option<String>,
+    pub content_type_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permitted_cross_domain_policies: Option<String>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case", default)]
+pub struct Cors {
+    /// default to the comma separated `CORS_ALLOW_ORIGIN` env variable. `*` allows any origin
+    pub allowed_origins: Option<Vec<String>>,
+    /// default to GET, HEAD, POST
+    pub allowed_methods: Option<Vec<String>>,
+    /// default to the headers requested by the preflight
+    pub allowed_headers: Option<Vec<String>>,
+    pub exposed_headers: Option<Vec<String>>,
+    pub allow_credentials: Option<bool>,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case", default)]
+pub struct Compression {
+    /// default to true
+    pub gzip: Option<bool>,
+    /// default to true
+    pub br: Option<bool>,
+    /// responses smaller than this are sent as is, default to 32 bytes
+    pub min_size: Option<u16>,
+}
+
+/// Responses served from memory, for the routes whose responses only depend on the path,
+/// the query and the tenant
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case", default)]
+pub struct Cache {
+    /// how long a response is fresh when the upstream does not tell (`Cache-Control: max-age`), default to 60
+    pub ttl_secs: Option<u64>,
+    /// max size of the cached responses of the route, in bytes, default to 10MB
+    pub max_size: Option<u64>,
+    /// bigger responses are not cached, default to 1MB
+    pub max_entry_size: Option<u64>,
+    /// the query is part of the key, default to true
+    pub key_query: Option<bool>,
+    /// each tenant has its own entries, default to true. Only disable it for data shared by every tenant
+    pub key_tenant: Option<bool>,
+    /// each user has its own entries, default to true. Only disable it for data shared by the users of a tenant
+    pub key_user: Option<bool>,
+    /// the responses to the requests of a user are cached, default to false: they may be personal
+    pub authenticated: Option<bool>,
+    /// a POST, PUT, PATCH or DELETE through the route invalidates the entries of the tenant, default to true
+    pub invalidate_on_write: Option<bool>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub struct RateLimit {
+    pub key: RateLimitKey,
+    /// tokens added to the bucket every `period_secs`
+    pub replenish_rate: u64,
+    /// max tokens in the bucket, default to `replenish_rate`
+    pub burst_capacity: Option<u64>,
+    pub period_secs: Option<u64>,
+    /// tokens taken by a request, default to 1
+    pub requested_tokens: Option<u64>,
+    /// keep the buckets in redis, to share them between gateway instances
+    pub shared: Option<bool>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    User,
+    Tenant,
+    ClientIp,
+    Route,
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    msg: String,
+}
+
+impl ConfigError {
+    pub fn new(msg: impl Into<String>) -> Self {
+        ConfigError { msg: msg.into() }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Route {
+    /// upstreams of the route, `uri` being a shortcut for a single upstream
+    pub fn upstreams(&self) -> Vec<Upstream> {
+        let mut upstreams = self.upstreams.clone().unwrap_or_default();
+        if let Some(uri) = &self.uri {
+            upstreams.insert(
+                0,
+                Upstream {
+                    uri: uri.clone(),
+                    weight: None,
+                },
+            );
+        }
+        upstreams
+    }
 }
 
 impl Config {
@@ -52,34 +325,71 @@ impl Config {
     }
     pub fn merge(mut self, config: &mut Config) -> Self {
         self.routes.append(&mut config.routes);
+        if let Some(openapi) = &mut config.openapi {
+            self.openapi.get_or_insert_with(Vec::new).append(openapi);
+        }
         self
     }
-    pub fn from_file(config_path: &Path) -> Self {
-        let file = File::open(config_path).unwrap();
+    pub fn from_file(config_path: &Path) -> Result<Self, ConfigError> {
+        let file = File::open(config_path)
+            .map_err(|e| ConfigError::new(format!("could not open {config_path:?}: {e}")))?;
         let buf_reader = BufReader::new(file);
-        serde_yml::from_reader(buf_reader).unwrap()
+        serde_yml::from_reader(buf_reader)
+            .map_err(|e| ConfigError::new(format!("could not parse {config_path:?}: {e}")))
     }
 
-    pub fn from_dir(service_volume_path: &Path) -> Self {
+    /// yaml files of the config volume, sorted by name
+    pub fn config_files(service_volume_path: &Path) -> Result<Vec<PathBuf>, ConfigError> {
         if !service_volume_path.is_dir() {
-            panic!("{service_volume_path:?} not a directory!");
+            return Err(ConfigError::new(format!(
+                "{service_volume_path:?} not a directory!"
+            )));
         }
-        let mut configs: Vec<Config> = service_volume_path
+        let mut files: Vec<PathBuf> = service_volume_path
             .read_dir()
-            .expect("could not read config directory!")
+            .map_err(|e| ConfigError::new(format!("could not read config directory! {e}")))?
             .filter_map(|e| e.ok().map(|p| p.path()).filter(|p| p.is_file()))
+            .filter(|p| {
+                p.extension()
+                    .and_then(|ext| ext.to_str())
+                    .filter(|ext| matches!(*ext, "yml" | "yaml"))
+                    .is_some()
+            })
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /// cheap fingerprint of the config volume, to detect changes
+    pub fn fingerprint(service_volume_path: &Path) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+        Self::config_files(service_volume_path)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| {
+                let metadata = p.metadata().ok();
+                let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+                let len = metadata.map(|m| m.len()).unwrap_or_default();
+                (p, modified, len)
+            })
+            .collect()
+    }
+
+    pub fn from_dir(service_volume_path: &Path) -> Result<Self, ConfigError> {
+        let mut configs: Vec<Config> = Self::config_files(service_volume_path)?
+            .into_iter()
             .inspect(|p| tracing::debug!("loading config {p:?}"))
             .map(|p| Self::from_file(p.as_path()))
-            .collect();
+            .collect::<Result<_, _>>()?;
 
         configs.sort_by_key(|c| c.order);
 
         let config: Option<Config> = configs.into_iter().reduce(|acc, mut e| acc.merge(&mut e));
 
-        match config {
-            Some(config) => config,
-            None => panic!("Could not build config from volume!"),
-        }
+        config.ok_or_else(|| {
+            ConfigError::new(format!(
+                "Could not build config from volume {service_volume_path:?}!"
+            ))
+        })
     }
 
     #[allow(unused)]
@@ -93,7 +403,10 @@ impl Config {
 
 #[cfg(test)]
 mod test {
-    use crate::config::{Filter, Predicate, Route};
+    use crate::config::{
+        Cache, Compression, Cors, Filter, HealthCheck, LoadBalancer, PassiveHealth, Predicate,
+        RateLimit, RateLimitKey, Route, SecureHeaders, Strategy, Upstream,
+    };
 
     use super::Config;
 
@@ -134,7 +447,9 @@ mod test {
                 routes: vec![
                     Route {
                         id: "yahoo_finance_chart".into(),
-                        uri: "https://query1.finance.yahoo.com".into(),
+                        uri: Some("https://query1.finance.yahoo.com".into()),
+                        upstreams: None,
+                        load_balancer: None,
                         predicates: Some(vec![Predicate::Path(
                             "/proxy/yahoo-finance/chart/**".into()
                         )]),
@@ -146,7 +461,9 @@ mod test {
                     },
                     Route {
                         id: "auth".into(),
-                        uri: "http://auth.somehost.org:8080".into(),
+                        uri: Some("http://auth.somehost.org:8080".into()),
+                        upstreams: None,
+                        load_balancer: None,
                         predicates: Some(vec![Predicate::Host("auth.somehost.org".into())]),
                         filters: Some(vec![Filter::AddRequestHeader {
                             key: "X-Forwarded-Port".into(),
@@ -156,13 +473,216 @@ mod test {
                     },
                     Route {
                         id: "auth2".into(),
-                        uri: "http://auth2.somehost.org:8080".into(),
+                        uri: Some("http://auth2.somehost.org:8080".into()),
+                        upstreams: None,
+                        load_balancer: None,
                         predicates: Some(vec![Predicate::Host("auth2.somehost.org".into())]),
                         filters: Some(vec![Filter::RemoveRequestHeader("X-Forwarded-Port".into())]),
                         authorizations: None,
                     }
                 ],
+                openapi: None,
             }
         );
     }
+
+    #[test]
+    fn test_deserialize_predicates() {
+        let config = r#"
+         order: 0
+         routes:
+            - id: invoice_v2
+              uri: http://invoice-v2
+              predicates:
+              - !path /invoice/**
+              - !path_regex ^/invoice/.*$
+              - !header
+                 name: X-Api-Version
+                 regex: ^2
+              - !query
+                 name: version
+              - !cookie
+                 name: canary
+                 regex: "true"
+              - !remote_addr
+                 - 10.0.0.0/8
+                 - 192.168.1.10
+              - !weight
+                 group: invoice
+                 weight: 10
+        "#;
+
+        let config = Config::deserialize(config);
+        assert_eq!(
+            config.routes[0].predicates,
+            Some(vec![
+                Predicate::Path("/invoice/**".into()),
+                Predicate::PathRegex("^/invoice/.*$".into()),
+                Predicate::Header {
+                    name: "X-Api-Version".into(),
+                    regex: Some("^2".into())
+                },
+                Predicate::Query {
+                    name: "version".into(),
+                    regex: None
+                },
+                Predicate::Cookie {
+                    name: "canary".into(),
+                    regex: Some("true".into())
+                },
+                Predicate::RemoteAddr(vec!["10.0.0.0/8".into(), "192.168.1.10".into()]),
+                Predicate::Weight {
+                    group: "invoice".into(),
+                    weight: 10
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_deserialize_rate_limit() {
+        let config = r#"
+         order: 0
+         routes:
+            - id: template_render
+              uri: http://template
+              filters:
+              - !rate_limit
+                 key: client_ip
+                 replenish_rate: 10
+                 period_secs: 60
+                 shared: true
+        "#;
+
+        let config = Config::deserialize(config);
+        assert_eq!(
+            config.routes[0].filters,
+            Some(vec![Filter::RateLimit(RateLimit {
+                key: RateLimitKey::ClientIp,
+                replenish_rate: 10,
+                burst_capacity: None,
+                period_secs: Some(60),
+                requested_tokens: None,
+                shared: Some(true),
+            })])
+        );
+    }
+
+    #[test]
+    fn test_deserialize_response_filters() {
+        let config = r#"
+         order: 0
+         routes:
+            - id: template_render
+              uri: http://template
+              filters:
+              - !set_response_header
+                 key: Cache-Control
+                 value: no-store
+              - !remove_response_header Server
+              - !secure_headers
+                 frame_options: SAMEORIGIN
+                 content_security_policy: ""
+              - !cors
+                 allowed_origins:
+                 - https://app.somehost.org
+                 allow_credentials: true
+              - !compression {}
+              - !cache
+                 ttl_secs: 30
+                 key_query: false
+              - !redirect_to
+                 status: 308
+                 url: https://api.somehost.org
+        "#;
+
+        let config = Config::deserialize(config);
+        assert_eq!(
+            config.routes[0].filters,
+            Some(vec![
+                Filter::SetResponseHeader {
+                    key: "Cache-Control".into(),
+                    value: "no-store".into()
+                },
+                Filter::RemoveResponseHeader("Server".into()),
+                Filter::SecureHeaders(SecureHeaders {
+                    frame_options: Some("SAMEORIGIN".into()),
+                    content_security_policy: Some("".into()),
+                    ..Default::default()
+                }),
+                Filter::Cors(Cors {
+                    allowed_origins: Some(vec!["https://app.somehost.org".into()]),
+                    allow_credentials: Some(true),
+                    ..Default::default()
+                }),
+                Filter::Compression(Compression::default()),
+                Filter::Cache(Cache {
+                    ttl_secs: Some(30),
+                    key_query: Some(false),
+                    ..Default::default()
+                }),
+                Filter::RedirectTo {
+                    status: 308,
+                    url: "https://api.somehost.org".into()
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_deserialize_upstreams() {
+        let config = r#"
+         order: 0
+         routes:
+            - id: invoice
+              upstreams:
+                - uri: http://invoice-1
+                  weight: 2
+                - uri: http://invoice-2
+              load_balancer:
+                strategy: least_connections
+                passive_health:
+                  max_failures: 3
+                  eject_secs: 30
+                health_check:
+                  path: /find-all
+                  interval_secs: 10
+              predicates:
+              - !path /invoice/**
+        "#;
+
+        let config = Config::deserialize(config);
+        let route = &config.routes[0];
+        assert_eq!(
+            route.load_balancer,
+            Some(LoadBalancer {
+                strategy: Some(Strategy::LeastConnections),
+                passive_health: Some(PassiveHealth {
+                    max_failures: 3,
+                    eject_secs: 30
+                }),
+                health_check: Some(HealthCheck {
+                    path: "/find-all".into(),
+                    interval_secs: 10,
+                    timeout_secs: None,
+                    expected_status: None,
+                    healthy_threshold: None,
+                    unhealthy_threshold: None,
+                }),
+            })
+        );
+        assert_eq!(
+            route.upstreams(),
+            vec![
+                Upstream {
+                    uri: "http://invoice-1".into(),
+                    weight: Some(2)
+                },
+                Upstream {
+                    uri: "http://invoice-2".into(),
+                    weight: None
+                }
+            ]
+        );
+    }
 }
@@ -0,0 +1,52 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    config::{Config, ConfigError},
+    request_handler::{HandlerContext, RequestHandler, SharedRequestHandler},
+    Client,
+};
+
+/// Polls the config volume and recompiles the routes when a yaml file is added,
+/// removed or modified. Polling rather than inotify, because file events are not
+/// propagated reliably through docker volumes.
+/// If the new config is invalid, the current one is kept.
+pub fn spawn_config_watcher(
+    config_dir: PathBuf,
+    interval: Duration,
+    request_handler: SharedRequestHandler,
+    context: HandlerContext,
+    client: Client,
+) {
+    tokio::spawn(async move {
+        let mut fingerprint = Config::fingerprint(&config_dir);
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let new_fingerprint = Config::fingerprint(&config_dir);
+            if new_fingerprint == fingerprint {
+                continue;
+            }
+            fingerprint = new_fingerprint;
+            tracing::info!("config change detected in {config_dir:?}, reloading...");
+            match load(&config_dir, &context) {
+                Ok(new_request_handler) => {
+                    new_request_handler.spawn_health_checks(&client);
+                    request_handler.store(new_request_handler);
+                    tracing::info!("config reloaded");
+                }
+                Err(e) => {
+                    tracing::error!("could not reload config, keeping the current one: {e}");
+                }
+            }
+        }
+    });
+}
+
+pub fn load(config_dir: &Path, context: &HandlerContext) -> Result<RequestHandler, ConfigError> {
+    let config = Config::from_dir(config_dir)?;
+    RequestHandler::from_config(config, context)
+}
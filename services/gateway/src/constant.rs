@@ -1,6 +1,7 @@
 pub const REDIS_URL: &str = "SESSION_REDIS_URL";
 pub const COOKIE_NAME: &str = "SEQUEDA_SESSION";
 pub const AUTH_REDIRECT_PATH: &str = "/login/authorized";
+pub const USER_INFO_JWKS_PATH: &str = "/.well-known/jwks.json";
 
 pub const OPENID_CLIENT_ID: &str = "OPENID_CLIENT_ID";
 pub const OPENID_CLIENT_SECRET: &str = "OPENID_CLIENT_SECRET";
@@ -9,5 +10,21 @@ pub const OPENID_SCOPES: &str = "OPENID_SCOPES";
 pub const APP_ROOT_URL: &str = "APP_ROOT_URL";
 pub const OPENID_ENABLED: &str = "OPENID_ENABLED";
 pub const OPENID_DISABLE_SSL_ISSUER: &str = "OPENID_DISABLE_SSL_ISSUER";
+pub const RATE_LIMIT_REDIS_URL: &str = "RATE_LIMIT_REDIS_URL";
+pub const TRUST_FORWARDED_FOR: &str = "TRUST_FORWARDED_FOR";
+pub const SERVICE_CONFIG_RELOAD_INTERVAL: &str = "SERVICE_CONFIG_RELOAD_INTERVAL";
+pub const OPENID_BEARER_VALIDATION: &str = "OPENID_BEARER_VALIDATION";
+pub const OPENID_BEARER_AUDIENCE: &str = "OPENID_BEARER_AUDIENCE";
+pub const GATEWAY_ADMIN_ROLE: &str = "GATEWAY_ADMIN_ROLE";
+pub const X_API_KEY: &str = "X-API-KEY";
+pub const SESSION_STORE: &str = "SESSION_STORE";
+pub const SESSION_TTL: &str = "SESSION_TTL";
+pub const SESSION_SLIDING: &str = "SESSION_SLIDING";
+pub const SESSION_COOKIE_KEY: &str = "SESSION_COOKIE_KEY";
+pub const SESSION_COOKIE_SECURE: &str = "SESSION_COOKIE_SECURE";
+pub const SESSION_COOKIE_HTTP_ONLY: &str = "SESSION_COOKIE_HTTP_ONLY";
+pub const SESSION_COOKIE_SAME_SITE: &str = "SESSION_COOKIE_SAME_SITE";
+pub const DEMO_ACCOUNT: &str = "DEMO_ACCOUNT";
+pub const DEMO_CONFIG: &str = "DEMO_CONFIG";
 
 pub use sequeda_service_common::{SERVICE_CONFIG_VOLUME, SERVICE_HOST, SERVICE_PORT};
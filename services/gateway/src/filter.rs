@@ -0,0 +1,661 @@
+use std::{convert::Infallible, error::Error, str::FromStr, sync::Arc};
+
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderName, HeaderValue, Request, Response},
+};
+use hyper::{
+    header::{
+        ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+        CONTENT_SECURITY_POLICY, LOCATION, ORIGIN, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+        VARY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    },
+    Method, StatusCode,
+};
+use regex::Regex;
+use tower::{Layer, ServiceExt};
+use tower_http::compression::{
+    predicate::{NotForContentType, Predicate, SizeAbove},
+    CompressionLayer,
+};
+
+use crate::{
+    cache::ResponseCache,
+    config::{Compression, ConfigError, Cors, Filter, SecureHeaders},
+    rate_limiter::RateLimiter,
+    request_handler::HandlerContext,
+};
+
+const DEFAULT_CORS_METHODS: [&str; 3] = ["GET", "HEAD", "POST"];
+const DEFAULT_COMPRESSION_MIN_SIZE: u16 = 32;
+
+// same defaults as spring cloud gateway
+const DEFAULT_STRICT_TRANSPORT_SECURITY: &str = "max-age=631138519";
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'self' https:; font-src 'self' https: data:; img-src 'self' https: data:; object-src 'none'; script-src https:; style-src 'self' https: 'unsafe-inline'";
+const DEFAULT_FRAME_OPTIONS: &str = "DENY";
+const DEFAULT_CONTENT_TYPE_OPTIONS: &str = "nosniff";
+const DEFAULT_REFERRER_POLICY: &str = "no-referrer";
+const DEFAULT_PERMITTED_CROSS_DOMAIN_POLICIES: &str = "none";
+
+#[derive(Debug)]
+pub enum CompiledFilter {
+    RewritePath {
+        source: Regex,
+        dest: String,
+    },
+    AddRequestHeader {
+        key: HeaderName,
+        value: HeaderValue,
+    },
+    RemoveRequestHeader(HeaderName),
+    RateLimit(RateLimiter),
+    AddResponseHeader {
+        key: HeaderName,
+        value: HeaderValue,
+    },
+    SetResponseHeader {
+        key: HeaderName,
+        value: HeaderValue,
+    },
+    RemoveResponseHeader(HeaderName),
+    SecureHeaders(Vec<(HeaderName, HeaderValue)>),
+    Cors(CompiledCors),
+    Compression(CompiledCompression),
+    Cache(Arc<ResponseCache>),
+    SetStatus(StatusCode),
+    RedirectTo {
+        status: StatusCode,
+        url: HeaderValue,
+    },
+}
+
+impl CompiledFilter {
+    pub fn compile(
+        route_id: &str,
+        filter: Filter,
+        context: &HandlerContext,
+    ) -> Result<Self, ConfigError> {
+        let invalid = |what: &str, value: &str, e: &dyn Error| {
+            ConfigError::new(format!("route {route_id}: invalid {what} `{value}`: {e}"))
+        };
+        let header_name =
+            |name: &str| HeaderName::from_str(name).map_err(|e| invalid("header name", name, &e));
+        let header_value = |value: &str| {
+            HeaderValue::from_str(value).map_err(|e| invalid("header value", value, &e))
+        };
+        let status = |status: u16| {
+            StatusCode::from_u16(status).map_err(|e| invalid("status", &status.to_string(), &e))
+        };
+        let filter = match filter {
+            Filter::RewritePath { source, dest } => CompiledFilter::RewritePath {
+                source: Regex::new(&source)
+                    .map_err(|e| invalid("rewrite_path source", &source, &e))?,
+                dest,
+            },
+            Filter::AddRequestHeader { key, value } => CompiledFilter::AddRequestHeader {
+                key: header_name(&key)?,
+                value: header_value(&value)?,
+            },
+            Filter::RemoveRequestHeader(header) => {
+                CompiledFilter::RemoveRequestHeader(header_name(&header)?)
+            }
+            Filter::RateLimit(rate_limit) => CompiledFilter::RateLimit(
+                RateLimiter::new(route_id, rate_limit, context.redis.clone())
+                    .map_err(|e| ConfigError::new(format!("route {route_id}: {e}")))?,
+            ),
+            Filter::AddResponseHeader { key, value } => CompiledFilter::AddResponseHeader {
+                key: header_name(&key)?,
+                value: header_value(&value)?,
+            },
+            Filter::SetResponseHeader { key, value } => CompiledFilter::SetResponseHeader {
+                key: header_name(&key)?,
+                value: header_value(&value)?,
+            },
+            Filter::RemoveResponseHeader(header) => {
+                CompiledFilter::RemoveResponseHeader(header_name(&header)?)
+            }
+            Filter::SecureHeaders(secure_headers) => {
+                let SecureHeaders {
+                    strict_transport_security,
+                    content_security_policy,
+                    frame_options,
+                    content_type_options,
+                    referrer_policy,
+                    permitted_cross_domain_policies,
+                } = secure_headers;
+                let headers = [
+                    (
+                        STRICT_TRANSPORT_SECURITY,
+                        strict_transport_security,
+                        DEFAULT_STRICT_TRANSPORT_SECURITY,
+                    ),
+                    (
+                        CONTENT_SECURITY_POLICY,
+                        content_security_policy,
+                        DEFAULT_CONTENT_SECURITY_POLICY,
+                    ),
+                    (X_FRAME_OPTIONS, frame_options, DEFAULT_FRAME_OPTIONS),
+                    (
+                        X_CONTENT_TYPE_OPTIONS,
+                        content_type_options,
+                        DEFAULT_CONTENT_TYPE_OPTIONS,
+                    ),
+                    (REFERRER_POLICY, referrer_policy, DEFAULT_REFERRER_POLICY),
+                    (
+                        HeaderName::from_static("x-permitted-cross-domain-policies"),
+                        permitted_cross_domain_policies,
+                        DEFAULT_PERMITTED_CROSS_DOMAIN_POLICIES,
+                    ),
+                ];
+                let mut compiled = vec![];
+                for (name, value, default) in headers {
+                    let value = value.unwrap_or_else(|| default.to_string());
+                    if !value.is_empty() {
+                        compiled.push((name, header_value(&value)?));
+                    }
+                }
+                CompiledFilter::SecureHeaders(compiled)
+            }
+            Filter::Cors(cors) => CompiledFilter::Cors(CompiledCors::compile(
+                cors,
+                context.cors_allow_origin.as_deref(),
+                &header_value,
+            )?),
+            Filter::Compression(Compression { gzip, br, min_size }) => {
+                CompiledFilter::Compression(CompiledCompression {
+                    gzip: gzip.unwrap_or(true),
+                    br: br.unwrap_or(true),
+                    min_size: min_size.unwrap_or(DEFAULT_COMPRESSION_MIN_SIZE),
+                })
+            }
+            Filter::Cache(cache) => CompiledFilter::Cache(Arc::new(ResponseCache::new(cache))),
+            Filter::SetStatus(code) => CompiledFilter::SetStatus(status(code)?),
+            Filter::RedirectTo { status: code, url } => {
+                let status = status(code)?;
+                if !status.is_redirection() {
+                    return Err(ConfigError::new(format!(
+                        "route {route_id}: redirect_to status must be 3xx, got {code}"
+                    )));
+                }
+                CompiledFilter::RedirectTo {
+                    status,
+                    url: header_value(&url)?,
+                }
+            }
+        };
+        Ok(filter)
+    }
+}
+
+/// What the response filters need to know about the original request
+#[derive(Debug, Default, Clone)]
+pub struct ResponseContext {
+    origin: Option<HeaderValue>,
+    accept_encoding: Option<HeaderValue>,
+}
+
+impl ResponseContext {
+    pub fn new(req: &Request<Body>) -> Self {
+        ResponseContext {
+            origin: req.headers().get(ORIGIN).cloned(),
+            accept_encoding: req.headers().get(ACCEPT_ENCODING).cloned(),
+        }
+    }
+}
+
+/// Apply the header filters (cors, secure headers...) to a response of the gateway itself,
+/// e.g. an error. The status and the body are kept as is
+pub fn filter_gateway_response(
+    filters: &[CompiledFilter],
+    ctx: &ResponseContext,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    for filter in filters {
+        filter_headers(filter, ctx, response.headers_mut());
+    }
+    response
+}
+
+fn filter_headers(filter: &CompiledFilter, ctx: &ResponseContext, headers: &mut HeaderMap) {
+    match filter {
+        CompiledFilter::AddResponseHeader { key, value } => {
+            headers.append(key, value.clone());
+        }
+        CompiledFilter::SetResponseHeader { key, value } => {
+            headers.insert(key, value.clone());
+        }
+        CompiledFilter::RemoveResponseHeader(key) => {
+            headers.remove(key);
+        }
+        CompiledFilter::SecureHeaders(secure_headers) => {
+            for (key, value) in secure_headers {
+                if !headers.contains_key(key) {
+                    headers.insert(key, value.clone());
+                }
+            }
+        }
+        CompiledFilter::Cors(cors) => cors.apply(ctx.origin.as_ref(), headers),
+        _ => {}
+    }
+}
+
+/// Apply the response side filters, in the order of the config.
+/// Compression is applied last, once the headers are final.
+pub async fn filter_response(
+    filters: &[CompiledFilter],
+    ctx: &ResponseContext,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    let mut compression = None;
+    for filter in filters {
+        match filter {
+            CompiledFilter::SetStatus(status) => *response.status_mut() = *status,
+            CompiledFilter::Compression(c) => compression = Some(c),
+            _ => filter_headers(filter, ctx, response.headers_mut()),
+        }
+    }
+    match compression {
+        Some(compression) => {
+            compression
+                .compress(ctx.accept_encoding.as_ref(), response)
+                .await
+        }
+        None => response,
+    }
+}
+
+/// Answer the request directly, without calling the upstream (redirect, CORS preflight)
+pub fn short_circuit(filters: &[CompiledFilter], req: &Request<Body>) -> Option<Response<Body>> {
+    filters.iter().find_map(|filter| match filter {
+        CompiledFilter::RedirectTo { status, url } => Some(
+            Response::builder()
+                .status(*status)
+                .header(LOCATION, url.clone())
+                .body(Body::empty())
+                .unwrap(),
+        ),
+        CompiledFilter::Cors(cors) if is_preflight(req) => Some(cors.preflight(req)),
+        _ => None,
+    })
+}
+
+pub fn is_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(ORIGIN)
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+#[derive(Debug)]
+pub struct CompiledCors {
+    /// `None` allows any origin
+    allowed_origins: Option<Vec<HeaderValue>>,
+    allowed_methods: Vec<Method>,
+    allowed_headers: Option<HeaderValue>,
+    exposed_headers: Option<HeaderValue>,
+    allow_credentials: bool,
+    max_age_secs: Option<u64>,
+}
+
+impl CompiledCors {
+    fn compile(
+        cors: Cors,
+        default_origins: Option<&str>,
+        header_value: &dyn Fn(&str) -> Result<HeaderValue, ConfigError>,
+    ) -> Result<Self, ConfigError> {
+        let Cors {
+            allowed_origins,
+            allowed_methods,
+            allowed_headers,
+            exposed_headers,
+            allow_credentials,
+            max_age_secs,
+        } = cors;
+        let allowed_origins = allowed_origins.unwrap_or_else(|| {
+            default_origins
+                .map(|origins| {
+                    origins
+                        .split(',')
+                        .map(|o| o.trim().to_string())
+                        .filter(|o| !o.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default()
+        });
+        let allow_credentials = allow_credentials.unwrap_or(false);
+        let allowed_origins = if allowed_origins.iter().any(|o| o == "*") {
+            // any website could send requests with the session cookie
+            if allow_credentials {
+                return Err(ConfigError::new(
+                    "cors: allow_credentials cannot be used with the `*` origin",
+                ));
+            }
+            None
+        } else {
+            Some(
+                allowed_origins
+                    .iter()
+                    .map(|o| header_value(o))
+                    .collect::<Result<_, _>>()?,
+            )
+        };
+        let allowed_methods = allowed_methods
+            .unwrap_or_else(|| DEFAULT_CORS_METHODS.map(String::from).to_vec())
+            .iter()
+            .map(|m| {
+                Method::from_str(&m.to_uppercase())
+                    .map_err(|e| ConfigError::new(format!("invalid cors method `{m}`: {e}")))
+            })
+            .collect::<Result<_, _>>()?;
+        let join = |values: Option<Vec<String>>| {
+            values
+                .filter(|v| !v.is_empty())
+                .map(|v| header_value(&v.join(", ")))
+                .transpose()
+        };
+        Ok(CompiledCors {
+            allowed_origins,
+            allowed_methods,
+            allowed_headers: join(allowed_headers)?,
+            exposed_headers: join(exposed_headers)?,
+            allow_credentials,
+            max_age_secs,
+        })
+    }
+
+    /// the value of `Access-Control-Allow-Origin`, if the origin is allowed
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        match &self.allowed_origins {
+            None => Some(HeaderValue::from_static("*")),
+            Some(origins) => origins.contains(origin).then(|| origin.clone()),
+        }
+    }
+
+    fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+        let Some(allow_origin) = origin.and_then(|o| self.allow_origin(o)) else {
+            return;
+        };
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if let Some(exposed_headers) = &self.exposed_headers {
+            headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, exposed_headers.clone());
+        }
+    }
+
+    fn preflight(&self, req: &Request<Body>) -> Response<Body> {
+        let origin = req.headers().get(ORIGIN);
+        let method_allowed = req
+            .headers()
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|m| Method::from_bytes(m.as_bytes()).ok())
+            .map(|m| self.allowed_methods.contains(&m))
+            .unwrap_or(false);
+        let allow_origin = origin.and_then(|o| self.allow_origin(o));
+        let (Some(allow_origin), true) = (allow_origin, method_allowed) else {
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header(VARY, "Origin")
+                .body(Body::from("Invalid CORS request"))
+                .unwrap();
+        };
+        let methods = self
+            .allowed_methods
+            .iter()
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(
+                VARY,
+                "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+            )
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        // without an explicit list, allow the headers asked by the browser
+        let allowed_headers = self
+            .allowed_headers
+            .as_ref()
+            .or_else(|| req.headers().get(ACCESS_CONTROL_REQUEST_HEADERS));
+        if let Some(allowed_headers) = allowed_headers {
+            response = response.header(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers.clone());
+        }
+        if self.allow_credentials {
+            response = response.header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        if let Some(max_age) = self.max_age_secs {
+            response = response.header(ACCESS_CONTROL_MAX_AGE, max_age);
+        }
+        response.body(Body::empty()).unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct CompiledCompression {
+    gzip: bool,
+    br: bool,
+    min_size: u16,
+}
+
+impl CompiledCompression {
+    /// compress the response with the best encoding accepted by the client.
+    /// Responses already encoded, images, grpc and server sent events are left as is.
+    async fn compress(
+        &self,
+        accept_encoding: Option<&HeaderValue>,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        let mut req = Request::new(());
+        if let Some(accept_encoding) = accept_encoding {
+            req.headers_mut()
+                .insert(ACCEPT_ENCODING, accept_encoding.clone());
+        }
+        let layer = CompressionLayer::new()
+            .gzip(self.gzip)
+            .br(self.br)
+            .compress_when(
+                SizeAbove::new(self.min_size)
+                    .and(NotForContentType::GRPC)
+                    .and(NotForContentType::IMAGES)
+                    .and(NotForContentType::SSE),
+            );
+        let mut response = Some(response);
+        let service = layer.layer(tower::service_fn(move |_: Request<()>| {
+            let response = response.take().expect("called once");
+            async move { Ok::<_, Infallible>(response) }
+        }));
+        match service.oneshot(req).await {
+            Ok(response) => response.map(Body::new),
+            Err(e) => match e {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        body::Body,
+        http::{HeaderValue, Request, Response},
+    };
+    use hyper::{
+        header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_ENCODING, CONTENT_SECURITY_POLICY, SERVER,
+            STRICT_TRANSPORT_SECURITY, X_FRAME_OPTIONS,
+        },
+        StatusCode,
+    };
+
+    use crate::{config::Config, request_handler::HandlerContext};
+
+    use super::{
+        filter_gateway_response, filter_response, short_circuit, CompiledFilter, ResponseContext,
+    };
+
+    fn compile(yaml: &str, context: &HandlerContext) -> Vec<CompiledFilter> {
+        let config = Config::deserialize(yaml);
+        config.routes[0]
+            .filters
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|f| CompiledFilter::compile("template", f, context).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_response_headers() {
+        let filters = compile(
+            r#"
+         order: 0
+         routes:
+            - id: template
+              uri: http://template
+              filters:
+              - !remove_response_header Server
+              - !set_response_header
+                 key: X-Frame-Options
+                 value: SAMEORIGIN
+              - !secure_headers
+                 content_security_policy: ""
+              - !set_status 202
+        "#,
+            &Default::default(),
+        );
+        let response = Response::builder()
+            .header(SERVER, "nginx")
+            .body(Body::empty())
+            .unwrap();
+        let response = filter_response(&filters, &Default::default(), response).await;
+        assert_eq!(StatusCode::ACCEPTED, response.status());
+        let headers = response.headers();
+        assert!(!headers.contains_key(SERVER));
+        assert!(!headers.contains_key(CONTENT_SECURITY_POLICY));
+        assert_eq!("SAMEORIGIN", headers[X_FRAME_OPTIONS]);
+        assert_eq!("max-age=631138519", headers[STRICT_TRANSPORT_SECURITY]);
+
+        // an error of the gateway keeps its status
+        let response = Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .body(Body::empty())
+            .unwrap();
+        let response = filter_gateway_response(&filters, &Default::default(), response);
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("SAMEORIGIN", response.headers()[X_FRAME_OPTIONS]);
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let filters = compile(
+            r#"
+         order: 0
+         routes:
+            - id: template
+              uri: http://template
+              filters:
+              - !cors
+                 allowed_methods: [GET, POST]
+                 allow_credentials: true
+        "#,
+            &HandlerContext {
+                cors_allow_origin: Some("https://app.somehost.org, https://somehost.org".into()),
+                ..Default::default()
+            },
+        );
+        let preflight = |origin: &str, method: &str| {
+            Request::options("/template/render")
+                .header("Origin", origin)
+                .header("Access-Control-Request-Method", method)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = short_circuit(&filters, &preflight("https://somehost.org", "POST")).unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        assert_eq!(
+            "https://somehost.org",
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        assert_eq!(
+            "GET, POST",
+            response.headers()[ACCESS_CONTROL_ALLOW_METHODS]
+        );
+
+        let response = short_circuit(&filters, &preflight("https://evil.org", "POST")).unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+        let response =
+            short_circuit(&filters, &preflight("https://somehost.org", "DELETE")).unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        let req = Request::get("/template/find-all")
+            .header("Origin", "https://app.somehost.org")
+            .body(Body::empty())
+            .unwrap();
+        assert!(short_circuit(&filters, &req).is_none());
+        let response = filter_response(
+            &filters,
+            &ResponseContext::new(&req),
+            Response::new(Body::empty()),
+        )
+        .await;
+        assert_eq!(
+            "https://app.somehost.org",
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN]
+        );
+        assert_eq!("true", response.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS]);
+
+        let config = Config::deserialize(
+            r#"
+         order: 0
+         routes:
+            - id: template
+              uri: http://template
+              filters:
+              - !cors
+                 allowed_origins: ["*"]
+                 allow_credentials: true
+        "#,
+        );
+        let filter = config.routes[0].filters.clone().unwrap().remove(0);
+        assert!(CompiledFilter::compile("template", filter, &Default::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let filters = compile(
+            r#"
+         order: 0
+         routes:
+            - id: template
+              uri: http://template
+              filters:
+              - !compression
+                 br: false
+        "#,
+            &Default::default(),
+        );
+        let req = Request::get("/template/find-all")
+            .header("Accept-Encoding", "br, gzip")
+            .body(Body::empty())
+            .unwrap();
+        let response = Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from("[]".repeat(100)))
+            .unwrap();
+        let response = filter_response(&filters, &ResponseContext::new(&req), response).await;
+        assert_eq!(
+            Some(&HeaderValue::from_static("gzip")),
+            response.headers().get(CONTENT_ENCODING)
+        );
+
+        let response = Response::new(Body::from("[]"));
+        let response = filter_response(&filters, &ResponseContext::new(&req), response).await;
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+    }
+}
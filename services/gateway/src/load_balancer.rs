@@ -0,0 +1,383 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::body::Body;
+use hyper::{header::HOST, Request, Uri};
+
+use crate::{
+    config::{HealthCheck, LoadBalancer, PassiveHealth, Strategy, Upstream},
+    Client,
+};
+
+const DEFAULT_HEALTH_CHECK_TIMEOUT_SECS: u64 = 2;
+const DEFAULT_HEALTH_CHECK_THRESHOLD: usize = 1;
+
+/// A single upstream target of a route, along with its runtime health state.
+#[derive(Debug)]
+pub struct UpstreamTarget {
+    pub uri: String,
+    pub host: hyper::header::HeaderValue,
+    weight: usize,
+    active_connections: AtomicUsize,
+    consecutive_failures: AtomicUsize,
+    ejected_until: Mutex<Option<Instant>>,
+    healthy: AtomicBool,
+    check_successes: AtomicUsize,
+    check_failures: AtomicUsize,
+}
+
+impl UpstreamTarget {
+    fn new(uri: String, weight: usize) -> Result<Self, String> {
+        let host = {
+            let iri = Uri::try_from(&uri).map_err(|e| format!("invalid uri {uri}: {e}"))?;
+            let host = iri
+                .host()
+                .ok_or_else(|| format!("missing host in uri {uri}"))?;
+            hyper::header::HeaderValue::from_str(host)
+                .map_err(|e| format!("invalid host in uri {uri}: {e}"))?
+        };
+        Ok(UpstreamTarget {
+            uri,
+            host,
+            weight: weight.max(1),
+            active_connections: AtomicUsize::new(0),
+            consecutive_failures: AtomicUsize::new(0),
+            ejected_until: Mutex::new(None),
+            healthy: AtomicBool::new(true),
+            check_successes: AtomicUsize::new(0),
+            check_failures: AtomicUsize::new(0),
+        })
+    }
+
+    fn is_available(&self) -> bool {
+        if !self.healthy.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if until > Instant::now() => false,
+            Some(_) => {
+                // ejection period is over, give it another chance
+                *ejected_until = None;
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+/// Set of upstream targets behind a route, with the selection strategy
+/// and health checking configured for it.
+#[derive(Debug)]
+pub struct UpstreamPool {
+    route_id: String,
+    targets: Vec<Arc<UpstreamTarget>>,
+    strategy: Strategy,
+    passive_health: Option<PassiveHealth>,
+    health_check: Option<HealthCheck>,
+    schedule: Vec<usize>,
+    cursor: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(
+        route_id: &str,
+        upstreams: Vec<Upstream>,
+        load_balancer: Option<LoadBalancer>,
+    ) -> Result<Self, String> {
+        if upstreams.is_empty() {
+            return Err(format!("route {route_id} has no upstream"));
+        }
+        let targets = upstreams
+            .into_iter()
+            .map(|Upstream { uri, weight }| {
+                UpstreamTarget::new(uri, weight.unwrap_or(1)).map(Arc::new)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let LoadBalancer {
+            strategy,
+            passive_health,
+            health_check,
+        } = load_balancer.unwrap_or_default();
+        let schedule = Self::weighted_schedule(&targets);
+        Ok(UpstreamPool {
+            route_id: route_id.to_string(),
+            targets,
+            strategy: strategy.unwrap_or_default(),
+            passive_health,
+            health_check,
+            schedule,
+            cursor: AtomicUsize::new(0),
+        })
+    }
+
+    /// smooth weighted round robin (same as nginx), so that a target with
+    /// a heavy weight doesn't receive all its requests in a row.
+    fn weighted_schedule(targets: &[Arc<UpstreamTarget>]) -> Vec<usize> {
+        let total: usize = targets.iter().map(|t| t.weight).sum();
+        let mut current = vec![0isize; targets.len()];
+        let mut schedule = Vec::with_capacity(total);
+        for _ in 0..total {
+            for (i, t) in targets.iter().enumerate() {
+                current[i] += t.weight as isize;
+            }
+            let (best, _) = current
+                .iter()
+                .enumerate()
+                .max_by_key(|(i, w)| (**w, std::cmp::Reverse(*i)))
+                .expect("at least one target");
+            current[best] -= total as isize;
+            schedule.push(best);
+        }
+        schedule
+    }
+
+    pub fn select(&self) -> Option<UpstreamGuard> {
+        let target = match self.strategy {
+            Strategy::RoundRobin => {
+                let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+                (0..self.schedule.len())
+                    .map(|i| &self.targets[self.schedule[(start + i) % self.schedule.len()]])
+                    .find(|t| t.is_available())
+            }
+            Strategy::LeastConnections => self
+                .targets
+                .iter()
+                .filter(|t| t.is_available())
+                .min_by_key(|t| t.active_connections.load(Ordering::Relaxed) * 1000 / t.weight),
+        }?;
+        target.active_connections.fetch_add(1, Ordering::Relaxed);
+        Some(UpstreamGuard {
+            target: target.clone(),
+            passive_health: self.passive_health.clone(),
+        })
+    }
+
+    pub fn spawn_health_check(self: &Arc<Self>, client: Client) {
+        let Some(health_check) = self.health_check.clone() else {
+            return;
+        };
+        let pool = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(health_check.interval_secs.max(1)));
+            loop {
+                interval.tick().await;
+                // stop as soon as the route is gone (e.g. after a config reload)
+                let Some(pool) = Weak::upgrade(&pool) else {
+                    break;
+                };
+                for target in &pool.targets {
+                    let healthy = check_target(&client, target, &health_check).await;
+                    pool.record_health_check(target, healthy, &health_check);
+                }
+            }
+        });
+    }
+
+    fn record_health_check(&self, target: &UpstreamTarget, success: bool, hc: &HealthCheck) {
+        let (counter, reset, threshold) = if success {
+            (
+                &target.check_successes,
+                &target.check_failures,
+                hc.healthy_threshold,
+            )
+        } else {
+            (
+                &target.check_failures,
+                &target.check_successes,
+                hc.unhealthy_threshold,
+            )
+        };
+        reset.store(0, Ordering::Relaxed);
+        let count = counter.fetch_add(1, Ordering::Relaxed) + 1;
+        let threshold = threshold.unwrap_or(DEFAULT_HEALTH_CHECK_THRESHOLD);
+        if count >= threshold && target.healthy.swap(success, Ordering::Relaxed) != success {
+            if success {
+                tracing::info!(
+                    "route {}: upstream {} is back up",
+                    self.route_id,
+                    target.uri
+                );
+            } else {
+                tracing::warn!("route {}: upstream {} is down", self.route_id, target.uri);
+            }
+        }
+    }
+}
+
+async fn check_target(client: &Client, target: &UpstreamTarget, hc: &HealthCheck) -> bool {
+    let Ok(req) = Request::get(format!("{}{}", target.uri, hc.path))
+        .header(HOST, target.host.clone())
+        .body(Body::empty())
+    else {
+        return false;
+    };
+    let timeout = Duration::from_secs(hc.timeout_secs.unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT_SECS));
+    match tokio::time::timeout(timeout, client.request(req)).await {
+        Ok(Ok(resp)) => match &hc.expected_status {
+            Some(expected) => expected.contains(&resp.status().as_u16()),
+            None => !resp.status().is_server_error(),
+        },
+        Ok(Err(e)) => {
+            tracing::debug!("health check {} failed: {e}", target.uri);
+            false
+        }
+        Err(_) => {
+            tracing::debug!("health check {} timed out", target.uri);
+            false
+        }
+    }
+}
+
+/// Target selected for a request. Keeps track of the active connections
+/// for the least connections strategy, until dropped.
+#[derive(Debug)]
+pub struct UpstreamGuard {
+    target: Arc<UpstreamTarget>,
+    passive_health: Option<PassiveHealth>,
+}
+
+impl UpstreamGuard {
+    pub fn uri(&self) -> &str {
+        &self.target.uri
+    }
+
+    pub fn host(&self) -> &hyper::header::HeaderValue {
+        &self.target.host
+    }
+
+    /// passive health check: eject the target after too many consecutive failures
+    pub fn report(&self, success: bool) {
+        let Some(passive_health) = &self.passive_health else {
+            return;
+        };
+        if success {
+            self.target.consecutive_failures.store(0, Ordering::Relaxed);
+            return;
+        }
+        let failures = self
+            .target
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if failures >= passive_health.max_failures {
+            tracing::warn!(
+                "upstream {} ejected for {}s after {failures} failures",
+                self.target.uri,
+                passive_health.eject_secs
+            );
+            *self.target.ejected_until.lock().unwrap() =
+                Some(Instant::now() + Duration::from_secs(passive_health.eject_secs));
+        }
+    }
+}
+
+impl Drop for UpstreamGuard {
+    fn drop(&mut self) {
+        self.target
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{LoadBalancer, PassiveHealth, Strategy, Upstream};
+
+    use super::UpstreamPool;
+
+    fn upstream(uri: &str, weight: usize) -> Upstream {
+        Upstream {
+            uri: uri.into(),
+            weight: Some(weight),
+        }
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let pool = UpstreamPool::new(
+            "invoice",
+            vec![
+                upstream("http://invoice-1", 2),
+                upstream("http://invoice-2", 1),
+            ],
+            None,
+        )
+        .unwrap();
+        let picked: Vec<String> = (0..6)
+            .map(|_| pool.select().unwrap().uri().to_string())
+            .collect();
+        assert_eq!(
+            picked,
+            vec![
+                "http://invoice-1",
+                "http://invoice-2",
+                "http://invoice-1",
+                "http://invoice-1",
+                "http://invoice-2",
+                "http://invoice-1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let pool = UpstreamPool::new(
+            "template",
+            vec![
+                upstream("http://template-1", 1),
+                upstream("http://template-2", 1),
+            ],
+            Some(LoadBalancer {
+                strategy: Some(Strategy::LeastConnections),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        let first = pool.select().unwrap();
+        let second = pool.select().unwrap();
+        assert_ne!(first.uri(), second.uri());
+        drop(first);
+        let third = pool.select().unwrap();
+        assert_ne!(third.uri(), second.uri());
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let pool = UpstreamPool::new(
+            "template",
+            vec![
+                upstream("http://template-1", 1),
+                upstream("http://template-2", 1),
+            ],
+            Some(LoadBalancer {
+                passive_health: Some(PassiveHealth {
+                    max_failures: 2,
+                    eject_secs: 60,
+                }),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        for _ in 0..2 {
+            let guard = pool.select().unwrap();
+            if guard.uri() == "http://template-1" {
+                guard.report(false);
+            }
+            let guard = pool.select().unwrap();
+            if guard.uri() == "http://template-1" {
+                guard.report(false);
+            }
+        }
+        for _ in 0..4 {
+            assert_eq!("http://template-2", pool.select().unwrap().uri());
+        }
+    }
+}
@@ -1,52 +1,77 @@
+mod access_log;
+mod admin;
+mod authorization;
+mod cache;
 mod config;
+mod config_watcher;
 mod constant;
+mod filter;
+mod load_balancer;
+mod openapi;
 mod openid;
+mod predicate;
+mod rate_limiter;
+mod redis_connection;
 mod request_handler;
-use async_redis_session_v2::RedisSessionStore;
+mod upgrade;
 use axum::{
     body::Body,
     extract::{Extension, State},
     http::{Request, Response},
     response::IntoResponse,
-    Router,
+    routing::get,
+    Json, Router,
 };
 use axum_extra::headers::ContentType;
 pub use constant::{OPENID_ENABLED, SERVICE_CONFIG_VOLUME, SERVICE_HOST, SERVICE_PORT};
-use http_body_util::Empty;
 use hyper::{
-    header::{CONTENT_TYPE, LOCATION},
-    StatusCode,
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    Method, StatusCode,
 };
 use hyper_tls::HttpsConnector;
 
-use openid::User;
-use sequeda_service_common::setup_tracing;
+use openid::{LoginPageRedirect, User};
+use sequeda_service_common::{
+    setup_tracing, user_token::jwks_from_dir, user_token::UserTokenSigner, CORS_ALLOW_ORIGIN,
+    USER_INFO_PUBLIC_KEYS, USER_INFO_SIGNING_KEY,
+};
 use std::{
     env::{self, var},
     net::SocketAddr,
     path::PathBuf,
     str::FromStr,
     sync::Arc,
+    time::Duration,
 };
 
 use crate::{
-    config::Config,
-    constant::{APP_ROOT_URL, AUTH_REDIRECT_PATH, REDIS_URL},
-    openid::{open_id_router, AuthConfig, OpenIdClient},
-    request_handler::RequestHandler,
+    access_log::{access_log, AccessLogEntry, MatchedRoute},
+    admin::admin_router,
+    constant::{
+        APP_ROOT_URL, AUTH_REDIRECT_PATH, RATE_LIMIT_REDIS_URL, REDIS_URL,
+        SERVICE_CONFIG_RELOAD_INTERVAL, TRUST_FORWARDED_FOR, USER_INFO_JWKS_PATH,
+    },
+    openid::{
+        api_key_router, open_id_router, session_cookie, ApiKeyStore, AuthConfig, BearerValidator,
+        DemoAccount, DemoSession, MachineClient, OpenIdClient, RevokedSessions, SessionBackend,
+        SessionConfig, UserCache,
+    },
+    redis_connection::RedisConnection,
+    request_handler::{
+        Handled, HandlerContext, RequestHandler, RouteFilters, SharedRequestHandler,
+    },
+    upgrade::{is_websocket_upgrade, proxy_upgrade, remove_hop_by_hop_headers},
 };
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
 
 type Client = hyper_util::client::legacy::Client<HttpsConnector<HttpConnector>, axum::body::Body>;
 
-const DEMO_ACCOUNT: &str = "DEMO_ACCOUNT";
+/// how long a back-channel logout is remembered, when the sessions have no expiry
+const SESSION_REVOCATION_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
 
 #[tokio::main]
 async fn main() {
     setup_tracing();
-    let demo_account = var(DEMO_ACCOUNT)
-        .map(|s| s.parse::<bool>().unwrap_or(false))
-        .unwrap_or(false);
     let host = var(SERVICE_HOST).unwrap_or_else(|_| String::from("127.0.0.1"));
     let port = var(SERVICE_PORT).unwrap_or_else(|_| String::from("0"));
     let openid_enabled = var(OPENID_ENABLED)
@@ -57,104 +82,325 @@ async fn main() {
 
     let path_dir = PathBuf::new().join(&config_volume);
 
-    let config: Config = Config::from_dir(path_dir.as_path());
-    let request_handler: RequestHandler = RequestHandler::from_config(config);
+    let user_token_signer = match UserTokenSigner::from_env() {
+        Ok(Some(signer)) => Some(Arc::new(signer)),
+        Ok(None) => {
+            tracing::warn!("{USER_INFO_SIGNING_KEY} is not set, the user info will not be signed");
+            None
+        }
+        Err(e) => {
+            tracing::error!("could not load the user info signing key: {e}");
+            std::process::exit(1);
+        }
+    };
+    let handler_context = HandlerContext {
+        redis: var(RATE_LIMIT_REDIS_URL)
+            .or_else(|_| var(REDIS_URL))
+            .ok()
+            .and_then(|url| match RedisConnection::new(&url) {
+                Ok(redis) => Some(redis),
+                Err(e) => {
+                    tracing::error!("invalid redis url for rate limiting: {e}");
+                    None
+                }
+            }),
+        cors_allow_origin: var(CORS_ALLOW_ORIGIN).ok(),
+        // `true` for a single proxy, or the number of proxies
+        trusted_proxies: var(TRUST_FORWARDED_FOR)
+            .ok()
+            .and_then(|trust| match trust.parse::<bool>() {
+                Ok(trust) => Some(trust as usize),
+                Err(_) => trust.parse::<usize>().ok(),
+            })
+            .unwrap_or(0),
+        user_token_signer,
+    };
+    let request_handler: RequestHandler = match config_watcher::load(&path_dir, &handler_context) {
+        Ok(request_handler) => request_handler,
+        Err(e) => {
+            tracing::error!("could not load config: {e}");
+            std::process::exit(1);
+        }
+    };
+    let trusted_proxies = handler_context.trusted_proxies;
+    let reload_interval = var(SERVICE_CONFIG_RELOAD_INTERVAL)
+        .ok()
+        .and_then(|interval| interval.parse::<u64>().ok())
+        .unwrap_or(5);
 
     let https = HttpsConnector::new();
     let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
         .build::<_, axum::body::Body>(https);
+    request_handler.spawn_health_checks(&client);
+    let request_handler = SharedRequestHandler::new(request_handler);
+    if reload_interval > 0 {
+        config_watcher::spawn_config_watcher(
+            path_dir,
+            Duration::from_secs(reload_interval),
+            request_handler.clone(),
+            handler_context,
+            client.clone(),
+        );
+    }
     let mut app = Router::new()
+        .route(USER_INFO_JWKS_PATH, get(jwks))
         .fallback(handler)
-        .with_state(client)
-        .layer(Extension(Arc::new(request_handler)));
+        .with_state(client.clone())
+        .layer(Extension(request_handler.clone()));
 
     if openid_enabled {
-        let redis_url = env::var(REDIS_URL)
-            .expect("Missing the REDIS_URL environment variable. e.g `redis://127.0.0.1`");
-
         let root_url =
             env::var(APP_ROOT_URL).expect("Missing the APP_ROOT_URL environment variable.");
 
         let redirect_url = root_url.clone() + AUTH_REDIRECT_PATH;
 
-        let store = RedisSessionStore::new(redis_url).unwrap();
-        let openid_client = OpenIdClient::new().await;
-        let auth_redirect = format!("{AUTH_REDIRECT_PATH}/:nonce");
+        let store = match SessionBackend::from_env() {
+            Ok(store) => store,
+            Err(e) => {
+                tracing::error!("could not create the session store: {e}");
+                std::process::exit(1);
+            }
+        };
+        let session_config = match SessionConfig::from_env() {
+            Ok(session_config) => session_config,
+            Err(e) => {
+                tracing::error!("invalid session config: {e}");
+                std::process::exit(1);
+            }
+        };
+        let demo_account = match DemoAccount::from_env().await {
+            Ok(demo_account) => demo_account,
+            Err(e) => {
+                tracing::error!("invalid demo account: {e}");
+                std::process::exit(1);
+            }
+        };
+        if let Some(demo_account) = &demo_account {
+            tracing::info!("demo account enabled, {:?}", demo_account.policy());
+            demo_account.spawn_reset();
+        }
+        let openid_client = OpenIdClient::new().await.with_redirect_url(&redirect_url);
         let auth_config = AuthConfig {
-            auth_redirect,
+            auth_redirect: AUTH_REDIRECT_PATH.to_string(),
             redirect_url: redirect_url.to_string(),
             root_url: root_url.to_string(),
             demo_account,
+            trusted_proxies,
         };
+        let bearer_validator = BearerValidator::new(&openid_client);
         let openid_router =
             open_id_router(auth_config.clone(), store.clone(), openid_client.clone()).await;
-        app = openid_router
-            .merge(app)
+        app = openid_router.merge(app).merge(
+            admin_router()
+                .layer(Extension(request_handler))
+                .layer(Extension(client)),
+        );
+        // api keys and revoked sessions are stored in redis, even when the sessions are not
+        let redis = match env::var(REDIS_URL).map(|redis_url| RedisConnection::new(&redis_url)) {
+            Ok(Ok(redis)) => Some(redis),
+            Ok(Err(e)) => {
+                tracing::error!("invalid {REDIS_URL}: {e}");
+                None
+            }
+            Err(_) => None,
+        };
+        match &redis {
+            Some(redis) => {
+                app = app
+                    .merge(api_key_router())
+                    .layer(Extension(ApiKeyStore::new(redis.clone())));
+            }
+            None => tracing::warn!("no redis, api keys are disabled"),
+        }
+        let revoked_sessions =
+            RevokedSessions::new(redis, session_config.ttl.unwrap_or(SESSION_REVOCATION_TTL));
+        app = app
+            .layer(Extension(bearer_validator))
+            .layer(Extension(revoked_sessions))
+            .layer(Extension(UserCache::default()))
             .layer(Extension(store))
             .layer(Extension(openid_client))
-            .layer(Extension(auth_config));
+            .layer(Extension(auth_config))
+            .layer(axum::middleware::from_fn_with_state(
+                session_config,
+                session_cookie,
+            ));
     }
+    let app = app.layer(axum::middleware::from_fn(access_log));
     let addr = SocketAddr::from_str(&format!("{host}:{port}")).unwrap();
 
     tracing::info!("proxy gateway listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
 
-    axum::serve(listener, app.into_make_service())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+/// public keys used by the services to verify `X-USER-INFO`
+async fn jwks() -> impl IntoResponse {
+    let Ok(dir) = var(USER_INFO_PUBLIC_KEYS) else {
+        return (StatusCode::NOT_FOUND, Json(serde_json::json!({"keys": []})));
+    };
+    match jwks_from_dir(&PathBuf::from(dir)) {
+        Ok(jwks) => (StatusCode::OK, Json(jwks)),
+        Err(e) => {
+            tracing::error!("could not read the user info public keys: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"error": "could not read the public keys"})),
+            )
+        }
+    }
 }
 
 async fn handler(
     State(client): State<Client>,
-    Extension(request_handler): Extension<Arc<RequestHandler>>,
+    Extension(request_handler): Extension<SharedRequestHandler>,
     user: Option<User>,
     mut req: Request<Body>,
 ) -> impl IntoResponse {
+    let request_handler = request_handler.load();
     tracing::debug!("req: {req:?}");
+    let machine_client = req.extensions().get::<MachineClient>().is_some();
+    // a browser navigating to a protected page goes through the login, and comes back
+    let return_to = (!machine_client && req.method() == Method::GET)
+        .then(|| req.uri().path_and_query().map(|p| p.to_string()))
+        .flatten();
     let handle_forbidden = |status: StatusCode| {
         tracing::error!("unauthorized access: {:?}", &status);
+        if status == StatusCode::UNAUTHORIZED && return_to.is_some() {
+            return LoginPageRedirect::default()
+                .return_to(return_to.clone())
+                .into_response();
+        }
         Response::builder()
-            .status(StatusCode::PERMANENT_REDIRECT)
-            .header(LOCATION, "/logout")
-            .body(Empty::new())
+            .status(status)
+            .header(CONTENT_TYPE, ContentType::json().to_string())
+            .body(Body::from(format!(
+                r#"{{"error": "{}"}}"#,
+                status.canonical_reason().unwrap_or_default()
+            )))
             .unwrap()
-            .into_response()
     };
-    match request_handler.handle(&mut req, user).await {
-        Ok(_) => match client.request(req).await {
+    let mut access_log_entry = AccessLogEntry {
+        user: user.as_ref().map(|user| user.id.clone()),
+        tenant: user.as_ref().and_then(|user| user.tenant.clone()),
+        ..Default::default()
+    };
+    if let Some(demo_session) = req.extensions().get::<DemoSession>() {
+        if !demo_session.allows(req.method()) {
+            let mut response = Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header(CONTENT_TYPE, ContentType::json().to_string())
+                .body(Body::from(r#"{"error": "the demo account is read-only"}"#))
+                .unwrap();
+            if let Some(route_filters) = request_handler.route_filters(&req) {
+                response = route_filters.filter_gateway_response(response);
+            }
+            response.extensions_mut().insert(access_log_entry);
+            return response;
+        }
+    }
+    let handled = request_handler.handle(&mut req, user).await;
+    access_log_entry.route_id = req
+        .extensions()
+        .get::<MatchedRoute>()
+        .map(|MatchedRoute(id)| id.clone());
+    if let Ok(Handled::Proxy(proxy)) = &handled {
+        access_log_entry.upstream = Some(proxy.upstream.uri().to_string());
+    }
+    // the responses of the gateway get the cors and security headers of the route too
+    let route_filters = req.extensions().get::<RouteFilters>().cloned();
+    let gateway_response = |response: Response<Body>| match &route_filters {
+        Some(route_filters) => route_filters.filter_gateway_response(response),
+        None => response,
+    };
+    let mut response = match handled {
+        Ok(Handled::Respond(response)) => response,
+        Ok(Handled::Proxy(proxy)) => match forward(&client, req).await {
             Ok(response)
                 if response.status() == StatusCode::UNAUTHORIZED
                     || response.status() == StatusCode::FORBIDDEN =>
             {
-                handle_forbidden(response.status())
+                proxy.upstream.report(true);
+                gateway_response(handle_forbidden(response.status()))
+            }
+            // the connection now belongs to the websocket, the filters do not apply
+            Ok(response) if response.status() == StatusCode::SWITCHING_PROTOCOLS => {
+                proxy.upstream.report(true);
+                response
+            }
+            Ok(mut response) => {
+                proxy.upstream.report(!response.status().is_server_error());
+                remove_hop_by_hop_headers(response.headers_mut());
+                proxy.filter_response(response).await
             }
-
-            Ok(response) => response.into_response(),
             Err(er) => {
+                proxy.upstream.report(false);
                 tracing::debug!("error in request {er}");
-                Response::builder()
-                    .status(500)
-                    .header(CONTENT_TYPE, ContentType::json().to_string())
-                    .body(Body::from(format!(
-                        r#"{{"error": "unexpected error: {er}"}}"#
-                    )))
-                    .unwrap()
+                gateway_response(
+                    Response::builder()
+                        .status(500)
+                        .header(CONTENT_TYPE, ContentType::json().to_string())
+                        .body(Body::from(format!(
+                            r#"{{"error": "unexpected error: {er}"}}"#
+                        )))
+                        .unwrap(),
+                )
             }
         },
         Err(e)
             if e.status == Some(StatusCode::FORBIDDEN)
                 || e.status == Some(StatusCode::UNAUTHORIZED) =>
         {
-            handle_forbidden(e.status.unwrap())
+            gateway_response(handle_forbidden(e.status.unwrap()))
         }
 
-        Err(e) => Response::builder()
-            .status(e.status.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
-            .header(CONTENT_TYPE, ContentType::json().to_string())
-            .body(Body::from(format!(
-                r#"{{"error": "unexpected error: {e}"}}"#
-            )))
-            .unwrap(),
+        Err(e) if e.status == Some(StatusCode::TOO_MANY_REQUESTS) => gateway_response(
+            Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(CONTENT_TYPE, ContentType::json().to_string())
+                .header(
+                    RETRY_AFTER,
+                    e.retry_after
+                        .map(|d| d.as_secs_f64().ceil() as u64)
+                        .unwrap_or(1),
+                )
+                .body(Body::from(format!(r#"{{"error": "{e}"}}"#)))
+                .unwrap(),
+        ),
+
+        Err(e) => gateway_response(
+            Response::builder()
+                .status(e.status.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+                .header(CONTENT_TYPE, ContentType::json().to_string())
+                .body(Body::from(format!(
+                    r#"{{"error": "unexpected error: {e}"}}"#
+                )))
+                .unwrap(),
+        ),
+    };
+    response.extensions_mut().insert(access_log_entry);
+    response
+}
+
+/// sends the request to the upstream. The bodies are streamed in both directions,
+/// and a websocket upgrade is piped once accepted by the upstream
+async fn forward(
+    client: &Client,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper_util::client::legacy::Error> {
+    if is_websocket_upgrade(req.headers()) {
+        proxy_upgrade(client, req).await
+    } else {
+        client
+            .request(req)
+            .await
+            .map(|response| response.map(Body::new))
     }
 }
 
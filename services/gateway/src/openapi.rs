@@ -0,0 +1,264 @@
+use std::{error::Error, fmt::Display, time::Duration};
+
+use axum::body::Body;
+use hyper::{header::ACCEPT, Request};
+use serde_json::{json, Map, Value};
+use tokio::task::JoinSet;
+
+use crate::{config::OpenApiSource, Client};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_DOCUMENT_SIZE: usize = 5 * 1024 * 1024;
+const OPERATIONS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+/// referenced by name from the security requirements, not by `$ref`
+const SECURITY_SCHEMES: &str = "securitySchemes";
+
+#[derive(Debug)]
+pub struct OpenApiError {
+    msg: String,
+}
+
+impl Error for OpenApiError {}
+
+impl Display for OpenApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+/// Fetches the documents of the upstreams, and merges them in a single spec.
+/// An upstream that cannot be reached is listed in `x-unavailable`
+pub async fn aggregate(client: &Client, sources: &[OpenApiSource]) -> Value {
+    let mut fetches = JoinSet::new();
+    for (index, source) in sources.iter().enumerate() {
+        let client = client.clone();
+        let uri = source.uri.clone();
+        fetches.spawn(async move { (index, fetch(&client, &uri).await) });
+    }
+    let mut documents: Vec<Option<Result<Value, OpenApiError>>> =
+        sources.iter().map(|_| None).collect();
+    while let Some(fetched) = fetches.join_next().await {
+        match fetched {
+            Ok((index, document)) => documents[index] = Some(document),
+            Err(e) => tracing::error!("could not fetch an openapi document: {e}"),
+        }
+    }
+    merge(
+        sources
+            .iter()
+            .zip(documents)
+            .map(|(source, document)| {
+                let document = document.unwrap_or_else(|| {
+                    Err(OpenApiError {
+                        msg: "fetch aborted".into(),
+                    })
+                });
+                (source, document)
+            })
+            .collect(),
+    )
+}
+
+async fn fetch(client: &Client, uri: &str) -> Result<Value, OpenApiError> {
+    let error = |e: &dyn Display| OpenApiError {
+        msg: format!("could not fetch {uri}: {e}"),
+    };
+    let req = Request::get(uri)
+        .header(ACCEPT, "application/json")
+        .body(Body::empty())
+        .map_err(|e| error(&e))?;
+    let response = tokio::time::timeout(FETCH_TIMEOUT, client.request(req))
+        .await
+        .map_err(|e| error(&e))?
+        .map_err(|e| error(&e))?;
+    if !response.status().is_success() {
+        return Err(error(&response.status()));
+    }
+    let body = axum::body::to_bytes(Body::new(response.into_body()), MAX_DOCUMENT_SIZE)
+        .await
+        .map_err(|e| error(&e))?;
+    serde_json::from_slice(&body).map_err(|e| error(&e))
+}
+
+/// The paths of each document are prefixed like on the gateway, and its components
+/// renamed `<source id>_<name>`, so that two services can both define a `Person` schema.
+/// Operations without tags are tagged with the source id
+fn merge(documents: Vec<(&OpenApiSource, Result<Value, OpenApiError>)>) -> Value {
+    let mut paths = Map::new();
+    let mut components: Map<String, Value> = Map::new();
+    let mut tags: Vec<Value> = vec![];
+    let mut unavailable = vec![];
+    for (source, document) in documents {
+        let mut document = match document {
+            Ok(document) => document,
+            Err(e) => {
+                tracing::warn!("openapi {}: {e}", source.id);
+                unavailable.push(json!({"id": source.id, "error": e.to_string()}));
+                continue;
+            }
+        };
+        rename_refs(&mut document, &source.id);
+        if let Some(Value::Object(source_components)) = document.get("components") {
+            for (kind, entries) in source_components {
+                let Value::Object(entries) = entries else {
+                    continue;
+                };
+                let merged = components
+                    .entry(kind.clone())
+                    .or_insert_with(|| json!({}))
+                    .as_object_mut()
+                    .expect("components are objects");
+                for (name, component) in entries {
+                    if kind == SECURITY_SCHEMES {
+                        merged
+                            .entry(name.clone())
+                            .or_insert_with(|| component.clone());
+                    } else {
+                        merged.insert(format!("{}_{name}", source.id), component.clone());
+                    }
+                }
+            }
+        }
+        if let Some(Value::Array(source_tags)) = document.get("tags") {
+            for tag in source_tags {
+                if !tags.contains(tag) {
+                    tags.push(tag.clone());
+                }
+            }
+        }
+        let security = document.get("security").cloned();
+        let prefix = source
+            .prefix
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches('/');
+        let Some(Value::Object(source_paths)) = document.get_mut("paths") else {
+            continue;
+        };
+        for (path, mut item) in std::mem::take(source_paths) {
+            for operation in OPERATIONS {
+                let Some(Value::Object(operation)) = item.get_mut(operation) else {
+                    continue;
+                };
+                operation
+                    .entry("tags")
+                    .or_insert_with(|| json!([source.id]));
+                if let Some(security) = &security {
+                    operation
+                        .entry("security")
+                        .or_insert_with(|| security.clone());
+                }
+            }
+            let path = format!("{prefix}{path}");
+            if paths.contains_key(&path) {
+                tracing::warn!("openapi {}: path {path} already defined", source.id);
+                continue;
+            }
+            paths.insert(path, item);
+        }
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "sequeda",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": components,
+        "tags": tags,
+        "x-unavailable": unavailable,
+    })
+}
+
+/// `#/components/schemas/Person` => `#/components/schemas/<source id>_Person`
+fn rename_refs(value: &mut Value, source_id: &str) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value {
+                    Value::String(reference) if key == "$ref" => {
+                        if let Some((kind, name)) = reference
+                            .strip_prefix("#/components/")
+                            .and_then(|r| r.split_once('/'))
+                        {
+                            *reference = format!("#/components/{kind}/{source_id}_{name}");
+                        }
+                    }
+                    _ => rename_refs(value, source_id),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| rename_refs(v, source_id)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{merge, OpenApiError};
+    use crate::config::OpenApiSource;
+
+    fn source(id: &str, prefix: &str) -> OpenApiSource {
+        OpenApiSource {
+            id: id.into(),
+            uri: format!("http://{id}/openapi.json"),
+            prefix: Some(prefix.into()),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let person = json!({
+            "openapi": "3.0.3",
+            "security": [{"bearer": []}],
+            "paths": {
+                "/find-one/{id}": {
+                    "get": {
+                        "responses": {"200": {"content": {"application/json": {
+                            "schema": {"$ref": "#/components/schemas/Person"}
+                        }}}}
+                    }
+                }
+            },
+            "components": {
+                "schemas": {"Person": {"type": "object"}},
+                "securitySchemes": {"bearer": {"type": "http", "scheme": "bearer"}}
+            }
+        });
+        let customer = json!({
+            "paths": {"/": {"post": {"tags": ["customers"]}}},
+            "components": {"schemas": {"Person": {"type": "string"}}}
+        });
+        let merged = merge(vec![
+            (&source("person", "/person/"), Ok(person)),
+            (&source("customer", "/customer"), Ok(customer)),
+            (
+                &source("invoice", "/invoice"),
+                Err(OpenApiError {
+                    msg: "connection refused".into(),
+                }),
+            ),
+        ]);
+
+        let find_one = &merged["paths"]["/person/find-one/{id}"]["get"];
+        assert_eq!(
+            "#/components/schemas/person_Person",
+            find_one["responses"]["200"]["content"]["application/json"]["schema"]["$ref"]
+        );
+        assert_eq!(json!(["person"]), find_one["tags"]);
+        assert_eq!(json!([{"bearer": []}]), find_one["security"]);
+        assert_eq!(
+            json!(["customers"]),
+            merged["paths"]["/customer/"]["post"]["tags"]
+        );
+        let schemas = &merged["components"]["schemas"];
+        assert_eq!("object", schemas["person_Person"]["type"]);
+        assert_eq!("string", schemas["customer_Person"]["type"]);
+        assert!(merged["components"]["securitySchemes"]["bearer"].is_object());
+        assert_eq!("invoice", merged["x-unavailable"][0]["id"]);
+    }
+}
@@ -0,0 +1,334 @@
+use std::env::var;
+
+use axum::{
+    extract::Path,
+    response::IntoResponse,
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use base64::Engine;
+use hyper::StatusCode;
+use rand::Rng;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use super::{client::ClientError, User};
+use crate::{constant::GATEWAY_ADMIN_ROLE, redis_connection::RedisConnection};
+
+pub const API_KEY_PREFIX: &str = "sqd_";
+const API_KEY_USER_PREFIX: &str = "api-key:";
+const DEFAULT_ADMIN_ROLE: &str = "admin";
+const NO_TENANT: &str = "-";
+
+/// Api key of a machine client. The secret is only known by the client,
+/// the gateway keeps its hash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub tenant: Option<String>,
+    /// roles granted to the client
+    pub scopes: Vec<String>,
+    pub created_by: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredApiKey {
+    #[serde(flatten)]
+    api_key: ApiKey,
+    hash: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<u64>,
+}
+
+impl ApiKey {
+    fn into_user(self) -> User {
+        User {
+            id: format!("{API_KEY_USER_PREFIX}{}", self.id),
+            full_name: None,
+            given_name: None,
+            family_name: None,
+            middle_name: None,
+            username: Some(self.name),
+            email: None,
+            roles: self.scopes,
+            groups: vec![],
+            tenant: self.tenant,
+        }
+    }
+}
+
+/// Api keys, stored in redis
+#[derive(Clone, Debug)]
+pub struct ApiKeyStore {
+    redis: RedisConnection,
+}
+
+impl ApiKeyStore {
+    pub fn new(redis: RedisConnection) -> Self {
+        ApiKeyStore { redis }
+    }
+
+    fn key(id: &str) -> String {
+        format!("sequeda:api-key:{id}")
+    }
+
+    fn tenant_key(tenant: Option<&str>) -> String {
+        format!("sequeda:api-keys:{}", tenant.unwrap_or(NO_TENANT))
+    }
+
+    /// returns the api key and its secret, which cannot be retrieved afterward
+    pub async fn create(
+        &self,
+        admin: &User,
+        new_api_key: NewApiKey,
+    ) -> Result<(ApiKey, String), ClientError> {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let secret = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(rand::thread_rng().gen::<[u8; 32]>());
+        let now = jsonwebtoken::get_current_timestamp();
+        let api_key = ApiKey {
+            id: id.clone(),
+            name: new_api_key.name,
+            tenant: admin.tenant.clone(),
+            scopes: new_api_key.scopes,
+            created_by: admin.id.clone(),
+            created_at: now,
+            expires_at: new_api_key.expires_in_days.map(|d| now + d * 24 * 3600),
+        };
+        let stored = StoredApiKey {
+            api_key: api_key.clone(),
+            hash: hash(&secret),
+        };
+        let value = serde_json::to_string(&stored).map_err(|e| ClientError(e.to_string()))?;
+        let mut conn = self.connection().await?;
+        let key = Self::key(&id);
+        match api_key.expires_at {
+            Some(expires_at) => conn.set_ex::<_, _, ()>(&key, value, expires_at - now).await,
+            None => conn.set::<_, _, ()>(&key, value).await,
+        }
+        .map_err(redis_error)?;
+        conn.sadd::<_, _, ()>(Self::tenant_key(api_key.tenant.as_deref()), &id)
+            .await
+            .map_err(redis_error)?;
+        Ok((api_key, format!("{API_KEY_PREFIX}{id}_{secret}")))
+    }
+
+    pub async fn list(&self, tenant: Option<&str>) -> Result<Vec<ApiKey>, ClientError> {
+        let mut conn = self.connection().await?;
+        let ids: Vec<String> = conn
+            .smembers(Self::tenant_key(tenant))
+            .await
+            .map_err(redis_error)?;
+        let mut api_keys = vec![];
+        for id in ids {
+            match self.get(&id).await? {
+                Some(StoredApiKey { api_key, .. }) => api_keys.push(api_key),
+                // expired
+                None => conn
+                    .srem::<_, _, ()>(Self::tenant_key(tenant), &id)
+                    .await
+                    .map_err(redis_error)?,
+            }
+        }
+        api_keys.sort_by_key(|k| k.created_at);
+        Ok(api_keys)
+    }
+
+    /// returns false if the key doesn't exist in the tenant
+    pub async fn revoke(&self, tenant: Option<&str>, id: &str) -> Result<bool, ClientError> {
+        match self.get(id).await? {
+            Some(StoredApiKey { api_key, .. }) if api_key.tenant.as_deref() == tenant => {
+                let mut conn = self.connection().await?;
+                conn.del::<_, ()>(Self::key(id))
+                    .await
+                    .map_err(redis_error)?;
+                conn.srem::<_, _, ()>(Self::tenant_key(tenant), id)
+                    .await
+                    .map_err(redis_error)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub async fn authenticate(&self, key: &str) -> Result<User, ClientError> {
+        let (id, secret) = parse_key(key).ok_or_else(|| ClientError("malformed api key".into()))?;
+        let stored = self
+            .get(id)
+            .await?
+            .ok_or_else(|| ClientError(format!("unknown api key {id}")))?;
+        if ring::constant_time::verify_slices_are_equal(
+            stored.hash.as_bytes(),
+            hash(secret).as_bytes(),
+        )
+        .is_err()
+        {
+            return Err(ClientError(format!("invalid secret for api key {id}")));
+        }
+        if matches!(stored.api_key.expires_at, Some(exp) if exp <= jsonwebtoken::get_current_timestamp())
+        {
+            return Err(ClientError(format!("api key {id} expired")));
+        }
+        Ok(stored.api_key.into_user())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<StoredApiKey>, ClientError> {
+        let mut conn = self.connection().await?;
+        let value: Option<String> = conn.get(Self::key(id)).await.map_err(redis_error)?;
+        value
+            .map(|v| serde_json::from_str(&v).map_err(|e| ClientError(e.to_string())))
+            .transpose()
+    }
+
+    async fn connection(&self) -> Result<redis::aio::MultiplexedConnection, ClientError> {
+        match self.redis.get().await {
+            Ok(conn) => Ok(conn),
+            Err(e) => {
+                self.redis.reset().await;
+                Err(redis_error(e))
+            }
+        }
+    }
+}
+
+fn redis_error(e: redis::RedisError) -> ClientError {
+    ClientError(format!("redis error: {e}"))
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// `sqd_<id>_<secret>`
+fn parse_key(key: &str) -> Option<(&str, &str)> {
+    key.strip_prefix(API_KEY_PREFIX)?
+        .split_once('_')
+        .filter(|(id, secret)| !id.is_empty() && !secret.is_empty())
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(API_KEY_PREFIX)
+}
+
+pub fn api_key_router() -> Router {
+    Router::new()
+        .route("/admin/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/admin/api-keys/:id", delete(revoke_api_key))
+}
+
+/// only a user with the admin role can manage the api keys, not an api key
+pub fn check_admin(user: &User) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let admin_role = var(GATEWAY_ADMIN_ROLE).unwrap_or_else(|_| DEFAULT_ADMIN_ROLE.into());
+    if user.id.starts_with(API_KEY_USER_PREFIX) || !user.roles.contains(&admin_role) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({"error": "Forbidden access"})),
+        ));
+    }
+    Ok(())
+}
+
+fn internal_error(e: ClientError) -> (StatusCode, Json<serde_json::Value>) {
+    tracing::error!("{e}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({"error": "unexpected error"})),
+    )
+}
+
+async fn list_api_keys(
+    Extension(store): Extension<ApiKeyStore>,
+    user: User,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    check_admin(&user)?;
+    let api_keys = store
+        .list(user.tenant.as_deref())
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(api_keys))
+}
+
+async fn create_api_key(
+    Extension(store): Extension<ApiKeyStore>,
+    user: User,
+    Json(new_api_key): Json<NewApiKey>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    check_admin(&user)?;
+    // an admin cannot grant more than what it has
+    if let Some(scope) = new_api_key.scopes.iter().find(|s| !user.roles.contains(s)) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": format!("scope {scope} not granted to the current user")})),
+        ));
+    }
+    let (api_key, key) = store
+        .create(&user, new_api_key)
+        .await
+        .map_err(internal_error)?;
+    tracing::info!("api key {} created by {}", api_key.id, user.id);
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({"api_key": api_key, "key": key})),
+    ))
+}
+
+async fn revoke_api_key(
+    Extension(store): Extension<ApiKeyStore>,
+    user: User,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    check_admin(&user)?;
+    match store.revoke(user.tenant.as_deref(), &id).await {
+        Ok(true) => {
+            tracing::info!("api key {id} revoked by {}", user.id);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Ok(false) => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("api key {id} not found")})),
+        )),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash, parse_key, ApiKey};
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            Some(("4f2c", "a_b-c")),
+            parse_key("sqd_4f2c_a_b-c"),
+            "the secret can contain an underscore"
+        );
+        assert_eq!(None, parse_key("sqd_4f2c"));
+        assert_eq!(None, parse_key("sqd__secret"));
+        assert_eq!(None, parse_key("eyJhbGciOiJSUzI1NiJ9.e30.sig"));
+        assert_ne!(hash("a"), hash("b"));
+
+        let user = ApiKey {
+            id: "4f2c".into(),
+            name: "ci".into(),
+            tenant: Some("Xre".into()),
+            scopes: vec!["creep".into()],
+            created_by: "nb".into(),
+            created_at: 0,
+            expires_at: None,
+        }
+        .into_user();
+        assert_eq!("api-key:4f2c", user.id);
+        assert_eq!(vec!["creep".to_string()], user.roles);
+        assert_eq!(Some("Xre".into()), user.tenant);
+    }
+}
@@ -1,10 +1,141 @@
-use axum::response::{IntoResponse, Redirect, Response};
+use axum::{
+    http::{header::WWW_AUTHENTICATE, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    Json,
+};
+use openidconnect::url::form_urlencoded;
 
-#[derive(Debug)]
-pub struct LoginPageRedirect;
+/// Redirects to the login page, which brings the user back to `return_to` once logged in.
+/// A machine client cannot log in, it gets a 401 instead
+#[derive(Debug, Default)]
+pub struct LoginPageRedirect {
+    return_to: Option<String>,
+    machine_client: bool,
+}
+
+impl LoginPageRedirect {
+    pub fn return_to(self, return_to: Option<String>) -> Self {
+        LoginPageRedirect {
+            return_to: return_to.and_then(|r| safe_return_to(&r)),
+            ..self
+        }
+    }
+
+    pub fn machine_client() -> Self {
+        LoginPageRedirect {
+            machine_client: true,
+            ..Default::default()
+        }
+    }
+}
 
 impl IntoResponse for LoginPageRedirect {
     fn into_response(self) -> Response {
-        Redirect::temporary("/login").into_response()
+        if self.machine_client {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(WWW_AUTHENTICATE, "Bearer")],
+                Json(serde_json::json!({"error": "Unauthorized"})),
+            )
+                .into_response();
+        }
+        match self.return_to {
+            Some(return_to) => {
+                let query = form_urlencoded::Serializer::new(String::new())
+                    .append_pair("return_to", &return_to)
+                    .finish();
+                Redirect::temporary(&format!("/login?{query}")).into_response()
+            }
+            None => Redirect::temporary("/login").into_response(),
+        }
+    }
+}
+
+/// Failed login, rendered as a page rather than a blank error
+#[derive(Debug)]
+pub struct LoginError {
+    pub status: StatusCode,
+    pub msg: &'static str,
+}
+
+impl LoginError {
+    pub fn bad_request(msg: &'static str) -> Self {
+        LoginError {
+            status: StatusCode::BAD_REQUEST,
+            msg,
+        }
+    }
+
+    pub fn internal(msg: &'static str) -> Self {
+        LoginError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            msg,
+        }
+    }
+}
+
+impl IntoResponse for LoginError {
+    fn into_response(self) -> Response {
+        let page = format!(
+            r#"<!DOCTYPE html>
+<html>
+  <head><meta charset="utf-8"><title>Login failed</title></head>
+  <body>
+    <h1>Login failed</h1>
+    <p>{}</p>
+    <p><a href="/login">Try again</a></p>
+  </body>
+</html>"#,
+            self.msg
+        );
+        (self.status, Html(page)).into_response()
+    }
+}
+
+/// Only a path of the gateway is accepted, so that the login cannot be used
+/// to redirect to another site (`//evil.org`, `https://evil.org`, `/\evil.org`...)
+pub fn safe_return_to(return_to: &str) -> Option<String> {
+    let valid = return_to.starts_with('/')
+        && !return_to.starts_with("//")
+        && !return_to.starts_with("/\\")
+        && !return_to.chars().any(|c| c.is_control());
+    valid.then(|| return_to.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use axum::response::IntoResponse;
+    use hyper::{header::LOCATION, StatusCode};
+
+    use super::{safe_return_to, LoginPageRedirect};
+
+    #[test]
+    fn test_safe_return_to() {
+        assert_eq!(
+            Some("/invoice/find-all?page=2".to_string()),
+            safe_return_to("/invoice/find-all?page=2")
+        );
+        assert_eq!(None, safe_return_to("https://evil.org"));
+        assert_eq!(None, safe_return_to("//evil.org/path"));
+        assert_eq!(None, safe_return_to("/\\evil.org"));
+        assert_eq!(None, safe_return_to("/path\r\nSet-Cookie: a=b"));
+        assert_eq!(None, safe_return_to("invoice"));
+        assert_eq!(None, safe_return_to(""));
+
+        let response = LoginPageRedirect::default()
+            .return_to(Some("/invoice?page=2".into()))
+            .into_response();
+        assert_eq!(
+            "/login?return_to=%2Finvoice%3Fpage%3D2",
+            response.headers()[LOCATION]
+        );
+        let response = LoginPageRedirect::default()
+            .return_to(Some("//evil.org".into()))
+            .into_response();
+        assert_eq!("/login", response.headers()[LOCATION]);
+
+        let response = LoginPageRedirect::machine_client().into_response();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert!(!response.headers().contains_key(LOCATION));
     }
 }
@@ -1,8 +1,10 @@
 use serde::Deserialize;
 
+/// Query of the redirection from the provider, with either a code or an error
 #[derive(Debug, Deserialize)]
-#[allow(unused)]
 pub struct AuthRequest {
-    pub code: String,
-    pub state: String,
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
 }
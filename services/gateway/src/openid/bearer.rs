@@ -0,0 +1,459 @@
+use std::{
+    collections::HashMap,
+    env::var,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use base64::Engine;
+use jsonwebtoken::{
+    decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Header, Validation,
+};
+use openidconnect::{
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, Method,
+    },
+    url::Url,
+    HttpRequest,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+
+use super::{client::ClientError, reqwest_client::async_http_client, OpenIdClient, RealmAccess};
+use crate::{
+    constant::{OPENID_BEARER_AUDIENCE, OPENID_BEARER_VALIDATION},
+    openid::User,
+};
+
+const ALLOWED_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+/// minimum delay between two downloads of the issuer keys
+const MIN_JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// how long an introspected token is trusted without asking the issuer again
+const INTROSPECTION_CACHE_TTL: Duration = Duration::from_secs(30);
+const MAX_INTROSPECTION_CACHE: usize = 10_000;
+
+/// Claims of an access token, or of an introspection response
+#[derive(Debug, Deserialize)]
+struct AccessTokenClaims {
+    sub: String,
+    name: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+    middle_name: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+    realm_access: Option<RealmAccess>,
+    groups: Option<Vec<String>>,
+    tenant: Option<String>,
+    exp: Option<u64>,
+    /// a string or an array
+    aud: Option<serde_json::Value>,
+    /// the client the token was issued to
+    azp: Option<String>,
+}
+
+impl AccessTokenClaims {
+    /// the audience contains the client, or the token was issued to it
+    fn issued_for(&self, client_id: &str) -> bool {
+        let in_audience = match &self.aud {
+            Some(serde_json::Value::String(aud)) => aud == client_id,
+            Some(serde_json::Value::Array(aud)) => {
+                aud.iter().any(|a| a.as_str() == Some(client_id))
+            }
+            _ => false,
+        };
+        in_audience || self.azp.as_deref() == Some(client_id)
+    }
+}
+
+impl From<AccessTokenClaims> for User {
+    fn from(claims: AccessTokenClaims) -> Self {
+        User {
+            id: claims.sub,
+            full_name: claims.name,
+            given_name: claims.given_name,
+            family_name: claims.family_name,
+            middle_name: claims.middle_name,
+            username: claims.preferred_username,
+            email: claims.email,
+            roles: claims.realm_access.map(|r| r.roles).unwrap_or_default(),
+            groups: claims.groups.unwrap_or_default(),
+            tenant: claims.tenant,
+        }
+    }
+}
+
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Claims of a back-channel logout token. It has no expiry in the specification,
+/// but keycloak sets one
+#[derive(Debug, Deserialize)]
+pub struct LogoutTokenClaims {
+    pub sub: Option<String>,
+    pub sid: Option<String>,
+    pub iat: u64,
+    #[serde(default)]
+    events: HashMap<String, serde_json::Value>,
+    nonce: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(flatten)]
+    claims: Option<AccessTokenClaims>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ValidationMode {
+    /// verify the signature with the keys of the issuer
+    Jwks,
+    /// ask the issuer, works with opaque tokens and revoked tokens are rejected immediately
+    Introspection,
+}
+
+impl FromStr for ValidationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "jwks" => Ok(ValidationMode::Jwks),
+            "introspection" => Ok(ValidationMode::Introspection),
+            other => Err(format!("unknown bearer validation {other}")),
+        }
+    }
+}
+
+/// Validates the `Authorization: Bearer` access tokens sent by machine clients
+#[derive(Clone)]
+pub struct BearerValidator(Arc<BearerValidatorInner>);
+
+struct BearerValidatorInner {
+    mode: ValidationMode,
+    issuer: String,
+    /// `None` only when the check is disabled explicitly
+    audience: Option<String>,
+    jwks_uri: String,
+    introspection_endpoint: Option<String>,
+    client_id: String,
+    client_secret: String,
+    keys: RwLock<HashMap<String, DecodingKey>>,
+    last_jwks_refresh: Mutex<Option<Instant>>,
+    introspection_cache: Mutex<HashMap<String, (User, Instant)>>,
+}
+
+impl std::fmt::Debug for BearerValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BearerValidator")
+            .field("mode", &self.0.mode)
+            .field("issuer", &self.0.issuer)
+            .finish()
+    }
+}
+
+impl BearerValidator {
+    pub fn new(client: &OpenIdClient) -> Self {
+        let metadata = &client.metadata;
+        let mut mode = var(OPENID_BEARER_VALIDATION)
+            .ok()
+            .map(|mode| mode.parse::<ValidationMode>())
+            .transpose()
+            .unwrap_or_else(|e| panic!("{OPENID_BEARER_VALIDATION}: {e}"))
+            .unwrap_or(ValidationMode::Jwks);
+        if mode == ValidationMode::Introspection && metadata.introspection_endpoint.is_none() {
+            tracing::warn!("the issuer has no introspection endpoint, falling back to jwks");
+            mode = ValidationMode::Jwks;
+        }
+        BearerValidator(Arc::new(BearerValidatorInner {
+            mode,
+            issuer: metadata.issuer.clone(),
+            audience: match var(OPENID_BEARER_AUDIENCE) {
+                Ok(audience) if audience == "*" => {
+                    tracing::warn!("the audience of the bearer tokens is not checked");
+                    None
+                }
+                Ok(audience) => Some(audience),
+                Err(_) => Some(metadata.client_id.clone()),
+            },
+            jwks_uri: metadata.jwks_uri.clone(),
+            introspection_endpoint: metadata.introspection_endpoint.clone(),
+            client_id: metadata.client_id.clone(),
+            client_secret: metadata.client_secret.clone(),
+            keys: Default::default(),
+            last_jwks_refresh: Default::default(),
+            introspection_cache: Default::default(),
+        }))
+    }
+
+    pub async fn validate(&self, token: &str) -> Result<User, ClientError> {
+        match self.0.mode {
+            ValidationMode::Jwks => self.validate_jwt(token).await,
+            ValidationMode::Introspection => self.introspect(token).await,
+        }
+    }
+
+    async fn validate_jwt(&self, token: &str) -> Result<User, ClientError> {
+        let header = decode_header(token).map_err(|e| ClientError(e.to_string()))?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(ClientError(format!(
+                "algorithm {:?} not allowed",
+                header.alg
+            )));
+        }
+        // the audience is checked with the authorized party
+        let claims: AccessTokenClaims = self.decode_jwt(token, header, None, true).await?;
+        self.check_audience(&claims)?;
+        Ok(claims.into())
+    }
+
+    /// the logout token sent by the provider to the back-channel logout endpoint, signed with the keys of the issuer
+    pub async fn validate_logout_token(
+        &self,
+        token: &str,
+    ) -> Result<LogoutTokenClaims, ClientError> {
+        let header = decode_header(token).map_err(|e| ClientError(e.to_string()))?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(ClientError(format!(
+                "algorithm {:?} not allowed",
+                header.alg
+            )));
+        }
+        let claims: LogoutTokenClaims = self
+            .decode_jwt(token, header, Some(&self.0.client_id), false)
+            .await?;
+        if !claims.events.contains_key(BACKCHANNEL_LOGOUT_EVENT) {
+            return Err(ClientError("not a logout token".into()));
+        }
+        if claims.nonce.is_some() {
+            return Err(ClientError("a logout token has no nonce".into()));
+        }
+        if claims.sid.is_none() && claims.sub.is_none() {
+            return Err(ClientError("missing sid and sub".into()));
+        }
+        Ok(claims)
+    }
+
+    async fn decode_jwt<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        header: Header,
+        audience: Option<&str>,
+        require_exp: bool,
+    ) -> Result<T, ClientError> {
+        let kid = header
+            .kid
+            .ok_or_else(|| ClientError("missing kid".into()))?;
+        let key = match self.key(&kid) {
+            Some(key) => key,
+            None => {
+                self.refresh_jwks().await?;
+                self.key(&kid)
+                    .ok_or_else(|| ClientError(format!("unknown kid {kid}")))?
+            }
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.0.issuer]);
+        match audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if !require_exp {
+            // still checked when present
+            validation.required_spec_claims.remove("exp");
+        }
+        let claims = decode::<T>(token, &key, &validation)
+            .map_err(|e| ClientError(e.to_string()))?
+            .claims;
+        Ok(claims)
+    }
+
+    fn key(&self, kid: &str) -> Option<DecodingKey> {
+        self.0.keys.read().unwrap().get(kid).cloned()
+    }
+
+    async fn refresh_jwks(&self) -> Result<(), ClientError> {
+        {
+            let mut last_refresh = self.0.last_jwks_refresh.lock().unwrap();
+            if matches!(*last_refresh, Some(last) if last.elapsed() < MIN_JWKS_REFRESH_INTERVAL) {
+                return Ok(());
+            }
+            *last_refresh = Some(Instant::now());
+        }
+        let body = self
+            .request(Method::GET, &self.0.jwks_uri, HeaderMap::new(), vec![])
+            .await?;
+        let jwks: JwkSet =
+            serde_json::from_slice(&body).map_err(|e| ClientError(format!("invalid jwks: {e}")))?;
+        let mut keys = HashMap::new();
+        for jwk in &jwks.keys {
+            let Some(kid) = &jwk.common.key_id else {
+                continue;
+            };
+            match DecodingKey::from_jwk(jwk) {
+                Ok(key) => {
+                    keys.insert(kid.clone(), key);
+                }
+                // e.g. encryption keys
+                Err(e) => tracing::debug!("skipping key {kid}: {e}"),
+            }
+        }
+        *self.0.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    async fn introspect(&self, token: &str) -> Result<User, ClientError> {
+        let cache_key = format!("{:x}", Sha256::digest(token.as_bytes()));
+        if let Some((user, _)) = self
+            .0
+            .introspection_cache
+            .lock()
+            .unwrap()
+            .get(&cache_key)
+            .filter(|(_, until)| *until > Instant::now())
+        {
+            return Ok(user.clone());
+        }
+        let endpoint = self
+            .0
+            .introspection_endpoint
+            .as_ref()
+            .ok_or_else(|| ClientError("no introspection endpoint".into()))?;
+        let mut headers = HeaderMap::new();
+        let credentials = base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{}", self.0.client_id, self.0.client_secret));
+        headers.insert(
+            AUTHORIZATION,
+            format!("Basic {credentials}")
+                .parse()
+                .map_err(|_| ClientError("invalid client credentials".into()))?,
+        );
+        headers.insert(
+            CONTENT_TYPE,
+            "application/x-www-form-urlencoded".parse().unwrap(),
+        );
+        let body = openidconnect::url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", token)
+            .append_pair("token_type_hint", "access_token")
+            .finish();
+        let response = self
+            .request(Method::POST, endpoint, headers, body.into_bytes())
+            .await?;
+        let response: IntrospectionResponse = serde_json::from_slice(&response)
+            .map_err(|e| ClientError(format!("invalid introspection response: {e}")))?;
+        let claims = match response {
+            IntrospectionResponse {
+                active: true,
+                claims: Some(claims),
+            } => claims,
+            _ => return Err(ClientError("inactive token".into())),
+        };
+        self.check_audience(&claims)?;
+        // never trust a token longer than its own expiry
+        let ttl = claims
+            .exp
+            .map(|exp| {
+                let now = jsonwebtoken::get_current_timestamp();
+                Duration::from_secs(exp.saturating_sub(now))
+            })
+            .unwrap_or(INTROSPECTION_CACHE_TTL)
+            .min(INTROSPECTION_CACHE_TTL);
+        let user: User = claims.into();
+        let mut cache = self.0.introspection_cache.lock().unwrap();
+        if cache.len() >= MAX_INTROSPECTION_CACHE {
+            let now = Instant::now();
+            cache.retain(|_, (_, until)| *until > now);
+        }
+        if cache.len() < MAX_INTROSPECTION_CACHE {
+            cache.insert(cache_key, (user.clone(), Instant::now() + ttl));
+        }
+        Ok(user)
+    }
+
+    fn check_audience(&self, claims: &AccessTokenClaims) -> Result<(), ClientError> {
+        match &self.0.audience {
+            Some(audience) if !claims.issued_for(audience) => {
+                Err(ClientError(format!("token not issued for {audience}")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> Result<Vec<u8>, ClientError> {
+        let url = Url::parse(url).map_err(|e| ClientError(format!("invalid url {url}: {e}")))?;
+        let response = async_http_client(HttpRequest {
+            url,
+            method,
+            headers,
+            body,
+        })
+        .await
+        .map_err(|e| ClientError(e.to_string()))?;
+        if !response.status_code.is_success() {
+            return Err(ClientError(format!(
+                "unexpected status {}",
+                response.status_code
+            )));
+        }
+        Ok(response.body)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AccessTokenClaims, IntrospectionResponse};
+    use crate::openid::User;
+
+    #[test]
+    fn test_access_token_claims() {
+        let response: IntrospectionResponse = serde_json::from_str(
+            r#"{
+                "active": true,
+                "exp": 1729000000,
+                "sub": "3f2b6a4c",
+                "preferred_username": "ci-bot",
+                "realm_access": { "roles": ["creep"] },
+                "tenant": "Xre",
+                "scope": "roles tenant"
+            }"#,
+        )
+        .unwrap();
+        assert!(response.active);
+        let user: User = response.claims.unwrap().into();
+        assert_eq!("3f2b6a4c", user.id);
+        assert_eq!(vec!["creep".to_string()], user.roles);
+        assert_eq!(Some("Xre".into()), user.tenant);
+
+        let response: IntrospectionResponse = serde_json::from_str(r#"{"active": false}"#).unwrap();
+        assert!(!response.active);
+        assert!(response.claims.is_none());
+
+        let claims: AccessTokenClaims =
+            serde_json::from_str(r#"{"sub": "nb", "aud": "account", "azp": "other-client"}"#)
+                .unwrap();
+        assert!(!claims.issued_for("sequeda-auth"));
+        let claims: AccessTokenClaims = serde_json::from_str(
+            r#"{"sub": "nb", "aud": ["account", "sequeda-auth"], "azp": "other-client"}"#,
+        )
+        .unwrap();
+        assert!(claims.issued_for("sequeda-auth"));
+        let claims: AccessTokenClaims =
+            serde_json::from_str(r#"{"sub": "nb", "azp": "sequeda-auth"}"#).unwrap();
+        assert!(claims.issued_for("sequeda-auth"));
+        assert!(User::from(claims).roles.is_empty());
+    }
+}
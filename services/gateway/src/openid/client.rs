@@ -8,7 +8,8 @@ use openidconnect::core::{
 };
 use openidconnect::url::Url;
 use openidconnect::{
-    AuthenticationFlow, AuthorizationCode, CsrfToken, Nonce, OAuth2TokenResponse, RedirectUrl,
+    AccessToken, AuthenticationFlow, AuthorizationCode, CsrfToken, EmptyExtraTokenFields,
+    IdTokenFields, Nonce, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl,
     RevocationUrl, Scope, SubjectIdentifier, UserInfoClaims,
 };
 use openidconnect::{ClientId, ClientSecret, IssuerUrl};
@@ -21,12 +22,78 @@ use crate::openid::CustomIdTokenClaims;
 #[derive(Debug, Clone)]
 pub struct OpenIdClient {
     client: RawOpenIdClient,
+    pub(super) metadata: ProviderEndpoints,
 }
 
+/// Provider endpoints needed outside of the openidconnect client,
+/// to validate the access tokens of machine clients
+#[derive(Debug, Clone)]
+pub(super) struct ProviderEndpoints {
+    pub issuer: String,
+    pub jwks_uri: String,
+    pub introspection_endpoint: Option<String>,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// the access token is refreshed when it expires in less than this margin
+const REFRESH_MARGIN_SECS: i64 = 30;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OpenIdToken {
     pub claims: Option<CustomIdTokenClaims>,
     pub token: CustomTokenResponse,
+    /// expiry of the access token, unix timestamp
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+}
+
+impl OpenIdToken {
+    fn new(claims: Option<CustomIdTokenClaims>, token: CustomTokenResponse) -> Self {
+        let expires_at = token
+            .expires_in()
+            .map(|expires_in| Utc::now().timestamp() + expires_in.as_secs() as i64);
+        OpenIdToken {
+            claims,
+            token,
+            expires_at,
+        }
+    }
+
+    /// expiry of the access token, or of the id token for the sessions created before it was kept
+    pub fn expiration(&self) -> Option<i64> {
+        self.expires_at.or_else(|| {
+            self.claims
+                .as_ref()
+                .map(|claims| claims.expiration().timestamp())
+        })
+    }
+
+    pub fn needs_refresh(&self) -> bool {
+        self.expiration()
+            .map(|exp| exp - Utc::now().timestamp() < REFRESH_MARGIN_SECS)
+            .unwrap_or(true)
+    }
+
+    /// only the refresh token and the expiry, for the sessions kept in a cookie.
+    /// The access token and the claims are obtained again by a refresh
+    pub fn compact(&self) -> Self {
+        let mut token = CustomTokenResponse::new(
+            AccessToken::new(String::new()),
+            self.token.token_type().clone(),
+            IdTokenFields::new(None, EmptyExtraTokenFields {}),
+        );
+        token.set_refresh_token(self.token.refresh_token().cloned());
+        OpenIdToken {
+            claims: None,
+            token,
+            expires_at: self.expiration(),
+        }
+    }
+
+    pub fn is_compact(&self) -> bool {
+        self.claims.is_none() && self.token.access_token().secret().is_empty()
+    }
 }
 
 impl OpenIdClient {
@@ -41,27 +108,28 @@ impl OpenIdClient {
         let redirect_url =
             RedirectUrl::new(redirect_url.to_string()).expect("Invalid redirect URL");
         let client = self.client.clone().set_redirect_uri(redirect_url);
-        Self { client }
+        Self {
+            client,
+            metadata: self.metadata.clone(),
+        }
     }
 
-    pub fn get_authorize_url(
-        &self,
-        redirect_url: &str,
-        nonce: Nonce,
-    ) -> (RawOpenIdClient, Url, CsrfToken, Nonce) {
+    /// the state and the nonce are random, the code is bound to the PKCE challenge
+    pub fn get_authorize_url(&self, pkce_challenge: PkceCodeChallenge) -> (Url, CsrfToken, Nonce) {
         let scopes = Self::get_scopes();
-        let copy = self.with_redirect_url(redirect_url);
 
-        let mut authorize_url_req = copy.client.authorize_url(
-            AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
-            CsrfToken::new_random,
-            || nonce,
-        );
+        let mut authorize_url_req = self
+            .client
+            .authorize_url(
+                AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
+                CsrfToken::new_random,
+                Nonce::new_random,
+            )
+            .set_pkce_challenge(pkce_challenge);
         for scope in scopes {
             authorize_url_req = authorize_url_req.add_scope(scope);
         }
-        let (url, csrf, nonce) = authorize_url_req.url();
-        (copy.client, url, csrf, nonce)
+        authorize_url_req.url()
     }
     pub async fn new() -> Self {
         let client_id = ClientId::new(
@@ -89,6 +157,16 @@ impl OpenIdClient {
             .revocation_endpoint
             .clone();
         tracing::debug!("revocation endpoint: {}", revocation_endpoint);
+        let metadata = ProviderEndpoints {
+            issuer: provider_metadata.issuer().as_str().to_string(),
+            jwks_uri: provider_metadata.jwks_uri().url().to_string(),
+            introspection_endpoint: provider_metadata
+                .additional_metadata()
+                .introspection_endpoint
+                .clone(),
+            client_id: client_id.as_str().to_string(),
+            client_secret: client_secret.secret().to_string(),
+        };
 
         let client = RawOpenIdClient::from_provider_metadata(
             provider_metadata,
@@ -99,7 +177,7 @@ impl OpenIdClient {
             RevocationUrl::new(revocation_endpoint).expect("Invalid revocation endpoint URL"),
         );
 
-        OpenIdClient { client }
+        OpenIdClient { client, metadata }
     }
 
     pub async fn exchange_access_token(
@@ -122,34 +200,38 @@ impl OpenIdClient {
             .map_err(|err| handle_error(&err, "exchange_access_token"))
     }
 
+    /// exchanges the refresh token, the caller checks `OpenIdToken::needs_refresh` first
     pub async fn refresh_token(&self, id_token: OpenIdToken) -> Result<OpenIdToken, ClientError> {
-        let claims = &id_token.claims;
-        let diff = claims
-            .as_ref()
-            .map(|claim| (Utc::now() - claim.expiration()).num_seconds())
-            .unwrap_or_else(|| 1);
-        let token_response = &id_token.token;
-
-        tracing::debug!("token diff {diff:?}");
-        tracing::debug!("token expires_in {:?}", &token_response.expires_in());
-
-        if diff >= 0 {
-            tracing::debug!("token expired");
-            let token = self
-                .client
-                .exchange_refresh_token(
-                    id_token
-                        .token
-                        .refresh_token()
-                        .ok_or_else(|| ClientError("refresh token not present".into()))?,
-                )
-                .request_async(async_http_client)
-                .await
-                .map_err(|err| handle_error(&err, "refresh_token"))?;
-            Ok(OpenIdToken { token, ..id_token })
-        } else {
-            Ok(id_token)
+        tracing::debug!("token expires at {:?}, refreshing", id_token.expiration());
+        let mut token = self
+            .client
+            .exchange_refresh_token(
+                id_token
+                    .token
+                    .refresh_token()
+                    .ok_or_else(|| ClientError("refresh token not present".into()))?,
+            )
+            .request_async(async_http_client)
+            .await
+            .map_err(|err| handle_error(&err, "refresh_token"))?;
+        // the provider may not rotate the refresh token
+        if token.refresh_token().is_none() {
+            token.set_refresh_token(id_token.token.refresh_token().cloned());
         }
+        // the claims of a new id token, e.g. updated roles. Its nonce is the one of the login, or none
+        let claims = match token.extra_fields().id_token() {
+            Some(new_id_token) => Some(
+                new_id_token
+                    .claims(
+                        &self.client.id_token_verifier(),
+                        |_: Option<&Nonce>| -> Result<(), String> { Ok(()) },
+                    )
+                    .map_err(|err| handle_error(&err, "refresh_token"))?
+                    .clone(),
+            ),
+            None => id_token.claims,
+        };
+        Ok(OpenIdToken::new(claims, token))
     }
 
     //dev only todo add cfg dev todo doesn't work with keycloak.
@@ -183,17 +265,18 @@ impl OpenIdClient {
     //     })
     // }
 
+    /// the state must have been checked by the caller
     pub async fn exchange_token(
         &self,
-        auth_request: super::auth_request::AuthRequest,
-        nonce: Nonce,
+        code: String,
+        nonce: &Nonce,
+        pkce_verifier: PkceCodeVerifier,
     ) -> Result<OpenIdToken, ClientError> {
-        //let _state = CsrfToken::new(auth_request.state);
-        tracing::debug!("code: {:?}", &auth_request);
-        let code = AuthorizationCode::new(auth_request.code);
+        let code = AuthorizationCode::new(code);
         let token_response = self
             .client
             .exchange_code(code)
+            .set_pkce_verifier(pkce_verifier)
             .request_async(async_http_client)
             .await
             .map_err(|err| handle_error(&err, "exchange_token"))?;
@@ -202,13 +285,10 @@ impl OpenIdClient {
             .extra_fields()
             .id_token()
             .ok_or_else(|| ClientError("id token missing".into()))?
-            .claims(&id_token_verifier, &nonce)
+            .claims(&id_token_verifier, nonce)
             .map_err(|err| handle_error(&err, "exchange_token"))?;
 
-        Ok(OpenIdToken {
-            claims: Some(claims.clone()),
-            token: token_response,
-        })
+        Ok(OpenIdToken::new(Some(claims.clone()), token_response))
     }
 
     pub async fn logout(&self, id_token: &OpenIdToken) -> Result<(), ClientError> {
@@ -238,7 +318,7 @@ fn handle_error<T: std::error::Error>(fail: &T, msg: &'static str) -> ClientErro
 }
 
 #[derive(Debug)]
-pub struct ClientError(String);
+pub struct ClientError(pub(super) String);
 impl std::error::Error for ClientError {}
 
 impl Display for ClientError {
@@ -0,0 +1,593 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env::var,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_session::{chrono::Utc, Session, SessionStore};
+use hyper::Method;
+use mongodb::bson::{Bson, Document};
+use sequeda_store::StoreClient;
+use serde::{Deserialize, Serialize};
+
+use super::{PendingSessionCookie, SessionBackend, User};
+use crate::constant::{DEMO_ACCOUNT, DEMO_CONFIG};
+
+const SANDBOX_SESSION_KEY: &str = "demo_sandbox";
+/// window of the per ip limit of the sandbox creation
+const SANDBOX_RATE_WINDOW_SECS: i64 = 3600;
+
+/// Demo account configuration, read from the yaml file at `DEMO_CONFIG`
+#[derive(Deserialize, Debug, Clone)]
+pub struct DemoConfig {
+    #[serde(default = "default_demo_user")]
+    pub user: User,
+    #[serde(default)]
+    pub policy: DemoPolicy,
+    pub reset: Option<DemoReset>,
+    #[serde(default)]
+    pub sandboxes: SandboxLimits,
+}
+
+/// What the demo user can change
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DemoPolicy {
+    /// only GET, HEAD and OPTIONS requests go through
+    #[default]
+    ReadOnly,
+    /// each session works in its own copy of the demo tenant
+    Sandbox,
+    /// the demo user can change anything in the demo tenant
+    ReadWrite,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DemoReset {
+    /// seconds between two resets of the demo tenant, also the lifetime of a sandbox
+    pub interval: u64,
+    /// directory of `<collection>.json` files, each holding an array of documents
+    pub seed: PathBuf,
+}
+
+/// How many sandboxes can be created, counted by each instance of the gateway
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct SandboxLimits {
+    /// sandboxes alive at the same time
+    pub max: usize,
+    /// sandboxes created from the same ip in an hour
+    pub per_ip: usize,
+}
+
+impl Default for SandboxLimits {
+    fn default() -> Self {
+        SandboxLimits {
+            max: 100,
+            per_ip: 3,
+        }
+    }
+}
+
+impl Default for DemoConfig {
+    fn default() -> Self {
+        DemoConfig {
+            user: default_demo_user(),
+            policy: DemoPolicy::default(),
+            reset: None,
+            sandboxes: SandboxLimits::default(),
+        }
+    }
+}
+
+fn default_demo_user() -> User {
+    User {
+        id: "demo-16ba6cdd-59cd-4bcc-b7ef-240af07153fd".into(),
+        full_name: Some("Account Demo".into()),
+        given_name: Some("Account".into()),
+        family_name: Some("Demo".into()),
+        middle_name: Some("AD".into()),
+        username: Some("demo".into()),
+        email: Some("demo@random.corp".into()),
+        roles: vec!["demo".into()],
+        groups: vec!["demogroup".into()],
+        tenant: Some("demo".into()),
+    }
+}
+
+/// Sandbox of a demo session, named after its creation so that the reset can drop the expired ones
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct DemoSandbox {
+    id: String,
+    created_at: i64,
+}
+
+/// Creation times of the sandboxes, to enforce the `SandboxLimits`
+#[derive(Debug, Default)]
+struct SandboxCounter {
+    alive: VecDeque<i64>,
+    by_ip: HashMap<IpAddr, VecDeque<i64>>,
+}
+
+/// Marks a request made with the demo account
+#[derive(Debug, Clone, Copy)]
+pub struct DemoSession {
+    pub read_only: bool,
+}
+
+impl DemoSession {
+    pub fn allows(&self, method: &Method) -> bool {
+        !self.read_only || matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+    }
+}
+
+/// Identity used when nobody is logged in, enabled with `DEMO_ACCOUNT`
+#[derive(Clone, Debug)]
+pub struct DemoAccount {
+    config: Arc<DemoConfig>,
+    store: Option<StoreClient>,
+    sandboxes: Arc<Mutex<SandboxCounter>>,
+}
+
+impl DemoAccount {
+    pub fn new(config: DemoConfig, store: Option<StoreClient>) -> Self {
+        DemoAccount {
+            config: Arc::new(config),
+            store,
+            sandboxes: Arc::default(),
+        }
+    }
+
+    pub async fn from_env() -> Result<Option<Self>, DemoError> {
+        let enabled = var(DEMO_ACCOUNT)
+            .map(|s| s.parse::<bool>().unwrap_or(false))
+            .unwrap_or(false);
+        if !enabled {
+            return Ok(None);
+        }
+        let config = match var(DEMO_CONFIG) {
+            Ok(path) => DemoConfig::from_file(Path::new(&path))?,
+            Err(_) => {
+                tracing::warn!("{DEMO_CONFIG} is not set, the default demo account is read-only");
+                DemoConfig::default()
+            }
+        };
+        let store = match &config.reset {
+            Some(_) => Some(
+                StoreClient::new("sequeda-gateway".into())
+                    .await
+                    .map_err(|e| DemoError {
+                        msg: format!("could not connect to mongo: {e}"),
+                    })?,
+            ),
+            None => None,
+        };
+        Ok(Some(DemoAccount::new(config, store)))
+    }
+
+    pub fn policy(&self) -> DemoPolicy {
+        self.config.policy
+    }
+
+    fn tenant(&self) -> &str {
+        self.config.user.tenant.as_deref().unwrap_or("demo")
+    }
+
+    fn sandbox_prefix(&self) -> String {
+        format!("{}-sandbox-", self.tenant())
+    }
+
+    fn sandbox_tenant(&self, sandbox: &DemoSandbox) -> String {
+        format!(
+            "{}{}-{}",
+            self.sandbox_prefix(),
+            sandbox.created_at,
+            sandbox.id
+        )
+    }
+
+    /// sandboxes live as long as the demo tenant between two resets
+    fn is_expired(&self, created_at: i64, now: i64) -> bool {
+        self.config
+            .reset
+            .as_ref()
+            .is_some_and(|reset| now - created_at >= reset.interval as i64)
+    }
+
+    /// counts a new sandbox, unless there are too many of them
+    fn reserve_sandbox(&self, client_ip: Option<IpAddr>, now: i64) -> bool {
+        let limits = self.config.sandboxes;
+        let mut counter = self.sandboxes.lock().unwrap();
+        while counter
+            .alive
+            .front()
+            .is_some_and(|created_at| self.is_expired(*created_at, now))
+        {
+            counter.alive.pop_front();
+        }
+        counter.by_ip.retain(|_, created| {
+            created.retain(|created_at| now - created_at < SANDBOX_RATE_WINDOW_SECS);
+            !created.is_empty()
+        });
+        if counter.alive.len() >= limits.max {
+            tracing::warn!("{} demo sandboxes are alive, no new one", limits.max);
+            return false;
+        }
+        if let Some(ip) = client_ip {
+            let created = counter.by_ip.entry(ip).or_default();
+            if created.len() >= limits.per_ip {
+                tracing::warn!("too many demo sandboxes created from {ip}");
+                return false;
+            }
+            created.push_back(now);
+        }
+        counter.alive.push_back(now);
+        true
+    }
+
+    /// the demo user, in the sandbox of the session with the sandbox policy.
+    /// The sandbox is created by the first request that changes something,
+    /// without one the demo account falls back to read-only
+    pub async fn user(
+        &self,
+        store: &SessionBackend,
+        session_cookie: Option<&str>,
+        pending_cookie: Option<&PendingSessionCookie>,
+        method: &Method,
+        client_ip: Option<IpAddr>,
+    ) -> (User, DemoSession) {
+        let mut user = self.config.user.clone();
+        match self.config.policy {
+            DemoPolicy::ReadOnly => (user, DemoSession { read_only: true }),
+            DemoPolicy::ReadWrite => (user, DemoSession { read_only: false }),
+            DemoPolicy::Sandbox => {
+                match self
+                    .sandbox(store, session_cookie, pending_cookie, method, client_ip)
+                    .await
+                {
+                    Some(tenant) => {
+                        user.tenant = Some(tenant);
+                        (user, DemoSession { read_only: false })
+                    }
+                    None => (user, DemoSession { read_only: true }),
+                }
+            }
+        }
+    }
+
+    async fn sandbox(
+        &self,
+        store: &SessionBackend,
+        session_cookie: Option<&str>,
+        pending_cookie: Option<&PendingSessionCookie>,
+        method: &Method,
+        client_ip: Option<IpAddr>,
+    ) -> Option<String> {
+        let pending_cookie = pending_cookie?;
+        let session = match session_cookie {
+            Some(cookie) => store.load_session(cookie.to_string()).await.ok().flatten(),
+            None => None,
+        };
+        let now = Utc::now().timestamp();
+        let session = match session {
+            Some(session) => match session.get::<DemoSandbox>(SANDBOX_SESSION_KEY) {
+                Some(sandbox) if !self.is_expired(sandbox.created_at, now) => {
+                    return Some(self.sandbox_tenant(&sandbox));
+                }
+                _ => Some(session),
+            },
+            None => None,
+        };
+        // reading goes to the demo tenant, a visitor who only browses costs no database
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+            || !self.reserve_sandbox(client_ip, now)
+        {
+            return None;
+        }
+        let (mut session, cookie) = match session {
+            Some(session) => (session, session_cookie.map(|c| c.to_string())),
+            None => {
+                let mut session = Session::new();
+                pending_cookie.config().init(&mut session);
+                (session, None)
+            }
+        };
+        let sandbox = DemoSandbox {
+            id: uuid::Uuid::new_v4().simple().to_string()[..16].to_string(),
+            created_at: now,
+        };
+        let tenant = self.sandbox_tenant(&sandbox);
+        if let Err(e) = session.insert(SANDBOX_SESSION_KEY, &sandbox) {
+            tracing::error!("could not create the demo sandbox: {e}");
+            return None;
+        }
+        match store.store_session(session).await {
+            Ok(new_cookie) => match new_cookie.or(cookie) {
+                Some(cookie) => pending_cookie.set(cookie),
+                None => return None,
+            },
+            Err(e) => {
+                tracing::error!("could not store the demo sandbox: {e}");
+                return None;
+            }
+        }
+        if let Some(mongo) = &self.store {
+            if let Err(e) = self.seed(mongo, &tenant).await {
+                tracing::error!("could not seed the demo sandbox {tenant}: {e}");
+            }
+        }
+        tracing::info!("new demo sandbox {tenant}");
+        Some(tenant)
+    }
+
+    /// resets the demo tenant from the seed, and drops the expired sandboxes, every `reset.interval`
+    pub fn spawn_reset(&self) {
+        let (Some(reset), Some(mongo)) = (self.config.reset.clone(), self.store.clone()) else {
+            return;
+        };
+        let demo = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(reset.interval.max(1)));
+            loop {
+                interval.tick().await;
+                if let Err(e) = demo.reset(&mongo).await {
+                    tracing::error!("could not reset the demo tenant: {e}");
+                }
+            }
+        });
+    }
+
+    async fn reset(&self, mongo: &StoreClient) -> Result<(), DemoError> {
+        let tenant = self.tenant().to_string();
+        mongo
+            .get_db(&tenant)
+            .drop()
+            .await
+            .map_err(DemoError::mongo)?;
+        self.seed(mongo, &tenant).await?;
+
+        let prefix = self.sandbox_prefix();
+        let now = Utc::now().timestamp();
+        let databases = mongo
+            .get_raw_client()
+            .list_database_names()
+            .await
+            .map_err(DemoError::mongo)?;
+        for database in databases {
+            let expired = database
+                .strip_prefix(&prefix)
+                .and_then(|sandbox| sandbox.split('-').next())
+                .and_then(|created_at| created_at.parse::<i64>().ok())
+                .is_some_and(|created_at| self.is_expired(created_at, now));
+            if expired {
+                mongo
+                    .get_db(&database)
+                    .drop()
+                    .await
+                    .map_err(DemoError::mongo)?;
+            }
+        }
+        tracing::info!("demo tenant {tenant} reset");
+        Ok(())
+    }
+
+    async fn seed(&self, mongo: &StoreClient, tenant: &str) -> Result<(), DemoError> {
+        let Some(reset) = &self.config.reset else {
+            return Ok(());
+        };
+        let db = mongo.get_db(tenant);
+        for (collection, documents) in read_seed(&reset.seed)? {
+            if !documents.is_empty() {
+                db.collection::<Document>(&collection)
+                    .insert_many(documents)
+                    .await
+                    .map_err(DemoError::mongo)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DemoConfig {
+    pub fn from_file(path: &Path) -> Result<Self, DemoError> {
+        let content = std::fs::read_to_string(path).map_err(|e| DemoError {
+            msg: format!("could not read {}: {e}", path.display()),
+        })?;
+        let config: DemoConfig = serde_yml::from_str(&content).map_err(|e| DemoError {
+            msg: format!("invalid demo config {}: {e}", path.display()),
+        })?;
+        if config.policy == DemoPolicy::Sandbox && config.reset.is_none() {
+            return Err(DemoError {
+                msg: "the sandbox policy requires a reset, to drop the old sandboxes".into(),
+            });
+        }
+        Ok(config)
+    }
+}
+
+/// the documents of each collection, in MongoDB extended json (`{"$oid": ...}`, `{"$date": ...}`)
+fn read_seed(dir: &Path) -> Result<Vec<(String, Vec<Document>)>, DemoError> {
+    let entries = std::fs::read_dir(dir).map_err(|e| DemoError {
+        msg: format!("could not read the seed {}: {e}", dir.display()),
+    })?;
+    let mut collections = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(collection) = path
+            .extension()
+            .filter(|ext| *ext == "json")
+            .and(path.file_stem())
+            .and_then(|stem| stem.to_str())
+        else {
+            continue;
+        };
+        let content = std::fs::read_to_string(&path).map_err(|e| DemoError {
+            msg: format!("could not read {}: {e}", path.display()),
+        })?;
+        let invalid = |e: String| DemoError {
+            msg: format!("invalid seed {}: {e}", path.display()),
+        };
+        let values: Vec<serde_json::Value> =
+            serde_json::from_str(&content).map_err(|e| invalid(e.to_string()))?;
+        let documents = values
+            .into_iter()
+            .map(|value| match Bson::try_from(value) {
+                Ok(Bson::Document(document)) => Ok(document),
+                Ok(other) => Err(invalid(format!("expected a document, got {other}"))),
+                Err(e) => Err(invalid(e.to_string())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        collections.push((collection.to_string(), documents));
+    }
+    collections.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(collections)
+}
+
+#[derive(Debug)]
+pub struct DemoError {
+    pub msg: String,
+}
+
+impl DemoError {
+    fn mongo(e: mongodb::error::Error) -> Self {
+        DemoError { msg: e.to_string() }
+    }
+}
+
+impl std::fmt::Display for DemoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for DemoError {}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use async_session::MemoryStore;
+    use hyper::Method;
+
+    use super::{read_seed, DemoAccount, DemoConfig, DemoPolicy};
+    use crate::openid::{PendingSessionCookie, SessionBackend, SessionConfig};
+
+    #[tokio::test]
+    async fn test_demo_account() {
+        let seed = std::env::temp_dir().join(format!("demo-seed-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&seed).unwrap();
+        std::fs::write(
+            seed.join("invoice.json"),
+            r#"[{"_id": {"$oid": "65f1c2a4e4b0a1b2c3d4e5f6"}, "number": "2024-001"}]"#,
+        )
+        .unwrap();
+        std::fs::write(seed.join("README.md"), "not a collection").unwrap();
+        let collections = read_seed(&seed).unwrap();
+        assert_eq!(1, collections.len());
+        assert_eq!("invoice", collections[0].0);
+        assert!(collections[0].1[0].get_object_id("_id").is_ok());
+
+        let config: DemoConfig = serde_yml::from_str(&format!(
+            r#"
+            user:
+              id: demo-user
+              roles: [demo, creep]
+              groups: []
+              tenant: showroom
+            policy: sandbox
+            reset:
+              interval: 3600
+              seed: {}
+            sandboxes:
+              max: 3
+              per_ip: 2
+            "#,
+            seed.display()
+        ))
+        .unwrap();
+        assert_eq!(DemoPolicy::Sandbox, config.policy);
+        let demo = DemoAccount::new(config, None);
+        let store = SessionBackend::Memory(MemoryStore::new());
+
+        let ip: Option<IpAddr> = Some("10.0.0.1".parse().unwrap());
+
+        // without the session middleware, no sandbox can be kept
+        let (user, session) = demo.user(&store, None, None, &Method::POST, ip).await;
+        assert_eq!(Some("showroom".to_string()), user.tenant);
+        assert!(!session.allows(&Method::POST));
+        assert!(session.allows(&Method::GET));
+
+        // browsing creates no sandbox
+        let pending = PendingSessionCookie::new(SessionConfig::default());
+        let (user, session) = demo
+            .user(&store, None, Some(&pending), &Method::GET, ip)
+            .await;
+        assert_eq!(Some("showroom".to_string()), user.tenant);
+        assert!(!session.allows(&Method::POST));
+        assert!(pending.take().is_none());
+        assert_eq!(0, store_count(&store).await);
+
+        let (user, session) = demo
+            .user(&store, None, Some(&pending), &Method::POST, ip)
+            .await;
+        let tenant = user.tenant.unwrap();
+        assert!(tenant.starts_with("showroom-sandbox-"));
+        assert!(session.allows(&Method::DELETE));
+        let cookie = pending.take().unwrap().value().to_string();
+
+        // the same session keeps its sandbox
+        let (user, _) = demo
+            .user(&store, Some(&cookie), Some(&pending), &Method::GET, ip)
+            .await;
+        assert_eq!(Some(tenant.clone()), user.tenant);
+        assert!(pending.take().is_none());
+
+        // another one gets its own
+        let (user, _) = demo
+            .user(&store, None, Some(&pending), &Method::PUT, ip)
+            .await;
+        assert_ne!(Some(tenant), user.tenant);
+        assert_eq!(2, store_count(&store).await);
+
+        // until the ip has created too many
+        let (user, session) = demo
+            .user(&store, None, Some(&pending), &Method::PUT, ip)
+            .await;
+        assert_eq!(Some("showroom".to_string()), user.tenant);
+        assert!(!session.allows(&Method::PUT));
+        let other_ip = Some("10.0.0.2".parse().unwrap());
+        let (user, _) = demo
+            .user(&store, None, Some(&pending), &Method::PUT, other_ip)
+            .await;
+        assert!(user.tenant.unwrap().starts_with("showroom-sandbox-"));
+        // or too many are alive
+        let (user, _) = demo
+            .user(&store, None, Some(&pending), &Method::PUT, other_ip)
+            .await;
+        assert_eq!(Some("showroom".to_string()), user.tenant);
+        assert_eq!(3, store_count(&store).await);
+
+        let now = async_session::chrono::Utc::now().timestamp();
+        assert!(demo.is_expired(now - 3600, now));
+        assert!(!demo.is_expired(now - 10, now));
+
+        let demo = DemoAccount::new(DemoConfig::default(), None);
+        let (user, session) = demo
+            .user(&store, None, Some(&pending), &Method::PUT, ip)
+            .await;
+        assert_eq!(Some("demo".to_string()), user.tenant);
+        assert!(!session.allows(&Method::PUT));
+        assert!(session.allows(&Method::OPTIONS));
+
+        std::fs::remove_dir_all(seed).unwrap();
+    }
+
+    async fn store_count(store: &SessionBackend) -> usize {
+        match store {
+            SessionBackend::Memory(store) => store.count().await,
+            _ => unreachable!(),
+        }
+    }
+}
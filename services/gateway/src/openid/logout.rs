@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{response::IntoResponse, Extension, Form, Json};
+use hyper::{header::CACHE_CONTROL, StatusCode};
+use redis::AsyncCommands;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{bearer::LogoutTokenClaims, client::OpenIdToken, BearerValidator, UserCache};
+use crate::redis_connection::RedisConnection;
+
+/// Sessions logged out by the provider (back-channel logout). A session is revoked when
+/// its provider session (`sid`) is, or when its user logged out everywhere after its login.
+/// The list is shared in redis when available, as any instance of the gateway may receive the logout.
+#[derive(Clone)]
+pub struct RevokedSessions {
+    redis: Option<RedisConnection>,
+    /// how long a revocation is kept, at least as long as a session
+    ttl: Duration,
+    memory: Arc<Mutex<HashMap<String, (u64, Instant)>>>,
+}
+
+impl std::fmt::Debug for RevokedSessions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RevokedSessions")
+            .field("redis", &self.redis)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl RevokedSessions {
+    pub fn new(redis: Option<RedisConnection>, ttl: Duration) -> Self {
+        RevokedSessions {
+            redis,
+            ttl,
+            memory: Default::default(),
+        }
+    }
+
+    fn key(kind: &str, value: &str) -> String {
+        format!("sequeda:revoked-session:{kind}:{value}")
+    }
+
+    pub async fn revoke(&self, logout: &LogoutTokenClaims) {
+        let key = match (&logout.sid, &logout.sub) {
+            (Some(sid), _) => Self::key("sid", sid),
+            (None, Some(sub)) => Self::key("sub", sub),
+            (None, None) => return,
+        };
+        {
+            let mut memory = self.memory.lock().unwrap();
+            let now = Instant::now();
+            memory.retain(|_, (_, until)| *until > now);
+            memory.insert(key.clone(), (logout.iat, now + self.ttl));
+        }
+        if let Some(redis) = &self.redis {
+            let result = match redis.get().await {
+                Ok(mut conn) => {
+                    conn.set_ex::<_, _, ()>(&key, logout.iat, self.ttl.as_secs())
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                redis.reset().await;
+                tracing::error!("could not share the revocation of {key}: {e}");
+            }
+        }
+    }
+
+    pub async fn is_revoked(&self, token: &OpenIdToken) -> bool {
+        let Some(claims) = &token.claims else {
+            return false;
+        };
+        let logged_in_at = claims
+            .auth_time()
+            .unwrap_or_else(|| claims.issue_time())
+            .timestamp() as u64;
+        let sid_key = claims
+            .additional_claims()
+            .sid
+            .as_ref()
+            .map(|sid| Self::key("sid", sid));
+        let sub_key = Self::key("sub", claims.subject());
+        let revoked = |sid: Option<u64>, sub: Option<u64>| {
+            sid.is_some() || sub.is_some_and(|logout_at| logout_at >= logged_in_at)
+        };
+
+        {
+            let memory = self.memory.lock().unwrap();
+            let now = Instant::now();
+            let get = |key: &str| {
+                memory
+                    .get(key)
+                    .filter(|(_, until)| *until > now)
+                    .map(|(iat, _)| *iat)
+            };
+            if revoked(sid_key.as_deref().and_then(get), get(&sub_key)) {
+                return true;
+            }
+        }
+        let Some(redis) = &self.redis else {
+            return false;
+        };
+        let keys: Vec<String> = std::iter::once(sub_key).chain(sid_key).collect();
+        let result: redis::RedisResult<Vec<Option<u64>>> = match redis.get().await {
+            Ok(mut conn) => conn.mget(&keys).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(values) => revoked(
+                values.get(1).copied().flatten(),
+                values.first().copied().flatten(),
+            ),
+            Err(e) => {
+                // like the rate limiter, a redis failure lets the request through
+                redis.reset().await;
+                tracing::error!("could not check the revoked sessions: {e}");
+                false
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BackChannelLogout {
+    logout_token: String,
+}
+
+/// OpenID back-channel logout: the provider tells which session, or user, logged out
+pub async fn backchannel_logout(
+    Extension(validator): Extension<BearerValidator>,
+    Extension(revoked_sessions): Extension<RevokedSessions>,
+    Extension(user_cache): Extension<UserCache>,
+    Form(logout): Form<BackChannelLogout>,
+) -> impl IntoResponse {
+    match validator.validate_logout_token(&logout.logout_token).await {
+        Ok(claims) => {
+            tracing::info!(
+                "back-channel logout of sid {:?}, sub {:?}",
+                claims.sid,
+                claims.sub
+            );
+            revoked_sessions.revoke(&claims).await;
+            user_cache.evict(claims.sid.as_deref(), claims.sub.as_deref());
+            (
+                StatusCode::OK,
+                [(CACHE_CONTROL, "no-store")],
+                Json(json!({})),
+            )
+        }
+        Err(e) => {
+            tracing::warn!("invalid logout token: {e}");
+            (
+                StatusCode::BAD_REQUEST,
+                [(CACHE_CONTROL, "no-store")],
+                Json(json!({"error": "invalid_request"})),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use async_session::chrono::Utc;
+    use serde_json::json;
+
+    use super::RevokedSessions;
+    use crate::openid::{bearer::LogoutTokenClaims, client::OpenIdToken, User, UserCache};
+
+    fn token(sid: &str, auth_time: i64, expires_in: i64) -> OpenIdToken {
+        let now = Utc::now().timestamp();
+        serde_json::from_value(json!({
+            "claims": {
+                "iss": "https://auth.somehost.org/realms/sequeda",
+                "aud": "sequeda-auth",
+                "exp": now + 300,
+                "iat": now,
+                "auth_time": auth_time,
+                "sub": "nb",
+                "sid": sid,
+                "realm_access": { "roles": ["creep"] }
+            },
+            "token": { "access_token": "at", "token_type": "bearer" },
+            "expires_at": now + expires_in
+        }))
+        .unwrap()
+    }
+
+    fn logout(logout: serde_json::Value) -> LogoutTokenClaims {
+        serde_json::from_value(logout).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_revoked_sessions() {
+        let now = Utc::now().timestamp();
+        let revoked = RevokedSessions::new(None, Duration::from_secs(60));
+        let session = token("sid-1", now - 100, 300);
+        let other_session = token("sid-2", now - 100, 300);
+        assert!(!session.needs_refresh());
+        assert!(token("sid-1", now, 10).needs_refresh());
+        assert!(!revoked.is_revoked(&session).await);
+
+        revoked
+            .revoke(&logout(json!({"sid": "sid-1", "iat": now, "events": {}})))
+            .await;
+        assert!(revoked.is_revoked(&session).await);
+        assert!(!revoked.is_revoked(&other_session).await);
+
+        // every session of the user logged in before the logout
+        revoked
+            .revoke(&logout(json!({"sub": "nb", "iat": now - 50, "events": {}})))
+            .await;
+        assert!(revoked.is_revoked(&other_session).await);
+        assert!(!revoked.is_revoked(&token("sid-3", now, 300)).await);
+
+        let cache = UserCache::default();
+        let user = User::from_claims(session.claims.as_ref().unwrap());
+        cache.insert(
+            "s1",
+            user.clone(),
+            Some("sid-1".into()),
+            session.expiration(),
+        );
+        cache.insert("s2", user, Some("sid-2".into()), session.expiration());
+        assert!(cache.get("s1").is_some());
+        cache.evict(Some("sid-1"), Some("nb"));
+        assert!(cache.get("s1").is_none());
+        assert!(cache.get("s2").is_some());
+        cache.evict(None, Some("nb"));
+        assert!(cache.get("s2").is_none());
+    }
+}
@@ -1,16 +1,27 @@
+mod api_key;
 mod auth_redirect;
 mod auth_request;
+mod bearer;
 mod client;
+mod demo;
+mod logout;
 mod reqwest_client;
 mod router;
+mod session;
 mod user;
+mod user_cache;
 
-use async_redis_session_v2::RedisSessionStore;
+pub use api_key::{api_key_router, check_admin, ApiKeyStore};
 use async_session::{Session, SessionStore};
 pub use auth_redirect::LoginPageRedirect;
+pub use bearer::BearerValidator;
 pub use client::OpenIdClient;
+pub use demo::{DemoAccount, DemoSession};
+pub use logout::RevokedSessions;
 pub use router::open_id_router;
-pub use user::User;
+pub use session::{session_cookie, PendingSessionCookie, SessionBackend, SessionConfig};
+pub use user::{MachineClient, User};
+pub use user_cache::UserCache;
 
 use openidconnect::core::{
     CoreAuthDisplay, CoreAuthPrompt, CoreClaimName, CoreClaimType, CoreClientAuthMethod,
@@ -74,6 +85,7 @@ type OpenIdProviderMetadata = ProviderMetadata<
 #[derive(Clone, Debug, Deserialize, Serialize)]
 struct RevocationEndpointProviderMetadata {
     revocation_endpoint: String,
+    introspection_endpoint: Option<String>,
 }
 
 impl AdditionalProviderMetadata for RevocationEndpointProviderMetadata {}
@@ -83,6 +95,8 @@ pub struct AllOtherClaims {
     realm_access: RealmAccess,
     groups: Option<Vec<String>>,
     tenant: Option<String>,
+    /// session of the provider, used by the back-channel logout
+    sid: Option<String>,
 }
 #[derive(Debug, Deserialize, Clone, Serialize)]
 pub struct RealmAccess {
@@ -92,11 +106,19 @@ impl AdditionalClaims for AllOtherClaims {}
 
 pub type CustomIdTokenClaims = IdTokenClaims<AllOtherClaims, CoreGenderClaim>;
 
-pub async fn destroy_session(store: &RedisSessionStore, session: Session) -> LoginPageRedirect {
+/// destroys the session and removes the cookie, when the response goes through the session middleware
+pub async fn destroy_session(
+    store: &SessionBackend,
+    session: Session,
+    pending_cookie: Option<&PendingSessionCookie>,
+) -> LoginPageRedirect {
     if let Err(e) = store.destroy_session(session).await {
         tracing::error!("{e}");
     }
-    LoginPageRedirect
+    if let Some(pending_cookie) = pending_cookie {
+        pending_cookie.remove();
+    }
+    LoginPageRedirect::default()
 }
 
 #[derive(Clone)]
@@ -105,5 +127,7 @@ pub struct AuthConfig {
     pub auth_redirect: String,
     pub redirect_url: String,
     pub root_url: String,
-    pub demo_account: bool,
+    pub demo_account: Option<DemoAccount>,
+    /// to find the client ip of the demo visitors
+    pub trusted_proxies: usize,
 }
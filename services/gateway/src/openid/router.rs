@@ -1,29 +1,32 @@
-use async_redis_session_v2::RedisSessionStore;
+use std::time::Duration;
 
 use crate::{constant::COOKIE_NAME, openid::user::User};
 use async_session::{Session, SessionStore};
 use axum::{
-    extract::{Path, Query},
-    response::{IntoResponse, Redirect},
-    routing::get,
+    extract::Query,
+    response::{IntoResponse, Redirect, Response},
     routing::Router,
+    routing::{get, post},
     Extension, Json,
 };
 use axum_extra::headers;
 use axum_extra::TypedHeader;
-use hyper::{header::SET_COOKIE, HeaderMap, StatusCode};
-use openidconnect::Nonce;
+use hyper::StatusCode;
+use openidconnect::{Nonce, PkceCodeChallenge, PkceCodeVerifier};
+use serde::{Deserialize, Serialize};
 
 use super::{
-    auth_redirect::LoginPageRedirect,
+    auth_redirect::{safe_return_to, LoginError, LoginPageRedirect},
     auth_request::AuthRequest,
     client::{OpenIdClient, OpenIdToken},
-    destroy_session, AuthConfig,
+    destroy_session,
+    logout::backchannel_logout,
+    AuthConfig, PendingSessionCookie, SessionBackend, SessionConfig, UserCache,
 };
 
 pub async fn open_id_router(
     auth_config: AuthConfig,
-    store: RedisSessionStore,
+    store: SessionBackend,
     openid_client: OpenIdClient,
 ) -> Router {
     Router::new()
@@ -31,29 +34,88 @@ pub async fn open_id_router(
         // .route("/login-credentials", post(login_credentials))
         .route(&auth_config.auth_redirect, get(login_authorized))
         .route("/logout", get(logout))
+        .route("/logout/backchannel", post(backchannel_logout))
         .route("/@me", get(user_info))
         .layer(Extension(store))
         .layer(Extension(openid_client))
         .layer(Extension(auth_config))
 }
 
+/// how long the user has to log in at the provider
+const LOGIN_TTL: Duration = Duration::from_secs(600);
+const LOGIN_STATE_PREFIX: &str = "login:";
+const DEFAULT_RETURN_TO: &str = "/@me";
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    return_to: Option<String>,
+}
+
+/// Kept in the session between the redirection to the provider and its callback,
+/// under the key `login:<state>`, so that several logins can be pending in the same browser
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    nonce: Nonce,
+    pkce_verifier: String,
+    return_to: Option<String>,
+}
+
 async fn login(
+    Query(query): Query<LoginQuery>,
     Extension(client): Extension<OpenIdClient>,
-    Extension(config): Extension<AuthConfig>,
+    Extension(store): Extension<SessionBackend>,
+    Extension(pending_cookie): Extension<PendingSessionCookie>,
+    optional_cookies: Option<TypedHeader<headers::Cookie>>,
     user: Option<User>,
-) -> impl IntoResponse {
+) -> Result<Response, LoginError> {
+    let return_to = query.return_to.as_deref().and_then(safe_return_to);
     if user.is_some() {
-        return Redirect::permanent("/@me").into_response();
+        return Ok(Redirect::to(return_to.as_deref().unwrap_or(DEFAULT_RETURN_TO)).into_response());
+    }
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (authorize_url, state, nonce) = client.get_authorize_url(pkce_challenge);
+
+    let cookie = optional_cookies
+        .and_then(|cookies| cookies.get(COOKIE_NAME).map(|cookie| cookie.to_string()));
+    let existing = match cookie.clone() {
+        Some(cookie) => store.load_session(cookie).await.ok().flatten(),
+        None => None,
+    };
+    // a session of an authenticated user is never reused, the user would not be here otherwise
+    let mut session = existing
+        .filter(|session| session.get_raw("token").is_none())
+        .unwrap_or_default();
+    session.expire_in(LOGIN_TTL);
+    session
+        .insert(
+            &format!("{LOGIN_STATE_PREFIX}{}", state.secret()),
+            PendingLogin {
+                nonce,
+                pkce_verifier: pkce_verifier.secret().to_string(),
+                return_to,
+            },
+        )
+        .map_err(|_| LoginError::internal("could not start the login"))?;
+    match store.store_session(session).await {
+        Ok(Some(value)) => pending_cookie.set(value),
+        Ok(None) => {
+            if let Some(cookie) = cookie {
+                pending_cookie.set(cookie);
+            }
+        }
+        Err(e) => {
+            tracing::error!("could not store the login session: {e}");
+            return Err(LoginError::internal("could not start the login"));
+        }
     }
-    let nonce = Nonce::new_random();
-    let redirect_url = format!("{}/{}", &config.redirect_url, nonce.secret());
-    let (_, authorize_url, _, _) = client.get_authorize_url(&redirect_url, nonce);
-    Redirect::temporary(authorize_url.as_str()).into_response()
+    Ok(Redirect::temporary(authorize_url.as_str()).into_response())
 }
 
 async fn logout(
     Extension(client): Extension<OpenIdClient>,
-    Extension(store): Extension<RedisSessionStore>,
+    Extension(store): Extension<SessionBackend>,
+    Extension(pending_cookie): Extension<PendingSessionCookie>,
+    Extension(user_cache): Extension<UserCache>,
     optional_cookies: Option<TypedHeader<headers::Cookie>>,
 ) -> impl IntoResponse {
     let cookie = if let Some(cookies) = optional_cookies
@@ -61,12 +123,16 @@ async fn logout(
     {
         cookies
     } else {
-        return LoginPageRedirect;
+        return LoginPageRedirect::default();
     };
     let session = match store.load_session(cookie).await {
         Ok(Some(s)) => s,
-        Ok(None) | Err(_) => return LoginPageRedirect,
+        Ok(None) | Err(_) => {
+            pending_cookie.remove();
+            return LoginPageRedirect::default();
+        }
     };
+    user_cache.remove(session.id());
     if let Some(id_token) = session.get::<OpenIdToken>("token") {
         match client.logout(&id_token).await {
             Ok(_) => {}
@@ -76,7 +142,7 @@ async fn logout(
         }
     }
 
-    destroy_session(&store, session).await
+    destroy_session(&store, session, Some(&pending_cookie)).await
 }
 
 async fn user_info(user: User) -> impl IntoResponse {
@@ -87,7 +153,7 @@ async fn user_info(user: User) -> impl IntoResponse {
 //     user: Option<User>,
 //     Query(params): Query<HashMap<String, String>>,
 //     Extension(client): Extension<OpenIdClient>,
-//     Extension(store): Extension<RedisSessionStore>,
+//     Extension(store): Extension<SessionBackend>,
 // ) -> impl IntoResponse {
 //     if user.is_some() {
 //         return Redirect::permanent("/@me").into_response();
@@ -113,26 +179,72 @@ async fn user_info(user: User) -> impl IntoResponse {
 
 async fn login_authorized(
     Query(query): Query<AuthRequest>,
-    Path(nonce): Path<Nonce>,
-    Extension(config): Extension<AuthConfig>,
     Extension(client): Extension<OpenIdClient>,
-    Extension(store): Extension<RedisSessionStore>,
-) -> impl IntoResponse {
-    let redirect_url = format!("{}/{}", &config.redirect_url, nonce.secret());
-    let client = client.with_redirect_url(&redirect_url);
-    let token = client.exchange_token(query, nonce).await.unwrap();
-    tracing::debug!("{:?}", &token);
-    let mut session = Session::new();
-    session.insert("token", token).unwrap();
+    Extension(store): Extension<SessionBackend>,
+    Extension(session_config): Extension<SessionConfig>,
+    Extension(pending_cookie): Extension<PendingSessionCookie>,
+    optional_cookies: Option<TypedHeader<headers::Cookie>>,
+) -> Result<Response, LoginError> {
+    if let Some(error) = query.error {
+        tracing::warn!(
+            "login refused by the provider: {error} {}",
+            query.error_description.unwrap_or_default()
+        );
+        return Err(LoginError::bad_request("The login was refused."));
+    }
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        return Err(LoginError::bad_request("Invalid login response."));
+    };
+    let expired = || LoginError::bad_request("The login has expired, please try again.");
+    let cookie = optional_cookies
+        .and_then(|cookies| cookies.get(COOKIE_NAME).map(|cookie| cookie.to_string()))
+        .ok_or_else(expired)?;
+    let login_session = store
+        .load_session(cookie)
+        .await
+        .ok()
+        .flatten()
+        .ok_or_else(expired)?;
+    let state_key = format!("{LOGIN_STATE_PREFIX}{state}");
+    let pending_login: PendingLogin = login_session.get(&state_key).ok_or_else(|| {
+        tracing::warn!("unknown login state");
+        expired()
+    })?;
 
-    // Store session and get corresponding cookie
-    let cookie = store.store_session(session).await.unwrap().unwrap();
+    let token = client
+        .exchange_token(
+            code,
+            &pending_login.nonce,
+            PkceCodeVerifier::new(pending_login.pkce_verifier),
+        )
+        .await
+        .map_err(|e| {
+            tracing::error!("{e}");
+            LoginError::internal("Could not complete the login.")
+        })?;
+    tracing::debug!("{:?}", &token);
 
-    // Build the cookie
-    let cookie = format!("{}={}; SameSite=Lax; Path=/", COOKIE_NAME, cookie);
+    // a new session id once logged in, the login session may be known by someone else
+    if let Err(e) = store.destroy_session(login_session).await {
+        tracing::error!("could not destroy the login session: {e}");
+    }
+    let mut session = Session::new();
+    session_config.init(&mut session);
+    session
+        .insert("token", token)
+        .map_err(|_| LoginError::internal("Could not complete the login."))?;
+    let cookie = store
+        .store_session(session)
+        .await
+        .map_err(|e| {
+            tracing::error!("could not store the session: {e}");
+            LoginError::internal("Could not complete the login.")
+        })?
+        .ok_or_else(|| LoginError::internal("Could not complete the login."))?;
+    pending_cookie.set(cookie);
 
-    // Set cookie
-    let mut headers = HeaderMap::new();
-    headers.insert(SET_COOKIE, cookie.parse().unwrap());
-    (headers, Redirect::to("/@me")) // todo change this
+    let return_to = pending_login
+        .return_to
+        .unwrap_or_else(|| DEFAULT_RETURN_TO.to_string());
+    Ok(Redirect::to(&return_to).into_response())
 }
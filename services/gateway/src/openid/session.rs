@@ -0,0 +1,555 @@
+use std::{
+    env::var,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_redis_session_v2::RedisSessionStore;
+use async_session::{async_trait, MemoryStore, Session, SessionStore};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use base64::Engine;
+use cookie::{Cookie, SameSite};
+use hyper::header::SET_COOKIE;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use super::client::OpenIdToken;
+use crate::constant::{
+    APP_ROOT_URL, COOKIE_NAME, REDIS_URL, SESSION_COOKIE_HTTP_ONLY, SESSION_COOKIE_KEY,
+    SESSION_COOKIE_SAME_SITE, SESSION_COOKIE_SECURE, SESSION_SLIDING, SESSION_STORE, SESSION_TTL,
+};
+
+const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(8 * 3600);
+const MEMORY_STORE_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// browsers drop the cookies bigger than 4096 bytes, name and attributes included
+const MAX_COOKIE_VALUE_SIZE: usize = 3900;
+
+/// Where the sessions are kept, selected with `SESSION_STORE`
+#[derive(Clone, Debug)]
+pub enum SessionBackend {
+    /// shared by all the instances of the gateway
+    Redis(RedisSessionStore),
+    /// lost on restart, for local development and tests
+    Memory(MemoryStore),
+    /// the whole session is encrypted in the cookie, nothing is stored server side
+    Cookie(EncryptedCookieStore),
+}
+
+impl SessionBackend {
+    pub fn from_env() -> Result<Self, SessionError> {
+        let kind = var(SESSION_STORE).unwrap_or_else(|_| "redis".into());
+        match kind.trim().to_lowercase().as_str() {
+            "redis" => {
+                let redis_url = var(REDIS_URL).map_err(|_| SessionError {
+                    msg: format!(
+                        "Missing the {REDIS_URL} environment variable. e.g `redis://127.0.0.1`"
+                    ),
+                })?;
+                let store =
+                    RedisSessionStore::new(redis_url.as_str()).map_err(|e| SessionError {
+                        msg: format!("invalid {REDIS_URL}: {e}"),
+                    })?;
+                Ok(SessionBackend::Redis(store))
+            }
+            "memory" => {
+                tracing::warn!("sessions are kept in memory, they are lost on restart and not shared between instances");
+                let store = MemoryStore::new();
+                let cleanup = store.clone();
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(MEMORY_STORE_CLEANUP_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = cleanup.cleanup().await {
+                            tracing::error!("could not clean up the sessions: {e}");
+                        }
+                    }
+                });
+                Ok(SessionBackend::Memory(store))
+            }
+            "cookie" => {
+                let store = match var(SESSION_COOKIE_KEY) {
+                    Ok(key) => EncryptedCookieStore::from_base64(&key)?,
+                    Err(_) => {
+                        tracing::warn!("{SESSION_COOKIE_KEY} is not set, the sessions will not survive a restart");
+                        EncryptedCookieStore::random()?
+                    }
+                };
+                Ok(SessionBackend::Cookie(store))
+            }
+            other => Err(SessionError {
+                msg: format!("unknown {SESSION_STORE} {other}, expected redis, memory or cookie"),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for SessionBackend {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        match self {
+            SessionBackend::Redis(store) => store.load_session(cookie_value).await,
+            SessionBackend::Memory(store) => store.load_session(cookie_value).await,
+            SessionBackend::Cookie(store) => store.load_session(cookie_value).await,
+        }
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        match self {
+            SessionBackend::Redis(store) => store.store_session(session).await,
+            SessionBackend::Memory(store) => store.store_session(session).await,
+            SessionBackend::Cookie(store) => store.store_session(session).await,
+        }
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        match self {
+            SessionBackend::Redis(store) => store.destroy_session(session).await,
+            SessionBackend::Memory(store) => store.destroy_session(session).await,
+            SessionBackend::Cookie(store) => store.destroy_session(session).await,
+        }
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        match self {
+            SessionBackend::Redis(store) => store.clear_store().await,
+            SessionBackend::Memory(store) => store.clear_store().await,
+            SessionBackend::Cookie(store) => store.clear_store().await,
+        }
+    }
+}
+
+/// Session serialized in the cookie and encrypted with AES-256-GCM.
+/// Only the refresh token is kept, the user is cached by the gateway.
+/// A destroyed session can only be forgotten by removing the cookie,
+/// it stays valid until its expiry if the client kept a copy.
+#[derive(Clone)]
+pub struct EncryptedCookieStore {
+    key: Arc<LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl std::fmt::Debug for EncryptedCookieStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedCookieStore").finish()
+    }
+}
+
+impl EncryptedCookieStore {
+    pub fn new(key: &[u8]) -> Result<Self, SessionError> {
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| SessionError {
+            msg: format!(
+                "the session key must be {} bytes long",
+                AES_256_GCM.key_len()
+            ),
+        })?;
+        Ok(EncryptedCookieStore {
+            key: Arc::new(LessSafeKey::new(key)),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// e.g. `openssl rand -base64 32`
+    pub fn from_base64(key: &str) -> Result<Self, SessionError> {
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(key.trim())
+            .map_err(|e| SessionError {
+                msg: format!("invalid {SESSION_COOKIE_KEY}: {e}"),
+            })?;
+        Self::new(&key)
+    }
+
+    fn random() -> Result<Self, SessionError> {
+        let mut key = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| SessionError {
+                msg: "could not generate the session key".into(),
+            })?;
+        Self::new(&key)
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Option<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+        let mut in_out = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(COOKIE_NAME.as_bytes()),
+                &mut in_out,
+            )
+            .ok()?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(in_out);
+        Some(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    fn decrypt(&self, cookie_value: &str) -> Option<Vec<u8>> {
+        let sealed = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cookie_value)
+            .ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::from(COOKIE_NAME.as_bytes()), &mut in_out)
+            .ok()?;
+        Some(plaintext.to_vec())
+    }
+}
+
+#[async_trait]
+impl SessionStore for EncryptedCookieStore {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        // a cookie that cannot be decrypted (tampered, or sealed with another key) is no session
+        let Some(plaintext) = self.decrypt(&cookie_value) else {
+            return Ok(None);
+        };
+        let session: Session = serde_json::from_slice(&plaintext)?;
+        Ok(session.validate())
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        // the data is shared with the clones of the session, it is compacted in the copy
+        let mut value = serde_json::to_value(&session)?;
+        if let Some(token) = session.get::<OpenIdToken>("token") {
+            value["data"]["token"] = serde_json::to_string(&token.compact())?.into();
+        }
+        let plaintext = serde_json::to_vec(&value)?;
+        let cookie_value = self
+            .encrypt(&plaintext)
+            .ok_or_else(|| async_session::Error::msg("could not encrypt the session"))?;
+        if cookie_value.len() > MAX_COOKIE_VALUE_SIZE {
+            return Err(async_session::Error::msg(format!(
+                "the session cookie would be {} bytes long, browsers drop it",
+                cookie_value.len()
+            )));
+        }
+        session.reset_data_changed();
+        Ok(Some(cookie_value))
+    }
+
+    async fn destroy_session(&self, _session: Session) -> async_session::Result {
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        Ok(())
+    }
+}
+
+/// Expiry and cookie flags of the sessions
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    /// `None`: the session lasts until logout, in a browser session cookie
+    pub ttl: Option<Duration>,
+    /// extend the session on activity
+    pub sliding: bool,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: SameSite,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            ttl: Some(DEFAULT_SESSION_TTL),
+            sliding: true,
+            secure: true,
+            http_only: true,
+            same_site: SameSite::Lax,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn from_env() -> Result<Self, SessionError> {
+        let parse_bool = |name: &str, default: bool| -> Result<bool, SessionError> {
+            var(name)
+                .ok()
+                .map(|value| {
+                    value.trim().parse::<bool>().map_err(|e| SessionError {
+                        msg: format!("invalid {name}: {e}"),
+                    })
+                })
+                .transpose()
+                .map(|value| value.unwrap_or(default))
+        };
+        let ttl = match var(SESSION_TTL) {
+            Ok(ttl) => match ttl.trim().parse::<u64>() {
+                Ok(0) => None,
+                Ok(secs) => Some(Duration::from_secs(secs)),
+                Err(e) => {
+                    return Err(SessionError {
+                        msg: format!("invalid {SESSION_TTL}: {e}"),
+                    })
+                }
+            },
+            Err(_) => Some(DEFAULT_SESSION_TTL),
+        };
+        // by default, the cookie is secure when the gateway is served over https
+        let https = var(APP_ROOT_URL)
+            .map(|url| url.starts_with("https://"))
+            .unwrap_or(true);
+        let same_site = match var(SESSION_COOKIE_SAME_SITE)
+            .map(|s| s.trim().to_lowercase())
+            .as_deref()
+        {
+            Err(_) | Ok("lax") => SameSite::Lax,
+            Ok("strict") => SameSite::Strict,
+            Ok("none") => SameSite::None,
+            Ok(other) => {
+                return Err(SessionError {
+                    msg: format!("invalid {SESSION_COOKIE_SAME_SITE} {other}"),
+                })
+            }
+        };
+        Ok(SessionConfig {
+            ttl,
+            sliding: parse_bool(SESSION_SLIDING, true)?,
+            secure: parse_bool(SESSION_COOKIE_SECURE, https)?,
+            http_only: parse_bool(SESSION_COOKIE_HTTP_ONLY, true)?,
+            same_site,
+        })
+    }
+
+    /// sets the expiry of a new session
+    pub fn init(&self, session: &mut Session) {
+        if let Some(ttl) = self.ttl {
+            session.expire_in(ttl);
+        }
+    }
+
+    /// with a sliding expiry, the session is extended once half of its ttl has elapsed,
+    /// rather than on every request
+    pub fn renew(&self, session: &mut Session) -> bool {
+        match (self.sliding, self.ttl, session.expires_in()) {
+            (true, Some(ttl), Some(expires_in)) if expires_in < ttl / 2 => {
+                session.expire_in(ttl);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn cookie(&self, value: String) -> Cookie<'static> {
+        let mut cookie = self.base_cookie(value);
+        if let Some(ttl) = self.ttl {
+            cookie.set_max_age(cookie::time::Duration::seconds(ttl.as_secs() as i64));
+        }
+        cookie
+    }
+
+    pub fn removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = self.base_cookie(String::new());
+        cookie.make_removal();
+        cookie
+    }
+
+    fn base_cookie(&self, value: String) -> Cookie<'static> {
+        Cookie::build((COOKIE_NAME, value))
+            .path("/")
+            .secure(self.secure)
+            .http_only(self.http_only)
+            .same_site(self.same_site)
+            .build()
+    }
+}
+
+/// Session cookie to send back with the response, set by the `User` extractor
+/// when the session is renewed or destroyed
+#[derive(Clone, Debug)]
+pub struct PendingSessionCookie {
+    config: SessionConfig,
+    cookie: Arc<Mutex<Option<Cookie<'static>>>>,
+}
+
+impl PendingSessionCookie {
+    pub(super) fn new(config: SessionConfig) -> Self {
+        PendingSessionCookie {
+            config,
+            cookie: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn set(&self, value: String) {
+        *self.cookie.lock().unwrap() = Some(self.config.cookie(value));
+    }
+
+    pub fn remove(&self) {
+        *self.cookie.lock().unwrap() = Some(self.config.removal_cookie());
+    }
+
+    pub(super) fn take(&self) -> Option<Cookie<'static>> {
+        self.cookie.lock().unwrap().take()
+    }
+}
+
+/// middleware writing the pending session cookie in the response
+pub async fn session_cookie(
+    State(config): State<SessionConfig>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let pending = PendingSessionCookie::new(config.clone());
+    req.extensions_mut().insert(config);
+    req.extensions_mut().insert(pending.clone());
+    let mut response = next.run(req).await;
+    if let Some(cookie) = pending.take() {
+        match cookie.to_string().parse() {
+            Ok(cookie) => {
+                response.headers_mut().append(SET_COOKIE, cookie);
+            }
+            Err(e) => tracing::error!("invalid session cookie: {e}"),
+        }
+    }
+    response
+}
+
+#[derive(Debug)]
+pub struct SessionError {
+    pub msg: String,
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use async_session::{Session, SessionStore};
+    use cookie::SameSite;
+
+    use super::{EncryptedCookieStore, SessionConfig};
+    use crate::openid::client::OpenIdToken;
+    use openidconnect::OAuth2TokenResponse;
+
+    #[tokio::test]
+    async fn test_encrypted_cookie_store() {
+        let store = EncryptedCookieStore::new(&[7u8; 32]).unwrap();
+        let mut session = Session::new();
+        session.expire_in(Duration::from_secs(60));
+        session.insert("token", "secret value").unwrap();
+        let cookie_value = store.store_session(session.clone()).await.unwrap().unwrap();
+        assert!(!cookie_value.contains("secret"));
+
+        let loaded = store
+            .load_session(cookie_value.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(session.id(), loaded.id());
+        assert_eq!(Some("secret value".to_string()), loaded.get("token"));
+
+        // tampered
+        let mut tampered = cookie_value.into_bytes();
+        let last = tampered.len() - 2;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(store.load_session(tampered).await.unwrap().is_none());
+
+        // another key
+        let other = EncryptedCookieStore::new(&[8u8; 32]).unwrap();
+        let cookie_value = other.store_session(session).await.unwrap().unwrap();
+        assert!(store.load_session(cookie_value).await.unwrap().is_none());
+
+        // expired
+        let mut session = Session::new();
+        session.set_expiry(chrono::Utc::now() - chrono::Duration::seconds(1));
+        let cookie_value = store.store_session(session).await.unwrap().unwrap();
+        assert!(store.load_session(cookie_value).await.unwrap().is_none());
+
+        assert!(EncryptedCookieStore::new(&[7u8; 16]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cookie_store_keeps_the_refresh_token() {
+        let store = EncryptedCookieStore::new(&[7u8; 32]).unwrap();
+        let token: OpenIdToken = serde_json::from_value(serde_json::json!({
+            "claims": null,
+            "token": {
+                "access_token": "a".repeat(4000),
+                "token_type": "bearer",
+                "refresh_token": "rt",
+                "id_token": null
+            },
+            "expires_at": 1000
+        }))
+        .unwrap();
+        let mut session = Session::new();
+        session.insert("token", &token).unwrap();
+        let cookie_value = store.store_session(session.clone()).await.unwrap().unwrap();
+        let loaded: OpenIdToken = store
+            .load_session(cookie_value)
+            .await
+            .unwrap()
+            .unwrap()
+            .get("token")
+            .unwrap();
+        assert!(loaded.is_compact());
+        assert_eq!(Some(1000), loaded.expires_at);
+        assert_eq!(
+            Some("rt"),
+            loaded.token.refresh_token().map(|t| t.secret().as_str())
+        );
+        // the session itself is left untouched
+        let kept: OpenIdToken = session.get("token").unwrap();
+        assert!(!kept.is_compact());
+
+        let mut session = Session::new();
+        session.insert("other", "a".repeat(4000)).unwrap();
+        assert!(store.store_session(session).await.is_err());
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        let config = SessionConfig::default();
+        let cookie = config.cookie("abc".into()).to_string();
+        assert!(cookie.starts_with("SEQUEDA_SESSION=abc"));
+        assert!(cookie.contains("HttpOnly"));
+        assert!(cookie.contains("Secure"));
+        assert!(cookie.contains("SameSite=Lax"));
+        assert!(cookie.contains("Path=/"));
+        assert!(cookie.contains("Max-Age=28800"));
+
+        let config = SessionConfig {
+            ttl: None,
+            secure: false,
+            same_site: SameSite::Strict,
+            ..Default::default()
+        };
+        let cookie = config.cookie("abc".into()).to_string();
+        assert!(!cookie.contains("Secure"));
+        assert!(!cookie.contains("Max-Age"));
+        assert!(cookie.contains("SameSite=Strict"));
+        assert!(config.removal_cookie().to_string().contains("Max-Age=0"));
+
+        let config = SessionConfig::default();
+        let mut session = Session::new();
+        config.init(&mut session);
+        assert!(!config.renew(&mut session), "renewed too early");
+        session.expire_in(Duration::from_secs(60));
+        assert!(config.renew(&mut session));
+        assert!(session.expires_in().unwrap() > Duration::from_secs(3600));
+    }
+}
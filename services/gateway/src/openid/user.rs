@@ -1,14 +1,24 @@
+use super::{
+    api_key::is_api_key, ApiKeyStore, BearerValidator, PendingSessionCookie, RevokedSessions,
+    SessionBackend, UserCache,
+};
 use super::{
     auth_redirect::LoginPageRedirect,
-    client::{OpenIdClient, OpenIdToken},
+    client::{ClientError, OpenIdClient, OpenIdToken},
     AllOtherClaims, AuthConfig, CustomIdTokenClaims,
 };
-use crate::{constant::COOKIE_NAME, openid::destroy_session};
-use async_redis_session_v2::RedisSessionStore;
+use crate::{
+    constant::{COOKIE_NAME, X_API_KEY},
+    openid::destroy_session,
+    request_handler::client_ip,
+};
 use async_session::{async_trait, SessionStore};
 use axum::{extract::FromRequestParts, http::request::Parts, Extension};
 use axum_extra::{headers, typed_header::TypedHeaderRejectionReason, TypedHeader};
-use hyper::header::{self};
+use hyper::{
+    header::{self},
+    HeaderMap, Method,
+};
 use openidconnect::{core::CoreGenderClaim, UserInfoClaims};
 use serde::{Deserialize, Serialize};
 
@@ -124,39 +134,97 @@ impl User {
         }
     }
 
+    /// the token is refreshed when it is about to expire, and written back to the session.
+    /// In between, the user is served from the cache
     pub async fn from_cookie(
-        store: RedisSessionStore,
+        store: SessionBackend,
         client: OpenIdClient,
         cookies: headers::Cookie,
+        pending_cookie: Option<&PendingSessionCookie>,
+        user_cache: &UserCache,
+        revoked_sessions: &RevokedSessions,
     ) -> Result<Self, LoginPageRedirect> {
-        let session_cookie = cookies.get(COOKIE_NAME).ok_or(LoginPageRedirect)?;
+        let session_cookie = cookies
+            .get(COOKIE_NAME)
+            .ok_or_else(LoginPageRedirect::default)?;
 
-        let session = store
+        let mut session = store
             .load_session(session_cookie.to_string())
             .await
-            .map_err(|_| LoginPageRedirect)?
-            .ok_or(LoginPageRedirect)?;
+            .map_err(|_| LoginPageRedirect::default())?
+            .ok_or_else(LoginPageRedirect::default)?;
 
-        let id_token: OpenIdToken = session.get("token").ok_or(LoginPageRedirect)?;
+        // no token: a login in progress
+        let id_token: OpenIdToken = session
+            .get("token")
+            .ok_or_else(LoginPageRedirect::default)?;
+        let session_id = session.id().to_string();
 
-        let id_token = match client.refresh_token(id_token).await {
-            Ok(id) => id,
-            Err(e) => {
-                tracing::error!("{e}");
-                return Err(destroy_session(&store, session).await);
+        if revoked_sessions.is_revoked(&id_token).await {
+            tracing::info!("session logged out by the provider");
+            user_cache.remove(&session_id);
+            return Err(destroy_session(&store, session, pending_cookie).await);
+        }
+
+        let mut changed =
+            pending_cookie.is_some_and(|pending| pending.config().renew(&mut session));
+        let cached = if id_token.needs_refresh() {
+            None
+        } else {
+            user_cache.get(&session_id)
+        };
+        let user = match cached {
+            Some(user) => user,
+            None => {
+                // a session kept in a cookie has no access token
+                let id_token = if id_token.needs_refresh() || id_token.is_compact() {
+                    match client.refresh_token(id_token).await {
+                        Ok(id_token) => {
+                            if let Err(e) = session.insert("token", &id_token) {
+                                tracing::error!("could not update the token: {e}");
+                            }
+                            changed = true;
+                            id_token
+                        }
+                        Err(e) => {
+                            tracing::error!("{e}");
+                            user_cache.remove(&session_id);
+                            return Err(destroy_session(&store, session, pending_cookie).await);
+                        }
+                    }
+                } else {
+                    id_token
+                };
+                let user = match &id_token.claims.as_ref() {
+                    Some(claims) => User::from_claims(claims),
+                    None => match client.exchange_access_token(&id_token, None).await {
+                        Ok(user_info) => User::from_user_info(&user_info),
+                        Err(e) => {
+                            tracing::error!("{e}");
+                            return Err(destroy_session(&store, session, pending_cookie).await);
+                        }
+                    },
+                };
+                let sid = id_token
+                    .claims
+                    .as_ref()
+                    .and_then(|claims| claims.additional_claims().sid.clone());
+                user_cache.insert(&session_id, user.clone(), sid, id_token.expiration());
+                user
             }
         };
 
-        let user = match &id_token.claims.as_ref() {
-            Some(claims) => User::from_claims(claims),
-            None => match client.exchange_access_token(&id_token, None).await {
-                Ok(user_info) => User::from_user_info(&user_info),
-                Err(e) => {
-                    tracing::error!("{e}");
-                    return Err(destroy_session(&store, session).await);
+        if changed {
+            match store.store_session(session).await {
+                Ok(cookie) => {
+                    if let Some(pending_cookie) = pending_cookie {
+                        // the cookie store seals a new cookie, the others keep the same id
+                        pending_cookie.set(cookie.unwrap_or_else(|| session_cookie.to_string()));
+                    }
                 }
-            },
-        };
+                Err(e) => tracing::error!("could not update the session: {e}"),
+            }
+        }
 
         tracing::debug!("user {user:?}");
 
@@ -164,6 +232,25 @@ impl User {
     }
 }
 
+/// Marks a request authenticated with an access token or an api key,
+/// which must be answered with an error rather than a redirect to the login page
+#[derive(Debug, Clone, Copy)]
+pub struct MachineClient;
+
+/// `Authorization: Bearer <token>`, or `X-API-KEY: <api key>`
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| {
+            h.strip_prefix("Bearer ")
+                .or_else(|| h.strip_prefix("bearer "))
+        })
+        .or_else(|| headers.get(X_API_KEY).and_then(|h| h.to_str().ok()))
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
 #[async_trait]
 impl<B> FromRequestParts<B> for User
 where
@@ -173,9 +260,9 @@ where
     type Rejection = LoginPageRedirect;
 
     async fn from_request_parts(req: &mut Parts, state: &B) -> Result<Self, Self::Rejection> {
-        let Extension(store) = Extension::<RedisSessionStore>::from_request_parts(req, state)
+        let Extension(store) = Extension::<SessionBackend>::from_request_parts(req, state)
             .await
-            .expect("`RedisSessionStore` extension is missing");
+            .expect("`SessionBackend` extension is missing");
 
         let Extension(config) = Extension::<AuthConfig>::from_request_parts(req, state)
             .await
@@ -185,43 +272,95 @@ where
             .await
             .expect("`OpenIdClient` extension is missing");
 
-        let fallback_user_demo = || {
-            Ok(User {
-                id: "demo-16ba6cdd-59cd-4bcc-b7ef-240af07153fd".into(),
-                full_name: Some("Account Demo".into()),
-                given_name: Some("Account".into()),
-                family_name: Some("Demo".into()),
-                middle_name: Some("AD".into()),
-                username: Some("demo".into()),
-                email: Some("demo@random.corp".into()),
-                roles: vec!["demo".into()], // todo should be configurable
-                groups: vec!["demogroup".into()],
-                tenant: Some("demo".into()),
-            })
-        };
+        let Extension(user_cache) = Extension::<UserCache>::from_request_parts(req, state)
+            .await
+            .expect("`UserCache` extension is missing");
+
+        let Extension(revoked_sessions) =
+            Extension::<RevokedSessions>::from_request_parts(req, state)
+                .await
+                .expect("`RevokedSessions` extension is missing");
+
+        // machine clients authenticate with each request, never with a session or the demo account
+        if let Some(token) = bearer_token(&req.headers) {
+            req.extensions.insert(MachineClient);
+            // the credentials are not forwarded to the upstream
+            req.headers.remove(header::AUTHORIZATION);
+            req.headers.remove(X_API_KEY);
+            let user = if is_api_key(&token) {
+                match Extension::<ApiKeyStore>::from_request_parts(req, state).await {
+                    Ok(Extension(api_keys)) => api_keys.authenticate(&token).await,
+                    Err(_) => Err(ClientError("api keys require redis".into())),
+                }
+            } else {
+                let Extension(validator) =
+                    Extension::<BearerValidator>::from_request_parts(req, state)
+                        .await
+                        .expect("`BearerValidator` extension is missing");
+                validator.validate(&token).await
+            };
+            return user.map_err(|e| {
+                tracing::debug!("invalid machine client credentials: {e}");
+                LoginPageRedirect::machine_client()
+            });
+        }
 
-        match TypedHeader::<headers::Cookie>::from_request_parts(req, state)
+        // brings the user back to the requested page once logged in
+        let return_to = (req.method == Method::GET)
+            .then(|| req.uri.path_and_query().map(|p| p.to_string()))
+            .flatten();
+
+        let cookies = TypedHeader::<headers::Cookie>::from_request_parts(req, state)
             .await
             .map_err(|e| match *e.name() {
                 header::COOKIE => match e.reason() {
-                    TypedHeaderRejectionReason::Missing => LoginPageRedirect,
+                    TypedHeaderRejectionReason::Missing => LoginPageRedirect::default(),
                     _ => {
                         tracing::error!("unexpected error getting Cookie header(s): {}", e);
-                        LoginPageRedirect
+                        LoginPageRedirect::default()
                     }
                 },
                 _ => {
                     tracing::error!("unexpected error getting cookies: {}", e);
-                    LoginPageRedirect
+                    LoginPageRedirect::default()
                 }
-            }) {
-            Ok(TypedHeader(cookies)) => match User::from_cookie(store, client, cookies).await {
-                user @ Ok(_) => user,
-                Err(_) if config.demo_account => fallback_user_demo(),
-                err @ Err(_) => err,
-            },
-            Err(_) if config.demo_account => fallback_user_demo(),
+            });
+        let session_cookie = cookies
+            .as_ref()
+            .ok()
+            .and_then(|TypedHeader(cookies)| cookies.get(COOKIE_NAME).map(|c| c.to_string()));
+        let pending_cookie = req.extensions.get::<PendingSessionCookie>().cloned();
+        let user = match cookies {
+            Ok(TypedHeader(cookies)) => {
+                User::from_cookie(
+                    store.clone(),
+                    client,
+                    cookies,
+                    pending_cookie.as_ref(),
+                    &user_cache,
+                    &revoked_sessions,
+                )
+                .await
+            }
             Err(e) => Err(e),
-        }
+        };
+        let user = match (user, &config.demo_account) {
+            (Err(_), Some(demo)) => {
+                let client_ip = client_ip(&req.headers, &req.extensions, config.trusted_proxies);
+                let (user, demo_session) = demo
+                    .user(
+                        &store,
+                        session_cookie.as_deref(),
+                        pending_cookie.as_ref(),
+                        &req.method,
+                        client_ip,
+                    )
+                    .await;
+                req.extensions.insert(demo_session);
+                Ok(user)
+            }
+            (user, _) => user,
+        };
+        user.map_err(|e| e.return_to(return_to))
     }
 }
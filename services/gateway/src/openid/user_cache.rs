@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_session::chrono::Utc;
+
+use super::User;
+
+/// how long a user is served from the cache, without asking the provider
+const USER_CACHE_TTL: Duration = Duration::from_secs(60);
+const MAX_CACHED_USERS: usize = 10_000;
+
+struct CachedUser {
+    user: User,
+    sid: Option<String>,
+    until: Instant,
+}
+
+/// Users of the sessions, so that the provider is only called to refresh the token,
+/// or to read the user info of a session without id token
+#[derive(Clone, Default)]
+pub struct UserCache(Arc<Mutex<HashMap<String, CachedUser>>>);
+
+impl std::fmt::Debug for UserCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserCache")
+            .field("len", &self.0.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl UserCache {
+    pub fn get(&self, session_id: &str) -> Option<User> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .filter(|cached| cached.until > Instant::now())
+            .map(|cached| cached.user.clone())
+    }
+
+    /// the user is never cached beyond the expiry of the access token (unix timestamp)
+    pub fn insert(
+        &self,
+        session_id: &str,
+        user: User,
+        sid: Option<String>,
+        expiration: Option<i64>,
+    ) {
+        let ttl = expiration
+            .map(
+                |exp| Duration::from_secs(exp.saturating_sub(Utc::now().timestamp()).max(0) as u64),
+            )
+            .unwrap_or(USER_CACHE_TTL)
+            .min(USER_CACHE_TTL);
+        let mut cache = self.0.lock().unwrap();
+        if cache.len() >= MAX_CACHED_USERS {
+            let now = Instant::now();
+            cache.retain(|_, cached| cached.until > now);
+        }
+        if cache.len() < MAX_CACHED_USERS {
+            cache.insert(
+                session_id.to_string(),
+                CachedUser {
+                    user,
+                    sid,
+                    until: Instant::now() + ttl,
+                },
+            );
+        }
+    }
+
+    pub fn remove(&self, session_id: &str) {
+        self.0.lock().unwrap().remove(session_id);
+    }
+
+    /// drops the sessions of a provider session, or of a user
+    pub fn evict(&self, sid: Option<&str>, sub: Option<&str>) {
+        self.0.lock().unwrap().retain(|_, cached| match (sid, sub) {
+            (Some(sid), _) => cached.sid.as_deref() != Some(sid),
+            (None, Some(sub)) => cached.user.id != sub,
+            (None, None) => true,
+        });
+    }
+}
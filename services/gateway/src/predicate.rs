@@ -0,0 +1,279 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use axum::{body::Body, http::Request};
+use hyper::header::{ACCESS_CONTROL_REQUEST_METHOD, COOKIE, HOST};
+use ipnet::IpNet;
+use openidconnect::url::form_urlencoded;
+use rand::Rng;
+use regex::Regex;
+
+use crate::{
+    config::{ConfigError, Predicate},
+    filter::is_preflight,
+};
+
+#[derive(Debug)]
+pub enum CompiledPredicate {
+    Host(String),
+    Path(Regex),
+    Method(String),
+    Header { name: String, value: Option<Regex> },
+    Query { name: String, value: Option<Regex> },
+    Cookie { name: String, value: Option<Regex> },
+    RemoteAddr(Vec<IpNet>),
+    Weight { group: String, route_id: String },
+}
+
+/// Per request state needed to evaluate the predicates
+pub struct MatchContext<'a> {
+    pub client_ip: Option<IpAddr>,
+    weight_groups: &'a WeightGroups,
+    /// route picked for each weight group, drawn once per request
+    picked_routes: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> MatchContext<'a> {
+    pub fn new(client_ip: Option<IpAddr>, weight_groups: &'a WeightGroups) -> Self {
+        MatchContext {
+            client_ip,
+            weight_groups,
+            picked_routes: HashMap::new(),
+        }
+    }
+
+    fn picked_route(&mut self, group: &str) -> Option<&'a str> {
+        if let Some(route_id) = self.picked_routes.get(group) {
+            return Some(route_id);
+        }
+        let (group, route_id) = self
+            .weight_groups
+            .0
+            .get_key_value(group)
+            .and_then(|(group, routes)| Some((group.as_str(), WeightGroups::pick(routes)?)))?;
+        self.picked_routes.insert(group, route_id);
+        Some(route_id)
+    }
+}
+
+/// Routes sharing a weight group, with their weight
+#[derive(Debug, Default)]
+pub struct WeightGroups(HashMap<String, Vec<(String, u32)>>);
+
+impl WeightGroups {
+    pub fn add(&mut self, group: &str, route_id: &str, weight: u32) {
+        self.0
+            .entry(group.to_string())
+            .or_default()
+            .push((route_id.to_string(), weight));
+    }
+
+    fn pick(routes: &[(String, u32)]) -> Option<&str> {
+        let total: u32 = routes.iter().map(|(_, w)| w).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut draw = rand::thread_rng().gen_range(0..total);
+        for (route_id, weight) in routes {
+            if draw < *weight {
+                return Some(route_id);
+            }
+            draw -= weight;
+        }
+        None
+    }
+}
+
+impl CompiledPredicate {
+    pub fn compile(
+        route_id: &str,
+        predicate: Predicate,
+        weight_groups: &mut WeightGroups,
+    ) -> Result<Self, ConfigError> {
+        let invalid = |what: &str, value: &str, e: &dyn std::error::Error| {
+            ConfigError::new(format!("route {route_id}: invalid {what} `{value}`: {e}"))
+        };
+        let optional_regex = |what: &str, value: Option<String>| {
+            value
+                .map(|v| Regex::new(&v).map_err(|e| invalid(what, &v, &e)))
+                .transpose()
+        };
+        let predicate = match predicate {
+            Predicate::Host(host) => CompiledPredicate::Host(host),
+            Predicate::Path(path) => CompiledPredicate::Path(
+                ant_pattern_to_regex(&path).map_err(|e| invalid("path", &path, &e))?,
+            ),
+            Predicate::PathRegex(path) => CompiledPredicate::Path(
+                Regex::new(&path).map_err(|e| invalid("path_regex", &path, &e))?,
+            ),
+            Predicate::Method(method) => CompiledPredicate::Method(method),
+            Predicate::Header { name, regex } => CompiledPredicate::Header {
+                value: optional_regex("header regex", regex)?,
+                name,
+            },
+            Predicate::Query { name, regex } => CompiledPredicate::Query {
+                value: optional_regex("query regex", regex)?,
+                name,
+            },
+            Predicate::Cookie { name, regex } => CompiledPredicate::Cookie {
+                value: optional_regex("cookie regex", regex)?,
+                name,
+            },
+            Predicate::RemoteAddr(addrs) => CompiledPredicate::RemoteAddr(
+                addrs
+                    .iter()
+                    .map(|addr| {
+                        addr.parse::<IpNet>()
+                            .or_else(|_| addr.parse::<IpAddr>().map(IpNet::from))
+                            .map_err(|e| invalid("remote_addr", addr, &e))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            Predicate::Weight { group, weight } => {
+                weight_groups.add(&group, route_id, weight);
+                CompiledPredicate::Weight {
+                    group,
+                    route_id: route_id.to_string(),
+                }
+            }
+        };
+        Ok(predicate)
+    }
+
+    pub fn match_req(&self, req: &Request<Body>, ctx: &mut MatchContext) -> bool {
+        let uri = req.uri();
+        match self {
+            CompiledPredicate::Host(host) => {
+                let host_from_req = uri
+                    .host()
+                    .or_else(|| req.headers().get(HOST).and_then(|h| h.to_str().ok()));
+                tracing::debug!("{host}, {:?}", &host_from_req);
+                host_from_req.eq(&Some(host))
+            }
+            CompiledPredicate::Path(path_re) => path_re.is_match(uri.path()),
+            CompiledPredicate::Method(method) => {
+                // a CORS preflight is routed like the request it announces
+                let req_method = if is_preflight(req) {
+                    req.headers()
+                        .get(ACCESS_CONTROL_REQUEST_METHOD)
+                        .and_then(|m| m.to_str().ok())
+                        .unwrap_or_default()
+                } else {
+                    req.method().as_str()
+                };
+                method.eq_ignore_ascii_case(req_method)
+            }
+            CompiledPredicate::Header { name, value } => req
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .any(|v| matches(value, v)),
+            CompiledPredicate::Query { name, value } => uri
+                .query()
+                .map(|q| {
+                    form_urlencoded::parse(q.as_bytes())
+                        .any(|(k, v)| k == name.as_str() && matches(value, &v))
+                })
+                .unwrap_or(false),
+            CompiledPredicate::Cookie { name, value } => req
+                .headers()
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|c| c.split_once('='))
+                .any(|(k, v)| k.trim() == name && matches(value, v.trim())),
+            CompiledPredicate::RemoteAddr(nets) => ctx
+                .client_ip
+                .map(|ip| nets.iter().any(|net| net.contains(&ip)))
+                .unwrap_or(false),
+            CompiledPredicate::Weight { group, route_id } => {
+                ctx.picked_route(group) == Some(route_id.as_str())
+            }
+        }
+    }
+}
+
+fn matches(regex: &Option<Regex>, value: &str) -> bool {
+    regex.as_ref().map(|re| re.is_match(value)).unwrap_or(true)
+}
+
+/// Compile an ant style path pattern (as in spring):
+/// - `?` matches one character, except `/`
+/// - `*` matches zero or more characters within a segment
+/// - `**` matches zero or more segments, e.g. `/api/**` matches `/api`, `/api/` and `/api/x/y`
+/// - `{name}` captures a segment, `{name:regex}` a segment matching the regex
+pub fn ant_pattern_to_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    let mut re = String::from("^");
+    for (i, segment) in pattern.split('/').enumerate() {
+        if segment == "**" {
+            re.push_str("(?:/.*)?");
+            continue;
+        }
+        if i > 0 {
+            re.push('/');
+        }
+        let mut chars = segment.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => {
+                    // `**` inside a segment behaves like `*`
+                    while chars.peek() == Some(&'*') {
+                        chars.next();
+                    }
+                    re.push_str("[^/]*");
+                }
+                '?' => re.push_str("[^/]"),
+                '{' => {
+                    let variable: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                    match variable.split_once(':') {
+                        Some((name, constraint)) => {
+                            re.push_str(&format!("(?P<{name}>{constraint})"))
+                        }
+                        None => re.push_str(&format!("(?P<{variable}>[^/]+)")),
+                    }
+                }
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+    }
+    re.push('$');
+    Regex::new(&re)
+}
+
+#[cfg(test)]
+mod test {
+    use super::ant_pattern_to_regex;
+
+    #[test]
+    fn test_ant_pattern() {
+        let re = ant_pattern_to_regex("/api/**").unwrap();
+        assert!(re.is_match("/api"));
+        assert!(re.is_match("/api/"));
+        assert!(re.is_match("/api/invoice/find-all"));
+        assert!(!re.is_match("/apis"));
+        assert!(!re.is_match("/other/api"));
+
+        let re = ant_pattern_to_regex("/invoice/*.pdf").unwrap();
+        assert!(re.is_match("/invoice/2024-01.pdf"));
+        assert!(!re.is_match("/invoice/2024/01.pdf"));
+
+        let re = ant_pattern_to_regex("/v?/person/**/find-one").unwrap();
+        assert!(re.is_match("/v2/person/find-one"));
+        assert!(re.is_match("/v2/person/a/b/find-one"));
+        assert!(!re.is_match("/v22/person/find-one"));
+
+        let re = ant_pattern_to_regex("/person/find-one/{id}").unwrap();
+        let captures = re.captures("/person/find-one/42").unwrap();
+        assert_eq!(Some("42"), captures.name("id").map(|c| c.as_str()));
+        assert!(!re.is_match("/person/find-one/42/43"));
+
+        let re = ant_pattern_to_regex("/person/find-one/{id:[0-9]+}").unwrap();
+        assert!(re.is_match("/person/find-one/42"));
+        assert!(!re.is_match("/person/find-one/abc"));
+
+        let re = ant_pattern_to_regex("/product/tag/search/**").unwrap();
+        assert!(re.is_match("/product/tag/search/abc"));
+        assert!(!re.is_match("/product/tag/searchabc"));
+    }
+}
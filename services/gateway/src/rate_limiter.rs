@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use redis::Script;
+
+use crate::{
+    config::{RateLimit, RateLimitKey},
+    redis_connection::RedisConnection,
+};
+
+const MAX_LOCAL_BUCKETS: usize = 10_000;
+/// a full map is cleaned down to this size, so that the next keys do not scan it again
+const LOCAL_BUCKETS_AFTER_CLEANUP: usize = MAX_LOCAL_BUCKETS - MAX_LOCAL_BUCKETS / 10;
+
+/// Token bucket, refilled by `refill_rate` tokens / ms. Expired buckets are
+/// removed from redis by the script, using the time needed to refill completely.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local refill_rate = tonumber(ARGV[2])
+local requested = tonumber(ARGV[3])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(bucket[1]) or capacity
+local ts = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * refill_rate)
+local retry_after = 0
+if tokens >= requested then
+  tokens = tokens - requested
+else
+  retry_after = math.ceil((requested - tokens) / refill_rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(capacity / refill_rate) + 1000)
+return retry_after
+"#;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RateLimitDecision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+/// Token bucket rate limiter of a route. Buckets are kept in memory,
+/// or in redis when the limit must be shared between gateway instances.
+#[derive(Debug)]
+pub struct RateLimiter {
+    route_id: String,
+    pub key: RateLimitKey,
+    capacity: f64,
+    requested_tokens: f64,
+    /// tokens per millisecond
+    refill_rate: f64,
+    redis: Option<RedisConnection>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        route_id: &str,
+        rate_limit: RateLimit,
+        redis: Option<RedisConnection>,
+    ) -> Result<Self, String> {
+        let RateLimit {
+            key,
+            replenish_rate,
+            burst_capacity,
+            period_secs,
+            requested_tokens,
+            shared,
+        } = rate_limit;
+        let period_secs = period_secs.unwrap_or(1);
+        let burst_capacity = burst_capacity.unwrap_or(replenish_rate);
+        let requested_tokens = requested_tokens.unwrap_or(1);
+        if replenish_rate == 0 || period_secs == 0 {
+            return Err("replenish_rate and period_secs must be greater than 0".into());
+        }
+        if requested_tokens > burst_capacity {
+            return Err("requested_tokens cannot be greater than burst_capacity".into());
+        }
+        let redis = match (shared.unwrap_or(false), redis) {
+            (true, None) => {
+                tracing::warn!(
+                    "route {route_id}: shared rate limit without redis, falling back to memory"
+                );
+                None
+            }
+            (true, redis) => redis,
+            (false, _) => None,
+        };
+        Ok(RateLimiter {
+            route_id: route_id.to_string(),
+            key,
+            capacity: burst_capacity as f64,
+            requested_tokens: requested_tokens as f64,
+            refill_rate: replenish_rate as f64 / (period_secs as f64 * 1000.),
+            redis,
+            buckets: Default::default(),
+        })
+    }
+
+    pub async fn check(&self, key: &str) -> RateLimitDecision {
+        let key = format!("sequeda:rate-limit:{}:{key}", self.route_id);
+        match &self.redis {
+            Some(redis) => match self.check_redis(redis, &key).await {
+                Ok(decision) => decision,
+                Err(e) => {
+                    // fail open, redis being down should not take the gateway down
+                    tracing::error!("could not check rate limit {key} in redis: {e}");
+                    redis.reset().await;
+                    RateLimitDecision::Allowed
+                }
+            },
+            None => self.check_local(&key, Instant::now()),
+        }
+    }
+
+    async fn check_redis(
+        &self,
+        redis: &RedisConnection,
+        key: &str,
+    ) -> redis::RedisResult<RateLimitDecision> {
+        let mut conn = redis.get().await?;
+        let retry_after_ms: u64 = Script::new(TOKEN_BUCKET_SCRIPT)
+            .key(key)
+            .arg(self.capacity)
+            .arg(self.refill_rate)
+            .arg(self.requested_tokens)
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(Self::decision(retry_after_ms as f64))
+    }
+
+    fn check_local(&self, key: &str, now: Instant) -> RateLimitDecision {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_LOCAL_BUCKETS && !buckets.contains_key(key) {
+            let full_after = self.capacity / self.refill_rate;
+            buckets
+                .retain(|_, b| (now.duration_since(b.last_refill).as_millis() as f64) < full_after);
+            // rotating keys (e.g. ipv6 addresses): the least recently used buckets are dropped
+            if buckets.len() > LOCAL_BUCKETS_AFTER_CLEANUP {
+                let mut refills: Vec<Instant> = buckets.values().map(|b| b.last_refill).collect();
+                let evicted = buckets.len() - LOCAL_BUCKETS_AFTER_CLEANUP;
+                let (_, oldest_kept, _) = refills.select_nth_unstable(evicted);
+                let oldest_kept = *oldest_kept;
+                buckets.retain(|_, b| b.last_refill >= oldest_kept);
+            }
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_millis() as f64;
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_rate).min(self.capacity);
+        bucket.last_refill = now;
+        if bucket.tokens >= self.requested_tokens {
+            bucket.tokens -= self.requested_tokens;
+            RateLimitDecision::Allowed
+        } else {
+            Self::decision(((self.requested_tokens - bucket.tokens) / self.refill_rate).ceil())
+        }
+    }
+
+    fn decision(retry_after_ms: f64) -> RateLimitDecision {
+        if retry_after_ms <= 0. {
+            RateLimitDecision::Allowed
+        } else {
+            RateLimitDecision::Limited {
+                retry_after: Duration::from_millis(retry_after_ms as u64),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::config::{RateLimit, RateLimitKey};
+
+    use super::{RateLimitDecision, RateLimiter, LOCAL_BUCKETS_AFTER_CLEANUP, MAX_LOCAL_BUCKETS};
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(
+            "template_render",
+            RateLimit {
+                key: RateLimitKey::User,
+                replenish_rate: 1,
+                burst_capacity: Some(2),
+                period_secs: Some(10),
+                requested_tokens: None,
+                shared: None,
+            },
+            None,
+        )
+        .unwrap();
+        let now = Instant::now();
+        assert_eq!(RateLimitDecision::Allowed, limiter.check_local("nb", now));
+        assert_eq!(RateLimitDecision::Allowed, limiter.check_local("nb", now));
+        assert_eq!(
+            RateLimitDecision::Limited {
+                retry_after: Duration::from_secs(10)
+            },
+            limiter.check_local("nb", now)
+        );
+        assert_eq!(RateLimitDecision::Allowed, limiter.check_local("bob", now));
+        assert_eq!(
+            RateLimitDecision::Allowed,
+            limiter.check_local("nb", now + Duration::from_secs(10))
+        );
+
+        // none of the buckets is refilled, the oldest ones are dropped
+        limiter.buckets.lock().unwrap().clear();
+        for i in 0..MAX_LOCAL_BUCKETS {
+            limiter.check_local(&i.to_string(), now + Duration::from_millis(i as u64));
+        }
+        let later = now + Duration::from_millis(MAX_LOCAL_BUCKETS as u64);
+        limiter.check_local("new", later);
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() <= LOCAL_BUCKETS_AFTER_CLEANUP + 1);
+        assert!(buckets.contains_key("new"));
+        assert!(buckets.contains_key(&(MAX_LOCAL_BUCKETS - 1).to_string()));
+        assert!(!buckets.contains_key("0"));
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use redis::{aio::MultiplexedConnection, Client, RedisResult};
+use tokio::sync::Mutex;
+
+/// Lazy, shared connection to redis. Connects on first use and reconnects
+/// after an error, so that the gateway can start while redis is down.
+#[derive(Clone)]
+pub struct RedisConnection {
+    client: Client,
+    connection: Arc<Mutex<Option<MultiplexedConnection>>>,
+}
+
+impl std::fmt::Debug for RedisConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisConnection")
+            .field("client", &self.client.get_connection_info().addr)
+            .finish()
+    }
+}
+
+impl RedisConnection {
+    pub fn new(redis_url: &str) -> RedisResult<Self> {
+        Ok(RedisConnection {
+            client: Client::open(redis_url)?,
+            connection: Default::default(),
+        })
+    }
+
+    pub async fn get(&self) -> RedisResult<MultiplexedConnection> {
+        let mut connection = self.connection.lock().await;
+        match connection.as_ref() {
+            Some(conn) => Ok(conn.clone()),
+            None => {
+                let conn = self.client.get_multiplexed_tokio_connection().await?;
+                *connection = Some(conn.clone());
+                Ok(conn)
+            }
+        }
+    }
+
+    /// drop the cached connection, next call to `get` will reconnect
+    pub async fn reset(&self) {
+        self.connection.lock().await.take();
+    }
+}
@@ -1,22 +1,55 @@
-use std::{error::Error, fmt::Display, str::FromStr};
+use std::{
+    error::Error,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use axum::{
     body::Body,
+    extract::ConnectInfo,
     http::HeaderValue,
-    http::{HeaderName, Request},
+    http::{Extensions, HeaderMap, Request, Response},
 };
 use base64::Engine;
-use hyper::{header::HOST, StatusCode, Uri};
-use regex::Regex;
-use sequeda_service_common::X_USER_INFO_HEADER;
+use hyper::{header::HOST, Method, StatusCode, Uri};
+use sequeda_service_common::{user_token::UserTokenSigner, X_USER_INFO_HEADER};
+use serde::Serialize;
 
 use crate::{
-    config::{Authorization, Config, Route},
+    access_log::MatchedRoute,
+    authorization::CompiledAuthorization,
+    cache::{CacheContext, CacheLookup, ResponseCache},
+    config::{Authorization, Config, ConfigError, OpenApiSource, Predicate, RateLimitKey, Route},
+    filter::{self, CompiledFilter, ResponseContext},
+    load_balancer::{UpstreamGuard, UpstreamPool},
     openid::User,
+    predicate::{CompiledPredicate, MatchContext, WeightGroups},
+    rate_limiter::{RateLimitDecision, RateLimiter},
+    redis_connection::RedisConnection,
+    upgrade::remove_hop_by_hop_headers,
+    Client,
 };
+
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+
+/// Runtime dependencies of the compiled routes
+#[derive(Debug, Clone, Default)]
+pub struct HandlerContext {
+    pub redis: Option<RedisConnection>,
+    /// default allowed origins of the cors filter, comma separated
+    pub cors_allow_origin: Option<String>,
+    /// number of proxies in front of the gateway appending to `X-Forwarded-For`.
+    /// 0 to use the address of the connection
+    pub trusted_proxies: usize,
+    /// signs `X-USER-INFO`. Without it, the user is sent as base64 json
+    pub user_token_signer: Option<Arc<UserTokenSigner>>,
+}
 #[derive(Debug)]
 pub struct RequestHandlerError {
     pub status: Option<StatusCode>,
+    pub retry_after: Option<Duration>,
     msg: String,
 }
 
@@ -27,88 +60,157 @@ impl Display for RequestHandlerError {
         write!(f, "{}", self.msg)
     }
 }
+/// Outcome of a matched route
+#[derive(Debug)]
+pub enum Handled {
+    /// forward the request to the upstream, then filter its response
+    Proxy(ProxyContext),
+    /// answered by the gateway itself (redirect, CORS preflight, cached response)
+    Respond(Response<Body>),
+}
+
+/// Selected upstream of a request, and the filters to apply to its response
+#[derive(Debug)]
+pub struct ProxyContext {
+    pub upstream: UpstreamGuard,
+    filters: Arc<Vec<CompiledFilter>>,
+    response_context: ResponseContext,
+    cache: Option<CacheContext>,
+}
+
+impl ProxyContext {
+    pub async fn filter_response(&self, response: Response<Body>) -> Response<Body> {
+        let response = match &self.cache {
+            Some(cache) => cache.store(response).await,
+            None => response,
+        };
+        filter::filter_response(&self.filters, &self.response_context, response).await
+    }
+}
+
+/// The response filters of the matched route, added to the extensions of the request
+/// to filter the responses of the gateway itself
+#[derive(Debug, Clone)]
+pub struct RouteFilters {
+    filters: Arc<Vec<CompiledFilter>>,
+    response_context: ResponseContext,
+}
+
+impl RouteFilters {
+    pub fn filter_gateway_response(&self, response: Response<Body>) -> Response<Body> {
+        filter::filter_gateway_response(&self.filters, &self.response_context, response)
+    }
+}
+
 #[derive(Debug)]
 pub struct RequestHandler {
     handlers: Vec<RouteHandler>,
+    weight_groups: WeightGroups,
+    trusted_proxies: usize,
+    user_token_signer: Option<Arc<UserTokenSigner>>,
+    openapi: Vec<OpenApiSource>,
+}
+
+/// Which route a request would take, and why
+#[derive(Debug, Serialize)]
+pub struct DryRun {
+    pub route: Option<String>,
+    /// path and query sent to the upstream
+    pub upstream_path: Option<String>,
+    /// the routes evaluated before the first match, in order
+    pub routes: Vec<RouteEvaluation>,
+    /// authorizations of the matched route applying to the request
+    pub authorizations: Vec<AuthorizationEvaluation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RouteEvaluation {
+    pub id: String,
+    pub matched: bool,
+    pub predicates: Vec<PredicateEvaluation>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PredicateEvaluation {
+    pub predicate: Predicate,
+    pub matched: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthorizationEvaluation {
+    pub authorization: Authorization,
+    /// none without a user
+    pub allowed: Option<bool>,
+}
+
+/// The request handler currently in use. Swapped atomically when the config is reloaded,
+/// requests in flight keep the handler they started with.
+#[derive(Debug, Clone)]
+pub struct SharedRequestHandler(Arc<RwLock<Arc<RequestHandler>>>);
+
+impl SharedRequestHandler {
+    pub fn new(request_handler: RequestHandler) -> Self {
+        SharedRequestHandler(Arc::new(RwLock::new(Arc::new(request_handler))))
+    }
+    pub fn load(&self) -> Arc<RequestHandler> {
+        self.0.read().unwrap().clone()
+    }
+    pub fn store(&self, request_handler: RequestHandler) {
+        *self.0.write().unwrap() = Arc::new(request_handler);
+    }
 }
 
 impl RequestHandler {
-    pub fn from_config(config: Config) -> Self {
+    /// compile the routes of the config. Fails on the first invalid route,
+    /// without side effects, so that a bad config can be safely rejected.
+    pub fn from_config(config: Config, context: &HandlerContext) -> Result<Self, ConfigError> {
         let mut route_handlers: Vec<RouteHandler> = vec![];
+        let mut weight_groups = WeightGroups::default();
         for route in config.routes {
+            let upstreams = route.upstreams();
+            let config = route.clone();
             let Route {
                 id,
-                uri,
+                load_balancer,
                 predicates,
                 filters,
                 authorizations,
+                ..
             } = route;
             tracing::info!("adding route with id {id}");
 
             if route_handlers.iter().any(|r| r.id == id) {
-                panic!("duplicate id in config {id}");
+                return Err(ConfigError::new(format!("duplicate id in config {id}")));
             }
 
             let mut compiled_predicates = vec![];
             let mut compiled_filters = vec![];
 
             for predicate in predicates.unwrap_or_default() {
-                let compiled_predicate = match predicate {
-                    crate::config::Predicate::Host(host) => CompiledPredicate::Host(host),
-                    crate::config::Predicate::Path(path) => {
-                        CompiledPredicate::Path(Regex::new(&path).unwrap())
-                    }
-                    crate::config::Predicate::Method(method) => CompiledPredicate::Method(method),
-                };
-                compiled_predicates.push(compiled_predicate);
+                compiled_predicates.push(CompiledPredicate::compile(
+                    &id,
+                    predicate,
+                    &mut weight_groups,
+                )?);
             }
 
             for filter in filters.unwrap_or_default() {
-                let compiled_filter = match filter {
-                    crate::config::Filter::RewritePath { source, dest } => {
-                        CompiledFilter::RewritePath {
-                            source: Regex::new(&source).unwrap(),
-                            dest,
-                        }
-                    }
-                    crate::config::Filter::AddRequestHeader { key, value } => {
-                        CompiledFilter::AddRequestHeader {
-                            key: HeaderName::from_str(&key).unwrap(),
-                            value: HeaderValue::from_str(&value).unwrap(),
-                        }
-                    }
-                    crate::config::Filter::RemoveRequestHeader(header) => {
-                        CompiledFilter::RemoveRequestHeader(HeaderName::from_str(&header).unwrap())
-                    }
-                };
-                compiled_filters.push(compiled_filter);
+                compiled_filters.push(CompiledFilter::compile(&id, filter, context)?);
             }
 
-            let host = {
-                let iri = Uri::try_from(&uri).unwrap();
-                HeaderValue::from_str(iri.host().unwrap()).unwrap()
-            };
+            let upstreams = UpstreamPool::new(&id, upstreams, load_balancer)
+                .map(Arc::new)
+                .map_err(|e| ConfigError::new(format!("route {id}: {e}")))?;
             let mut compiled_auth = vec![];
 
-            if let Some(authorizations) = authorizations {
-                for Authorization {
-                    method,
-                    has_roles,
-                    has_groups,
-                } in authorizations
-                {
-                    compiled_auth.push(CompiledAuthorization {
-                        method,
-                        has_groups,
-                        has_roles,
-                    })
-                }
+            for authorization in authorizations.unwrap_or_default() {
+                compiled_auth.push(CompiledAuthorization::compile(&id, authorization)?);
             }
             let route = RouteHandler {
-                uri,
                 id,
-                host,
-                filters: compiled_filters,
+                config,
+                upstreams,
+                filters: Arc::new(compiled_filters),
                 predicates: compiled_predicates,
                 authorizations: compiled_auth,
             };
@@ -117,20 +219,145 @@ impl RequestHandler {
 
             route_handlers.push(route);
         }
-        RequestHandler {
+        Ok(RequestHandler {
             handlers: route_handlers,
+            weight_groups,
+            trusted_proxies: context.trusted_proxies,
+            user_token_signer: context.user_token_signer.clone(),
+            openapi: config.openapi.unwrap_or_default(),
+        })
+    }
+
+    /// the loaded routes, in the order they are matched
+    pub fn routes(&self) -> Vec<&Route> {
+        self.handlers.iter().map(|h| &h.config).collect()
+    }
+
+    pub fn openapi_sources(&self) -> &[OpenApiSource] {
+        &self.openapi
+    }
+
+    /// evaluates the predicates of the routes like `handle`, without calling the upstream.
+    /// The authorizations are checked against `user` when given
+    pub fn dry_run(
+        &self,
+        req: &Request<Body>,
+        client_ip: Option<IpAddr>,
+        user: Option<&User>,
+    ) -> DryRun {
+        let mut match_context = MatchContext::new(
+            client_ip.or_else(|| self.client_ip(req)),
+            &self.weight_groups,
+        );
+        let uri = req.uri();
+        let mut routes = vec![];
+        for handler in &self.handlers {
+            let predicates: Vec<PredicateEvaluation> = handler
+                .config
+                .predicates
+                .iter()
+                .flatten()
+                .zip(&handler.predicates)
+                .map(|(predicate, compiled)| PredicateEvaluation {
+                    predicate: predicate.clone(),
+                    matched: compiled.match_req(req, &mut match_context),
+                })
+                .collect();
+            let matched = predicates.iter().all(|p| p.matched);
+            routes.push(RouteEvaluation {
+                id: handler.id.clone(),
+                matched,
+                predicates,
+            });
+            if !matched {
+                continue;
+            }
+            let authorizations = handler
+                .config
+                .authorizations
+                .iter()
+                .flatten()
+                .zip(&handler.authorizations)
+                .filter(|(_, compiled)| compiled.applies(req.method().as_str(), uri.path()))
+                .map(|(authorization, compiled)| AuthorizationEvaluation {
+                    authorization: authorization.clone(),
+                    allowed: user.map(|user| compiled.check_auth(uri.path(), user)),
+                })
+                .collect();
+            return DryRun {
+                route: Some(handler.id.clone()),
+                upstream_path: Some(handler.rewrite_path(uri)),
+                routes,
+                authorizations,
+            };
+        }
+        DryRun {
+            route: None,
+            upstream_path: None,
+            routes,
+            authorizations: vec![],
+        }
+    }
+
+    /// removes the cached responses of a route (every route when `None`), for the tenant, under the path prefix.
+    /// `None` when the route does not exist
+    pub fn invalidate_cache(
+        &self,
+        route_id: Option<&str>,
+        tenant: Option<&str>,
+        prefix: Option<&str>,
+    ) -> Option<usize> {
+        let handlers: Vec<&RouteHandler> = self
+            .handlers
+            .iter()
+            .filter(|h| route_id.is_none_or(|id| h.id == id))
+            .collect();
+        if handlers.is_empty() && route_id.is_some() {
+            return None;
         }
+        Some(
+            handlers
+                .iter()
+                .filter_map(|h| h.cache())
+                .map(|cache| cache.invalidate(tenant, prefix))
+                .sum(),
+        )
+    }
+
+    /// start the active health checks of the routes that configured one
+    pub fn spawn_health_checks(&self, client: &Client) {
+        for handler in &self.handlers {
+            handler.upstreams.spawn_health_check(client.clone());
+        }
+    }
+
+    /// the response filters of the route the request would take
+    pub fn route_filters(&self, req: &Request<Body>) -> Option<RouteFilters> {
+        let mut match_context = MatchContext::new(self.client_ip(req), &self.weight_groups);
+        self.handlers
+            .iter()
+            .find(|h| {
+                h.predicates
+                    .iter()
+                    .all(|p| p.match_req(req, &mut match_context))
+            })
+            .map(|handler| RouteFilters {
+                filters: handler.filters.clone(),
+                response_context: ResponseContext::new(req),
+            })
     }
 
     pub async fn handle(
         &self,
         req: &mut Request<Body>,
         user: Option<User>,
-    ) -> Result<(), RequestHandlerError> {
-        let handler = self
-            .handlers
-            .iter()
-            .find(|h| h.predicates.iter().all(|p| p.match_req(req)));
+    ) -> Result<Handled, RequestHandlerError> {
+        let mut match_context = MatchContext::new(self.client_ip(req), &self.weight_groups);
+        let handler = self.handlers.iter().find(|h| {
+            h.predicates
+                .iter()
+                .all(|p| p.match_req(req, &mut match_context))
+        });
         let uri = req.uri().clone();
 
         tracing::debug!(
@@ -139,17 +366,22 @@ impl RequestHandler {
         );
 
         if let Some(handler) = handler {
-            let mut path = uri
-                .path_and_query()
-                .map(|v| v.as_str())
-                .unwrap_or_else(|| uri.path())
-                .to_string();
+            req.extensions_mut()
+                .insert(MatchedRoute(handler.id.clone()));
+            if let Some(response) = filter::short_circuit(&handler.filters, req) {
+                return Ok(Handled::Respond(response));
+            }
+            let response_context = ResponseContext::new(req);
+            req.extensions_mut().insert(RouteFilters {
+                filters: handler.filters.clone(),
+                response_context: response_context.clone(),
+            });
+            // only the gateway can tell who the user is
+            req.headers_mut().remove(X_USER_INFO_HEADER);
+            let path = handler.rewrite_path(&uri);
 
-            for filter in &handler.filters {
+            for filter in handler.filters.iter() {
                 match filter {
-                    CompiledFilter::RewritePath { source, dest } => {
-                        path = source.replace(&path, dest).to_string();
-                    }
                     CompiledFilter::AddRequestHeader { key, value } => {
                         let headers = req.headers_mut();
                         headers.append(key, value.clone());
@@ -158,30 +390,30 @@ impl RequestHandler {
                         let headers = req.headers_mut();
                         headers.remove(header_name);
                     }
+                    _ => {} // rate limit is checked once the user is authorized, others apply to the response
                 }
             }
             let autorizations: Vec<&CompiledAuthorization> = handler
                 .authorizations
                 .iter()
-                .filter(|auth| auth.method.eq_ignore_ascii_case(req.method().as_str()))
+                .filter(|auth| auth.applies(req.method().as_str(), uri.path()))
                 .collect();
 
-            if let Some(user) = user {
+            if let Some(user) = &user {
                 for authorization in autorizations {
-                    if !authorization.check_auth(req.method().as_str(), &user) {
+                    if !authorization.check_auth(uri.path(), user) {
                         return Err(RequestHandlerError {
+                            retry_after: None,
                             status: Some(StatusCode::FORBIDDEN),
                             msg: "Forbidden access".into(),
                         });
                     }
                 }
-                if let Some(user_encoded) = serde_json::to_string(&user)
-                    .ok()
-                    .map(|u| base64::engine::general_purpose::STANDARD.encode(u))
-                {
+                if let Some(user_encoded) = self.encode_user(user) {
                     req.headers_mut().insert(
                         X_USER_INFO_HEADER,
                         HeaderValue::from_str(&user_encoded).map_err(|e| RequestHandlerError {
+                            retry_after: None,
                             msg: e.to_string(),
                             status: None,
                         })?,
@@ -189,105 +421,359 @@ impl RequestHandler {
                 }
             } else if !autorizations.is_empty() {
                 return Err(RequestHandlerError {
+                    retry_after: None,
                     status: Some(StatusCode::UNAUTHORIZED),
                     msg: "Could not retrieve user".to_string(),
                 });
             }
 
-            let uri = &handler.uri;
+            for filter in handler.filters.iter() {
+                if let CompiledFilter::RateLimit(rate_limiter) = filter {
+                    let key = self.rate_limit_key(rate_limiter, req, user.as_ref());
+                    if let RateLimitDecision::Limited { retry_after } =
+                        rate_limiter.check(&key).await
+                    {
+                        tracing::debug!("route {}: rate limit exceeded for {key}", handler.id);
+                        return Err(RequestHandlerError {
+                            status: Some(StatusCode::TOO_MANY_REQUESTS),
+                            retry_after: Some(retry_after),
+                            msg: "Too many requests".into(),
+                        });
+                    }
+                }
+            }
+
+            let mut cache = None;
+            if let Some(response_cache) = handler.cache() {
+                let tenant = user.as_ref().and_then(|u| u.tenant.as_deref());
+                if !req.method().is_safe() {
+                    if response_cache.invalidate_on_write() {
+                        response_cache.invalidate(tenant, None);
+                    }
+                } else if req.method() == Method::GET || req.method() == Method::HEAD {
+                    match CacheContext::lookup(response_cache, req, &uri, user.as_ref()) {
+                        CacheLookup::Hit(response) => {
+                            let response = filter::filter_response(
+                                &handler.filters,
+                                &response_context,
+                                response,
+                            )
+                            .await;
+                            return Ok(Handled::Respond(response));
+                        }
+                        CacheLookup::Forward(cache_context) => cache = Some(cache_context),
+                        CacheLookup::Bypass => {}
+                    }
+                }
+            }
+
+            let upstream = handler
+                .upstreams
+                .select()
+                .ok_or_else(|| RequestHandlerError {
+                    retry_after: None,
+                    status: Some(StatusCode::SERVICE_UNAVAILABLE),
+                    msg: format!("No healthy upstream for route {}", handler.id),
+                })?;
+            let uri = upstream.uri();
             let uri = Uri::try_from(format!("{uri}{path}")).map_err(|e| RequestHandlerError {
+                retry_after: None,
                 msg: e.to_string(),
                 status: None,
             })?;
             tracing::debug!("uri {uri}");
             *req.uri_mut() = uri;
+            remove_hop_by_hop_headers(req.headers_mut());
             req.headers_mut().remove(HOST);
-            req.headers_mut().insert(HOST, handler.host.clone());
+            req.headers_mut().insert(HOST, upstream.host().clone());
 
             tracing::debug!("headers {:?}", req.headers());
+            Ok(Handled::Proxy(ProxyContext {
+                upstream,
+                filters: handler.filters.clone(),
+                response_context,
+                cache,
+            }))
         } else {
-            return Err(RequestHandlerError {
+            Err(RequestHandlerError {
+                retry_after: None,
                 status: None,
                 msg: format!("Could not find an handler for that uri {}", req.uri()),
-            });
+            })
         }
-
-        Ok(())
     }
 }
 
-#[derive(Debug)]
-struct RouteHandler {
-    id: String,
-    uri: String,
-    host: HeaderValue,
-    predicates: Vec<CompiledPredicate>,
-    filters: Vec<CompiledFilter>,
-    authorizations: Vec<CompiledAuthorization>,
+impl RequestHandler {
+    fn encode_user(&self, user: &User) -> Option<String> {
+        match &self.user_token_signer {
+            Some(signer) => signer
+                .sign(user)
+                .map_err(|e| tracing::error!("could not sign user info: {e}"))
+                .ok(),
+            None => serde_json::to_string(user)
+                .ok()
+                .map(|u| base64::engine::general_purpose::STANDARD.encode(u)),
+        }
+    }
+
+    fn client_ip(&self, req: &Request<Body>) -> Option<IpAddr> {
+        client_ip(req.headers(), req.extensions(), self.trusted_proxies)
+    }
+
+    /// the bucket to take a token from. Falls back to the client ip for anonymous requests
+    fn rate_limit_key(
+        &self,
+        rate_limiter: &RateLimiter,
+        req: &Request<Body>,
+        user: Option<&User>,
+    ) -> String {
+        let client_ip = || {
+            self.client_ip(req)
+                .map(|ip| format!("ip:{ip}"))
+                .unwrap_or_else(|| "ip:unknown".into())
+        };
+        match rate_limiter.key {
+            RateLimitKey::User => user
+                .map(|u| format!("user:{}", u.id))
+                .unwrap_or_else(client_ip),
+            RateLimitKey::Tenant => user
+                .and_then(|u| u.tenant.as_ref())
+                .map(|t| format!("tenant:{t}"))
+                .unwrap_or_else(client_ip),
+            RateLimitKey::ClientIp => client_ip(),
+            RateLimitKey::Route => "route".into(),
+        }
+    }
 }
-#[derive(Debug)]
-enum CompiledPredicate {
-    Host(String),
-    Path(Regex),
-    Method(String),
+
+/// the address the request comes from, or the one forwarded by the trusted proxies
+pub fn client_ip(
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    trusted_proxies: usize,
+) -> Option<IpAddr> {
+    let forwarded_for = (trusted_proxies > 0)
+        .then(|| headers.get(X_FORWARDED_FOR))
+        .flatten()
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| forwarded_client_ip(h, trusted_proxies));
+    forwarded_for.or_else(|| {
+        extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    })
 }
 
-#[derive(Debug)]
-enum CompiledFilter {
-    RewritePath { source: Regex, dest: String },
-    AddRequestHeader { key: HeaderName, value: HeaderValue },
-    RemoveRequestHeader(HeaderName),
+/// the address seen by the farthest trusted proxy. Each proxy appends the address it
+/// received the request from, the entries on the left are sent by the client
+fn forwarded_client_ip(forwarded_for: &str, trusted_proxies: usize) -> Option<IpAddr> {
+    let entries: Vec<&str> = forwarded_for.split(',').map(str::trim).collect();
+    entries
+        .get(entries.len().saturating_sub(trusted_proxies))
+        .and_then(|ip| ip.parse().ok())
 }
 
 #[derive(Debug)]
-struct CompiledAuthorization {
-    method: String,
-    has_roles: Option<Vec<String>>,
-    has_groups: Option<Vec<String>>,
+struct RouteHandler {
+    id: String,
+    /// the route as configured
+    config: Route,
+    upstreams: Arc<UpstreamPool>,
+    predicates: Vec<CompiledPredicate>,
+    filters: Arc<Vec<CompiledFilter>>,
+    authorizations: Vec<CompiledAuthorization>,
 }
 
-impl CompiledAuthorization {
-    fn check_auth(&self, method: &str, user: &User) -> bool {
-        if !self.method.eq_ignore_ascii_case(method) {
-            true
-        } else {
-            let has_roles = if let Some(roles) = &self.has_roles {
-                roles.iter().any(|a| user.roles.contains(a)) // or
-            } else {
-                true
-            };
-            let has_groups = if let Some(groups) = &self.has_groups {
-                groups.iter().all(|a| user.groups.contains(a)) // and
-            } else {
-                true
-            };
-            has_roles && has_groups
+impl RouteHandler {
+    /// path and query of the upstream request
+    fn rewrite_path(&self, uri: &Uri) -> String {
+        let mut path = uri
+            .path_and_query()
+            .map(|v| v.as_str())
+            .unwrap_or_else(|| uri.path())
+            .to_string();
+        for filter in self.filters.iter() {
+            if let CompiledFilter::RewritePath { source, dest } = filter {
+                path = source.replace(&path, dest).to_string();
+            }
         }
+        path
     }
-}
 
-impl CompiledPredicate {
-    fn match_req(&self, req: &Request<Body>) -> bool {
-        let uri = req.uri();
-        match self {
-            CompiledPredicate::Host(host) => {
-                let host_from_req = uri
-                    .host()
-                    .or_else(|| req.headers().get(HOST).and_then(|h| h.to_str().ok()));
-                tracing::debug!("{host}, {:?}", &host_from_req);
-                host_from_req.eq(&Some(host))
-            }
-            CompiledPredicate::Path(path_re) => path_re.is_match(uri.path()),
-            CompiledPredicate::Method(method) => method.eq_ignore_ascii_case(req.method().as_str()),
-        }
+    fn cache(&self) -> Option<&Arc<ResponseCache>> {
+        self.filters.iter().find_map(|filter| match filter {
+            CompiledFilter::Cache(cache) => Some(cache),
+            _ => None,
+        })
     }
 }
 
 #[cfg(test)]
 mod test {
+    use axum::{body::Body, http::Request};
     use hyper::Uri;
     use regex::Regex;
 
+    use crate::{config::Config, openid::User, predicate::MatchContext};
+
+    use super::{HandlerContext, RequestHandler, X_FORWARDED_FOR};
+
+    #[test]
+    fn test_weight_predicate() {
+        let config = Config::deserialize(
+            r#"
+         order: 0
+         routes:
+            - id: invoice_canary
+              uri: http://invoice-canary
+              predicates:
+              - !path /invoice/**
+              - !weight
+                 group: invoice
+                 weight: 0
+            - id: invoice
+              uri: http://invoice
+              predicates:
+              - !path /invoice/**
+              - !weight
+                 group: invoice
+                 weight: 100
+        "#,
+        );
+        let request_handler = RequestHandler::from_config(config, &Default::default()).unwrap();
+        for _ in 0..10 {
+            let req = Request::get("/invoice/find-all")
+                .body(Body::empty())
+                .unwrap();
+            let mut ctx = MatchContext::new(None, &request_handler.weight_groups);
+            let handler = request_handler
+                .handlers
+                .iter()
+                .find(|h| h.predicates.iter().all(|p| p.match_req(&req, &mut ctx)))
+                .unwrap();
+            assert_eq!("invoice", handler.id);
+        }
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let config = Config::deserialize(
+            r#"
+         order: 0
+         routes:
+            - id: invoice_upsert
+              uri: http://invoice
+              predicates:
+              - !path /invoice/**
+              - !method POST
+            - id: invoice_find_one
+              uri: http://invoice
+              predicates:
+              - !path /invoice/find-one/**
+              filters:
+              - !rewrite_path
+                 source: /invoice/find-one/(?P<segment>.*)
+                 dest: /find-one/${segment}
+              authorizations:
+              - !authorization
+                 method: GET
+                 has_roles: [creep]
+        "#,
+        );
+        let request_handler = RequestHandler::from_config(config, &Default::default()).unwrap();
+        assert_eq!(2, request_handler.routes().len());
+
+        let req = Request::get("/invoice/find-one/42")
+            .body(Body::empty())
+            .unwrap();
+        let user: User = serde_json::from_value(serde_json::json!({
+            "id": "nbittich", "roles": ["demo"], "groups": []
+        }))
+        .unwrap();
+        let dry_run = request_handler.dry_run(&req, None, Some(&user));
+        assert_eq!(Some("invoice_find_one"), dry_run.route.as_deref());
+        assert_eq!(Some("/find-one/42"), dry_run.upstream_path.as_deref());
+        let upsert = &dry_run.routes[0];
+        assert!(!upsert.matched);
+        assert!(upsert.predicates[0].matched);
+        assert!(!upsert.predicates[1].matched);
+        assert_eq!(1, dry_run.authorizations.len());
+        assert_eq!(Some(false), dry_run.authorizations[0].allowed);
+
+        let req = Request::get("/person/find-all")
+            .body(Body::empty())
+            .unwrap();
+        let dry_run = request_handler.dry_run(&req, None, None);
+        assert_eq!(None, dry_run.route);
+        assert_eq!(2, dry_run.routes.len());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let config = Config::deserialize(
+            r#"
+         order: 0
+         routes:
+            - id: person
+              uri: http://person
+              filters:
+              - !add_request_header
+                 key: "X Forwarded Port"
+                 value: "443"
+        "#,
+        );
+        let err = RequestHandler::from_config(config, &Default::default()).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("route person: invalid header name `X Forwarded Port`"));
+
+        let config = Config::deserialize(
+            r#"
+         order: 0
+         routes:
+            - id: person
+              uri: http://person
+            - id: person
+              uri: http://person
+        "#,
+        );
+        let err = RequestHandler::from_config(config, &Default::default()).unwrap_err();
+        assert_eq!("duplicate id in config person", err.to_string());
+    }
+
+    #[test]
+    fn test_remote_addr_behind_proxy() {
+        let config = Config::deserialize(
+            r#"
+         order: 0
+         routes:
+            - id: admin
+              uri: http://admin
+              predicates:
+              - !path /admin/**
+              - !remote_addr ["10.0.0.0/8"]
+        "#,
+        );
+        let context = HandlerContext {
+            trusted_proxies: 1,
+            ..Default::default()
+        };
+        let request_handler = RequestHandler::from_config(config, &context).unwrap();
+        let dry_run = |forwarded_for: &str| {
+            let req = Request::get("/admin/config")
+                .header(X_FORWARDED_FOR, forwarded_for)
+                .body(Body::empty())
+                .unwrap();
+            request_handler.dry_run(&req, None, None).route
+        };
+        // the proxy appended the address it received the request from
+        assert_eq!(Some("admin".to_string()), dry_run("10.1.2.3"));
+        assert_eq!(Some("admin".to_string()), dry_run("203.0.113.9, 10.1.2.3"));
+        // the client spoofed the leftmost entry
+        assert_eq!(None, dry_run("10.1.2.3, 203.0.113.9"));
+    }
+
     #[test]
     fn test_path() {
         let regex = Regex::new("/hello/world/**").unwrap();
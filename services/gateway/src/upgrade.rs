@@ -0,0 +1,215 @@
+use axum::{
+    body::Body,
+    http::{HeaderMap, Request, Response},
+};
+use hyper::{
+    header::{CONNECTION, UPGRADE},
+    StatusCode,
+};
+use hyper_util::rt::TokioIo;
+
+use crate::Client;
+
+/// hop-by-hop headers, they concern a single connection and are not forwarded
+/// (https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1)
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// `Connection: upgrade` with `Upgrade: websocket`
+pub fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    let connection_upgrade = headers.get_all(CONNECTION).iter().any(|value| {
+        value
+            .to_str()
+            .map(|value| {
+                value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+            })
+            .unwrap_or(false)
+    });
+    connection_upgrade
+        && headers
+            .get(UPGRADE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("websocket"))
+}
+
+/// removes the hop-by-hop headers, and the ones named by `Connection`.
+/// A websocket upgrade keeps `Connection` and `Upgrade`, the upstream must see them
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let websocket = is_websocket_upgrade(headers);
+    let named: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in HOP_BY_HOP_HEADERS
+        .iter()
+        .copied()
+        .chain(named.iter().map(|name| name.as_str()))
+    {
+        if websocket && (name == "connection" || name == "upgrade") {
+            continue;
+        }
+        headers.remove(name);
+    }
+    if websocket {
+        headers.insert(CONNECTION, "upgrade".parse().unwrap());
+    }
+}
+
+/// Forwards the upgrade to the upstream. Once both sides switched protocols,
+/// the two connections are piped until one of them closes, without buffering
+pub async fn proxy_upgrade(
+    client: &Client,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, hyper_util::client::legacy::Error> {
+    let client_upgrade = hyper::upgrade::on(&mut req);
+    let mut response = client.request(req).await?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(response.map(Body::new));
+    }
+    let upstream_upgrade = hyper::upgrade::on(&mut response);
+    tokio::spawn(async move {
+        match tokio::try_join!(client_upgrade, upstream_upgrade) {
+            Ok((client, upstream)) => {
+                let mut client = TokioIo::new(client);
+                let mut upstream = TokioIo::new(upstream);
+                match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                    Ok((sent, received)) => {
+                        tracing::debug!(
+                            "upgraded connection closed, sent {sent}, received {received} bytes"
+                        )
+                    }
+                    Err(e) => tracing::debug!("upgraded connection closed: {e}"),
+                }
+            }
+            Err(e) => tracing::error!("could not upgrade the connection: {e}"),
+        }
+    });
+    let (parts, _) = response.into_parts();
+    Ok(Response::from_parts(parts, Body::empty()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use axum::{body::Body, extract::State, http::Request, routing::any, Router};
+    use hyper::{
+        body::Incoming,
+        header::{CONNECTION, UPGRADE},
+        server::conn::http1,
+        service::service_fn,
+        HeaderMap, Response, StatusCode,
+    };
+    use hyper_tls::HttpsConnector;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{is_websocket_upgrade, proxy_upgrade, remove_hop_by_hop_headers};
+    use crate::Client;
+
+    /// echoes everything it receives once upgraded
+    async fn echo_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|mut req: Request<Incoming>| async move {
+                tokio::spawn(async move {
+                    let upgraded = hyper::upgrade::on(&mut req).await.unwrap();
+                    let (mut reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+                    tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                });
+                Response::builder()
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header(CONNECTION, "upgrade")
+                    .header(UPGRADE, "websocket")
+                    .body(String::new())
+            });
+            http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    async fn gateway(
+        State((client, upstream)): State<(Client, SocketAddr)>,
+        mut req: Request<Body>,
+    ) -> Response<Body> {
+        *req.uri_mut() = format!("http://{upstream}/ws").parse().unwrap();
+        remove_hop_by_hop_headers(req.headers_mut());
+        proxy_upgrade(&client, req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, "keep-alive, Upgrade".parse().unwrap());
+        headers.insert(UPGRADE, "websocket".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        assert!(is_websocket_upgrade(&headers));
+        remove_hop_by_hop_headers(&mut headers);
+        assert_eq!("upgrade", headers[CONNECTION]);
+        assert_eq!("websocket", headers[UPGRADE]);
+        assert!(!headers.contains_key("keep-alive"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, "close, x-secret".parse().unwrap());
+        headers.insert("x-secret", "abc".parse().unwrap());
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        headers.insert("x-kept", "abc".parse().unwrap());
+        assert!(!is_websocket_upgrade(&headers));
+        remove_hop_by_hop_headers(&mut headers);
+        assert_eq!(1, headers.len());
+
+        let upstream = echo_upstream().await;
+        let client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
+            .build::<_, Body>(HttpsConnector::new());
+        let app = Router::new()
+            .route("/ws", any(gateway))
+            .with_state((client, upstream));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /ws HTTP/1.1\r\nHost: gateway\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut handshake = vec![];
+        while !handshake.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            handshake.push(byte[0]);
+        }
+        let handshake = String::from_utf8(handshake).unwrap();
+        assert!(handshake.starts_with("HTTP/1.1 101"), "{handshake}");
+
+        stream.write_all(b"live notification").await.unwrap();
+        let mut echo = [0u8; 17];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(b"live notification", &echo);
+    }
+}